@@ -1,4 +1,5 @@
 use yew::prelude::*;
+use crate::services::proxy_service::{fetch_upstreams, proxied_url};
 use crate::services::state_service::ContentType;
 use crate::components::content::main_svg_content::MainSvgContent;
 
@@ -24,20 +25,36 @@ pub fn content_renderer(props: &ContentRendererProps) -> Html {
         style
     };
 
+    // Third-party content is loaded through the server's proxy where it has one.
+    let upstreams = use_state(Vec::new);
+    {
+        let upstreams = upstreams.clone();
+        use_effect_with((), move |_| {
+            wasm_bindgen_futures::spawn_local(async move {
+                match fetch_upstreams().await {
+                    Ok(origins) => upstreams.set(origins),
+                    Err(e) => log::warn!("Failed to fetch proxy upstreams: {:?}", e),
+                }
+            });
+            || ()
+        });
+    }
+    let through_proxy = |url: &String| proxied_url(url, &upstreams);
+
     html! {
         <div class="content-renderer" style={style}>
             {
                 match &props.content_type {
                     ContentType::Empty => html! {},
                     ContentType::TransparentSvg => html! { <MainSvgContent /> },
-                    ContentType::IFrame(src) => html! {
-                        <iframe class="content-iframe" src={src.clone()} />
+                    ContentType::IFrame(url) => html! {
+                        <iframe class="content-iframe" src={through_proxy(url)} />
                     },
-                    ContentType::Video(src) => html! {
-                        <video class="content-video" src={src.clone()} controls=true />
+                    ContentType::Video(url) => html! {
+                        <video class="content-video" src={through_proxy(url)} controls=true />
                     },
-                    ContentType::WebGL(src) => html! {
-                        <iframe class="content-iframe" src={src.clone()} />
+                    ContentType::WebGL(url) => html! {
+                        <iframe class="content-iframe" src={through_proxy(url)} />
                     },
                     ContentType::Image(src) => html! {
                         <img class="content-image" src={src.clone()} />
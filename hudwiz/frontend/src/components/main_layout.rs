@@ -1,6 +1,7 @@
 use yew::prelude::*;
+use crate::services::auth_service::AuthContext;
 use crate::services::state_service::AppContext;
-use crate::services::config_service::ComponentType;
+use crate::services::config_service::{self, ComponentType};
 use crate::components::status_bar::StatusBar;
 use crate::components::content_renderer::ContentRenderer;
 use crate::components::background_renderer::BackgroundRenderer;
@@ -56,6 +57,28 @@ fn render_component(component_type: &ComponentType) -> Html {
 pub fn main_layout() -> Html {
     let app_context = use_context::<AppContext>().expect("no app context found");
     let state = &app_context.reducer;
+    let auth_context = use_context::<AuthContext>().expect("no auth context found");
+
+    // The config loaded at startup may predate the login; the server picks
+    // the layout by user, so fetch it again whenever the user changes.
+    {
+        let dispatch = state.dispatcher();
+        let user_id = auth_context.user.as_ref().map(|user| user.id.clone());
+        use_effect_with(user_id, move |_| {
+            config_service::load_config(dispatch);
+            || ()
+        });
+    }
+
+    // A layout that can't be loaded is reported rather than waited for.
+    if let (None, Some(error)) = (&state.config, &state.config_error) {
+        return html! {
+            <div class="loading-screen">
+                <h1>{ "Invalid Configuration" }</h1>
+                <pre>{ error }</pre>
+            </div>
+        };
+    }
 
     // Render a loading state if the config hasn't been loaded yet.
     if state.config.is_none() {
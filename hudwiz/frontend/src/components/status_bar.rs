@@ -1,8 +1,19 @@
 use yew::prelude::*;
 use material_yew::Icon;
+use crate::services::realtime_provider::use_connection_state;
+use crate::services::realtime_service::ConnectionState;
 
 #[function_component(StatusBar)]
 pub fn status_bar() -> Html {
+    let (connection_icon, connection) = match use_connection_state() {
+        ConnectionState::Connecting => ("cloud_sync", "Connecting…".to_string()),
+        ConnectionState::Connected(transport) => ("cloud_done", format!("Connected ({:?})", transport)),
+        ConnectionState::Reconnecting { attempt, retry_in_ms } => (
+            "cloud_off",
+            format!("Reconnecting in {}s (attempt {})", retry_in_ms.div_ceil(1000), attempt),
+        ),
+    };
+
     html! {
         <div id="status-bar" class="status-bar">
             <div class="status-bar-left-1 toolbar-group">
@@ -14,8 +25,8 @@ pub fn status_bar() -> Html {
                 <div><Icon icon={"bug_report"} /></div>
             </div>
             <div class="status-bar-center toolbar-group">
-                <div><Icon icon={{"history"}} /></div>
-                <span>{"System Status: OK"}</span>
+                <div><Icon icon={connection_icon} /></div>
+                <span class="connection-state">{connection}</span>
             </div>
             <div class="status-bar-right-1 toolbar-group">
                 <div><Icon icon={{"view_quilt"}} /></div>
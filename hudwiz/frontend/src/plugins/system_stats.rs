@@ -1,5 +1,12 @@
 use yew::prelude::*;
 use crate::plugins::Plugin;
+use crate::services::realtime_provider::use_realtime_topic;
+use gloo_net::http::Request;
+use serde::Deserialize;
+use wasm_bindgen_futures::spawn_local;
+
+/// How often to poll before the server has said its sampling interval.
+const DEFAULT_INTERVAL_SECS: u64 = 5;
 
 #[derive(Debug)]
 pub struct SystemStatsPlugin;
@@ -18,13 +25,181 @@ impl Plugin for SystemStatsPlugin {
     }
 }
 
+// The parts of the server's `/api/system/stats` report this plugin shows.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct StatsReport {
+    current: Sample,
+    history: Vec<HistoryPoint>,
+    interval_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Sample {
+    timestamp_ms: u64,
+    cpu: Cpu,
+    memory: Memory,
+    disks: Vec<Disk>,
+    process: Process,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Cpu {
+    usage_percent: f64,
+    cores: usize,
+    load_average: [f64; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Memory {
+    used_bytes: u64,
+    total_bytes: u64,
+    usage_percent: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Disk {
+    mount: String,
+    usage_percent: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Process {
+    cpu_percent: f64,
+    resident_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct HistoryPoint {
+    cpu_percent: f64,
+    memory_percent: f64,
+    rx_bytes_per_sec: f64,
+    tx_bytes_per_sec: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    Loading,
+    Ready(StatsReport),
+    Unavailable(String),
+}
+
+async fn fetch_report() -> Result<StatsReport, String> {
+    let response = Request::get("/api/system/stats").send().await.map_err(|e| e.to_string())?;
+    match response.status() {
+        200 => response.json().await.map_err(|e| e.to_string()),
+        401 => Err("Log in to see system statistics".to_string()),
+        _ => Err(format!("System statistics are unavailable ({})", response.status())),
+    }
+}
+
 #[function_component(SystemStats)]
 fn system_stats() -> Html {
+    let status = use_state(|| Status::Loading);
+    // Pushed as they are taken when the connection has the topic, between polls.
+    let pushed = use_realtime_topic::<Sample>("system.stats");
+    let interval_secs = match &*status {
+        Status::Ready(report) => report.interval_secs.max(1),
+        _ => DEFAULT_INTERVAL_SECS,
+    };
+
+    {
+        let status = status.clone();
+        use_effect_with(interval_secs, move |&interval_secs| {
+            let refresh = move || {
+                let status = status.clone();
+                spawn_local(async move {
+                    status.set(match fetch_report().await {
+                        Ok(report) => Status::Ready(report),
+                        Err(message) => Status::Unavailable(message),
+                    });
+                });
+            };
+            refresh();
+            let handle = gloo_timers::callback::Interval::new((interval_secs * 1000) as u32, refresh);
+            move || drop(handle)
+        });
+    }
+
+    let report = match &*status {
+        Status::Loading => return html! { <div>{ "Loading system statistics…" }</div> },
+        Status::Unavailable(message) => return html! { <div>{ message }</div> },
+        Status::Ready(report) => report,
+    };
+    let current = match &pushed {
+        Some(sample) if sample.timestamp_ms > report.current.timestamp_ms => sample,
+        _ => &report.current,
+    };
+    let history = &report.history;
+    let series = |value: fn(&HistoryPoint) -> f64| history.iter().map(value).collect::<Vec<_>>();
+    let network = series(|point| point.rx_bytes_per_sec + point.tx_bytes_per_sec);
+    let latest_network = network.last().copied().unwrap_or_default();
+
     html! {
-        <div>
-            <p>{ "CPU: 42%" }</p>
-            <p>{ "Memory: 64%" }</p>
-            <p>{ "Disk: 87%" }</p>
+        <div class="system-stats">
+            <p>
+                { format!("CPU: {:.0}% of {} cores, load {:.2}", current.cpu.usage_percent, current.cpu.cores, current.cpu.load_average[0]) }
+                { sparkline(&series(|point| point.cpu_percent), Some(100.0)) }
+            </p>
+            <p>
+                { format!(
+                    "Memory: {:.0}% ({} of {})",
+                    current.memory.usage_percent,
+                    format_bytes(current.memory.used_bytes as f64),
+                    format_bytes(current.memory.total_bytes as f64),
+                ) }
+                { sparkline(&series(|point| point.memory_percent), Some(100.0)) }
+            </p>
+            { for current.disks.iter().map(|disk| html! {
+                <p>{ format!("Disk {}: {:.0}%", disk.mount, disk.usage_percent) }</p>
+            }) }
+            <p>
+                { format!("Network: {}/s", format_bytes(latest_network)) }
+                { sparkline(&network, None) }
+            </p>
+            <p>
+                { format!(
+                    "Server: {:.0}% CPU, {}",
+                    current.process.cpu_percent,
+                    format_bytes(current.process.resident_bytes as f64),
+                ) }
+            </p>
         </div>
     }
 }
+
+/// A small line chart of `values`, scaled to `max` or to the largest value.
+fn sparkline(values: &[f64], max: Option<f64>) -> Html {
+    const WIDTH: f64 = 100.0;
+    const HEIGHT: f64 = 20.0;
+    if values.len() < 2 {
+        return html! {};
+    }
+    let max = max.unwrap_or_else(|| values.iter().copied().fold(0.0, f64::max)).max(f64::EPSILON);
+    let step = WIDTH / (values.len() - 1) as f64;
+    let points = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| format!("{:.1},{:.1}", i as f64 * step, HEIGHT - (value / max).clamp(0.0, 1.0) * HEIGHT))
+        .collect::<Vec<_>>()
+        .join(" ");
+    html! {
+        <svg class="sparkline" width={WIDTH.to_string()} height={HEIGHT.to_string()} viewBox={format!("0 0 {} {}", WIDTH, HEIGHT)}>
+            <polyline points={points} fill="none" stroke="currentColor" stroke-width="1" />
+        </svg>
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -1,40 +1,22 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use gloo_net::http::Request;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::Deserialize;
 use wasm_bindgen_futures::spawn_local;
 use yew::functional::UseReducerDispatcher;
 use crate::services::state_service::{AppAction, AppState};
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct Config {
-    pub layout: LayoutConfig,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct LayoutConfig {
-    #[serde(flatten)]
-    pub regions: HashMap<String, RegionConfig>,
-}
+pub use shared::layout::{ComponentType, Config, LayoutConfig, RegionConfig};
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct RegionConfig {
-    pub component: ComponentType,
+/// How the server refuses a layout that doesn't match the schema.
+#[derive(Deserialize)]
+struct InvalidLayout {
+    problems: Vec<SchemaProblem>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
-pub enum ComponentType {
-    ControlsDrawer,
-    DetailsDrawer,
-    UserDrawer,
-    DrawingToolbar,
-    TopLeftHud,
-    TopRightHud,
-    BottomLeftHud,
-    BottomRightHud,
-    BentoGrid,
-    ChatInputBar,
-    ChatHistoryView,
+#[derive(Deserialize)]
+struct SchemaProblem {
+    path: String,
+    message: String,
 }
 
 pub fn load_config(dispatch: UseReducerDispatcher<AppState>) {
@@ -45,16 +27,41 @@ pub fn load_config(dispatch: UseReducerDispatcher<AppState>) {
             }
             Err(e) => {
                 log::error!("Failed to load config: {}", e);
+                dispatch.dispatch(AppAction::SetConfigError(e.to_string()));
             }
         }
     });
 }
 
+/// The layout the server resolved for the logged in user, or `config.json`
+/// when logged out. The server checks `config.json` against the layout
+/// schema first; it is only fetched directly when served without the
+/// reference server.
 async fn fetch_config() -> Result<Config> {
+    match Request::get("/api/layout/current").send().await {
+        Ok(response) if response.ok() => match response.json::<Config>().await {
+            Ok(config) => return Ok(config),
+            Err(e) => log::warn!("Ignoring unreadable layout from the server: {}", e),
+        },
+        _ => {}
+    }
+    if let Ok(response) = Request::get("/api/config").send().await {
+        if response.ok() {
+            return Ok(response.json::<Config>().await?);
+        }
+        if let Ok(invalid) = response.json::<InvalidLayout>().await {
+            let problems = invalid
+                .problems
+                .iter()
+                .map(|problem| format!("{}: {}", if problem.path.is_empty() { "/" } else { &problem.path }, problem.message))
+                .collect::<Vec<_>>();
+            return Err(anyhow!("config.json is not a valid layout:\n{}", problems.join("\n")));
+        }
+    }
     let config: Config = Request::get("/static/config.json")
         .send()
         .await?
         .json()
         .await?;
     Ok(config)
-}
\ No newline at end of file
+}
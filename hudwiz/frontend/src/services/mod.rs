@@ -6,6 +6,7 @@ pub mod state_service;
 pub mod theme_service;
 pub mod transport;
 pub mod websocket_transport;
+pub mod sse_transport;
 pub mod webtransport_service;
 pub mod webtransport_transport;
 pub mod transport_manager;
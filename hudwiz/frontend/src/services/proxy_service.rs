@@ -0,0 +1,33 @@
+use anyhow::Result;
+use gloo_net::http::Request;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Upstreams {
+    upstreams: Vec<String>,
+}
+
+/// Origins the server proxies content from. Empty when the proxy is off or
+/// the server doesn't offer one.
+pub async fn fetch_upstreams() -> Result<Vec<String>> {
+    let response = Request::get("/api/proxy/upstreams").send().await?;
+    if !response.ok() {
+        return Ok(Vec::new());
+    }
+    let upstreams: Upstreams = response.json().await?;
+    Ok(upstreams.upstreams)
+}
+
+/// `url` through the server's `/api/proxy` when its origin is one of
+/// `upstreams`, otherwise unchanged.
+pub fn proxied_url(url: &str, upstreams: &[String]) -> String {
+    let proxied = upstreams.iter().any(|upstream| {
+        url.strip_prefix(upstream.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#']))
+    });
+    if proxied {
+        format!("/api/proxy?url={}", String::from(js_sys::encode_uri_component(url)))
+    } else {
+        url.to_string()
+    }
+}
@@ -1,4 +1,6 @@
-use super::realtime_service::RealtimeService;
+use super::realtime_service::{ConnectionState, RealtimeService};
+use protocol::{Envelope, Message};
+use serde::de::DeserializeOwned;
 use std::rc::Rc;
 use yew::prelude::*;
 use web_sys::window;
@@ -11,39 +13,77 @@ pub struct RealtimeProviderProps {
 
 #[function_component(RealtimeProvider)]
 pub fn realtime_provider(props: &RealtimeProviderProps) -> Html {
-    let realtime_service = use_state(|| None);
-    {
-        let realtime_service = realtime_service.clone();
-        use_effect_with((), move |_| {
-            wasm_bindgen_futures::spawn_local(async move {
-                let service = RealtimeService::new("ws://127.0.0.1:8080/ws").await;
-                match service {
-                    Ok(s) => {
-                        // Set the DOM attribute
-                        if let Some(window) = window() {
-                            if let Some(document) = window.document() {
-                                if let Some(body) = document.body() {
-                                    let transport_str = format!("{:?}", s.active_transport).to_lowercase();
-                                    let _ = body.set_attribute("data-transport", &transport_str);
-                                }
-                            }
-                        }
-                        realtime_service.set(Some(Rc::new(s)));
-                    },
-                    Err(e) => log::error!("Failed to create RealtimeService: {:?}", e),
+    let state = use_state(|| ConnectionState::Connecting);
+    let service = {
+        let set_state = state.setter();
+        use_memo((), move |_| RealtimeService::new(&realtime_url(), Callback::from(move |state| set_state.set(state))))
+    };
+    use_effect_with((*state).clone(), |state| {
+        // Exposes the transport in use as `data-transport` on `<body>`.
+        if let Some(body) = window().and_then(|window| window.document()).and_then(|document| document.body()) {
+            let _ = match state {
+                ConnectionState::Connected(transport) => {
+                    body.set_attribute("data-transport", &format!("{:?}", transport).to_lowercase())
                 }
+                _ => body.remove_attribute("data-transport"),
+            };
+        }
+    });
+
+    html! {
+        <ContextProvider<Rc<RealtimeService>> context={service}>
+            <ContextProvider<ConnectionState> context={(*state).clone()}>
+                { for props.children.iter() }
+            </ContextProvider<ConnectionState>>
+        </ContextProvider<Rc<RealtimeService>>>
+    }
+}
+
+/// Where the realtime connection stands.
+#[hook]
+pub fn use_connection_state() -> ConnectionState {
+    use_context::<ConnectionState>().unwrap_or(ConnectionState::Connecting)
+}
+
+/// The latest payload pushed on `topic`, or `None` until the first one
+/// arrives. Pushes that don't parse as `T` are logged and skipped.
+#[hook]
+pub fn use_realtime_topic<T>(topic: &str) -> Option<T>
+where
+    T: DeserializeOwned + Clone + 'static,
+{
+    let service = use_context::<Rc<RealtimeService>>();
+    let latest = use_state(|| None);
+    {
+        let latest = latest.clone();
+        use_effect_with((service, topic.to_string()), move |(service, topic)| {
+            let subscription = service.as_ref().map(|service| {
+                let name = topic.clone();
+                service.subscribe_topic(topic, Callback::from(move |envelope: Envelope| {
+                    let Message::Publish(payload) = envelope.message else { return };
+                    match serde_json::from_value::<T>(payload) {
+                        Ok(value) => latest.set(Some(value)),
+                        Err(e) => log::warn!("Unexpected payload on {}: {}", name, e),
+                    }
+                }))
             });
-            || ()
+            move || drop(subscription)
         });
     }
+    (*latest).clone()
+}
 
-    if let Some(service) = &*realtime_service {
-        html! {
-            <ContextProvider<Rc<RealtimeService>> context={service.clone()}>
-                { for props.children.iter() }
-            </ContextProvider<Rc<RealtimeService>>>
-        }
-    } else {
-        html! { for props.children.iter() }
-    }
-}
\ No newline at end of file
+/// Builds the realtime endpoint URL from the page's own origin, so the client
+/// follows whichever host and port the server is published on.
+fn realtime_url() -> String {
+    let location = window().map(|w| w.location());
+    let host = location
+        .as_ref()
+        .and_then(|l| l.host().ok())
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let scheme = match location.and_then(|l| l.protocol().ok()).as_deref() {
+        Some("https:") => "wss",
+        _ => "ws",
+    };
+    format!("{}://{}/ws", scheme, host)
+}
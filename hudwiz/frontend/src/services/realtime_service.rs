@@ -1,26 +1,278 @@
-use super::transport::Transport;
+use super::transport::{Receiver, Transport, TransportEvent};
 use super::transport_manager::{TransportManager, ActiveTransport};
-use anyhow::Result;
-use std::rc::Rc;
+use anyhow::{anyhow, Result};
+use gloo_timers::future::TimeoutFuture;
+use protocol::{Envelope, Message};
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use wasm_bindgen_futures::spawn_local;
+use yew::Callback;
 
+/// The first wait before reconnecting, doubled on every failed attempt.
+const BACKOFF_BASE_MS: u32 = 500;
+/// The longest wait before reconnecting.
+const BACKOFF_MAX_MS: u32 = 30_000;
+
+/// Where the connection to the server stands, for the UI.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected(ActiveTransport),
+    /// The connection was lost; attempt `attempt` starts in `retry_in_ms`.
+    Reconnecting { attempt: u32, retry_in_ms: u32 },
+}
+
+/// The realtime connection to the server. It reconnects on its own when
+/// the connection is lost, trying WebTransport again first, and resumes the
+/// server session so messages sent in between are replayed.
 pub struct RealtimeService {
-    transport: Rc<dyn Transport>,
-    pub active_transport: ActiveTransport,
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    server_url: String,
+    on_state: Callback<ConnectionState>,
+    transport: RefCell<Option<Rc<dyn Transport>>>,
+    /// Counts connections, so events from replaced ones are ignored.
+    connection: Cell<u64>,
+    /// The server session to resume and the last message received on it.
+    session: RefCell<Option<String>>,
+    last_seq: Cell<u64>,
+    subscribers: Subscribers,
 }
 
 impl PartialEq for RealtimeService {
     fn eq(&self, other: &Self) -> bool {
-        Rc::ptr_eq(&self.transport, &other.transport) && self.active_transport == other.active_transport
+        Rc::ptr_eq(&self.inner, &other.inner)
     }
 }
 
 impl RealtimeService {
-    pub async fn new(server_url: &str) -> Result<Self> {
-        let (transport, active_transport) = TransportManager::new(server_url).await?;
-        Ok(Self { transport, active_transport })
+    /// Starts connecting to `server_url` in the background. `on_state` is
+    /// called with every change of the connection's state.
+    pub fn new(server_url: &str, on_state: Callback<ConnectionState>) -> Self {
+        let inner = Rc::new(Inner {
+            server_url: server_url.to_string(),
+            on_state,
+            transport: RefCell::new(None),
+            connection: Cell::new(0),
+            session: RefCell::new(None),
+            last_seq: Cell::new(0),
+            subscribers: Subscribers::default(),
+        });
+        spawn_local(connect(Rc::downgrade(&inner), 0));
+        Self { inner }
+    }
+
+    /// Sends `message`, or fails while the connection is down.
+    pub async fn send(&self, message: &Envelope) -> Result<()> {
+        let transport = self.inner.transport.borrow().clone();
+        match transport {
+            Some(transport) => transport.send(message).await,
+            None => Err(anyhow!("Not connected to the server")),
+        }
+    }
+
+    /// Calls `callback` with every message from the server until the
+    /// returned `Subscription` is dropped.
+    pub fn subscribe(&self, callback: Callback<Envelope>) -> Subscription {
+        let id = self.inner.subscribers.add(None, callback);
+        Subscription { id, inner: Rc::downgrade(&self.inner) }
+    }
+
+    /// Calls `callback` with every `Publish` on `topic` until the returned
+    /// `Subscription` is dropped. The server is asked for the topic while
+    /// anything here is subscribed to it, on every connection.
+    pub fn subscribe_topic(&self, topic: &str, callback: Callback<Envelope>) -> Subscription {
+        let first = !self.inner.subscribers.has_topic(topic);
+        let id = self.inner.subscribers.add(Some(topic.to_string()), callback);
+        if first {
+            self.inner.send_in_background(Envelope::subscribe(topic));
+        }
+        Subscription { id, inner: Rc::downgrade(&self.inner) }
+    }
+}
+
+impl Inner {
+    fn handle(self: &Rc<Self>, event: TransportEvent) {
+        match event {
+            TransportEvent::Message(envelope) => {
+                if let Message::Session { session_id, resumed } = &envelope.message {
+                    let previous = self.session.replace(Some(session_id.clone()));
+                    if !resumed {
+                        if previous.is_some() {
+                            log::warn!("Realtime session could not be resumed; messages sent while disconnected were lost.");
+                        }
+                        self.last_seq.set(0);
+                    }
+                }
+                if let Message::Error { message } = &envelope.message {
+                    log::warn!("Realtime message refused by the server: {}", message);
+                }
+                if let Some(seq) = envelope.seq {
+                    // Already received before the connection was lost.
+                    if seq <= self.last_seq.get() {
+                        return;
+                    }
+                    self.last_seq.set(seq);
+                }
+                self.subscribers.dispatch(envelope);
+            }
+            TransportEvent::Closed => {
+                if self.transport.borrow_mut().take().is_some() {
+                    spawn_local(connect(Rc::downgrade(self), 1));
+                }
+            }
+        }
     }
 
-    pub async fn send(&self, message: &str) -> Result<()> {
-        self.transport.send(message).await
+    /// Sends `message` if connected. Topics are subscribed to again on
+    /// every connection, so nothing is lost by skipping it otherwise.
+    fn send_in_background(&self, message: Envelope) {
+        let Some(transport) = self.transport.borrow().clone() else { return };
+        spawn_local(async move {
+            if let Err(e) = transport.send(&message).await {
+                log::warn!("Failed to send realtime message: {:?}", e);
+            }
+        });
     }
-}
\ No newline at end of file
+
+    /// The query string resuming the current session, if there is one.
+    fn resume_query(&self) -> String {
+        match &*self.session.borrow() {
+            Some(session_id) => format!("session={}&last_seq={}", session_id, self.last_seq.get()),
+            None => String::new(),
+        }
+    }
+}
+
+/// Connects, retrying with backoff until it works or the service is
+/// dropped. `attempt` 0 connects right away.
+async fn connect(inner: Weak<Inner>, mut attempt: u32) {
+    loop {
+        let Some(service) = inner.upgrade() else { return };
+        if attempt == 0 {
+            service.on_state.emit(ConnectionState::Connecting);
+        } else {
+            let retry_in_ms = backoff_ms(attempt);
+            service.on_state.emit(ConnectionState::Reconnecting { attempt, retry_in_ms });
+            drop(service);
+            TimeoutFuture::new(retry_in_ms).await;
+        }
+        let Some(service) = inner.upgrade() else { return };
+
+        let connection = service.connection.get() + 1;
+        service.connection.set(connection);
+        let receiver: Receiver = {
+            let inner = inner.clone();
+            Rc::new(move |event| {
+                if let Some(service) = inner.upgrade()
+                    && service.connection.get() == connection
+                {
+                    service.handle(event);
+                }
+            })
+        };
+        let query = service.resume_query();
+        match TransportManager::new(&service.server_url, &query, receiver).await {
+            Ok((transport, active_transport)) => {
+                service.transport.replace(Some(transport));
+                // Subscriptions belong to the connection, not the session.
+                for topic in service.subscribers.topics() {
+                    service.send_in_background(Envelope::subscribe(&topic));
+                }
+                service.on_state.emit(ConnectionState::Connected(active_transport));
+                return;
+            }
+            Err(e) => {
+                log::warn!("Realtime connection attempt {} failed: {:?}", attempt + 1, e);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// How long to wait before `attempt`: exponential, with the upper half
+/// randomised so clients dropped together don't all return at once.
+fn backoff_ms(attempt: u32) -> u32 {
+    let ceiling = BACKOFF_BASE_MS.saturating_mul(1 << (attempt - 1).min(16)).min(BACKOFF_MAX_MS);
+    ceiling / 2 + (js_sys::Math::random() * f64::from(ceiling / 2)) as u32
+}
+
+struct Subscriber {
+    id: u64,
+    topic: Option<String>,
+    callback: Callback<Envelope>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: Cell<u64>,
+    subscribers: RefCell<Vec<Subscriber>>,
+}
+
+impl Subscribers {
+    fn add(&self, topic: Option<String>, callback: Callback<Envelope>) -> u64 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.subscribers.borrow_mut().push(Subscriber { id, topic, callback });
+        id
+    }
+
+    /// Removes subscriber `id`, returning its topic if nothing else is
+    /// subscribed to it.
+    fn remove(&self, id: u64) -> Option<String> {
+        let mut subscribers = self.subscribers.borrow_mut();
+        let index = subscribers.iter().position(|subscriber| subscriber.id == id)?;
+        let topic = subscribers.remove(index).topic?;
+        drop(subscribers);
+        (!self.has_topic(&topic)).then_some(topic)
+    }
+
+    fn has_topic(&self, topic: &str) -> bool {
+        self.subscribers.borrow().iter().any(|subscriber| subscriber.topic.as_deref() == Some(topic))
+    }
+
+    /// Every topic subscribed to, once each.
+    fn topics(&self) -> Vec<String> {
+        let mut topics: Vec<_> = self.subscribers.borrow().iter().filter_map(|subscriber| subscriber.topic.clone()).collect();
+        topics.sort();
+        topics.dedup();
+        topics
+    }
+
+    fn dispatch(&self, envelope: Envelope) {
+        // Collected first, so callbacks may subscribe and unsubscribe.
+        let callbacks: Vec<_> = self
+            .subscribers
+            .borrow()
+            .iter()
+            .filter(|subscriber| match &subscriber.topic {
+                None => true,
+                Some(topic) => {
+                    matches!(envelope.message, Message::Publish(_)) && envelope.topic.as_ref() == Some(topic)
+                }
+            })
+            .map(|subscriber| subscriber.callback.clone())
+            .collect();
+        for callback in callbacks {
+            callback.emit(envelope.clone());
+        }
+    }
+}
+
+/// Keeps a callback subscribed to a `RealtimeService` until dropped.
+pub struct Subscription {
+    id: u64,
+    inner: Weak<Inner>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.upgrade()
+            && let Some(topic) = inner.subscribers.remove(self.id)
+        {
+            inner.send_in_background(Envelope::unsubscribe(&topic));
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use super::transport::{self, Receiver, Transport, TransportEvent};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures_channel::oneshot;
+use gloo_net::http::Request;
+use protocol::Envelope;
+use serde::Deserialize;
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{EventSource, MessageEvent};
+
+/// The first event on the stream, naming the session to post messages to.
+#[derive(Deserialize)]
+struct SseSession {
+    session_id: String,
+}
+
+/// Receives over Server-Sent Events and sends with `POST`s, for networks
+/// that let neither WebTransport nor WebSocket through.
+pub struct SseTransport {
+    // Kept so the stream stays open as long as the transport.
+    events: EventSource,
+    post_url: String,
+}
+
+#[async_trait(?Send)]
+impl Transport for SseTransport {
+    async fn connect(server_url: &str, receiver: Receiver) -> Result<Self> {
+        let events = EventSource::new(server_url)
+            .map_err(|e| anyhow!("Failed to create EventSource: {:?}", e))?;
+
+        let (session_tx, session_rx) = oneshot::channel::<Result<String>>();
+        let session_tx = Rc::new(RefCell::new(Some(session_tx)));
+
+        // session handler
+        let session_callback = {
+            let session_tx = session_tx.clone();
+            Closure::wrap(Box::new(move |e: MessageEvent| {
+                let session = e
+                    .data()
+                    .as_string()
+                    .ok_or_else(|| anyhow!("Session event without data"))
+                    .and_then(|data| Ok(serde_json::from_str::<SseSession>(&data)?.session_id));
+                if let Some(tx) = session_tx.borrow_mut().take() {
+                    let _ = tx.send(session);
+                }
+            }) as Box<dyn FnMut(_)>)
+        };
+        events
+            .add_event_listener_with_callback("session", session_callback.as_ref().unchecked_ref())
+            .map_err(|e| anyhow!("Failed to listen for the session: {:?}", e))?;
+        session_callback.forget();
+
+        // onerror handler. The stream is closed rather than left to the
+        // browser's retries, so the realtime service reconnects it with the
+        // session to resume.
+        let onerror_callback = {
+            let events = events.clone();
+            let receiver = receiver.clone();
+            Closure::wrap(Box::new(move || {
+                events.close();
+                if let Some(tx) = session_tx.borrow_mut().take() {
+                    let _ = tx.send(Err(anyhow!("EventSource connection failed")));
+                } else {
+                    log::warn!("SSE connection interrupted.");
+                    receiver(TransportEvent::Closed);
+                }
+            }) as Box<dyn FnMut()>)
+        };
+        events.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
+        onerror_callback.forget();
+
+        // close handler, sent before the server shuts down
+        let close_callback = {
+            let events = events.clone();
+            let receiver = receiver.clone();
+            Closure::wrap(Box::new(move |_: MessageEvent| {
+                log::info!("SSE connection closed by the server.");
+                events.close();
+                receiver(TransportEvent::Closed);
+            }) as Box<dyn FnMut(_)>)
+        };
+        events
+            .add_event_listener_with_callback("close", close_callback.as_ref().unchecked_ref())
+            .map_err(|e| anyhow!("Failed to listen for close: {:?}", e))?;
+        close_callback.forget();
+
+        // onmessage handler
+        let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
+            if let Some(text) = e.data().as_string() {
+                transport::deliver(&receiver, &text);
+            }
+        }) as Box<dyn FnMut(_)>);
+        events.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+        onmessage_callback.forget();
+
+        let session_id = session_rx.await??;
+        log::info!("SSE connection established.");
+
+        // The resume parameters only matter to the stream.
+        let path = server_url.split('?').next().unwrap_or_default().trim_end_matches('/');
+        let post_url = format!("{}/{}", path, session_id);
+        Ok(Self { events, post_url })
+    }
+
+    async fn send(&self, message: &Envelope) -> Result<()> {
+        let response = Request::post(&self.post_url)
+            .header("Content-Type", "text/plain")
+            .body(message.encode())
+            .send()
+            .await?;
+        if !response.ok() {
+            return Err(anyhow!("Failed to send message: {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SseTransport {
+    fn drop(&mut self) {
+        self.events.set_onerror(None);
+        self.events.close();
+    }
+}
@@ -4,17 +4,7 @@ use yew::functional::Reducible;
 use crate::services::config_service::Config;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum ContentType {
-    TransparentSvg,
-    IFrame(String),
-    Video(String),
-    WebGL(String),
-    Empty,
-    AnimatedSvg,
-    SolidColor(String),
-    Image(String),
-}
+pub use protocol::ContentType;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Author {
@@ -59,6 +49,8 @@ pub struct AppState {
     pub background_content: ContentType,
     pub main_content: ContentType,
     pub config: Option<Config>,
+    /// Why the layout couldn't be loaded, shown instead of it.
+    pub config_error: Option<String>,
     pub conversation: Conversation,
     pub language: String,
 }
@@ -80,6 +72,7 @@ impl Default for AppState {
             background_content: ContentType::AnimatedSvg,
             main_content: ContentType::Image("/static/images/background_content.jpg".to_string()),
             config: None,
+            config_error: None,
             conversation: Conversation::default(),
             language: "en-US".to_string(),
         }
@@ -102,6 +95,7 @@ pub enum AppAction {
     SetBackgroundContent(ContentType),
     SetMainContent(ContentType),
     SetConfig(Config),
+    SetConfigError(String),
     SendChatMessage(String),
     SetLanguage(String),
 }
@@ -125,7 +119,11 @@ impl Reducible for AppState {
             AppAction::ToggleActivityBar => next_state.activity_bar_collapsed = !next_state.activity_bar_collapsed,
             AppAction::SetBackgroundContent(content) => next_state.background_content = content,
             AppAction::SetMainContent(content) => next_state.main_content = content,
-            AppAction::SetConfig(config) => next_state.config = Some(config),
+            AppAction::SetConfig(config) => {
+                next_state.config = Some(config);
+                next_state.config_error = None;
+            }
+            AppAction::SetConfigError(error) => next_state.config_error = Some(error),
             AppAction::SendChatMessage(text) => {
                 let new_message = Message {
                     id: next_state.conversation.messages.len(),
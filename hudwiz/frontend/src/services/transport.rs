@@ -1,10 +1,33 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use protocol::Envelope;
+use std::rc::Rc;
+
+/// What happens on an open transport.
+pub enum TransportEvent {
+    Message(Envelope),
+    /// The connection was lost or closed by the server. Nothing more
+    /// arrives on it.
+    Closed,
+}
+
+/// Called with every event on a transport once it has connected.
+pub type Receiver = Rc<dyn Fn(TransportEvent)>;
 
 #[async_trait(?Send)]
 pub trait Transport {
-    async fn connect(server_url: &str) -> Result<Self>
+    async fn connect(server_url: &str, receiver: Receiver) -> Result<Self>
     where
         Self: Sized;
-    async fn send(&self, message: &str) -> Result<()>;
-}
\ No newline at end of file
+    async fn send(&self, message: &Envelope) -> Result<()>;
+}
+
+/// Decodes `text` received from the server and hands it to `receiver`.
+/// Messages that aren't envelopes of this protocol version are logged and
+/// dropped.
+pub fn deliver(receiver: &Receiver, text: &str) {
+    match Envelope::decode(text) {
+        Ok(envelope) => receiver(TransportEvent::Message(envelope)),
+        Err(e) => log::warn!("Dropped a realtime message: {}", e),
+    }
+}
@@ -1,39 +1,122 @@
-use super::transport::Transport;
+use super::sse_transport::SseTransport;
+use super::transport::{Receiver, Transport};
 use super::websocket_transport::WebSocketTransport;
 use super::webtransport_transport::WebTransportTransport;
 use anyhow::Result;
+use gloo_net::http::Request;
+use serde::Deserialize;
 use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ActiveTransport {
     WebTransport,
     WebSocket,
+    Sse,
+}
+
+/// Response of the server's `/api/realtime/info` endpoint.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RealtimeInfo {
+    pub websocket_path: String,
+    pub sse_path: String,
+    pub webtransport: Option<WebTransportInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebTransportInfo {
+    pub url: String,
+    /// Hex SHA-256 of the server's self-signed development certificate, if it uses one.
+    pub cert_hash: Option<String>,
+    pub cert_expires_at: i64,
 }
 
 pub struct TransportManager {
     // No-op
 }
 
+/// Where the reference server serves Server-Sent Events, when
+/// `/api/realtime/info` can't say.
+const DEFAULT_SSE_PATH: &str = "/sse";
+
 impl TransportManager {
-    pub async fn new(server_url: &str) -> Result<(Rc<dyn Transport>, ActiveTransport)> {
-        match WebTransportTransport::connect(server_url).await {
-            Ok(transport) => {
-                log::info!("Successfully connected using WebTransport.");
-                Ok((Rc::new(transport), ActiveTransport::WebTransport))
-            }
-            Err(e) => {
-                log::warn!("WebTransport connection failed: {:?}. Falling back to WebSocket.", e);
-                match WebSocketTransport::connect(server_url).await {
+    /// Connects with the first transport that works, trying WebTransport,
+    /// then WebSocket, then Server-Sent Events. `query`, such as the session
+    /// to resume, is added to whichever endpoint is used.
+    pub async fn new(websocket_url: &str, query: &str, receiver: Receiver) -> Result<(Rc<dyn Transport>, ActiveTransport)> {
+        let info = fetch_realtime_info().await;
+        let sse_path = info.as_ref().map_or(DEFAULT_SSE_PATH, |info| info.sse_path.as_str());
+        let sse_url = with_query(sse_path, query);
+        match info {
+            Ok(RealtimeInfo { webtransport: Some(info), .. }) => {
+                // WebTransport can't send the session cookie, so logged in
+                // users swap it for a single-use token first.
+                let url = match fetch_session_token().await {
+                    Some(token) => with_query(&with_query(&info.url, query), &format!("token={}", token)),
+                    None => with_query(&info.url, query),
+                };
+                match WebTransportTransport::connect_with_cert_hash(&url, info.cert_hash.as_deref(), receiver.clone()).await {
                     Ok(transport) => {
-                        log::info!("Successfully connected using WebSocket.");
-                        Ok((Rc::new(transport), ActiveTransport::WebSocket))
-                    }
-                    Err(e_ws) => {
-                        log::error!("WebSocket connection also failed: {:?}", e_ws);
-                        Err(e_ws)
+                        log::info!("Successfully connected using WebTransport.");
+                        return Ok((Rc::new(transport), ActiveTransport::WebTransport));
                     }
+                    Err(e) => log::warn!("WebTransport connection failed: {:?}. Falling back to WebSocket.", e),
                 }
             }
+            Ok(_) => log::info!("Server offers no WebTransport endpoint. Using WebSocket."),
+            Err(e) => log::warn!("Failed to fetch realtime info: {:?}. Falling back to WebSocket.", e),
+        }
+
+        match WebSocketTransport::connect(&with_query(websocket_url, query), receiver.clone()).await {
+            Ok(transport) => {
+                log::info!("Successfully connected using WebSocket.");
+                return Ok((Rc::new(transport), ActiveTransport::WebSocket));
+            }
+            Err(e_ws) => log::warn!("WebSocket connection failed: {:?}. Falling back to Server-Sent Events.", e_ws),
+        }
+
+        match SseTransport::connect(&sse_url, receiver).await {
+            Ok(transport) => {
+                log::info!("Successfully connected using Server-Sent Events.");
+                Ok((Rc::new(transport), ActiveTransport::Sse))
+            }
+            Err(e_sse) => {
+                log::error!("Server-Sent Events connection also failed: {:?}", e_sse);
+                Err(e_sse)
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+fn with_query(url: &str, query: &str) -> String {
+    match (query.is_empty(), url.contains('?')) {
+        (true, _) => url.to_string(),
+        (false, true) => format!("{}&{}", url, query),
+        (false, false) => format!("{}?{}", url, query),
+    }
+}
+
+/// Response of the server's `/api/realtime/token` endpoint.
+#[derive(Debug, Clone, Deserialize)]
+struct SessionToken {
+    token: String,
+}
+
+/// A token logging in the WebTransport session, or `None` for users who
+/// aren't logged in.
+async fn fetch_session_token() -> Option<String> {
+    let response = Request::post("/api/realtime/token").send().await.ok()?;
+    if !response.ok() {
+        return None;
+    }
+    let token: SessionToken = response.json().await.ok()?;
+    Some(token.token)
+}
+
+async fn fetch_realtime_info() -> Result<RealtimeInfo> {
+    let info: RealtimeInfo = Request::get("/api/realtime/info")
+        .send()
+        .await?
+        .json()
+        .await?;
+    Ok(info)
+}
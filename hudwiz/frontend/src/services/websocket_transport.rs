@@ -1,7 +1,9 @@
-use super::transport::Transport;
+use super::transport::{self, Receiver, Transport, TransportEvent};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use futures_channel::oneshot;
+use protocol::Envelope;
+use std::{cell::RefCell, rc::Rc};
 use wasm_bindgen::{prelude::*, JsCast};
 use web_sys::{WebSocket, MessageEvent, ErrorEvent};
 
@@ -11,20 +13,23 @@ pub struct WebSocketTransport {
 
 #[async_trait(?Send)]
 impl Transport for WebSocketTransport {
-    async fn connect(server_url: &str) -> Result<Self> {
+    async fn connect(server_url: &str, receiver: Receiver) -> Result<Self> {
         let ws = WebSocket::new(server_url)
             .map_err(|e| anyhow!("Failed to create WebSocket: {:?}", e))?;
 
         let (open_tx, open_rx) = oneshot::channel::<Result<()>>();
-        let mut open_tx = Some(open_tx);
+        let open_tx = Rc::new(RefCell::new(Some(open_tx)));
 
         // onopen handler
-        let onopen_callback = Closure::wrap(Box::new(move || {
-            log::info!("WebSocket connection established.");
-            if let Some(tx) = open_tx.take() {
-                let _ = tx.send(Ok(()));
-            }
-        }) as Box<dyn FnMut()>);
+        let onopen_callback = {
+            let open_tx = open_tx.clone();
+            Closure::wrap(Box::new(move || {
+                log::info!("WebSocket connection established.");
+                if let Some(tx) = open_tx.borrow_mut().take() {
+                    let _ = tx.send(Ok(()));
+                }
+            }) as Box<dyn FnMut()>)
+        };
         ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
         onopen_callback.forget();
 
@@ -35,17 +40,26 @@ impl Transport for WebSocketTransport {
         ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
         onerror_callback.forget();
 
-        // onclose handler
-        let onclose_callback = Closure::wrap(Box::new(move || {
-            log::info!("WebSocket connection closed.");
-        }) as Box<dyn FnMut()>);
+        // onclose handler. Before the socket opened it means connecting failed.
+        let onclose_callback = {
+            let receiver = receiver.clone();
+            Closure::wrap(Box::new(move || {
+                if let Some(tx) = open_tx.borrow_mut().take() {
+                    let _ = tx.send(Err(anyhow!("WebSocket connection failed")));
+                } else {
+                    log::info!("WebSocket connection closed.");
+                    receiver(TransportEvent::Closed);
+                }
+            }) as Box<dyn FnMut()>)
+        };
         ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
         onclose_callback.forget();
 
-        // onmessage handler (dummy)
+        // onmessage handler
         let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
-            if let Ok(txt) = e.data().dyn_into::<js_sys::JsString>() {
-                log::info!("Dummy onmessage: {:?}", txt);
+            match e.data().as_string() {
+                Some(text) => transport::deliver(&receiver, &text),
+                None => log::warn!("Dropped a binary WebSocket message."),
             }
         }) as Box<dyn FnMut(_)>);
         ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
@@ -56,10 +70,18 @@ impl Transport for WebSocketTransport {
         Ok(Self { ws })
     }
 
-    async fn send(&self, message: &str) -> Result<()> {
+    async fn send(&self, message: &Envelope) -> Result<()> {
         self.ws
-            .send_with_str(message)
+            .send_with_str(&message.encode())
             .map_err(|e| anyhow!("Failed to send message: {:?}", e))?;
         Ok(())
     }
+}
+
+impl Drop for WebSocketTransport {
+    fn drop(&mut self) {
+        self.ws.set_onclose(None);
+        self.ws.set_onmessage(None);
+        let _ = self.ws.close();
+    }
 }
\ No newline at end of file
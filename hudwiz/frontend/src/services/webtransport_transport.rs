@@ -1,69 +1,152 @@
-use super::transport::Transport;
+use super::transport::{self, Receiver, Transport, TransportEvent};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use futures_channel::oneshot;
-use std::cell::RefCell;
-use yew::Callback;
-use yew_webtransport::webtransport::{WebTransportService, WebTransportStatus, WebTransportTask};
-use web_sys::{WebTransportReceiveStream, WebTransportBidirectionalStream};
+use protocol::Envelope;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{
+    ReadableStreamDefaultReader, WebTransport, WebTransportBidirectionalStream, WebTransportHash, WebTransportOptions,
+    WritableStreamDefaultWriter,
+};
 
+/// Messages go both ways on one bidirectional stream, each framed by its
+/// length as a big-endian `u32`. The server also sends telemetry, which may
+/// be lost, as datagrams of one message each.
 pub struct WebTransportTransport {
-    task: WebTransportTask,
+    transport: WebTransport,
+    writer: WritableStreamDefaultWriter,
 }
 
-#[async_trait(?Send)]
-impl Transport for WebTransportTransport {
-    async fn connect(server_url: &str) -> Result<Self> {
-        let (open_tx, open_rx) = oneshot::channel::<Result<WebTransportTask>>();
-        let open_tx = RefCell::new(Some(open_tx)); // Wrap in RefCell
+impl WebTransportTransport {
+    /// Connects to `server_url`. When `cert_hash` (hex SHA-256) is given the
+    /// browser pins the server certificate to it, which is how self-signed
+    /// development certificates are accepted.
+    pub async fn connect_with_cert_hash(server_url: &str, cert_hash: Option<&str>, receiver: Receiver) -> Result<Self> {
+        let options = WebTransportOptions::new();
+        if let Some(cert_hash) = cert_hash {
+            let hash = WebTransportHash::new();
+            hash.set_algorithm("sha-256");
+            hash.set_value(&js_sys::Uint8Array::from(decode_hex(cert_hash)?.as_slice()));
+            options.set_server_certificate_hashes(&[hash]);
+        }
 
-        let on_datagram = Callback::from(|data: Vec<u8>| {
-            log::info!("Dummy on_datagram: {:?}", data);
-        });
+        let transport = WebTransport::new_with_options(server_url, &options)
+            .map_err(|e| anyhow!("Failed to initiate connection: {:?}", e))?;
+        JsFuture::from(transport.ready())
+            .await
+            .map_err(|e| anyhow!("Connection failed: {:?}", e))?;
+        log::info!("WebTransport connection established.");
 
-        let on_unidirectional_stream = Callback::from(|stream: WebTransportReceiveStream| {
-            log::info!("Dummy on_unidirectional_stream: {:?}", stream);
-        });
+        let stream: WebTransportBidirectionalStream = JsFuture::from(transport.create_bidirectional_stream())
+            .await
+            .map_err(|e| anyhow!("Failed to open the session stream: {:?}", e))?
+            .unchecked_into();
+        let writer = stream
+            .writable()
+            .get_writer()
+            .map_err(|e| anyhow!("Failed to open the session stream writer: {:?}", e))?;
+        let reader: ReadableStreamDefaultReader = stream.readable().get_reader().unchecked_into();
+        spawn_local(read_frames(reader, receiver.clone()));
 
-        let on_bidirectional_stream = Callback::from(|stream: WebTransportBidirectionalStream| {
-            log::info!("Dummy on_bidirectional_stream: {:?}", stream);
-        });
+        let datagram_reader: ReadableStreamDefaultReader = transport
+            .datagrams()
+            .readable()
+            .get_reader()
+            .unchecked_into();
+        spawn_local(read_datagrams(datagram_reader, receiver.clone()));
 
-        let server_url_clone = server_url.to_string();
-        let notification = Callback::from(move |status: WebTransportStatus| {
-            if let Some(tx) = open_tx.borrow_mut().take() { // Use borrow_mut()
-                match status {
-                    WebTransportStatus::Opened => {
-                        let task_result = WebTransportService::connect(
-                            &server_url_clone,
-                            Callback::from(|_: Vec<u8>| {}),
-                            Callback::from(|_: WebTransportReceiveStream| {}),
-                            Callback::from(|_: WebTransportBidirectionalStream| {}),
-                            Callback::from(|_: WebTransportStatus| {}),
-                        ).map_err(|e| anyhow!("Failed to reconnect for task: {:?}", e));
-                        let _ = tx.send(task_result);
-                    }
-                    WebTransportStatus::Closed(reason) | WebTransportStatus::Error(reason) => {
-                        let _ = tx.send(Err(anyhow!("Connection failed: {:?}", reason)));
-                    }
-                }
-            }
+        let closed = JsFuture::from(transport.closed());
+        spawn_local(async move {
+            let reason = closed.await;
+            log::info!("WebTransport connection closed: {:?}", reason);
+            receiver(TransportEvent::Closed);
         });
 
-        let _task = WebTransportService::connect(
-            server_url,
-            on_datagram,
-            on_unidirectional_stream,
-            on_bidirectional_stream,
-            notification,
-        ).map_err(|e| anyhow!("Failed to initiate connection: {:?}", e))?;
+        Ok(Self { transport, writer })
+    }
+}
 
-        let task = open_rx.await??;
-        Ok(Self { task })
+#[async_trait(?Send)]
+impl Transport for WebTransportTransport {
+    async fn connect(server_url: &str, receiver: Receiver) -> Result<Self> {
+        Self::connect_with_cert_hash(server_url, None, receiver).await
     }
 
-    async fn send(&self, message: &str) -> Result<()> {
-        WebTransportTask::send_datagram(self.task.transport.clone(), message.as_bytes().to_vec());
+    async fn send(&self, message: &Envelope) -> Result<()> {
+        let text = message.encode();
+        let mut frame = (text.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(text.as_bytes());
+        let chunk = js_sys::Uint8Array::from(frame.as_slice());
+        JsFuture::from(self.writer.write_with_chunk(&chunk))
+            .await
+            .map_err(|e| anyhow!("Failed to send message: {:?}", e))?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+impl Drop for WebTransportTransport {
+    fn drop(&mut self) {
+        self.transport.close();
+    }
+}
+
+/// Delivers the frames on the session stream until it ends.
+async fn read_frames(reader: ReadableStreamDefaultReader, receiver: Receiver) {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = read_chunk(&reader, "session stream").await {
+        buffer.extend_from_slice(&chunk);
+        while buffer.len() >= 4 {
+            let length = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+            if buffer.len() < 4 + length {
+                break;
+            }
+            let frame: Vec<u8> = buffer.drain(..4 + length).skip(4).collect();
+            deliver(&receiver, &frame);
+        }
+    }
+}
+
+/// Delivers the server's datagrams, each one message, until the session
+/// closes.
+async fn read_datagrams(reader: ReadableStreamDefaultReader, receiver: Receiver) {
+    while let Some(datagram) = read_chunk(&reader, "datagrams").await {
+        deliver(&receiver, &datagram);
+    }
+}
+
+/// The next chunk from `reader`, or `None` once `what` has closed.
+async fn read_chunk(reader: &ReadableStreamDefaultReader, what: &str) -> Option<Vec<u8>> {
+    let chunk = match JsFuture::from(reader.read()).await {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            log::info!("WebTransport {} closed: {:?}", what, e);
+            return None;
+        }
+    };
+    let done = js_sys::Reflect::get(&chunk, &"done".into()).map_or(true, |done| done.is_truthy());
+    if done {
+        return None;
+    }
+    let value = js_sys::Reflect::get(&chunk, &"value".into()).ok()?;
+    Some(js_sys::Uint8Array::new(&value).to_vec())
+}
+
+fn deliver(receiver: &Receiver, bytes: &[u8]) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => transport::deliver(receiver, text),
+        Err(_) => log::warn!("Dropped a WebTransport message that isn't text."),
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(anyhow!("Invalid certificate hash: {}", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| anyhow!("Invalid certificate hash: {}", hex))
+        })
+        .collect()
+}
@@ -0,0 +1,164 @@
+//! The realtime protocol. Every message on `/ws`, `/sse` and WebTransport,
+//! in either direction, is an `Envelope` sent as JSON text, so the server and
+//! the frontend agree on what can be sent at compile time.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The protocol version this crate speaks. Envelopes of another version are
+/// refused rather than guessed at.
+pub const VERSION: u32 = 1;
+
+/// One message with what is needed to route and answer it.
+///
+/// ```json
+/// {"version": 1, "id": "7", "topic": "system.stats", "type": "Publish", "payload": {...}}
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub version: u32,
+    /// Unique among the messages one side sends on a connection.
+    pub id: String,
+    /// The topic a `Publish` belongs to, or a `Subscribe` or `Unsubscribe`
+    /// is for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// The `id` of the message this one answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Where the message falls among those the server sent the session,
+    /// counting from 1. Clients resume from the last one they received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+/// What an envelope carries, as its `type` and `payload`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", content = "payload")]
+pub enum Message {
+    /// Text from the client, such as a chat message. The reference server
+    /// echoes it back.
+    Text(String),
+    /// A push on the envelope's `topic`, shaped by the topic.
+    Publish(serde_json::Value),
+    /// Asks for the `Publish`es on the envelope's `topic`. Answered with an
+    /// `Ack`, or an `Error` for topics that don't exist or aren't allowed.
+    Subscribe,
+    /// Stops the `Publish`es on the envelope's `topic`. Answered with an `Ack`.
+    Unsubscribe,
+    /// Changes what the main panel shows.
+    SetMainContent(ContentType),
+    /// Changes what is shown behind the UI.
+    SetBackgroundContent(ContentType),
+    /// The message named by `correlation_id` was received.
+    Ack,
+    /// Messages are over the server's rate limit. The one named by
+    /// `correlation_id` was dropped; send nothing more until the time is up.
+    Throttled { retry_after_ms: u64 },
+    /// The message named by `correlation_id`, if any, couldn't be handled.
+    Error { message: String },
+    /// The first message on every connection: the session to resume when
+    /// reconnecting, and whether this connection resumed the one asked for.
+    /// When it didn't, messages sent in between were lost.
+    Session { session_id: String, resumed: bool },
+}
+
+/// What the main panel or background shows, as `{"IFrame": "https://..."}`
+/// or `"AnimatedSvg"`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ContentType {
+    TransparentSvg,
+    IFrame(String),
+    Video(String),
+    WebGL(String),
+    Empty,
+    AnimatedSvg,
+    SolidColor(String),
+    Image(String),
+}
+
+impl Envelope {
+    /// A new message with the next id.
+    pub fn new(message: Message) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self {
+            version: VERSION,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed).to_string(),
+            topic: None,
+            correlation_id: None,
+            seq: None,
+            message,
+        }
+    }
+
+    /// A new message answering `request`.
+    pub fn reply(request: &Envelope, message: Message) -> Self {
+        Self {
+            correlation_id: Some(request.id.clone()),
+            ..Self::new(message)
+        }
+    }
+
+    /// A new `Publish` on `topic`.
+    pub fn publish(topic: &str, payload: serde_json::Value) -> Self {
+        Self::on_topic(topic, Message::Publish(payload))
+    }
+
+    /// A new `Subscribe` to `topic`.
+    pub fn subscribe(topic: &str) -> Self {
+        Self::on_topic(topic, Message::Subscribe)
+    }
+
+    /// A new `Unsubscribe` from `topic`.
+    pub fn unsubscribe(topic: &str) -> Self {
+        Self::on_topic(topic, Message::Unsubscribe)
+    }
+
+    fn on_topic(topic: &str, message: Message) -> Self {
+        Self {
+            topic: Some(topic.to_string()),
+            ..Self::new(message)
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("envelopes serialize")
+    }
+
+    /// Parses `text`, refusing other protocol versions.
+    pub fn decode(text: &str) -> Result<Self, DecodeError> {
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+        let Version { version } = serde_json::from_str(text).map_err(DecodeError::Invalid)?;
+        if version != VERSION {
+            return Err(DecodeError::Version(version));
+        }
+        serde_json::from_str(text).map_err(DecodeError::Invalid)
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Not an envelope, or one with an unknown `type` or wrong `payload`.
+    Invalid(serde_json::Error),
+    /// An envelope of another protocol version.
+    Version(u32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(e) => write!(f, "Not a valid message: {}", e),
+            Self::Version(version) => write!(f, "Protocol version {} is not supported, expected {}", version, VERSION),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,65 @@
+use protocol::{ContentType, DecodeError, Envelope, Message, VERSION};
+use serde_json::{json, Value};
+
+#[test]
+fn test_envelopes_round_trip() {
+    let request = Envelope::new(Message::SetMainContent(ContentType::IFrame("https://example.com".to_string())));
+    let messages = [
+        Envelope::reply(&request, Message::Throttled { retry_after_ms: 500 }),
+        Envelope::publish("system.stats", json!({"cpu": {"cores": 4}})),
+        Envelope::new(Message::Ack),
+        Envelope::subscribe("system.stats"),
+        Envelope::unsubscribe("system.stats"),
+        Envelope {
+            seq: Some(7),
+            ..Envelope::new(Message::Session {
+                session_id: "0b5d".to_string(),
+                resumed: true,
+            })
+        },
+        request,
+    ];
+    for message in messages {
+        assert_eq!(Envelope::decode(&message.encode()).unwrap(), message);
+    }
+}
+
+#[test]
+fn test_wire_format() {
+    let request = Envelope::new(Message::Text("hi".to_string()));
+    let reply = Envelope::reply(&request, Message::Throttled { retry_after_ms: 500 });
+    let json: Value = serde_json::from_str(&reply.encode()).unwrap();
+    assert_eq!(
+        json,
+        json!({
+            "version": VERSION,
+            "id": reply.id,
+            "correlation_id": request.id,
+            "type": "Throttled",
+            "payload": {"retry_after_ms": 500},
+        })
+    );
+    assert_ne!(reply.id, request.id);
+
+    let ack: Value = serde_json::from_str(&Envelope::new(Message::Ack).encode()).unwrap();
+    assert_eq!(ack["type"], "Ack");
+    assert!(ack.get("topic").is_none());
+
+    let subscribe: Value = serde_json::from_str(&Envelope::subscribe("system.stats").encode()).unwrap();
+    assert_eq!(subscribe["type"], "Subscribe");
+    assert_eq!(subscribe["topic"], "system.stats");
+}
+
+#[test]
+fn test_other_versions_and_types_are_refused() {
+    let newer = r#"{"version": 2, "id": "1", "type": "Text", "payload": "hi"}"#;
+    assert!(matches!(Envelope::decode(newer), Err(DecodeError::Version(2))));
+    for invalid in [
+        "hi",
+        r#"{"id": "1", "type": "Text", "payload": "hi"}"#,
+        r#"{"version": 1, "id": "1", "type": "Shout", "payload": "hi"}"#,
+        r#"{"version": 1, "id": "1", "type": "Text", "payload": 7}"#,
+    ] {
+        assert!(matches!(Envelope::decode(invalid), Err(DecodeError::Invalid(_))), "{}", invalid);
+    }
+}
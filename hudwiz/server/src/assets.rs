@@ -0,0 +1,264 @@
+use crate::config::ServerConfig;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use std::sync::Arc;
+
+/// For files whose name carries a content hash: a new build gets a new URL,
+/// so a cached copy never goes stale.
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// For everything else, including `index.html` and `static/config.json`:
+/// browsers may cache but must revalidate, which is a 304 while the ETag matches.
+pub const NO_CACHE: &str = "no-cache";
+
+/// Serves the built frontend: from the binary when built with the
+/// `embed-assets` feature, otherwise from `server.static_dir`. Paths that
+/// don't match a file get the SPA fallback page. Precompressed `.br` and
+/// `.gz` siblings are sent to clients accepting them.
+pub fn router(config: &ServerConfig) -> Router {
+    #[cfg(feature = "embed-assets")]
+    {
+        let fallback = config.spa_fallback.to_string_lossy().replace('\\', "/");
+        Router::new()
+            .fallback(embedded::serve)
+            .with_state(Arc::<str>::from(fallback))
+    }
+    #[cfg(not(feature = "embed-assets"))]
+    {
+        Router::new()
+            .fallback(disk::serve)
+            .with_state(Arc::new(disk::Assets::new(config)))
+    }
+}
+
+/// Where the assets come from, for the startup log.
+pub fn source(config: &ServerConfig) -> String {
+    if cfg!(feature = "embed-assets") {
+        "the server binary".to_string()
+    } else {
+        config.static_dir.display().to_string()
+    }
+}
+
+/// The SPA fallback page, or an empty string if it's missing.
+pub fn spa_fallback_html(config: &ServerConfig) -> String {
+    #[cfg(feature = "embed-assets")]
+    {
+        embedded::Dist::get(&config.spa_fallback.to_string_lossy())
+            .map(|file| String::from_utf8_lossy(&file.data).into_owned())
+            .unwrap_or_default()
+    }
+    #[cfg(not(feature = "embed-assets"))]
+    {
+        std::fs::read_to_string(config.spa_fallback_path()).unwrap_or_default()
+    }
+}
+
+/// The asset at `path`, relative to the asset root, if there is one.
+pub fn read(config: &ServerConfig, path: &str) -> Option<Vec<u8>> {
+    #[cfg(feature = "embed-assets")]
+    {
+        let _ = config;
+        embedded::Dist::get(path).map(|file| file.data.into_owned())
+    }
+    #[cfg(not(feature = "embed-assets"))]
+    {
+        std::fs::read(config.static_dir.join(path)).ok()
+    }
+}
+
+/// `Cache-Control` for the file called `name`.
+pub fn cache_control(name: &str) -> &'static str {
+    if is_hashed(name) { IMMUTABLE } else { NO_CACHE }
+}
+
+/// Whether a file name carries a content hash, like the
+/// `hudwiz-frontend-3f2a9c1e0b7d4a65_bg.wasm` or `main.3f2a9c1e.css` names
+/// bundlers produce: a run of at least eight hex digits, at least one of
+/// them a digit, between separators.
+fn is_hashed(name: &str) -> bool {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+    name.split(['-', '_', '.']).any(|part| {
+        part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()) && part.chars().any(|c| c.is_ascii_digit())
+    })
+}
+
+/// Whether `If-None-Match` lists `etag`, compared weakly as RFC 9110
+/// requires for GET.
+fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || strip(tag) == strip(etag))
+}
+
+/// Adds the caching headers to a successful response.
+fn with_cache_headers(mut response: Response, etag: Option<&str>, cache_control: &'static str) -> Response {
+    if !(response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED) {
+        return response;
+    }
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(etag).ok()) {
+        headers.insert(header::ETAG, etag);
+    }
+    response
+}
+
+fn not_modified_response(etag: &str, cache_control: &'static str) -> Response {
+    with_cache_headers(StatusCode::NOT_MODIFIED.into_response(), Some(etag), cache_control)
+}
+
+/// The request path relative to the asset root, percent-decoded, or `None`
+/// when it tries to leave the root.
+fn relative_path(request: &Request) -> Option<String> {
+    let path = percent_encoding::percent_decode_str(request.uri().path())
+        .decode_utf8()
+        .ok()?;
+    let path = path.trim_start_matches('/');
+    if path.split('/').any(|segment| segment == ".." || segment.contains('\\')) {
+        return None;
+    }
+    if path.is_empty() || path.ends_with('/') {
+        Some(format!("{}index.html", path))
+    } else {
+        Some(path.to_string())
+    }
+}
+
+#[cfg(not(feature = "embed-assets"))]
+mod disk {
+    use super::*;
+    use std::{
+        path::{Path, PathBuf},
+        time::UNIX_EPOCH,
+    };
+    use tower::ServiceExt;
+    use tower_http::services::{ServeDir, ServeFile};
+
+    pub struct Assets {
+        dir: PathBuf,
+        fallback: PathBuf,
+        files: ServeDir<ServeFile>,
+    }
+
+    impl Assets {
+        pub fn new(config: &ServerConfig) -> Self {
+            let fallback = config.spa_fallback_path();
+            let files = ServeDir::new(&config.static_dir)
+                .precompressed_br()
+                .precompressed_gzip()
+                .fallback(ServeFile::new(&fallback).precompressed_br().precompressed_gzip());
+            Self {
+                dir: config.static_dir.clone(),
+                fallback,
+                files,
+            }
+        }
+
+        /// The file `ServeDir` will answer `request` with.
+        fn resolve(&self, request: &Request) -> PathBuf {
+            relative_path(request)
+                .map(|path| self.dir.join(path))
+                .filter(|path| path.is_file())
+                .unwrap_or_else(|| self.fallback.clone())
+        }
+    }
+
+    /// Weak ETag from the size and modification time, which is all `ServeDir`
+    /// looks at too. Weak because precompressed variants share it.
+    fn etag(path: &Path) -> Option<String> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(format!("W/\"{:x}-{:x}\"", metadata.len(), modified.as_nanos()))
+    }
+
+    pub async fn serve(State(assets): State<Arc<Assets>>, request: Request) -> Response {
+        let file = assets.resolve(&request);
+        let cache_control = cache_control(&file.to_string_lossy());
+        let etag = etag(&file);
+        if let Some(etag) = &etag
+            && not_modified(request.headers(), etag)
+        {
+            return not_modified_response(etag, cache_control);
+        }
+        let response = match assets.files.clone().oneshot(request).await {
+            Ok(response) => response.map(Body::new),
+            Err(never) => match never {},
+        };
+        with_cache_headers(response, etag.as_deref(), cache_control)
+    }
+}
+
+#[cfg(feature = "embed-assets")]
+mod embedded {
+    use super::*;
+    use rust_embed::{EmbeddedFile, RustEmbed};
+    use std::borrow::Cow;
+
+    /// The frontend, which must be built into `hudwiz/frontend/dist` before
+    /// compiling the server.
+    #[derive(RustEmbed)]
+    #[folder = "../frontend/dist"]
+    pub struct Dist;
+
+    /// Whether `Accept-Encoding` allows `encoding`.
+    fn accepts(headers: &HeaderMap, encoding: &str) -> bool {
+        headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                parts.next() == Some(encoding) && !parts.any(|param| matches!(param, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"))
+            })
+    }
+
+    pub async fn serve(State(fallback): State<Arc<str>>, request: Request) -> Response {
+        let found = relative_path(&request).and_then(|path| Dist::get(&path).map(|file| (path, file)));
+        let Some((name, file)) = found.or_else(|| Dist::get(&fallback).map(|file| (fallback.to_string(), file)))
+        else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+
+        let mimetype = file.metadata.mimetype().to_string();
+        let (file, encoding): (EmbeddedFile, Option<&'static str>) = [("br", "br"), ("gzip", "gz")]
+            .into_iter()
+            .filter(|(encoding, _)| accepts(request.headers(), encoding))
+            .find_map(|(encoding, extension)| {
+                Dist::get(&format!("{}.{}", name, extension)).map(|file| (file, Some(encoding)))
+            })
+            .unwrap_or((file, None));
+
+        let cache_control = cache_control(&name);
+        let hash: String = file.metadata.sha256_hash().iter().map(|byte| format!("{:02x}", byte)).collect();
+        let etag = format!("\"{}\"", &hash[..32]);
+        if not_modified(request.headers(), &etag) {
+            return not_modified_response(&etag, cache_control);
+        }
+
+        let body = match file.data {
+            Cow::Borrowed(bytes) => Body::from(bytes),
+            Cow::Owned(bytes) => Body::from(bytes),
+        };
+        let mut response = Response::new(body);
+        let headers = response.headers_mut();
+        if let Ok(mimetype) = HeaderValue::from_str(&mimetype) {
+            headers.insert(header::CONTENT_TYPE, mimetype);
+        }
+        if let Some(encoding) = encoding {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        with_cache_headers(response, Some(&etag), cache_control)
+    }
+}
@@ -0,0 +1,76 @@
+use crate::storage::AUDIT_FILE;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use time::OffsetDateTime;
+use tracing::error;
+
+/// Security-relevant events, one JSON object per line in the audit log.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Signup { username: String },
+    Login { username: String, method: String, success: bool },
+    Logout { username: Option<String> },
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: i64,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Append-only audit log in the data directory. Writes are buffered until
+/// `flush`; without a data directory events are discarded.
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    writer: Option<Arc<Mutex<BufWriter<File>>>>,
+}
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(AUDIT_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(Self {
+            writer: Some(Arc::new(Mutex::new(BufWriter::new(file)))),
+        })
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let entry = Entry {
+            time: OffsetDateTime::now_utc().unix_timestamp(),
+            event: &event,
+        };
+        let mut writer = writer.lock().unwrap();
+        let written = serde_json::to_writer(&mut *writer, &entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| Ok(writer.write_all(b"\n")?));
+        if let Err(e) = written {
+            error!("Failed to write audit event {:?}: {}", event, e);
+        }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.lock().unwrap().flush().context("Failed to flush the audit log")?;
+        }
+        Ok(())
+    }
+}
@@ -1,34 +1,307 @@
+use crate::{storage::{self, JsonFile}, telemetry::username};
 use async_trait::async_trait;
-use axum::response::Redirect;
+use crate::ApiError;
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use tower_sessions::Session;
 use serde::{Deserialize, Serialize};
-use anyhow::bail;
-use argon2::{password_hash::PasswordVerifier, Argon2};
+use anyhow::{anyhow, bail};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use tracing::{info, warn, error};
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::User => f.write_str("user"),
+            Role::Admin => f.write_str("admin"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => bail!("Unknown role '{}', expected 'user' or 'admin'", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
     pub username: String,
     pub password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl User {
+    pub fn new(username: &str, password_hash: String, role: Role) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            password_hash,
+            role,
+            disabled: false,
+        }
+    }
+}
+
+/// Hashes a password with the default Argon2id parameters.
+pub fn hash_password(password: &str) -> Result<String, anyhow::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))
+}
+
+#[derive(Debug)]
+pub enum UserStoreError {
+    AlreadyExists(String),
+    NotFound(String),
+    Storage(anyhow::Error),
+}
+
+impl fmt::Display for UserStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserStoreError::AlreadyExists(name) => write!(f, "User '{}' already exists", name),
+            UserStoreError::NotFound(name) => write!(f, "User '{}' not found", name),
+            UserStoreError::Storage(e) => write!(f, "User store error: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for UserStoreError {}
+
+impl From<anyhow::Error> for UserStoreError {
+    fn from(e: anyhow::Error) -> Self {
+        UserStoreError::Storage(e)
+    }
+}
+
+/// Users keyed by username. A file-backed store keeps the file parsed in
+/// memory and reads it again when it changes, so edits made by the CLI while
+/// the server runs are never lost. File access happens on the blocking pool.
+#[derive(Clone)]
+pub struct UserStore {
+    backend: Arc<Mutex<Backend>>,
+}
+
+enum Backend {
+    Memory(HashMap<String, User>),
+    File(JsonFile<Users>),
+}
+
+/// The users file, a list sorted by username.
+#[derive(Default, Deserialize)]
+#[serde(from = "Vec<User>")]
+struct Users(HashMap<String, User>);
+
+impl From<Vec<User>> for Users {
+    fn from(list: Vec<User>) -> Self {
+        Self(list.into_iter().map(|u| (u.username.clone(), u)).collect())
+    }
+}
+
+impl Serialize for Users {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut list: Vec<&User> = self.0.values().collect();
+        list.sort_by(|a, b| a.username.cmp(&b.username));
+        list.serialize(serializer)
+    }
+}
+
+impl UserStore {
+    pub fn in_memory() -> Self {
+        Self { backend: Arc::new(Mutex::new(Backend::Memory(HashMap::new()))) }
+    }
+
+    /// A store persisted to `users.json` in `data_dir`.
+    pub fn open(data_dir: &std::path::Path) -> Self {
+        Self { backend: Arc::new(Mutex::new(Backend::File(JsonFile::new(data_dir.join(storage::USERS_FILE))))) }
+    }
+
+    /// Applies `f` to the users on the calling thread, writing them back if it reports a change.
+    fn with_users<R>(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, User>) -> Result<(R, bool), UserStoreError>,
+    ) -> Result<R, UserStoreError> {
+        let mut backend = self.backend.lock().unwrap();
+        match &mut *backend {
+            Backend::Memory(users) => f(users).map(|(result, _)| result),
+            Backend::File(file) => {
+                let (result, modified) = f(&mut file.read()?.0)?;
+                if modified {
+                    file.write()?;
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    /// `with_users` on the blocking pool.
+    async fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, User>) -> Result<(R, bool), UserStoreError> + Send + 'static,
+    ) -> Result<R, UserStoreError> {
+        let store = self.clone();
+        storage::blocking(move || store.with_users(f)).await?
+    }
+
+    pub async fn get(&self, username: &str) -> Result<Option<User>, UserStoreError> {
+        let username = username.to_string();
+        self.run(move |users| Ok((users.get(&username).cloned(), false))).await
+    }
+
+    /// All users, sorted by username.
+    pub async fn list(&self) -> Result<Vec<User>, UserStoreError> {
+        self.run(|users| {
+            let mut list: Vec<User> = users.values().cloned().collect();
+            list.sort_by(|a, b| a.username.cmp(&b.username));
+            Ok((list, false))
+        })
+        .await
+    }
+
+    pub async fn create(&self, user: User) -> Result<(), UserStoreError> {
+        self.run(|users| insert(users, user).map(|()| ((), true))).await
+    }
+
+    /// Adds `user` unless a user with the same name exists. Returns whether
+    /// it was added. Runs on the calling thread, for use at startup.
+    pub fn ensure(&self, user: User) -> Result<bool, UserStoreError> {
+        match self.with_users(|users| insert(users, user).map(|()| ((), true))) {
+            Ok(()) => Ok(true),
+            Err(UserStoreError::AlreadyExists(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The role of a logged in `user`. Users who log in with SSO aren't in
+    /// the store and have the `user` role.
+    pub async fn role_of(&self, user: &UserInfo) -> Role {
+        match self.get(&user.name).await {
+            Ok(Some(stored)) if stored.id == user.id => stored.role,
+            _ => Role::User,
+        }
+    }
+
+    /// Applies `change` to an existing user and returns the updated record.
+    pub async fn update(
+        &self,
+        username: &str,
+        change: impl FnOnce(&mut User) + Send + 'static,
+    ) -> Result<User, UserStoreError> {
+        let username = username.to_string();
+        self.run(move |users| match users.get_mut(&username) {
+            Some(user) => {
+                change(user);
+                Ok((user.clone(), true))
+            }
+            None => Err(UserStoreError::NotFound(username)),
+        })
+        .await
+    }
 }
 
-pub type UserStore = Arc<Mutex<HashMap<String, User>>>;
+fn insert(users: &mut HashMap<String, User>, user: User) -> Result<(), UserStoreError> {
+    if users.contains_key(&user.username) {
+        return Err(UserStoreError::AlreadyExists(user.username));
+    }
+    users.insert(user.username.clone(), user);
+    Ok(())
+}
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct Credentials {
     pub username: String,
     pub password: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &username(&self.username).to_string())
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct UserInfo {
     pub id: String,
     pub name: String,
     pub email: String,
 }
 
+/// The logged in user, from the session. Handlers taking it answer
+/// `401 Unauthorized` to everyone else.
+pub struct CurrentUser(pub UserInfo);
+
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        match session.get::<UserInfo>("user").await {
+            Ok(Some(user)) => Ok(Self(user)),
+            Ok(None) => Err(ApiError::response(StatusCode::UNAUTHORIZED, "Not logged in")),
+            Err(e) => {
+                error!("Failed to read the session: {}", e);
+                Err(ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read the session"))
+            }
+        }
+    }
+}
+
+/// Logs out sessions of users disabled since they logged in, before
+/// anything reads the session. Users who log in with SSO aren't in the
+/// store and are left alone, as are stored users of the same name.
+pub async fn drop_disabled_sessions(State(users): State<UserStore>, session: Session, request: Request, next: Next) -> Response {
+    if let Ok(Some(user)) = session.get::<UserInfo>("user").await
+        && let Ok(Some(stored)) = users.get(&user.name).await
+        && stored.id == user.id
+        && stored.disabled
+    {
+        warn!("Ending the session of disabled user {}", username(&user.name));
+        if let Err(e) = session.flush().await {
+            error!("Failed to end the session: {}", e);
+            return ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to end the session");
+        }
+    }
+    next.run(request).await
+}
+
 #[async_trait]
 pub trait AuthProvider {
     /// Authenticates a user with a username and password.
@@ -62,17 +335,21 @@ impl DbPrimaryProvider {
 #[async_trait]
 impl AuthProvider for DbPrimaryProvider {
     async fn login_with_credentials(&self, creds: Credentials) -> Result<UserInfo, anyhow::Error> {
-        info!("Attempting to log in user: {}", creds.username);
+        info!("Attempting to log in user: {}", username(&creds.username));
 
-        let users = self.user_store.lock().unwrap();
-        let user = match users.get(&creds.username) {
+        let user = match self.user_store.get(&creds.username).await? {
             Some(user) => user,
             None => {
-                warn!("Invalid username provided: {}", creds.username);
+                warn!("Invalid username provided: {}", username(&creds.username));
                 bail!("Invalid username");
             }
         };
 
+        if user.disabled {
+            warn!("Login attempt for disabled user: {}", username(&creds.username));
+            bail!("User is disabled");
+        }
+
         info!("Username matched. Proceeding to password verification.");
         let password_hash = &user.password_hash;
 
@@ -87,16 +364,16 @@ impl AuthProvider for DbPrimaryProvider {
             }
         };
 
-        info!("Verifying password for user: {}", creds.username);
+        info!("Verifying password for user: {}", username(&creds.username));
         if Argon2::default().verify_password(creds.password.as_bytes(), &parsed_hash).is_ok() {
-            info!("Password verification successful for user: {}", creds.username);
+            info!("Password verification successful for user: {}", username(&creds.username));
             Ok(UserInfo {
                 id: user.id.clone(),
                 name: user.username.clone(),
                 email: user.username.clone(),
             })
         } else {
-            warn!("Invalid password for user: {}", creds.username);
+            warn!("Invalid password for user: {}", username(&creds.username));
             bail!("Invalid password");
         }
     }
@@ -122,21 +399,14 @@ impl AuthProvider for DbPrimaryProvider {
 
 #[cfg(test)]
 mod tests {
-    use argon2::{
-        password_hash::{
-            rand_core::OsRng,
-            PasswordHasher, SaltString
-        },
-        Argon2
-    };
-    use tracing::info;
+    use super::hash_password;
+    use argon2::{password_hash::{PasswordHash, PasswordVerifier}, Argon2};
 
     #[test]
-    fn generate_password_hash() {
-        let password = b"password";
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2.hash_password(password, &salt).unwrap().to_string();
-        info!("START_HASH:{}END_HASH", password_hash);
+    fn hash_password_round_trip() {
+        let password_hash = hash_password("password").unwrap();
+        let parsed = PasswordHash::new(&password_hash).unwrap();
+        assert!(Argon2::default().verify_password(b"password", &parsed).is_ok());
+        assert!(Argon2::default().verify_password(b"wrong", &parsed).is_err());
     }
 }
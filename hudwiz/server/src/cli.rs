@@ -0,0 +1,195 @@
+use crate::{
+    auth::{hash_password, Role, User, UserStore},
+    config::{self, Config},
+    serve::serve,
+    storage,
+    telemetry,
+    tls,
+};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+/// The hudwiz server and its administration commands.
+#[derive(Debug, Parser)]
+#[command(name = "hudwiz-server", version, about)]
+pub struct Cli {
+    /// Configuration file, instead of `$HUDWIZ_CONFIG` or ./server_config.toml.
+    #[arg(long, short, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server. This is the default when no command is given.
+    Serve,
+    /// Validate the configuration and print it with all overrides applied.
+    CheckConfig,
+    /// Hash a password for `auth.providers.mock.password_hash`.
+    HashPassword {
+        /// Read from standard input when omitted.
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Manage users in the configured data directory.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Write a self-signed development certificate to the configured paths.
+    GenCert {
+        /// Replace existing certificate files.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Bring the data directory up to the current schema version.
+    Migrate,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Add a user.
+    Create {
+        username: String,
+        #[arg(long, default_value_t = Role::User)]
+        role: Role,
+        /// Read from standard input when omitted.
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// List all users.
+    List,
+    /// Prevent a user from logging in, and end the sessions they have.
+    Disable { username: String },
+    /// Change a user's role.
+    SetRole { username: String, role: Role },
+}
+
+pub async fn run(cli: Cli) -> Result<()> {
+    let config_path = cli.config.unwrap_or_else(config::config_path);
+    let read_config = || {
+        config::load_config_from(&config_path)
+            .with_context(|| format!("Failed to load configuration from {}", config_path.display()))
+    };
+    // Administrative commands log like the server but don't export traces, so
+    // the telemetry guard holds nothing and needn't be kept.
+    let load = || -> Result<Config> {
+        let config = read_config()?;
+        let mut logging = config.logging.clone();
+        logging.otlp_endpoint = None;
+        let _telemetry = telemetry::init(&logging)?;
+        Ok(config)
+    };
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            let config = read_config()?;
+            let _telemetry = telemetry::init(&config.logging)?;
+            serve(config, config_path).await
+        }
+        Command::CheckConfig => check_config(&load()?),
+        Command::HashPassword { password } => {
+            println!("{}", hash_password(&read_password(password)?)?);
+            Ok(())
+        }
+        Command::User(command) => user(&load()?, command).await,
+        Command::GenCert { force } => gen_cert(&load()?, force),
+        Command::Migrate => migrate(&load()?),
+    }
+}
+
+fn check_config(config: &Config) -> Result<()> {
+    print!("{}", toml::to_string_pretty(config)?);
+    let problems = config.validate();
+    for problem in &problems {
+        eprintln!("problem: {}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("Configuration has {} problem(s)", problems.len());
+    }
+    eprintln!("Configuration OK");
+    Ok(())
+}
+
+async fn user(config: &Config, command: UserCommand) -> Result<()> {
+    let data_dir = data_dir(config)?;
+    storage::open(&data_dir)?;
+    let store = UserStore::open(&data_dir);
+
+    match command {
+        UserCommand::Create { username, role, password } => {
+            let password_hash = hash_password(&read_password(password)?)?;
+            store.create(User::new(&username, password_hash, role)).await?;
+            println!("Created {} {}", role, username);
+        }
+        UserCommand::List => {
+            println!("{:<24} {:<6} {:<8} ID", "USERNAME", "ROLE", "STATUS");
+            for user in store.list().await? {
+                let status = if user.disabled { "disabled" } else { "active" };
+                println!("{:<24} {:<6} {:<8} {}", user.username, user.role, status, user.id);
+            }
+        }
+        UserCommand::Disable { username } => {
+            store.update(&username, |user| user.disabled = true).await?;
+            println!("Disabled {}", username);
+        }
+        UserCommand::SetRole { username, role } => {
+            store.update(&username, move |user| user.role = role).await?;
+            println!("{} is now {}", username, role);
+        }
+    }
+    Ok(())
+}
+
+fn gen_cert(config: &Config, force: bool) -> Result<()> {
+    let tls = &config.tls;
+    if !force && (tls.cert_path.exists() || tls.key_path.exists()) {
+        bail!("{} already exists, pass --force to replace it", tls.cert_path.display());
+    }
+    tls::generate_dev_certificate(&tls.cert_path, &tls.key_path, &tls.dev_cert_names)?;
+    let info = tls::read_certificate(&tls.cert_path)?;
+    println!("Wrote {} and {}", tls.cert_path.display(), tls.key_path.display());
+    println!("SHA-256: {}", info.sha256_hex());
+    Ok(())
+}
+
+fn migrate(config: &Config) -> Result<()> {
+    let data_dir = data_dir(config)?;
+    let applied = storage::migrate(&data_dir)?;
+    if applied.is_empty() {
+        println!("{} is up to date (schema version {})", data_dir.display(), storage::SCHEMA_VERSION);
+    }
+    for description in applied {
+        println!("Applied: {}", description);
+    }
+    Ok(())
+}
+
+fn data_dir(config: &Config) -> Result<PathBuf> {
+    config
+        .storage
+        .data_dir
+        .clone()
+        .context("storage.data_dir is not set, so the server keeps its state in memory only")
+}
+
+fn read_password(password: Option<String>) -> Result<String> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            eprint!("Password: ");
+            std::io::stderr().flush()?;
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if password.is_empty() {
+        bail!("Password must not be empty");
+    }
+    Ok(password)
+}
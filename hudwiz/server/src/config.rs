@@ -1,45 +1,861 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug, Deserialize)]
+/// Environment variable pointing at the configuration file to load.
+pub const CONFIG_PATH_ENV: &str = "HUDWIZ_CONFIG";
+
+/// Prefix for environment variables overriding configuration values, e.g.
+/// `HUDWIZ__SERVER__HTTP_ADDR=0.0.0.0:9000`.
+pub const ENV_PREFIX: &str = "HUDWIZ";
+
+const DEFAULT_CONFIG_PATH: &str = "server_config.toml";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub layouts: LayoutsConfig,
+    #[serde(default)]
+    pub uploads: UploadsConfig,
+    #[serde(default)]
+    pub system_stats: SystemStatsConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfig,
+    #[serde(default)]
+    pub realtime: RealtimeConfig,
     pub auth: AuthConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address the HTTP server binds to.
+    pub http_addr: SocketAddr,
+    /// Address the WebTransport (QUIC) server binds to.
+    pub webtransport_addr: SocketAddr,
+    /// Externally visible base URL, used when building absolute links.
+    pub public_url: String,
+    /// Directory holding the built frontend assets.
+    pub static_dir: PathBuf,
+    /// File served for any path that doesn't match an asset, relative to `static_dir`.
+    pub spa_fallback: PathBuf,
+    /// How long shutdown waits for open connections to close, in seconds.
+    pub drain_timeout_secs: u64,
+    /// Separate listener for `/healthz`, `/readyz` and `/metrics`. When set,
+    /// `/metrics` is only served here.
+    pub admin_addr: Option<SocketAddr>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            http_addr: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            webtransport_addr: SocketAddr::from(([0, 0, 0, 0], 4433)),
+            public_url: "http://localhost:8080".to_string(),
+            static_dir: PathBuf::from("hudwiz/frontend/dist"),
+            spa_fallback: PathBuf::from("index.html"),
+            drain_timeout_secs: 30,
+            admin_addr: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Full path of the SPA fallback file.
+    pub fn spa_fallback_path(&self) -> PathBuf {
+        self.static_dir.join(&self.spa_fallback)
+    }
+
+    /// URL browsers use for WebTransport: the public host on the WebTransport port.
+    pub fn webtransport_url(&self) -> String {
+        let port = self.webtransport_addr.port();
+        match url::Url::parse(&self.public_url) {
+            Ok(mut url) => {
+                let _ = url.set_scheme("https");
+                let _ = url.set_port(Some(port));
+                url.set_path("/");
+                url.set_query(None);
+                url.to_string()
+            }
+            Err(_) => format!("https://localhost:{}/", port),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// Serve HTTPS instead of plain HTTP on `server.http_addr`.
+    pub enabled: bool,
+    /// PEM certificate chain shared by the HTTPS and WebTransport servers.
+    pub cert_path: PathBuf,
+    /// PEM private key matching `cert_path`.
+    pub key_path: PathBuf,
+    /// Subject names of the development certificate generated when neither file exists.
+    pub dev_cert_names: Vec<String>,
+    /// Plain-HTTP address redirecting every request to `server.public_url`. Only used when `enabled`.
+    pub redirect_addr: Option<SocketAddr>,
+    /// How often the certificate files are checked for changes, in seconds.
+    pub reload_interval_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: PathBuf::from("certs/cert.pem"),
+            key_path: PathBuf::from("certs/key.pem"),
+            dev_cert_names: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+            redirect_addr: None,
+            reload_interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Directory for users and other server-side state. Everything is kept in
+    /// memory and lost on restart when unset.
+    pub data_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// `tracing` filter directives such as `info,server_lib=debug`. `RUST_LOG` takes precedence.
+    pub level: String,
+    pub format: LogFormat,
+    /// Log a stable pseudonym instead of each username or email address.
+    pub redact_usernames: bool,
+    /// Secret the pseudonyms are keyed with, so they can't be reversed by
+    /// hashing likely names. Empty uses a random key, and pseudonyms change
+    /// with every start.
+    #[serde(serialize_with = "redact")]
+    pub redact_key: String,
+    /// OTLP/HTTP endpoint spans are exported to, e.g. `http://localhost:4318/v1/traces`.
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` reported with exported spans.
+    pub service_name: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            redact_usernames: true,
+            redact_key: String::new(),
+            otlp_endpoint: None,
+            service_name: "hudwiz-server".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// `max-age` of the `Strict-Transport-Security` header sent when TLS is
+    /// enabled. 0 leaves the header out.
+    pub hsts_max_age_secs: u64,
+    /// Value of the `Referrer-Policy` header.
+    pub referrer_policy: String,
+    pub cors: CorsConfig,
+    pub content_sources: ContentSources,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            hsts_max_age_secs: 31_536_000,
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            cors: CorsConfig::default(),
+            content_sources: ContentSources::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins such as `https://app.example.com` allowed to call `/api` from
+    /// the browser, or `*` for any. Cross-origin requests are refused when empty.
+    pub allowed_origins: Vec<String>,
+    /// Let allowed origins send cookies, and so use the caller's session.
+    pub allow_credentials: bool,
+}
+
+/// Origins the UI may load content from, added to the page's
+/// `Content-Security-Policy`. Entries are CSP source expressions such as
+/// `https://www.openstreetmap.org` or `https://*.example.com`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ContentSources {
+    /// Pages shown by `IFrame` and `WebGL` content (`frame-src`).
+    pub frame: Vec<String>,
+    /// `Video` content (`media-src`).
+    pub media: Vec<String>,
+    /// `Image` content (`img-src`).
+    pub image: Vec<String>,
+    pub style: Vec<String>,
+    pub font: Vec<String>,
+    /// APIs the UI calls besides this server (`connect-src`).
+    pub connect: Vec<String>,
+}
+
+impl Default for ContentSources {
+    /// What the bundled frontend and its demo content use.
+    fn default() -> Self {
+        let sources = |sources: &[&str]| sources.iter().map(|source| source.to_string()).collect();
+        Self {
+            frame: sources(&["https://www.openstreetmap.org", "https://codepen.io"]),
+            media: sources(&["http://commondatastorage.googleapis.com"]),
+            image: Vec::new(),
+            style: sources(&["https://fonts.googleapis.com"]),
+            font: sources(&["https://fonts.gstatic.com"]),
+            connect: Vec::new(),
+        }
+    }
+}
+
+impl ContentSources {
+    /// Each list with the name of its setting.
+    pub fn by_kind(&self) -> [(&'static str, &[String]); 6] {
+        [
+            ("frame", &self.frame),
+            ("media", &self.media),
+            ("image", &self.image),
+            ("style", &self.style),
+            ("font", &self.font),
+            ("connect", &self.connect),
+        ]
+    }
+}
+
+const REFERRER_POLICIES: &[&str] = &[
+    "no-referrer",
+    "no-referrer-when-downgrade",
+    "origin",
+    "origin-when-cross-origin",
+    "same-origin",
+    "strict-origin",
+    "strict-origin-when-cross-origin",
+    "unsafe-url",
+];
+
+/// Which named layout users start from. `/api/layout/current` applies the
+/// default, then the one for the user's organisation, then the one for their
+/// role, then the user's own override, each replacing the regions it sets.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LayoutsConfig {
+    /// Layout for everyone. `standard` is built in, unless one is stored
+    /// under that name.
+    pub default: String,
+    /// Layouts by role, `user` or `admin`.
+    pub roles: BTreeMap<String, String>,
+    /// Layouts by organisation, which is the domain of the user's email
+    /// address, such as `example.com`.
+    pub orgs: BTreeMap<String, String>,
+}
+
+impl Default for LayoutsConfig {
+    fn default() -> Self {
+        Self {
+            default: crate::layouts::STANDARD.to_string(),
+            roles: BTreeMap::new(),
+            orgs: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UploadsConfig {
+    /// Directory for uploaded files. Defaults to `uploads` in
+    /// `storage.data_dir`; uploads are disabled when neither is set.
+    pub dir: Option<PathBuf>,
+    /// Largest accepted file.
+    pub max_file_bytes: u64,
+    /// Total size of the files each user may keep.
+    pub quota_bytes: u64,
+    /// Accepted media types. Images are also checked against their content.
+    pub allowed_types: Vec<String>,
+    /// How long download links stay valid.
+    pub url_ttl_secs: u64,
+    /// Longest side of the thumbnails made for images.
+    pub thumbnail_px: u32,
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_file_bytes: 25 * 1024 * 1024,
+            quota_bytes: 250 * 1024 * 1024,
+            allowed_types: ["image/png", "image/jpeg", "image/gif", "image/webp", "application/pdf", "text/plain"]
+                .map(String::from)
+                .to_vec(),
+            url_ttl_secs: 3600,
+            thumbnail_px: 256,
+        }
+    }
+}
+
+impl UploadsConfig {
+    /// Where uploads are kept, if anywhere.
+    pub fn resolved_dir(&self, storage: &StorageConfig) -> Option<PathBuf> {
+        self.dir.clone().or_else(|| storage.data_dir.as_ref().map(|data_dir| data_dir.join(crate::storage::UPLOADS_DIR)))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Origins `/api/proxy` may fetch from, such as
+    /// `https://www.openstreetmap.org`. The proxy is off while empty.
+    pub upstreams: Vec<String>,
+    /// How long an upstream has to answer.
+    pub timeout_secs: u64,
+    /// Memory kept for cached responses. 0 turns caching off.
+    pub cache_bytes: u64,
+    /// Largest response, or range of one, that is cached.
+    pub cache_entry_bytes: u64,
+    /// How long cached responses are reused.
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            timeout_secs: 30,
+            cache_bytes: 64 * 1024 * 1024,
+            cache_entry_bytes: 8 * 1024 * 1024,
+            cache_ttl_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimitsConfig {
+    /// Refuse requests and realtime messages over the limits below.
+    pub enabled: bool,
+    /// Count clients by the first `X-Forwarded-For` address rather than the
+    /// connection's, for servers behind a reverse proxy.
+    pub trust_forwarded_for: bool,
+    /// `POST`s under `/api/auth/`: logins, signups and the like.
+    pub auth: RateLimitGroup,
+    /// Every other `/api` request.
+    pub api: RateLimitGroup,
+    /// Messages received on `/ws`, `/sse` and WebTransport.
+    pub realtime: RateLimitGroup,
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trust_forwarded_for: false,
+            auth: RateLimitGroup {
+                per_ip: Some(TokenBucket { per_sec: 1.0, burst: 20 }),
+                per_user: None,
+            },
+            api: RateLimitGroup {
+                per_ip: Some(TokenBucket { per_sec: 50.0, burst: 200 }),
+                per_user: Some(TokenBucket { per_sec: 20.0, burst: 100 }),
+            },
+            realtime: RateLimitGroup {
+                per_ip: Some(TokenBucket { per_sec: 100.0, burst: 200 }),
+                per_user: Some(TokenBucket { per_sec: 20.0, burst: 100 }),
+            },
+        }
+    }
+}
+
+/// The limits on one kind of traffic. Either may be left out.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimitGroup {
+    /// Shared by everything from one client address.
+    pub per_ip: Option<TokenBucket>,
+    /// Shared by all of a logged in user's sessions.
+    pub per_user: Option<TokenBucket>,
+}
+
+/// Allows `burst` requests at once, refilled at `per_sec`.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct TokenBucket {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SystemStatsConfig {
+    /// Serve host statistics on `/api/system/stats` and to realtime
+    /// subscribers. Only Linux is supported.
+    pub enabled: bool,
+    /// Seconds between samples.
+    pub interval_secs: u64,
+    /// Samples kept for the sparklines.
+    pub history_len: usize,
+    /// Mount points whose disk usage is reported.
+    pub mounts: Vec<PathBuf>,
+}
+
+impl Default for SystemStatsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 5,
+            history_len: 60,
+            mounts: vec![PathBuf::from("/")],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RealtimeConfig {
+    /// Seconds a disconnected client has to reconnect and resume its session
+    /// before the session is forgotten.
+    pub resume_secs: u64,
+    /// Messages kept per session to replay to a client that resumes it.
+    pub replay_len: usize,
+    /// Topic messages queued per connection for it to send.
+    pub queue_len: usize,
+    /// What happens to a connection whose queue is full.
+    pub slow_consumers: SlowConsumers,
+}
+
+impl Default for RealtimeConfig {
+    fn default() -> Self {
+        Self {
+            resume_secs: 120,
+            replay_len: 256,
+            queue_len: 64,
+            slow_consumers: SlowConsumers::Drop,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowConsumers {
+    /// Messages that don't fit are dropped; the connection gets the rest.
+    #[default]
+    Drop,
+    /// The connection is closed, so the client reconnects and starts over.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignupMode {
+    /// Anyone can create an account with `POST /api/auth/signup`.
+    #[default]
+    Open,
+    /// Accounts are only created with `hudwiz-server user create`.
+    Disabled,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AuthConfig {
     pub primary_provider: String,
+    #[serde(default)]
+    pub signup: SignupMode,
     pub providers: Providers,
     pub sso_providers: SsoProviders,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Providers {
     pub mock: MockProviderConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MockProviderConfig {
     pub username: String,
+    #[serde(serialize_with = "redact")]
     pub password_hash: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SsoProviders {
     pub google: SsoProviderConfig,
     pub microsoft: SsoProviderConfig,
     pub apple: SsoProviderConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SsoProviderConfig {
     pub client_id: String,
+    #[serde(serialize_with = "redact")]
     pub client_secret: String,
+    /// Defaults to `<public_url>/api/auth/sso/callback/<provider>` when omitted.
+    #[serde(default)]
     pub redirect_uri: String,
 }
 
+/// Keeps secrets out of printed or logged configuration.
+fn redact<S: Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+const REDACTED: &str = "<redacted>";
+
+/// One setting that differs between two configurations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Dotted path of the setting, e.g. `auth.signup`.
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "<unset>".to_string());
+        write!(f, "{}: {} -> {}", self.key, show(&self.old), show(&self.new))
+    }
+}
+
+/// Loads the configuration from `$HUDWIZ_CONFIG`, or `server_config.toml` in the
+/// working directory when the variable is unset.
 pub fn load_config() -> Result<Config, config::ConfigError> {
+    load_config_from(&config_path())
+}
+
+/// The configuration file `load_config` reads.
+pub fn config_path() -> PathBuf {
+    std::env::var_os(CONFIG_PATH_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+/// Loads the configuration from `path`, layering `HUDWIZ__*` environment
+/// variables on top. Relative paths in the file are resolved against the
+/// directory containing it.
+pub fn load_config_from(path: &Path) -> Result<Config, config::ConfigError> {
     let settings = config::Config::builder()
-        .add_source(config::File::with_name("server_config"))
+        .add_source(config::File::from(path))
+        .add_source(
+            config::Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("__")
+                .separator("__"),
+        )
         .build()?;
-    settings.try_deserialize()
+    let mut config: Config = settings.try_deserialize()?;
+    config.resolve(path.parent().unwrap_or_else(|| Path::new("")));
+    Ok(config)
+}
+
+impl Config {
+    fn resolve(&mut self, base_dir: &Path) {
+        for path in [
+            &mut self.server.static_dir,
+            &mut self.tls.cert_path,
+            &mut self.tls.key_path,
+        ]
+        .into_iter()
+        .chain(self.storage.data_dir.as_mut())
+        .chain(self.uploads.dir.as_mut())
+        {
+            if path.is_relative() {
+                *path = base_dir.join(&*path);
+            }
+        }
+
+        let public_url = self.server.public_url.trim_end_matches('/').to_string();
+        let sso = &mut self.auth.sso_providers;
+        for (name, provider) in [
+            ("google", &mut sso.google),
+            ("microsoft", &mut sso.microsoft),
+            ("apple", &mut sso.apple),
+        ] {
+            if provider.redirect_uri.is_empty() {
+                provider.redirect_uri = format!("{}/api/auth/sso/callback/{}", public_url, name);
+            }
+        }
+    }
+
+    /// Settings that differ from `self` in `other`. Secrets are compared but
+    /// never shown.
+    pub fn diff(&self, other: &Config) -> Vec<Change> {
+        let (old, new) = (self.flatten(), other.flatten());
+        let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        let mut changes: Vec<Change> = keys
+            .into_iter()
+            .filter(|key| old.get(*key) != new.get(*key))
+            .map(|key| Change {
+                key: key.clone(),
+                old: old.get(key).cloned(),
+                new: new.get(key).cloned(),
+            })
+            .collect();
+        for ((key, old), (_, new)) in self.secrets().into_iter().zip(other.secrets()) {
+            if old != new {
+                changes.push(Change {
+                    key: key.to_string(),
+                    old: Some(REDACTED.to_string()),
+                    new: Some(format!("{} (changed)", REDACTED)),
+                });
+            }
+        }
+        changes
+    }
+
+    /// Hex SHA-256 over every setting, secrets included.
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        for (key, value) in self.flatten() {
+            hasher.update(format!("{}={}\n", key, value));
+        }
+        for (key, secret) in self.secrets() {
+            hasher.update(format!("{}={}\n", key, secret));
+        }
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn secrets(&self) -> [(&'static str, &str); 5] {
+        let sso = &self.auth.sso_providers;
+        [
+            ("logging.redact_key", &self.logging.redact_key),
+            ("auth.providers.mock.password_hash", &self.auth.providers.mock.password_hash),
+            ("auth.sso_providers.google.client_secret", &sso.google.client_secret),
+            ("auth.sso_providers.microsoft.client_secret", &sso.microsoft.client_secret),
+            ("auth.sso_providers.apple.client_secret", &sso.apple.client_secret),
+        ]
+    }
+
+    /// Every setting as a dotted key and its TOML value, secrets redacted.
+    fn flatten(&self) -> BTreeMap<String, String> {
+        fn walk(prefix: &str, value: &toml::Value, out: &mut BTreeMap<String, String>) {
+            match value {
+                toml::Value::Table(table) => {
+                    for (key, value) in table {
+                        let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                        walk(&key, value, out);
+                    }
+                }
+                value => {
+                    out.insert(prefix.to_string(), value.to_string());
+                }
+            }
+        }
+        let mut out = BTreeMap::new();
+        if let Ok(value) = toml::Value::try_from(self) {
+            walk("", &value, &mut out);
+        }
+        out
+    }
+
+    /// Checks the parts of the configuration that deserializing can't,
+    /// returning a description of every problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if url::Url::parse(&self.server.public_url).is_err() {
+            problems.push(format!("server.public_url '{}' is not a valid URL", self.server.public_url));
+        }
+        // Built with `embed-assets`, the frontend comes from the binary instead.
+        if !cfg!(feature = "embed-assets") {
+            if !self.server.static_dir.is_dir() {
+                problems.push(format!("server.static_dir {} is not a directory", self.server.static_dir.display()));
+            } else if !self.server.spa_fallback_path().is_file() {
+                problems.push(format!("server.spa_fallback {} does not exist", self.server.spa_fallback_path().display()));
+            }
+        }
+        if self.server.http_addr == self.server.webtransport_addr {
+            problems.push("server.http_addr and server.webtransport_addr must differ".to_string());
+        }
+        if self.server.admin_addr == Some(self.server.http_addr) {
+            problems.push("server.admin_addr and server.http_addr must differ".to_string());
+        }
+
+        if self.tls.enabled && !self.server.public_url.starts_with("https://") {
+            problems.push("tls.enabled is set but server.public_url is not an https:// URL".to_string());
+        }
+        if self.tls.cert_path.exists() != self.tls.key_path.exists() {
+            problems.push(format!(
+                "only one of tls.cert_path {} and tls.key_path {} exists",
+                self.tls.cert_path.display(),
+                self.tls.key_path.display()
+            ));
+        }
+
+        if tracing_subscriber::EnvFilter::try_new(&self.logging.level).is_err() {
+            problems.push(format!("logging.level '{}' is not a valid filter", self.logging.level));
+        }
+        if let Some(endpoint) = &self.logging.otlp_endpoint
+            && url::Url::parse(endpoint).is_err()
+        {
+            problems.push(format!("logging.otlp_endpoint '{}' is not a valid URL", endpoint));
+        }
+        if !self.logging.redact_key.is_empty() && self.logging.redact_key.len() < 16 {
+            problems.push("logging.redact_key is shorter than 16 characters".to_string());
+        }
+
+        let security = &self.security;
+        if !REFERRER_POLICIES.contains(&security.referrer_policy.as_str()) {
+            problems.push(format!("security.referrer_policy '{}' is not a referrer policy", security.referrer_policy));
+        }
+        for origin in &security.cors.allowed_origins {
+            if origin == "*" {
+                if security.cors.allow_credentials {
+                    problems.push("security.cors.allowed_origins can't contain '*' with allow_credentials".to_string());
+                }
+            } else if url::Url::parse(origin).map(|url| url.origin().ascii_serialization()).as_deref() != Ok(origin) {
+                problems.push(format!("security.cors.allowed_origins '{}' is not an origin like https://example.com", origin));
+            }
+        }
+        for (name, sources) in security.content_sources.by_kind() {
+            for source in sources {
+                if source.is_empty() || !source.is_ascii() || source.contains(|c: char| c.is_whitespace() || c == ';' || c == ',') {
+                    problems.push(format!("security.content_sources.{} '{}' is not a CSP source", name, source));
+                }
+            }
+        }
+
+        let layouts = &self.layouts;
+        let assigned = std::iter::once(("default".to_string(), &layouts.default))
+            .chain(layouts.roles.iter().map(|(role, name)| (format!("roles.{}", role), name)))
+            .chain(layouts.orgs.iter().map(|(org, name)| (format!("orgs.{}", org), name)));
+        for (key, name) in assigned {
+            if !crate::layouts::is_layout_name(name) {
+                problems.push(format!("layouts.{} '{}' is not a layout name", key, name));
+            }
+        }
+        for role in layouts.roles.keys() {
+            if role.parse::<crate::auth::Role>().is_err() {
+                problems.push(format!("layouts.roles.{} is not a role, expected 'user' or 'admin'", role));
+            }
+        }
+        for org in layouts.orgs.keys() {
+            if org.is_empty() || org.contains('@') || *org != org.to_lowercase() {
+                problems.push(format!("layouts.orgs.{} is not a lowercase email domain", org));
+            }
+        }
+
+        let uploads = &self.uploads;
+        if uploads.max_file_bytes == 0 || uploads.quota_bytes < uploads.max_file_bytes {
+            problems.push("uploads.max_file_bytes must be positive and at most uploads.quota_bytes".to_string());
+        }
+        for media_type in &uploads.allowed_types {
+            let valid = media_type.split_once('/').is_some_and(|(kind, subtype)| {
+                [kind, subtype].iter().all(|part| {
+                    !part.is_empty()
+                        && part.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c))
+                })
+            });
+            if !valid {
+                problems.push(format!("uploads.allowed_types '{}' is not a lowercase media type", media_type));
+            }
+        }
+        if uploads.url_ttl_secs == 0 {
+            problems.push("uploads.url_ttl_secs must be positive".to_string());
+        }
+        if !(16..=1024).contains(&uploads.thumbnail_px) {
+            problems.push(format!("uploads.thumbnail_px {} is not between 16 and 1024", uploads.thumbnail_px));
+        }
+
+        let system_stats = &self.system_stats;
+        if system_stats.interval_secs == 0 {
+            problems.push("system_stats.interval_secs must be positive".to_string());
+        }
+        if !(1..=3600).contains(&system_stats.history_len) {
+            problems.push(format!("system_stats.history_len {} is not between 1 and 3600", system_stats.history_len));
+        }
+        for mount in &system_stats.mounts {
+            if !mount.is_absolute() {
+                problems.push(format!("system_stats.mounts {} is not an absolute path", mount.display()));
+            }
+        }
+
+        let proxy = &self.proxy;
+        for upstream in &proxy.upstreams {
+            let is_origin = url::Url::parse(upstream).is_ok_and(|url| {
+                ["http", "https"].contains(&url.scheme())
+                    && url.has_host()
+                    && url.origin().ascii_serialization() == upstream.as_str()
+            });
+            if !is_origin {
+                problems.push(format!("proxy.upstreams '{}' is not an http(s) origin such as https://example.com", upstream));
+            }
+        }
+        if proxy.timeout_secs == 0 {
+            problems.push("proxy.timeout_secs must be positive".to_string());
+        }
+        if proxy.cache_entry_bytes > proxy.cache_bytes {
+            problems.push("proxy.cache_entry_bytes must be at most proxy.cache_bytes".to_string());
+        }
+
+        let limits = &self.rate_limits;
+        for (group, limit) in [("auth", &limits.auth), ("api", &limits.api), ("realtime", &limits.realtime)] {
+            for (scope, bucket) in [("per_ip", &limit.per_ip), ("per_user", &limit.per_user)] {
+                let Some(bucket) = bucket else { continue };
+                if !(bucket.per_sec.is_finite() && bucket.per_sec > 0.0) {
+                    problems.push(format!("rate_limits.{}.{}.per_sec must be positive", group, scope));
+                }
+                if bucket.burst == 0 {
+                    problems.push(format!("rate_limits.{}.{}.burst must be positive", group, scope));
+                }
+            }
+        }
+
+        let realtime = &self.realtime;
+        if realtime.resume_secs == 0 {
+            problems.push("realtime.resume_secs must be positive".to_string());
+        }
+        if !(1..=10_000).contains(&realtime.replay_len) {
+            problems.push(format!("realtime.replay_len {} is not between 1 and 10000", realtime.replay_len));
+        }
+        if !(1..=10_000).contains(&realtime.queue_len) {
+            problems.push(format!("realtime.queue_len {} is not between 1 and 10000", realtime.queue_len));
+        }
+
+        if self.auth.primary_provider != "mock" {
+            problems.push(format!("auth.primary_provider '{}' is not supported", self.auth.primary_provider));
+        }
+        if argon2::PasswordHash::new(&self.auth.providers.mock.password_hash).is_err() {
+            problems.push("auth.providers.mock.password_hash is not a valid password hash".to_string());
+        }
+        let sso = &self.auth.sso_providers;
+        for (name, provider) in [("google", &sso.google), ("microsoft", &sso.microsoft), ("apple", &sso.apple)] {
+            if url::Url::parse(&provider.redirect_uri).is_err() {
+                problems.push(format!("auth.sso_providers.{}.redirect_uri is not a valid URL", name));
+            }
+        }
+
+        problems
+    }
 }
@@ -0,0 +1,34 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::watch;
+
+/// Polls the modification times of `paths` every `interval` and bumps the
+/// returned generation counter whenever any of them changes, appears or
+/// disappears. The polling task stops once every receiver has been dropped.
+pub fn watch_files(paths: Vec<PathBuf>, interval: Duration) -> watch::Receiver<u64> {
+    let (tx, rx) = watch::channel(0);
+    tokio::spawn(async move {
+        let mut last = modified_times(&paths);
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately.
+        ticker.tick().await;
+        while !tx.is_closed() {
+            ticker.tick().await;
+            let current = modified_times(&paths);
+            if current != last {
+                last = current;
+                tx.send_modify(|generation| *generation += 1);
+            }
+        }
+    });
+    rx
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
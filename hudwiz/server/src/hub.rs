@@ -0,0 +1,346 @@
+use crate::{
+    auth::UserInfo,
+    config::{RealtimeConfig, SlowConsumers},
+    metrics::Metrics,
+};
+use axum::http::StatusCode;
+use protocol::{Envelope, Message};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+use utoipa::IntoParams;
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct TopicsQuery {
+    /// Comma-separated topics to subscribe to straight away, as if sent
+    /// `Subscribe` for each.
+    pub topics: Option<String>,
+}
+
+type Authorize = Box<dyn Fn(Option<&UserInfo>) -> bool + Send + Sync>;
+type OnSubscribe = Box<dyn Fn(&Hub) + Send + Sync>;
+
+/// A topic realtime connections can subscribe to, once registered with
+/// `Hub::register`.
+pub struct Topic {
+    name: String,
+    authorize: Authorize,
+    retain: bool,
+    telemetry: bool,
+    on_subscribe: Option<OnSubscribe>,
+}
+
+impl Topic {
+    /// A topic anyone may subscribe to, logged in or not.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            authorize: Box::new(|_| true),
+            retain: false,
+            telemetry: false,
+            on_subscribe: None,
+        }
+    }
+
+    /// Lets only the users `authorize` allows subscribe. It is passed `None`
+    /// for connections that aren't logged in.
+    pub fn authorize(mut self, authorize: impl Fn(Option<&UserInfo>) -> bool + Send + Sync + 'static) -> Self {
+        self.authorize = Box::new(authorize);
+        self
+    }
+
+    /// Lets only logged in users subscribe.
+    pub fn logged_in(self) -> Self {
+        self.authorize(|user| user.is_some())
+    }
+
+    /// Sends new subscribers the last message published, so they needn't
+    /// wait for the next one.
+    pub fn retain_last(mut self) -> Self {
+        self.retain = true;
+        self
+    }
+
+    /// Marks the messages as telemetry, each superseding the last: they
+    /// aren't replayed to clients that resume, and WebTransport sends them
+    /// as datagrams, which may be lost.
+    pub fn telemetry(mut self) -> Self {
+        self.telemetry = true;
+        self
+    }
+
+    /// Calls `on_subscribe` after every subscription, for publishers that
+    /// only start once someone listens.
+    pub fn on_subscribe(mut self, on_subscribe: impl Fn(&Hub) + Send + Sync + 'static) -> Self {
+        self.on_subscribe = Some(Box::new(on_subscribe));
+        self
+    }
+}
+
+/// Why a connection couldn't subscribe.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubscribeError {
+    Unknown(String),
+    NotLoggedIn,
+    Forbidden(String),
+}
+
+impl SubscribeError {
+    /// The status refusing a connection that asked for the topic in its URL.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Unknown(_) => StatusCode::BAD_REQUEST,
+            Self::NotLoggedIn => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(topic) => write!(f, "Unknown topic '{}'", topic),
+            Self::NotLoggedIn => f.write_str("Not logged in"),
+            Self::Forbidden(topic) => write!(f, "Not allowed to subscribe to '{}'", topic),
+        }
+    }
+}
+
+impl std::error::Error for SubscribeError {}
+
+/// A message published to a connection.
+#[derive(Debug, Clone)]
+pub struct Push {
+    pub envelope: Envelope,
+    /// Published on a `Topic::telemetry` topic.
+    pub telemetry: bool,
+}
+
+struct TopicState {
+    topic: Arc<Topic>,
+    subscribers: HashSet<u64>,
+    last: Option<Push>,
+}
+
+struct Subscriber {
+    user: Option<UserInfo>,
+    queue: mpsc::Sender<Push>,
+    /// Dropped to tell the connection it has been disconnected.
+    _connected: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    topics: HashMap<String, TopicState>,
+    connections: HashMap<u64, Subscriber>,
+}
+
+impl State {
+    fn disconnect(&mut self, id: u64) {
+        self.connections.remove(&id);
+        for topic in self.topics.values_mut() {
+            topic.subscribers.remove(&id);
+        }
+    }
+}
+
+/// Routes topic messages to the realtime connections subscribed to them,
+/// whichever transport they use. Every connection has a queue of
+/// `realtime.queue_len` messages; `realtime.slow_consumers` decides what
+/// happens when it is full.
+#[derive(Clone)]
+pub struct Hub {
+    config: RealtimeConfig,
+    metrics: Metrics,
+    state: Arc<Mutex<State>>,
+}
+
+impl Hub {
+    pub fn new(config: RealtimeConfig, metrics: Metrics) -> Self {
+        Self {
+            config,
+            metrics,
+            state: Arc::default(),
+        }
+    }
+
+    /// Makes `topic` available to subscribe to, replacing any topic of the
+    /// same name but keeping its subscribers.
+    pub fn register(&self, topic: Topic) {
+        let mut state = self.state.lock().unwrap();
+        let topic = Arc::new(topic);
+        match state.topics.get_mut(&topic.name) {
+            Some(existing) => existing.topic = topic,
+            None => {
+                let entry = TopicState {
+                    topic: topic.clone(),
+                    subscribers: HashSet::new(),
+                    last: None,
+                };
+                state.topics.insert(topic.name.clone(), entry);
+            }
+        }
+    }
+
+    /// Publishes `payload` to the subscribers of `topic`. Returns how many
+    /// connections it was queued for.
+    pub fn publish(&self, topic: &str, payload: serde_json::Value) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let State { topics, connections, .. } = &mut *state;
+        let Some(entry) = topics.get_mut(topic) else {
+            return 0;
+        };
+        let push = Push {
+            envelope: Envelope::publish(topic, payload),
+            telemetry: entry.topic.telemetry,
+        };
+        if entry.topic.retain {
+            entry.last = Some(push.clone());
+        }
+        let mut queued = 0;
+        let mut too_slow = Vec::new();
+        for id in &entry.subscribers {
+            let Some(subscriber) = connections.get(id) else { continue };
+            match subscriber.queue.try_send(push.clone()) {
+                Ok(()) => queued += 1,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.metrics.record_slow_consumer(self.config.slow_consumers);
+                    if self.config.slow_consumers == SlowConsumers::Disconnect {
+                        too_slow.push(*id);
+                    }
+                }
+                // The connection is closing and about to unsubscribe.
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+        }
+        for id in too_slow {
+            warn!(connection = id, topic, "Disconnecting a realtime connection that fell behind");
+            state.disconnect(id);
+        }
+        queued
+    }
+
+    /// Adds a connection for `user`, or for someone not logged in, with no
+    /// subscriptions yet.
+    pub fn connect(&self, user: Option<UserInfo>) -> HubConnection {
+        let (queue, queue_rx) = mpsc::channel(self.config.queue_len);
+        let (connected, disconnected) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.connections.insert(
+            id,
+            Subscriber {
+                user,
+                queue,
+                _connected: connected,
+            },
+        );
+        HubConnection {
+            id,
+            hub: self.clone(),
+            queue: queue_rx,
+            disconnected: Some(disconnected),
+        }
+    }
+}
+
+/// One realtime connection's subscriptions. Dropping it unsubscribes from
+/// everything.
+pub struct HubConnection {
+    id: u64,
+    hub: Hub,
+    queue: mpsc::Receiver<Push>,
+    disconnected: Option<oneshot::Receiver<()>>,
+}
+
+impl HubConnection {
+    /// Subscribes to `topic` if its authorization allows the connection's
+    /// user. Subscribing again is allowed and changes nothing.
+    pub fn subscribe(&self, topic: &str) -> Result<(), SubscribeError> {
+        let mut state = self.hub.state.lock().unwrap();
+        let State { topics, connections, .. } = &mut *state;
+        let entry = topics.get_mut(topic).ok_or_else(|| SubscribeError::Unknown(topic.to_string()))?;
+        // Disconnected for being too slow; the connection is about to close.
+        let Some(subscriber) = connections.get(&self.id) else {
+            return Ok(());
+        };
+        if !(entry.topic.authorize)(subscriber.user.as_ref()) {
+            return Err(match subscriber.user {
+                Some(_) => SubscribeError::Forbidden(topic.to_string()),
+                None => SubscribeError::NotLoggedIn,
+            });
+        }
+        if entry.subscribers.insert(self.id)
+            && let Some(last) = &entry.last
+        {
+            let _ = subscriber.queue.try_send(last.clone());
+        }
+        let topic = entry.topic.clone();
+        drop(state);
+        if let Some(on_subscribe) = &topic.on_subscribe {
+            on_subscribe(&self.hub);
+        }
+        Ok(())
+    }
+
+    /// Subscribes to each topic in `query`, stopping at the first refused.
+    pub fn subscribe_query(&self, query: &TopicsQuery) -> Result<(), SubscribeError> {
+        let topics = query.topics.iter().flat_map(|topics| topics.split(','));
+        topics.filter(|topic| !topic.is_empty()).try_for_each(|topic| self.subscribe(topic))
+    }
+
+    pub fn unsubscribe(&self, topic: &str) {
+        if let Some(entry) = self.hub.state.lock().unwrap().topics.get_mut(topic) {
+            entry.subscribers.remove(&self.id);
+        }
+    }
+
+    /// Handles `Subscribe` and `Unsubscribe`, and passes everything else to
+    /// `answer`.
+    pub fn answer(&self, request: &Envelope, answer: impl FnOnce(&Envelope) -> Envelope) -> Envelope {
+        let subscribe = match request.message {
+            Message::Subscribe => true,
+            Message::Unsubscribe => false,
+            _ => return answer(request),
+        };
+        let Some(topic) = &request.topic else {
+            let message = "Subscribe and Unsubscribe need a topic".to_string();
+            return Envelope::reply(request, Message::Error { message });
+        };
+        if !subscribe {
+            self.unsubscribe(topic);
+            return Envelope::reply(request, Message::Ack);
+        }
+        match self.subscribe(topic) {
+            Ok(()) => Envelope::reply(request, Message::Ack),
+            Err(e) => Envelope::reply(request, Message::Error { message: e.to_string() }),
+        }
+    }
+
+    /// The next message on the connection's topics, or `None` once it has
+    /// been disconnected for falling behind.
+    pub async fn next(&mut self) -> Option<Push> {
+        let disconnected = self.disconnected.as_mut()?;
+        tokio::select! {
+            biased;
+            _ = disconnected => {
+                self.disconnected = None;
+                None
+            }
+            push = self.queue.recv() => push,
+        }
+    }
+}
+
+impl Drop for HubConnection {
+    fn drop(&mut self) {
+        self.hub.state.lock().unwrap().disconnect(self.id);
+    }
+}
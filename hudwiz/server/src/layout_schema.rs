@@ -0,0 +1,150 @@
+use crate::{assets, config::ServerConfig, layouts::Layout, ApiError};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shared::layout::Config;
+use std::sync::{Arc, OnceLock};
+use tracing::error;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// Where the frontend's layout file lives, relative to `server.static_dir`.
+pub const CONFIG_FILE: &str = "static/config.json";
+
+/// A place in a document that doesn't match its schema.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct SchemaProblem {
+    /// JSON pointer to the offending value, such as
+    /// `/layout/main_panel/component`. Empty for the document itself.
+    pub path: String,
+    pub message: String,
+}
+
+/// Body of responses refusing a layout: an `ApiError` that also lists every
+/// problem found.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct InvalidLayout {
+    pub error: String,
+    pub problems: Vec<SchemaProblem>,
+}
+
+impl InvalidLayout {
+    pub(crate) fn response(status: StatusCode, problems: Vec<SchemaProblem>) -> Response {
+        let listed = problems
+            .iter()
+            .map(|problem| format!("{}: {}", display_path(&problem.path), problem.message))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let error = format!("Not a valid layout: {}", listed);
+        (status, Json(Self { error, problems })).into_response()
+    }
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() { "/" } else { path }
+}
+
+/// A JSON Schema generated from a Rust type, compiled for validation.
+pub struct Schema {
+    schema: Value,
+    validator: jsonschema::Validator,
+}
+
+impl Schema {
+    fn generate<T: JsonSchema>() -> Self {
+        let schema = schemars::schema_for!(T).to_value();
+        let validator = jsonschema::validator_for(&schema).expect("generated schemas are valid");
+        Self { schema, validator }
+    }
+
+    pub fn json(&self) -> &Value {
+        &self.schema
+    }
+
+    /// Every place `document` doesn't match, empty when it does.
+    pub fn check(&self, document: &Value) -> Vec<SchemaProblem> {
+        self.validator
+            .iter_errors(document)
+            .map(|e| SchemaProblem {
+                path: e.instance_path.to_string(),
+                message: e.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// What the frontend parses as its `Config`: `config.json` and
+/// `/api/layout/current`.
+pub fn config() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(Schema::generate::<Config>)
+}
+
+/// Stored layouts and overrides, which may leave regions out or empty them
+/// with `null`.
+pub fn document() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(Schema::generate::<Layout>)
+}
+
+/// `/api/config` and `/api/layout/schema`: the frontend's layout file,
+/// checked before it is served, and the schema it is checked against.
+pub fn router<S: Clone + Send + Sync + 'static>(server: ServerConfig) -> OpenApiRouter<S> {
+    OpenApiRouter::new()
+        .routes(routes!(static_config))
+        .routes(routes!(schema))
+        .with_state(Arc::new(server))
+}
+
+/// The layout in `static/config.json`, which the frontend shows when
+/// nobody is logged in. It is refused rather than served when it doesn't
+/// match the layout schema, so the frontend can show what is wrong instead
+/// of failing to parse it.
+#[utoipa::path(
+    get,
+    path = "/api/config",
+    tag = "layouts",
+    responses(
+        (status = OK, description = "The layout file", body = Object),
+        (status = NOT_FOUND, description = "The frontend has no layout file", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "The layout file doesn't match the schema", body = InvalidLayout),
+    )
+)]
+async fn static_config(State(server): State<Arc<ServerConfig>>) -> Response {
+    let Some(contents) = assets::read(&server, CONFIG_FILE) else {
+        return ApiError::response(StatusCode::NOT_FOUND, "No layout file");
+    };
+    let document: Value = match serde_json::from_slice(&contents) {
+        Ok(document) => document,
+        Err(e) => {
+            let problem = SchemaProblem {
+                path: String::new(),
+                message: format!("not JSON: {}", e),
+            };
+            error!("{} is not JSON: {}", CONFIG_FILE, e);
+            return InvalidLayout::response(StatusCode::INTERNAL_SERVER_ERROR, vec![problem]);
+        }
+    };
+    let problems = config().check(&document);
+    if !problems.is_empty() {
+        error!("{} doesn't match the layout schema: {:?}", CONFIG_FILE, problems);
+        return InvalidLayout::response(StatusCode::INTERNAL_SERVER_ERROR, problems);
+    }
+    Json(document).into_response()
+}
+
+/// The JSON Schema of `config.json`, generated from the frontend's types.
+#[utoipa::path(
+    get,
+    path = "/api/layout/schema",
+    tag = "layouts",
+    responses((status = OK, description = "A JSON Schema (2020-12)", body = Object))
+)]
+async fn schema() -> Response {
+    Json(config().json().clone()).into_response()
+}
@@ -0,0 +1,570 @@
+use crate::{
+    auth::{CurrentUser, Role, UserInfo, UserStore},
+    config::LayoutsConfig,
+    hub::{Hub, Topic},
+    layout_schema::{self, InvalidLayout, SchemaProblem},
+    reload::LiveConfig,
+    storage::{self, JsonFile, LAYOUTS_FILE},
+    telemetry::username,
+    ApiError,
+};
+use anyhow::Result;
+use axum::{
+    extract::{rejection::JsonRejection, DefaultBodyLimit, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use shared::layout::{ComponentType, REGIONS};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// The built-in layout, which is what `static/config.json` ships with.
+pub const STANDARD: &str = "standard";
+
+/// Realtime topic announcing changes to stored layouts, with the layout's
+/// `name` and whether it was `deleted`.
+pub const TOPIC: &str = "layouts";
+
+/// Largest accepted layout document.
+pub const MAX_DOCUMENT_BYTES: usize = 16 * 1024;
+
+/// Which component goes in which region, in the shape of the frontend's
+/// `config.json`. Regions a layout doesn't mention are left to the layouts
+/// applied before it; `null` empties a region.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    /// Regions by name, `null` to empty one.
+    #[schema(value_type = BTreeMap<String, Region>)]
+    #[schemars(with = "BTreeMap<String, Region>", extend("propertyNames" = {"enum": REGIONS}))]
+    pub layout: BTreeMap<String, Option<Region>>,
+}
+
+/// Its schema also allows `null`, rather than wrapping it in an `anyOf`
+/// with null, so problems are reported at the field that has them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(extend("type" = ["object", "null"]))]
+pub struct Region {
+    /// One of the frontend's `ComponentType` variants.
+    #[schemars(with = "ComponentType")]
+    pub component: String,
+}
+
+impl Layout {
+    /// The layout the frontend used to load from `static/config.json`.
+    pub fn standard() -> Self {
+        let regions = [
+            ("sidebar_left", "ControlsDrawer"),
+            ("sidebar_right", "DetailsDrawer"),
+            ("activity_bar", "UserDrawer"),
+            ("floating_toolbar", "DrawingToolbar"),
+            ("hud_top_left", "TopLeftHud"),
+            ("hud_top_right", "TopRightHud"),
+            ("hud_bottom_left", "BottomLeftHud"),
+            ("hud_bottom_right", "BottomRightHud"),
+            ("main_panel", "ChatHistoryView"),
+        ];
+        Self {
+            layout: regions
+                .into_iter()
+                .map(|(region, component)| {
+                    (region.to_string(), Some(Region { component: component.to_string() }))
+                })
+                .collect(),
+        }
+    }
+
+    /// Where the layout doesn't match the layout schema, empty when it is
+    /// valid. Stored layouts are checked again when they are used, since
+    /// the frontend's components may have changed since.
+    pub fn problems(&self) -> Vec<SchemaProblem> {
+        layout_schema::document().check(&serde_json::to_value(self).expect("layouts serialize"))
+    }
+}
+
+/// Whether `name` can name a layout, in the API and in `[layouts]`.
+pub fn is_layout_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// What `/api/layout/current` returns: the merged regions, readable as the
+/// frontend's `Config`, and where they came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct CurrentLayout {
+    pub layout: BTreeMap<String, Region>,
+    /// The layouts applied, in order, such as `default: standard`,
+    /// `org example.com: field`, `role admin: operations` and `user override`.
+    pub applied: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredLayout {
+    pub layout: Layout,
+    /// Unix timestamp of the last change.
+    pub updated_at: u64,
+    /// Username of whoever made it.
+    pub updated_by: String,
+}
+
+impl StoredLayout {
+    fn new(layout: Layout, updated_by: &UserInfo) -> Self {
+        Self {
+            layout,
+            updated_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            updated_by: updated_by.name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Layouts {
+    /// By name.
+    #[serde(default)]
+    named: BTreeMap<String, StoredLayout>,
+    /// By user id.
+    #[serde(default)]
+    overrides: BTreeMap<String, StoredLayout>,
+}
+
+/// Named layouts and per-user overrides. Like the user store, a file-backed
+/// store keeps its file parsed in memory, reads it again when it changes and
+/// accesses it on the blocking pool.
+#[derive(Clone)]
+pub struct LayoutStore {
+    backend: Arc<Mutex<Backend>>,
+}
+
+enum Backend {
+    Memory(Layouts),
+    File(JsonFile<Layouts>),
+}
+
+impl LayoutStore {
+    pub fn in_memory() -> Self {
+        Self { backend: Arc::new(Mutex::new(Backend::Memory(Layouts::default()))) }
+    }
+
+    /// A store persisted to `layouts.json` in `data_dir`.
+    pub fn open(data_dir: &std::path::Path) -> Self {
+        Self { backend: Arc::new(Mutex::new(Backend::File(JsonFile::new(data_dir.join(LAYOUTS_FILE))))) }
+    }
+
+    /// Applies `f` to the layouts on the blocking pool, writing them back if
+    /// it reports a change.
+    async fn with_layouts<R: Send + 'static>(&self, f: impl FnOnce(&mut Layouts) -> (R, bool) + Send + 'static) -> Result<R> {
+        let backend = self.backend.clone();
+        storage::blocking(move || {
+            let mut backend = backend.lock().unwrap();
+            match &mut *backend {
+                Backend::Memory(layouts) => Ok(f(layouts).0),
+                Backend::File(file) => {
+                    let (result, modified) = f(file.read()?);
+                    if modified {
+                        file.write()?;
+                    }
+                    Ok(result)
+                }
+            }
+        })
+        .await?
+    }
+
+    /// Stored layouts by name. The built-in `standard` is only listed once
+    /// it has been stored.
+    pub async fn list(&self) -> Result<BTreeMap<String, StoredLayout>> {
+        self.with_layouts(|layouts| (layouts.named.clone(), false)).await
+    }
+
+    /// The layout called `name`, falling back to the built-in `standard`.
+    pub async fn get(&self, name: &str) -> Result<Option<Layout>> {
+        let key = name.to_string();
+        let stored = self
+            .with_layouts(move |layouts| (layouts.named.get(&key).map(|stored| stored.layout.clone()), false))
+            .await?;
+        Ok(stored.or_else(|| (name == STANDARD).then(Layout::standard)))
+    }
+
+    /// Stores `layout` as `name`, returning whether it is new.
+    pub async fn put(&self, name: &str, layout: StoredLayout) -> Result<bool> {
+        let name = name.to_string();
+        self.with_layouts(move |layouts| (layouts.named.insert(name, layout).is_none(), true)).await
+    }
+
+    /// Removes the layout called `name`, returning whether it existed.
+    pub async fn delete(&self, name: &str) -> Result<bool> {
+        let name = name.to_string();
+        self.with_layouts(move |layouts| {
+            let existed = layouts.named.remove(&name).is_some();
+            (existed, existed)
+        })
+        .await
+    }
+
+    pub async fn get_override(&self, user_id: &str) -> Result<Option<Layout>> {
+        let user_id = user_id.to_string();
+        self.with_layouts(move |layouts| (layouts.overrides.get(&user_id).map(|stored| stored.layout.clone()), false))
+            .await
+    }
+
+    pub async fn put_override(&self, user_id: &str, layout: StoredLayout) -> Result<()> {
+        let user_id = user_id.to_string();
+        self.with_layouts(move |layouts| {
+            layouts.overrides.insert(user_id, layout);
+            ((), true)
+        })
+        .await
+    }
+
+    /// Removes the override of `user_id`, returning whether there was one.
+    pub async fn delete_override(&self, user_id: &str) -> Result<bool> {
+        let user_id = user_id.to_string();
+        self.with_layouts(move |layouts| {
+            let existed = layouts.overrides.remove(&user_id).is_some();
+            (existed, existed)
+        })
+        .await
+    }
+
+    /// The layout for `user`: the configured default, then the layouts for
+    /// their organisation and role, then their own override. Assigned
+    /// layouts that don't exist are skipped.
+    pub async fn resolve(&self, config: &LayoutsConfig, user: &UserInfo, role: Role) -> Result<CurrentLayout> {
+        let org = user.email.rsplit_once('@').map(|(_, domain)| domain.to_lowercase());
+        let role = role.to_string();
+        let assigned = [
+            Some(("default".to_string(), &config.default)),
+            org.as_ref()
+                .and_then(|org| config.orgs.get(org).map(|name| (format!("org {}", org), name))),
+            config.roles.get(&role).map(|name| (format!("role {}", role), name)),
+        ];
+
+        let mut regions = BTreeMap::new();
+        let mut applied = Vec::new();
+        for (source, name) in assigned.into_iter().flatten() {
+            match self.get(name).await? {
+                Some(layout) if !layout.problems().is_empty() => {
+                    error!("Skipping layout '{}' assigned to {}: {:?}", name, source, layout.problems());
+                }
+                Some(layout) => {
+                    regions.extend(layout.layout);
+                    applied.push(format!("{}: {}", source, name));
+                }
+                None => warn!("Layout '{}' assigned to {} doesn't exist", name, source),
+            }
+        }
+        if let Some(layout) = self.get_override(&user.id).await? {
+            let problems = layout.problems();
+            if problems.is_empty() {
+                regions.extend(layout.layout);
+                applied.push("user override".to_string());
+            } else {
+                error!("Skipping the layout override of {}: {:?}", username(&user.email), problems);
+            }
+        }
+
+        Ok(CurrentLayout {
+            layout: regions
+                .into_iter()
+                .filter_map(|(region, component)| Some((region, component?)))
+                .collect(),
+            applied,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct LayoutState {
+    pub store: LayoutStore,
+    pub users: UserStore,
+    pub config: LiveConfig,
+    pub hub: Hub,
+}
+
+/// The `TOPIC` topic, for logged in users.
+pub fn topic() -> Topic {
+    Topic::new(TOPIC).logged_in()
+}
+
+impl LayoutState {
+    /// The response refusing a non-admin `user`, or `None` for an admin.
+    async fn refuse_non_admin(&self, user: &UserInfo) -> Option<Response> {
+        if self.users.role_of(user).await == Role::Admin {
+            return None;
+        }
+        warn!("Rejected layout change by non-admin {}", username(&user.email));
+        Some(ApiError::response(StatusCode::FORBIDDEN, "Only admins can change shared layouts"))
+    }
+}
+
+/// Named layouts, the per-user override and the merged current layout.
+pub fn router<S: Clone + Send + Sync + 'static>(state: LayoutState) -> OpenApiRouter<S> {
+    OpenApiRouter::new()
+        .routes(routes!(list_layouts))
+        .routes(routes!(get_layout, put_layout, delete_layout))
+        .routes(routes!(get_override, put_override, delete_override))
+        .routes(routes!(current_layout))
+        .layer(DefaultBodyLimit::max(MAX_DOCUMENT_BYTES))
+        .with_state(state)
+}
+
+fn internal_error(action: &str, e: anyhow::Error) -> Response {
+    error!("Failed to {}: {:#}", action, e);
+    ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to {}", action))
+}
+
+/// The layout in a request body, checked against the layout schema, or the
+/// status and problems to reject it with.
+fn parse(document: Result<Json<Value>, JsonRejection>) -> Result<Layout, (StatusCode, Vec<SchemaProblem>)> {
+    let unreadable = |status, message: String| (status, vec![SchemaProblem { path: String::new(), message }]);
+    let document = match document {
+        Ok(Json(document)) => document,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return Err(unreadable(StatusCode::PAYLOAD_TOO_LARGE, rejection.body_text()));
+        }
+        Err(rejection) => return Err(unreadable(StatusCode::UNPROCESSABLE_ENTITY, rejection.body_text())),
+    };
+    let problems = layout_schema::document().check(&document);
+    if !problems.is_empty() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, problems));
+    }
+    serde_json::from_value(document).map_err(|e| unreadable(StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))
+}
+
+#[derive(Serialize, ToSchema)]
+struct LayoutSummary {
+    name: String,
+    updated_at: u64,
+    updated_by: String,
+}
+
+/// The stored named layouts.
+#[utoipa::path(
+    get,
+    path = "/api/layouts",
+    tag = "layouts",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The layouts by name", body = Vec<LayoutSummary>),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+    )
+)]
+async fn list_layouts(_user: CurrentUser, State(state): State<LayoutState>) -> Response {
+    match state.store.list().await {
+        Ok(layouts) => Json(
+            layouts
+                .into_iter()
+                .map(|(name, stored)| LayoutSummary {
+                    name,
+                    updated_at: stored.updated_at,
+                    updated_by: stored.updated_by,
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(e) => internal_error("list layouts", e),
+    }
+}
+
+/// The layout called `name`.
+#[utoipa::path(
+    get,
+    path = "/api/layouts/{name}",
+    tag = "layouts",
+    security(("session" = [])),
+    params(("name" = String, Path, description = "Layout name, such as `standard`")),
+    responses(
+        (status = OK, description = "The layout", body = Layout),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = NOT_FOUND, description = "No layout by that name", body = ApiError),
+    )
+)]
+async fn get_layout(_user: CurrentUser, State(state): State<LayoutState>, Path(name): Path<String>) -> Response {
+    match state.store.get(&name).await {
+        Ok(Some(layout)) => Json(layout).into_response(),
+        Ok(None) => ApiError::response(StatusCode::NOT_FOUND, "No layout by that name"),
+        Err(e) => internal_error("read the layout", e),
+    }
+}
+
+/// Creates or replaces the layout called `name`. Users it is assigned to
+/// get it on their next load. Admins only.
+#[utoipa::path(
+    put,
+    path = "/api/layouts/{name}",
+    tag = "layouts",
+    security(("session" = [])),
+    params(("name" = String, Path, description = "Lowercase letters, digits, '-' and '_'")),
+    request_body = Layout,
+    responses(
+        (status = CREATED, description = "The layout was created"),
+        (status = OK, description = "The layout was replaced"),
+        (status = BAD_REQUEST, description = "Not a layout name", body = ApiError),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = FORBIDDEN, description = "Not an admin", body = ApiError),
+        (status = PAYLOAD_TOO_LARGE, description = "The document is larger than 16 KiB", body = InvalidLayout),
+        (status = UNPROCESSABLE_ENTITY, description = "Not a valid layout, with where", body = InvalidLayout),
+    )
+)]
+async fn put_layout(
+    CurrentUser(user): CurrentUser,
+    State(state): State<LayoutState>,
+    Path(name): Path<String>,
+    document: Result<Json<Value>, JsonRejection>,
+) -> Response {
+    if let Some(response) = state.refuse_non_admin(&user).await {
+        return response;
+    }
+    if !is_layout_name(&name) {
+        return ApiError::response(StatusCode::BAD_REQUEST, "Layout names are lowercase letters, digits, '-' and '_'");
+    }
+    let layout = match parse(document) {
+        Ok(layout) => layout,
+        Err((status, problems)) => return InvalidLayout::response(status, problems),
+    };
+    match state.store.put(&name, StoredLayout::new(layout, &user)).await {
+        Ok(created) => {
+            info!("Layout '{}' {} by {}", name, if created { "created" } else { "replaced" }, username(&user.email));
+            state.hub.publish(TOPIC, json!({"name": name, "deleted": false}));
+            if created { StatusCode::CREATED } else { StatusCode::OK }.into_response()
+        }
+        Err(e) => internal_error("store the layout", e),
+    }
+}
+
+/// Deletes the layout called `name`. Users it was assigned to fall back to
+/// the layouts applied before it. Admins only.
+#[utoipa::path(
+    delete,
+    path = "/api/layouts/{name}",
+    tag = "layouts",
+    security(("session" = [])),
+    params(("name" = String, Path, description = "Layout name")),
+    responses(
+        (status = NO_CONTENT, description = "The layout was deleted"),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = FORBIDDEN, description = "Not an admin", body = ApiError),
+        (status = NOT_FOUND, description = "No stored layout by that name", body = ApiError),
+    )
+)]
+async fn delete_layout(CurrentUser(user): CurrentUser, State(state): State<LayoutState>, Path(name): Path<String>) -> Response {
+    if let Some(response) = state.refuse_non_admin(&user).await {
+        return response;
+    }
+    match state.store.delete(&name).await {
+        Ok(true) => {
+            info!("Layout '{}' deleted by {}", name, username(&user.email));
+            state.hub.publish(TOPIC, json!({"name": name, "deleted": true}));
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => ApiError::response(StatusCode::NOT_FOUND, "No stored layout by that name"),
+        Err(e) => internal_error("delete the layout", e),
+    }
+}
+
+/// The user's own changes to their layout, empty when they have none.
+#[utoipa::path(
+    get,
+    path = "/api/layout/override",
+    tag = "layouts",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The override", body = Layout),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+    )
+)]
+async fn get_override(CurrentUser(user): CurrentUser, State(state): State<LayoutState>) -> Response {
+    match state.store.get_override(&user.id).await {
+        Ok(layout) => Json(layout.unwrap_or_default()).into_response(),
+        Err(e) => internal_error("read the layout override", e),
+    }
+}
+
+/// Replaces the user's own changes, which apply on top of every assigned layout.
+#[utoipa::path(
+    put,
+    path = "/api/layout/override",
+    tag = "layouts",
+    security(("session" = [])),
+    request_body = Layout,
+    responses(
+        (status = OK, description = "The override was stored"),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = PAYLOAD_TOO_LARGE, description = "The document is larger than 16 KiB", body = InvalidLayout),
+        (status = UNPROCESSABLE_ENTITY, description = "Not a valid layout, with where", body = InvalidLayout),
+    )
+)]
+async fn put_override(
+    CurrentUser(user): CurrentUser,
+    State(state): State<LayoutState>,
+    document: Result<Json<Value>, JsonRejection>,
+) -> Response {
+    let layout = match parse(document) {
+        Ok(layout) => layout,
+        Err((status, problems)) => return InvalidLayout::response(status, problems),
+    };
+    match state.store.put_override(&user.id, StoredLayout::new(layout, &user)).await {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(e) => internal_error("store the layout override", e),
+    }
+}
+
+/// Drops the user's own changes.
+#[utoipa::path(
+    delete,
+    path = "/api/layout/override",
+    tag = "layouts",
+    security(("session" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The override was removed, or there was none"),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+    )
+)]
+async fn delete_override(CurrentUser(user): CurrentUser, State(state): State<LayoutState>) -> Response {
+    match state.store.delete_override(&user.id).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => internal_error("remove the layout override", e),
+    }
+}
+
+/// The layout to show the user, merged from the layouts assigned in
+/// `[layouts]` and their override.
+#[utoipa::path(
+    get,
+    path = "/api/layout/current",
+    tag = "layouts",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The merged layout", body = CurrentLayout),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "The merged layout doesn't match the schema", body = InvalidLayout),
+    )
+)]
+async fn current_layout(CurrentUser(user): CurrentUser, State(state): State<LayoutState>) -> Response {
+    let config = state.config.current();
+    match state.store.resolve(&config.config.layouts, &user, state.users.role_of(&user).await).await {
+        Ok(current) => {
+            let document = serde_json::to_value(&current).expect("layouts serialize");
+            let problems = layout_schema::config().check(&document);
+            if !problems.is_empty() {
+                error!("Refusing to serve an invalid layout to {}: {:?}", username(&user.email), problems);
+                return InvalidLayout::response(StatusCode::INTERNAL_SERVER_ERROR, problems);
+            }
+            Json(document).into_response()
+        }
+        Err(e) => internal_error("resolve the layout", e),
+    }
+}
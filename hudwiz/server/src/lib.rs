@@ -1,202 +1,800 @@
+pub mod assets;
+pub mod audit;
 pub mod auth;
+pub mod cli;
 pub mod config;
+pub mod file_watch;
+pub mod hub;
+pub mod layout_schema;
+pub mod layouts;
+pub mod metrics;
+pub mod openapi;
+pub mod ops;
+pub mod preferences;
+pub mod proxy;
+pub mod rate_limit;
+pub mod realtime;
+pub mod replay;
+pub mod reload;
+pub mod security;
+pub mod serve;
+pub mod sessions;
+pub mod shutdown;
+pub mod sse;
 pub mod sso;
+pub mod storage;
+pub mod system_stats;
+pub mod telemetry;
+pub mod tls;
+pub mod uploads;
 pub mod webtransport;
 
 use crate::{
-    auth::{AuthProvider, Credentials, DbPrimaryProvider, UserInfo, UserStore, User},
+    audit::{AuditEvent, AuditLog},
+    auth::{AuthProvider, Credentials, DbPrimaryProvider, Role, User, UserInfo, UserStore, UserStoreError},
+    config::SignupMode,
+    hub::{Hub, HubConnection, TopicsQuery},
+    layouts::{LayoutState, LayoutStore},
+    metrics::{Direction, Metrics, Transport},
+    preferences::PreferenceStore,
+    proxy::ProxyState,
+    rate_limit::{Client, RateLimiter},
+    replay::{ReplayStore, ResumeQuery},
+    reload::LiveConfig,
+    security::SecurityHeaders,
+    sessions::FileSessionStore,
+    sse::SseState,
+    shutdown::Shutdown,
     sso::{GoogleSsoProvider, MicrosoftSsoProvider, SsoProvider},
+    system_stats::SystemStatsService,
+    telemetry::username,
+    uploads::{UploadState, UploadStore},
+    webtransport::{SessionTokens, WebTransportInfo, WebTransportStatus},
 };
 use axum::{
     extract::{
-        ws::{WebSocket, WebSocketUpgrade},
+        rejection::QueryRejection,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
         Path, Query, State,
     },
     http::StatusCode,
-    response::{IntoResponse, Json as JsonResponse},
-    routing::{get, post, get_service},
+    middleware,
+    response::{IntoResponse, Json as JsonResponse, Redirect},
+    routing::{get, get_service, Route},
     Extension, Json, Router,
 };
 use futures::sink::SinkExt;
-use std::{collections::HashMap, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use tower::{util::BoxCloneSyncService, Layer, Service};
 use tower_cookies::{CookieManagerLayer, Key};
-use tower_http::{services::{ServeDir, ServeFile}, trace::TraceLayer};
-use tower_sessions::{MemoryStore, Session, SessionManagerLayer};
-use argon2::{
-    password_hash::{
-        rand_core::OsRng,
-        PasswordHasher, SaltString
-    },
-    Argon2
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::TraceLayer,
 };
-use tracing::{info, warn};
+use tower_sessions::{Session, SessionManagerLayer, SessionStore};
+use tracing::{error, info, info_span, warn, Instrument};
+use utoipa::{openapi::Server, OpenApi, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// SSO providers by the name used in `/api/auth/sso/login/{provider}`.
+pub type SsoProviders = HashMap<String, Arc<dyn SsoProvider + Send + Sync>>;
 
 pub struct SsoAuth {
-    providers: HashMap<String, Box<dyn SsoProvider + Send + Sync>>,
+    providers: SsoProviders,
+}
+
+impl SsoAuth {
+    /// The providers configured in `config`, plus `extra` ones added in code,
+    /// which win on a name clash.
+    pub fn new(config: &config::AuthConfig, extra: &SsoProviders) -> anyhow::Result<Self> {
+        let mut providers = SsoProviders::new();
+        providers.insert(
+            "google".to_string(),
+            Arc::new(GoogleSsoProvider::new(&config.sso_providers.google)?),
+        );
+        providers.insert(
+            "microsoft".to_string(),
+            Arc::new(MicrosoftSsoProvider::new(&config.sso_providers.microsoft)?),
+        );
+        providers.extend(extra.iter().map(|(name, provider)| (name.clone(), provider.clone())));
+        Ok(Self { providers })
+    }
 }
 
 struct AppState {
     auth_provider: Arc<DbPrimaryProvider>,
-    sso_auth: Arc<SsoAuth>,
+    config: LiveConfig,
     user_store: UserStore,
+    audit: AuditLog,
+    metrics: Metrics,
+    webtransport: WebTransportStatus,
+    rate_limiter: RateLimiter,
+    replay: ReplayStore,
+    hub: Hub,
+    shutdown: Shutdown,
 }
 
-pub fn app_router() -> Router {
-    let config = config::load_config().expect("Failed to load configuration");
+/// Stores and process-wide handles shared between the router and whoever
+/// runs it, so they can be flushed and shut down from outside.
+#[derive(Clone)]
+pub struct Services {
+    pub config: LiveConfig,
+    pub user_store: UserStore,
+    pub sessions: FileSessionStore,
+    pub audit: AuditLog,
+    pub preferences: PreferenceStore,
+    pub layouts: LayoutStore,
+    pub uploads: UploadStore,
+    pub metrics: Metrics,
+    pub webtransport: WebTransportStatus,
+    pub session_tokens: SessionTokens,
+    pub system_stats: SystemStatsService,
+    pub rate_limiter: RateLimiter,
+    pub replay: ReplayStore,
+    pub hub: Hub,
+    pub shutdown: Shutdown,
+}
 
-    let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store).with_secure(false);
+impl Services {
+    /// Opens the stores in `storage.data_dir`, or in-memory ones when it is
+    /// unset, and makes sure the configured user exists.
+    pub fn open(config: &config::Config) -> anyhow::Result<Self> {
+        let (user_store, sessions, audit, preferences, layouts) = match &config.storage.data_dir {
+            Some(data_dir) => {
+                storage::open(data_dir)?;
+                (
+                    UserStore::open(data_dir),
+                    FileSessionStore::open(data_dir)?,
+                    AuditLog::open(data_dir)?,
+                    PreferenceStore::open(data_dir),
+                    LayoutStore::open(data_dir),
+                )
+            }
+            None => (
+                UserStore::in_memory(),
+                FileSessionStore::in_memory(),
+                AuditLog::disabled(),
+                PreferenceStore::in_memory(),
+                LayoutStore::in_memory(),
+            ),
+        };
 
-    let key = Key::generate();
+        let uploads = match config.uploads.resolved_dir(&config.storage) {
+            Some(dir) => UploadStore::open(&dir, config.uploads.clone())?,
+            None => UploadStore::disabled(),
+        };
 
-    let user_store = Arc::new(std::sync::Mutex::new(HashMap::new()));
-    let auth_provider = Arc::new(DbPrimaryProvider::new(user_store.clone()));
+        let shutdown = Shutdown::default();
+        let system_stats = SystemStatsService::new(config.system_stats.clone(), shutdown.clone());
 
-    let mut sso_providers = HashMap::new();
-    sso_providers.insert(
-        "google".to_string(),
-        Box::new(GoogleSsoProvider::new(&config.auth.sso_providers.google).unwrap())
-            as Box<dyn SsoProvider + Send + Sync>,
-    );
-    sso_providers.insert(
-        "microsoft".to_string(),
-        Box::new(MicrosoftSsoProvider::new(&config.auth.sso_providers.microsoft).unwrap())
-            as Box<dyn SsoProvider + Send + Sync>,
-    );
-    let sso_auth = Arc::new(SsoAuth {
-        providers: sso_providers,
-    });
+        // Seeded as a plain user, since its password is in the config file;
+        // operators grant admin with `hudwiz-server user set-role`.
+        let mock = &config.auth.providers.mock;
+        if user_store.ensure(User::new(&mock.username, mock.password_hash.clone(), Role::User))? {
+            info!("Added configured user {} to the user store", username(&mock.username));
+        }
 
-    let app_state = Arc::new(AppState {
-        auth_provider,
-        sso_auth,
-        user_store,
-    });
+        let live_config = LiveConfig::new(config.clone())?;
+        let metrics = Metrics::default();
+        let hub = Hub::new(config.realtime.clone(), metrics.clone());
+        hub.register(system_stats.topic());
+        hub.register(layouts::topic());
+        Ok(Self {
+            rate_limiter: RateLimiter::new(live_config.clone(), metrics.clone()),
+            replay: ReplayStore::new(config.realtime.clone()),
+            hub,
+            config: live_config,
+            user_store,
+            sessions,
+            audit,
+            preferences,
+            layouts,
+            uploads,
+            metrics,
+            webtransport: WebTransportStatus::default(),
+            session_tokens: SessionTokens::default(),
+            system_stats,
+            shutdown,
+        })
+    }
+
+    /// Writes buffered session and audit data to disk.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.sessions.flush()?;
+        self.audit.flush()
+    }
+}
+
+/// The app as configured by `$HUDWIZ_CONFIG` or `server_config.toml` in the
+/// working directory.
+pub fn app_router() -> anyhow::Result<Router> {
+    AppBuilder::new(config::load_config()?).build()
+}
+
+/// The app for `config`, sharing `services` with the caller.
+pub fn app_router_with_config(config: config::Config, services: Services) -> anyhow::Result<Router> {
+    AppBuilder::new(config).services(services).build()
+}
+
+type RouterLayer = Box<dyn FnOnce(Router) -> Router + Send>;
+
+/// Builds the hudwiz router, for serving on its own or mounting inside
+/// another axum application. Everything but the configuration is optional
+/// and defaults to what `storage` and `auth` configure.
+pub struct AppBuilder {
+    config: config::Config,
+    services: Option<Services>,
+    user_store: Option<UserStore>,
+    session_layer: Option<RouterLayer>,
+    sso_providers: Vec<(String, Arc<dyn SsoProvider + Send + Sync>)>,
+    routers: Vec<Router>,
+    layers: Vec<RouterLayer>,
+    prefix: Option<String>,
+}
+
+impl AppBuilder {
+    pub fn new(config: config::Config) -> Self {
+        Self {
+            config,
+            services: None,
+            user_store: None,
+            session_layer: None,
+            sso_providers: Vec::new(),
+            routers: Vec::new(),
+            layers: Vec::new(),
+            prefix: None,
+        }
+    }
+
+    /// Stores and handles shared with whoever runs the router, such as
+    /// `serve::serve`. Opened from the configuration when not given.
+    pub fn services(mut self, services: Services) -> Self {
+        self.services = Some(services);
+        self
+    }
+
+    /// Users to authenticate against instead of the configured store. The
+    /// store is used as is, without adding the configured user.
+    pub fn user_store(mut self, user_store: UserStore) -> Self {
+        self.user_store = Some(user_store);
+        self
+    }
+
+    /// Keeps sessions in `store` instead of the configured store, so
+    /// `Services::flush` no longer persists them.
+    pub fn session_store<S: SessionStore + Clone>(mut self, store: S) -> Self {
+        self.session_layer = Some(Box::new(move |router: Router| {
+            router.layer(SessionManagerLayer::new(store).with_secure(false))
+        }));
+        self
+    }
+
+    /// Adds an SSO provider at `/api/auth/sso/login/{name}`, replacing a
+    /// configured provider of the same name.
+    pub fn sso_provider(mut self, name: impl Into<String>, provider: impl SsoProvider + Send + Sync + 'static) -> Self {
+        self.sso_providers.push((name.into(), Arc::new(provider)));
+        self
+    }
+
+    /// Adds routes of your own next to the API. They take precedence over the
+    /// static assets and go through the same layers. Like `Router::merge`,
+    /// `build` panics if a route is already taken.
+    pub fn merge(mut self, router: Router) -> Self {
+        self.routers.push(router);
+        self
+    }
+
+    /// Wraps all routes in `layer`. It runs inside the built-in layers, so
+    /// sessions, cookies and request ids are available to it.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<axum::extract::Request> + Clone + Send + Sync + 'static,
+        <L::Service as Service<axum::extract::Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<axum::extract::Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<axum::extract::Request>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |router: Router| router.layer(layer)));
+        self
+    }
+
+    /// Serves everything under `prefix`, such as `/hud`, instead of at the
+    /// root. `/hud` itself redirects to `/hud/`, where the UI lives. Set
+    /// `server.public_url` to match so SSO redirects find it.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Router> {
+        let Self {
+            config,
+            services,
+            user_store,
+            session_layer,
+            sso_providers,
+            routers,
+            layers,
+            prefix,
+        } = self;
+
+        if let Some(prefix) = &prefix
+            && (!prefix.starts_with('/') || prefix.ends_with('/') || prefix.contains(['{', '}', '*']))
+        {
+            anyhow::bail!("Prefix '{}' must start with '/', not end with one and not contain wildcards", prefix);
+        }
+
+        let mut services = match services {
+            Some(services) => services,
+            None => Services::open(&config)?,
+        };
+        if let Some(user_store) = user_store {
+            services.user_store = user_store;
+        }
+        for (name, provider) in sso_providers {
+            services.config.add_sso_provider(&name, provider)?;
+        }
+
+        let ops = ops::router(services.clone(), config.server.admin_addr.is_none());
+        let admin = ops::admin_router(services.clone());
+        let preferences = preferences::router(services.preferences.clone());
+        let layouts = layouts::router(LayoutState {
+            store: services.layouts.clone(),
+            users: services.user_store.clone(),
+            config: services.config.clone(),
+            hub: services.hub.clone(),
+        });
+        let uploads = uploads::router(UploadState {
+            store: services.uploads.clone(),
+            prefix: prefix.clone().unwrap_or_default(),
+        });
+        let proxy = proxy::router(ProxyState::new(&services.config)?);
+        let sse = sse::router(SseState::new(
+            services.metrics.clone(),
+            services.shutdown.clone(),
+            services.rate_limiter.clone(),
+            services.replay.clone(),
+            services.hub.clone(),
+        ));
+        let key = Key::generate();
+        let security_headers = SecurityHeaders::new(&config)?;
+
+        let user_store = services.user_store;
+        let auth_provider = Arc::new(DbPrimaryProvider::new(user_store.clone()));
+
+        let app_state = Arc::new(AppState {
+            auth_provider,
+            config: services.config,
+            user_store: user_store.clone(),
+            audit: services.audit,
+            metrics: services.metrics.clone(),
+            webtransport: services.webtransport,
+            rate_limiter: services.rate_limiter.clone(),
+            replay: services.replay.clone(),
+            hub: services.hub.clone(),
+            shutdown: services.shutdown,
+        });
+
+        // Routes are registered with their OpenAPI operation, so the document
+        // can't miss one.
+        let (mut api, mut spec) = OpenApiRouter::with_openapi(openapi::ApiDoc::openapi())
+            .routes(routes!(signup))
+            .routes(routes!(login))
+            .routes(routes!(logout))
+            .routes(routes!(get_user))
+            .routes(routes!(sso_login))
+            .routes(routes!(sso_callback))
+            .routes(routes!(realtime_info))
+            .routes(routes!(openapi::document))
+            .merge(preferences)
+            .merge(layouts)
+            .merge(layout_schema::router(config.server.clone()))
+            .merge(uploads)
+            .merge(proxy)
+            .merge(system_stats::router(services.system_stats.clone()))
+            .merge(webtransport::router(services.session_tokens.clone()))
+            .split_for_parts();
+        if let Some(cors) = security::cors_layer(&config.security.cors) {
+            api = api.route_layer(cors);
+        }
+        let (others, others_spec) = OpenApiRouter::default()
+            .routes(routes!(ws_handler))
+            .merge(sse)
+            .merge(ops)
+            .merge(admin)
+            .split_for_parts();
+        spec.merge(others_spec);
+        if let Some(prefix) = &prefix {
+            spec.servers = Some(vec![Server::new(prefix)]);
+        }
+        let document = openapi::Document::new(&spec)?;
+
+        let mut router = api.merge(others).merge(openapi::viewer()).with_state(app_state);
+        for extra in routers {
+            router = router.merge(extra);
+        }
+        router = router.fallback_service(get_service(assets::router(&config.server)));
+        for layer in layers {
+            router = layer(router);
+        }
+        router = router
+            .layer(middleware::from_fn_with_state(services.rate_limiter, rate_limit::limit_requests))
+            .layer(middleware::from_fn_with_state(user_store, auth::drop_disabled_sessions))
+            .layer(middleware::from_fn_with_state(security_headers, security::add_security_headers))
+            .layer(middleware::from_fn_with_state(services.metrics, metrics::track_requests))
+            .layer(TraceLayer::new_for_http().make_span_with(request_span))
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+            .layer(Extension(key))
+            .layer(Extension(document))
+            .layer(CookieManagerLayer::new());
+        router = match session_layer {
+            Some(session_layer) => session_layer(router),
+            None => router.layer(SessionManagerLayer::new(services.sessions).with_secure(false)),
+        };
 
-    Router::new()
-        .route("/api/auth/signup", post(signup))
-        .route("/api/auth/login", post(login))
-        .route("/api/auth/logout", post(logout))
-        .route("/api/auth/user", get(get_user))
-        .route("/api/auth/sso/login/{provider}", get(sso_login))
-        .route("/api/auth/sso/callback/{provider}", get(sso_callback))
-        .route("/ws", get(ws_handler))
-        .fallback_service(get_service(
-            ServeDir::new("hudwiz/frontend/dist")
-                .fallback(ServeFile::new("hudwiz/frontend/dist/index.html")),
-        ))
-        .layer(TraceLayer::new_for_http())
-        .layer(Extension(key))
-        .layer(CookieManagerLayer::new())
-        .layer(session_layer)
-        .with_state(app_state)
+        let Some(prefix) = prefix else { return Ok(router) };
+        let stripped: Arc<str> = prefix.clone().into();
+        // The prefix is stripped around the router rather than with
+        // `Router::layer`, whose middleware only runs once a route matched.
+        // Boxed since `route_service` rejects routers, expecting them to be nested.
+        let strip = middleware::map_request(move |request| strip_prefix(stripped.clone(), request));
+        let app = BoxCloneSyncService::new(strip.layer(router));
+        let root = format!("{}/", prefix);
+        Ok(Router::new()
+            .route(&prefix, get(move || async move { Redirect::permanent(&root) }))
+            .route_service(&format!("{}/", prefix), app.clone())
+            .route_service(&format!("{}/{{*path}}", prefix), app))
+    }
 }
 
+/// Removes the `AppBuilder::prefix` from the request path so the app's own
+/// routes match.
+async fn strip_prefix(prefix: Arc<str>, mut request: axum::extract::Request) -> axum::extract::Request {
+    let path_and_query = request.uri().path_and_query().map_or("/", |path| path.as_str());
+    if let Some(rest) = path_and_query.strip_prefix(&*prefix)
+        && let Ok(uri) = rest.parse()
+    {
+        *request.uri_mut() = uri;
+    }
+    request
+}
+
+/// Span for one HTTP request, carrying the `x-request-id` assigned by
+/// `SetRequestIdLayer`. The query string is left out since it can carry
+/// credentials such as SSO authorization codes.
+fn request_span(request: &axum::http::Request<axum::body::Body>) -> tracing::Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or("-");
+    info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        path = request.uri().path(),
+    )
+}
+
+/// Body of API error responses.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiError {
+    /// What went wrong, for showing to the user.
+    pub error: String,
+}
+
+impl ApiError {
+    pub(crate) fn response(status: StatusCode, error: &str) -> axum::response::Response {
+        (status, JsonResponse(Self { error: error.to_string() })).into_response()
+    }
+}
+
+/// Creates an account with the `user` role.
+#[utoipa::path(
+    post,
+    path = "/api/auth/signup",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = CREATED, description = "The account was created"),
+        (status = FORBIDDEN, description = "Signup is disabled by `auth.signup`", body = ApiError),
+        (status = CONFLICT, description = "The username is taken", body = ApiError),
+        (status = INTERNAL_SERVER_ERROR, description = "The account couldn't be stored", body = ApiError),
+    )
+)]
 async fn signup(
     State(state): State<Arc<AppState>>,
     Json(creds): Json<Credentials>,
 ) -> impl IntoResponse {
-    info!("Signup attempt for user: {}", creds.username);
-    let mut users = state.user_store.lock().unwrap();
-    if users.contains_key(&creds.username) {
-        warn!("Signup failed for user {}: username already exists", creds.username);
-        return StatusCode::CONFLICT;
+    info!("Signup attempt for user: {}", username(&creds.username));
+    if state.config.current().config.auth.signup == SignupMode::Disabled {
+        warn!("Signup rejected for user {}: signup is disabled", username(&creds.username));
+        return ApiError::response(StatusCode::FORBIDDEN, "Signup is disabled");
     }
-
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2.hash_password(creds.password.as_bytes(), &salt).unwrap().to_string();
-
-    let user = User {
-        id: uuid::Uuid::new_v4().to_string(),
-        username: creds.username.clone(),
-        password_hash,
+    let password_hash = match auth::hash_password(&creds.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Signup failed for user {}: {}", username(&creds.username), e);
+            return ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, "Signup failed");
+        }
     };
 
-    users.insert(creds.username.clone(), user);
-    info!("Successfully signed up user: {}", creds.username);
-
-    StatusCode::CREATED
+    match state.user_store.create(User::new(&creds.username, password_hash, Role::User)).await {
+        Ok(()) => {
+            info!("Successfully signed up user: {}", username(&creds.username));
+            state.audit.record(AuditEvent::Signup { username: creds.username });
+            StatusCode::CREATED.into_response()
+        }
+        Err(UserStoreError::AlreadyExists(_)) => {
+            warn!("Signup failed for user {}: username already exists", username(&creds.username));
+            ApiError::response(StatusCode::CONFLICT, "Username already exists")
+        }
+        Err(e) => {
+            error!("Signup failed for user {}: {}", username(&creds.username), e);
+            ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, "Signup failed")
+        }
+    }
 }
 
+/// Checks a username and password and starts a session.
+#[utoipa::path(
+    post,
+    path = "/api/auth/login",
+    tag = "auth",
+    request_body = Credentials,
+    responses(
+        (status = OK, description = "Logged in; the session cookie is set", body = UserInfo),
+        (status = UNAUTHORIZED, description = "Wrong username or password", body = ApiError),
+    )
+)]
 async fn login(
     session: Session,
     State(state): State<Arc<AppState>>,
     Json(creds): Json<Credentials>,
 ) -> impl IntoResponse {
-    info!("Login attempt for user: {}", creds.username);
+    info!("Login attempt for user: {}", username(&creds.username));
+    let attempted = creds.username.clone();
     let result = state.auth_provider.login_with_credentials(creds).await;
+    state.metrics.record_login("password", result.is_ok());
+    state.audit.record(AuditEvent::Login {
+        username: attempted,
+        method: "password".to_string(),
+        success: result.is_ok(),
+    });
     match result {
         Ok(user_info) => {
-            info!("Login successful for user: {}", user_info.email);
+            info!("Login successful for user: {}", username(&user_info.email));
             session.insert("user", user_info.clone()).await.unwrap();
             info!("Session created for user");
             (StatusCode::OK, JsonResponse(user_info)).into_response()
         }
         Err(e) => {
             warn!("Login failed: {}", e);
-            StatusCode::UNAUTHORIZED.into_response()
+            ApiError::response(StatusCode::UNAUTHORIZED, "Invalid username or password")
         }
     }
 }
 
-async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
+#[derive(Serialize, ToSchema)]
+struct RealtimeInfo {
+    websocket_path: &'static str,
+    /// Server-Sent Events, for when neither WebTransport nor WebSocket
+    /// gets through.
+    sse_path: &'static str,
+    /// Absent while the WebTransport server isn't running.
+    webtransport: Option<WebTransportInfo>,
+}
+
+/// Where to open real-time connections.
+#[utoipa::path(
+    get,
+    path = "/api/realtime/info",
+    tag = "realtime",
+    responses((status = OK, description = "The real-time endpoints", body = RealtimeInfo))
+)]
+async fn realtime_info(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    JsonResponse(RealtimeInfo {
+        websocket_path: "/ws",
+        sse_path: sse::PATH,
+        webtransport: state.webtransport.get(),
+    })
+}
+
+/// Upgrades to a WebSocket, which echoes text back and pushes the topics
+/// subscribed to.
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "realtime",
+    params(TopicsQuery, ResumeQuery),
+    responses(
+        (status = SWITCHING_PROTOCOLS, description = "The WebSocket is open"),
+        (status = BAD_REQUEST, description = "Unknown topic", body = ApiError),
+        (status = UNAUTHORIZED, description = "A topic needs a session", body = ApiError),
+        (status = FORBIDDEN, description = "A topic isn't allowed for the user", body = ApiError),
+    )
+)]
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TopicsQuery>,
+    resume: Result<Query<ResumeQuery>, QueryRejection>,
+    session: Session,
+    client: Option<Extension<Client>>,
+) -> axum::response::Response {
     info!("WebSocket connection upgrade requested");
-    ws.on_upgrade(handle_socket)
+    let user = session.get::<UserInfo>("user").await.ok().flatten();
+    let topics = state.hub.connect(user);
+    if let Err(e) = topics.subscribe_query(&query) {
+        return ApiError::response(e.status(), &e.to_string());
+    }
+    // A resume that doesn't parse starts a new session, as one that has expired does.
+    let resume = resume.map(|Query(resume)| resume).unwrap_or_default();
+    let client = client.map(|Extension(client)| client).unwrap_or_default();
+    // The connection outlives the request, but keeps its span as parent so
+    // its events carry the request id.
+    let span = info_span!("Connection", transport = "websocket");
+    ws.on_upgrade(move |socket| {
+        let shutdown = state.shutdown.clone();
+        let connection = handle_socket(socket, state, client, topics, resume);
+        shutdown.track(connection.instrument(span))
+    })
 }
 
-async fn handle_socket(mut socket: WebSocket) {
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    client: Client,
+    mut topics: HubConnection,
+    resume: ResumeQuery,
+) {
     info!("WebSocket connection established");
-    while let Some(msg) = socket.recv().await {
-        let msg = if let Ok(msg) = msg {
+    let metrics = &state.metrics;
+    let _connection = metrics.connection_opened(Transport::WebSocket);
+    let (replay, first) = state.replay.attach(&resume, &client);
+    for envelope in first {
+        if socket.send(Message::Text(envelope.encode().into())).await.is_err() {
+            return;
+        }
+        metrics.record_message(Transport::WebSocket, Direction::Sent);
+    }
+    loop {
+        let msg = tokio::select! {
+            msg = socket.recv() => msg,
+            push = topics.next() => {
+                let Some(push) = push else {
+                    let close = CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: "Too far behind on topic messages".into(),
+                    };
+                    let _ = socket.send(Message::Close(Some(close))).await;
+                    return;
+                };
+                let push = replay.push(push);
+                if socket.send(Message::Text(push.encode().into())).await.is_err() {
+                    return;
+                }
+                metrics.record_message(Transport::WebSocket, Direction::Sent);
+                continue;
+            }
+            () = state.shutdown.triggered() => {
+                let close = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server shutting down".into(),
+                };
+                let _ = socket.send(Message::Close(Some(close))).await;
+                return;
+            }
+        };
+        let msg = if let Some(Ok(msg)) = msg {
             msg
         } else {
             // client disconnected
             return;
         };
+        metrics.record_message(Transport::WebSocket, Direction::Received);
+        let reply = match msg {
+            Message::Text(text) => realtime::receive(&state.rate_limiter, &client, &text, |request| {
+                topics.answer(request, realtime::echo)
+            }),
+            Message::Close(_) => return,
+            // Pings are answered by axum; anything else isn't an envelope.
+            Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Binary(_) => realtime::error("Messages are sent as JSON text"),
+        };
 
-        if socket.send(msg).await.is_err() {
+        let reply = replay.sequence(reply);
+        if socket.send(Message::Text(reply.encode().into())).await.is_err() {
             // client disconnected
             return;
         }
+        metrics.record_message(Transport::WebSocket, Direction::Sent);
     }
 }
 
-async fn logout(session: Session) -> impl IntoResponse {
+/// Ends the session, if any.
+#[utoipa::path(
+    post,
+    path = "/api/auth/logout",
+    tag = "auth",
+    responses((status = OK, description = "Logged out"))
+)]
+async fn logout(session: Session, State(state): State<Arc<AppState>>) -> impl IntoResponse {
     info!("Logout attempt");
+    let user = session.get::<UserInfo>("user").await.ok().flatten();
+    state.audit.record(AuditEvent::Logout { username: user.map(|user| user.email) });
     session.clear().await;
     info!("Session cleared");
     StatusCode::OK
 }
 
+/// The user logged in with the session cookie.
+#[utoipa::path(
+    get,
+    path = "/api/auth/user",
+    tag = "auth",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The logged in user", body = UserInfo),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+    )
+)]
 async fn get_user(session: Session) -> impl IntoResponse {
     info!("Attempting to retrieve user from session");
     match session.get::<UserInfo>("user").await.unwrap() {
         Some(user) => {
-            info!("Found user {} in session", user.email);
+            info!("Found user {} in session", username(&user.email));
             (StatusCode::OK, JsonResponse(user)).into_response()
         },
         None => {
             warn!("No user found in session, returning UNAUTHORIZED");
-            StatusCode::UNAUTHORIZED.into_response()
+            ApiError::response(StatusCode::UNAUTHORIZED, "Not logged in")
         }
     }
 }
 
+/// Redirects to the provider's login page.
+#[utoipa::path(
+    get,
+    path = "/api/auth/sso/login/{provider}",
+    tag = "auth",
+    params(("provider" = String, Path, description = "A configured provider, such as `google`")),
+    responses(
+        (status = SEE_OTHER, description = "Redirect to the provider"),
+        (status = BAD_REQUEST, description = "Unknown provider", body = ApiError),
+    )
+)]
 async fn sso_login(
     Path(provider_name): Path<String>,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
     info!("Attempting SSO login with provider: {}", provider_name);
-    match state.sso_auth.providers.get(&provider_name) {
+    match state.config.current().sso.providers.get(&provider_name) {
         Some(p) => p.get_login_redirect().unwrap().into_response(),
         None => {
             warn!("Invalid SSO provider requested: {}", provider_name);
-            StatusCode::BAD_REQUEST.into_response()
+            ApiError::response(StatusCode::BAD_REQUEST, "Unknown SSO provider")
         }
     }
 }
 
+/// Where the provider sends the user back to after logging in. Starts a session.
+#[utoipa::path(
+    get,
+    path = "/api/auth/sso/callback/{provider}",
+    tag = "auth",
+    params(
+        ("provider" = String, Path, description = "The provider the login started with"),
+        ("code" = String, Query, description = "Authorization code from the provider"),
+        ("state" = String, Query, description = "State from the login redirect"),
+    ),
+    responses(
+        (status = OK, description = "Logged in; the session cookie is set"),
+        (status = BAD_REQUEST, description = "Unknown provider", body = ApiError),
+    )
+)]
 async fn sso_callback(
     Path(provider_name): Path<String>,
     Query(params): Query<HashMap<String, String>>,
@@ -206,17 +804,24 @@ async fn sso_callback(
     info!("Handling SSO callback for provider: {}", provider_name);
     let code = params.get("code").unwrap().to_string();
     let state_param = params.get("state").unwrap().to_string();
-    match state.sso_auth.providers.get(&provider_name) {
+    let active = state.config.current();
+    match active.sso.providers.get(&provider_name) {
         Some(p) => {
             let user_info = p.handle_callback(code, state_param).await.unwrap();
-            info!("SSO callback successful for user: {}", user_info.email);
+            info!("SSO callback successful for user: {}", username(&user_info.email));
+            state.metrics.record_login(&provider_name, true);
+            state.audit.record(AuditEvent::Login {
+                username: user_info.email.clone(),
+                method: provider_name.clone(),
+                success: true,
+            });
             session.insert("user", user_info).await.unwrap();
             info!("Session created for SSO user");
             StatusCode::OK.into_response()
         }
         None => {
             warn!("Invalid SSO provider in callback: {}", provider_name);
-            StatusCode::BAD_REQUEST.into_response()
+            ApiError::response(StatusCode::BAD_REQUEST, "Unknown SSO provider")
         }
     }
 }
@@ -0,0 +1,297 @@
+use crate::config::SlowConsumers;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use prometheus_client::{
+    encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use std::{
+    fmt::{self, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Content type of the text produced by `Metrics::encode`.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Transport {
+    WebSocket,
+    WebTransport,
+    Sse,
+}
+
+/// Traffic a rate limit applies to.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum LimitGroup {
+    /// Logins, signups and other credential checks.
+    Auth,
+    /// Every other `/api` request.
+    Api,
+    /// Messages received on realtime connections.
+    Realtime,
+}
+
+/// Who a rate limit is counted against.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum LimitScope {
+    Ip,
+    User,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    Failure,
+}
+
+/// Label enums are encoded as lowercase strings.
+macro_rules! label_values {
+    ($($ty:ty { $($variant:ident => $value:literal),* })*) => {$(
+        impl EncodeLabelValue for $ty {
+            fn encode(&self, encoder: &mut LabelValueEncoder) -> fmt::Result {
+                encoder.write_str(match self {
+                    $(Self::$variant => $value),*
+                })
+            }
+        }
+    )*};
+}
+
+label_values! {
+    Transport { WebSocket => "websocket", WebTransport => "webtransport", Sse => "sse" }
+    Direction { Received => "received", Sent => "sent" }
+    Outcome { Success => "success", Failure => "failure" }
+    LimitGroup { Auth => "auth", Api => "api", Realtime => "realtime" }
+    LimitScope { Ip => "ip", User => "user" }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    method: String,
+    route: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ResponseLabels {
+    method: String,
+    route: String,
+    status: u16,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TransportLabels {
+    transport: Transport,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MessageLabels {
+    transport: Transport,
+    direction: Direction,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LoginLabels {
+    method: String,
+    outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LimitLabels {
+    group: LimitGroup,
+    scope: LimitScope,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+struct Inner {
+    registry: Registry,
+    requests: Family<ResponseLabels, Counter>,
+    request_duration: HistogramFamily<RouteLabels>,
+    connections: Family<TransportLabels, Gauge>,
+    messages: Family<MessageLabels, Counter>,
+    logins: Family<LoginLabels, Counter>,
+    throttled: Family<LimitLabels, Counter>,
+    limit_buckets: Family<LimitLabels, Gauge>,
+    topic_dropped: Counter,
+    slow_disconnects: Counter,
+}
+
+/// Prometheus metrics for one server instance.
+#[derive(Clone)]
+pub struct Metrics(Arc<Inner>);
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut registry = Registry::with_prefix("hudwiz");
+        let requests = Family::<ResponseLabels, Counter>::default();
+        registry.register("http_requests", "HTTP requests by route and status", requests.clone());
+        let request_duration: HistogramFamily<RouteLabels> =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.001, 2.0, 15)));
+        registry.register(
+            "http_request_duration_seconds",
+            "HTTP request latency by route",
+            request_duration.clone(),
+        );
+        let connections = Family::<TransportLabels, Gauge>::default();
+        registry.register("realtime_connections", "Open realtime connections", connections.clone());
+        let messages = Family::<MessageLabels, Counter>::default();
+        registry.register("realtime_messages", "Realtime messages received and sent", messages.clone());
+        let logins = Family::<LoginLabels, Counter>::default();
+        registry.register("logins", "Login attempts by method and outcome", logins.clone());
+        let throttled = Family::<LimitLabels, Counter>::default();
+        registry.register("rate_limited", "Requests and messages refused by a rate limit", throttled.clone());
+        let limit_buckets = Family::<LimitLabels, Gauge>::default();
+        registry.register(
+            "rate_limit_buckets",
+            "Clients with a partly used rate limit",
+            limit_buckets.clone(),
+        );
+        let topic_dropped = Counter::default();
+        registry.register(
+            "realtime_topic_dropped",
+            "Topic messages dropped because a connection's queue was full",
+            topic_dropped.clone(),
+        );
+        let slow_disconnects = Counter::default();
+        registry.register(
+            "realtime_slow_disconnects",
+            "Connections closed for falling behind on topic messages",
+            slow_disconnects.clone(),
+        );
+
+        Self(Arc::new(Inner {
+            registry,
+            requests,
+            request_duration,
+            connections,
+            messages,
+            logins,
+            throttled,
+            limit_buckets,
+            topic_dropped,
+            slow_disconnects,
+        }))
+    }
+}
+
+impl Metrics {
+    /// All metrics in the OpenMetrics text format.
+    pub fn encode(&self) -> String {
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &self.0.registry)
+            .expect("Writing to a String can't fail");
+        text
+    }
+
+    pub fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.0
+            .requests
+            .get_or_create(&ResponseLabels {
+                method: method.to_string(),
+                route: route.to_string(),
+                status,
+            })
+            .inc();
+        self.0
+            .request_duration
+            .get_or_create(&RouteLabels {
+                method: method.to_string(),
+                route: route.to_string(),
+            })
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub fn connection_opened(&self, transport: Transport) -> ConnectionGuard {
+        let gauge = self.0.connections.get_or_create(&TransportLabels { transport }).clone();
+        gauge.inc();
+        ConnectionGuard(gauge)
+    }
+
+    pub fn record_message(&self, transport: Transport, direction: Direction) {
+        self.0
+            .messages
+            .get_or_create(&MessageLabels { transport, direction })
+            .inc();
+    }
+
+    pub fn record_login(&self, method: &str, success: bool) {
+        let outcome = if success { Outcome::Success } else { Outcome::Failure };
+        self.0
+            .logins
+            .get_or_create(&LoginLabels {
+                method: method.to_string(),
+                outcome,
+            })
+            .inc();
+    }
+
+    pub fn record_throttled(&self, group: LimitGroup, scope: LimitScope) {
+        self.0.throttled.get_or_create(&LimitLabels { group, scope }).inc();
+    }
+
+    pub fn limit_bucket_added(&self, group: LimitGroup, scope: LimitScope) {
+        self.0.limit_buckets.get_or_create(&LimitLabels { group, scope }).inc();
+    }
+
+    pub fn set_limit_buckets(&self, group: LimitGroup, scope: LimitScope, count: usize) {
+        self.0
+            .limit_buckets
+            .get_or_create(&LimitLabels { group, scope })
+            .set(count as i64);
+    }
+
+    /// Counts a topic message a connection was too far behind to take, and
+    /// what `policy` did about it.
+    pub fn record_slow_consumer(&self, policy: SlowConsumers) {
+        match policy {
+            SlowConsumers::Drop => self.0.topic_dropped.inc(),
+            SlowConsumers::Disconnect => self.0.slow_disconnects.inc(),
+        };
+    }
+}
+
+pub struct ConnectionGuard(Gauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Middleware recording the count and latency of every request, labelled
+/// with the route pattern rather than the raw path to keep cardinality low.
+pub async fn track_requests(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "static".to_string());
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics.record_request(&method, &route, response.status().as_u16(), start.elapsed());
+    response
+}
@@ -0,0 +1,86 @@
+use crate::{auth::UserInfo, ApiError};
+use axum::{
+    http::header,
+    response::IntoResponse,
+    Extension, Router,
+};
+use std::sync::Arc;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    Modify, OpenApi,
+};
+
+/// Where the embedded viewer is served with the `openapi-viewer` feature.
+pub const VIEWER_PATH: &str = "/api/docs";
+
+/// The parts of the OpenAPI document that don't belong to a handler. The
+/// operations are added as `AppBuilder::build` registers their routes.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "hudwiz",
+        description = "The HTTP API of the hudwiz reference server. Errors come with an `ApiError` body."
+    ),
+    components(schemas(ApiError, UserInfo)),
+    modifiers(&SessionCookie),
+    tags(
+        (name = "auth", description = "Accounts, logins and sessions"),
+        (name = "preferences", description = "Per-user HUD settings"),
+        (name = "layouts", description = "Which component goes in which region, per role, organisation and user"),
+        (name = "uploads", description = "Files users attach, and signed links to download them"),
+        (name = "system", description = "Host and server process statistics"),
+        (name = "proxy", description = "Third-party content fetched through the server"),
+        (name = "realtime", description = "WebSocket, WebTransport and Server-Sent Events connections"),
+        (name = "operations", description = "Health, readiness, metrics and configuration"),
+        (name = "docs", description = "This document"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The session cookie set by `tower-sessions` on login.
+struct SessionCookie;
+
+impl Modify for SessionCookie {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("session", SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("id"))));
+        }
+    }
+}
+
+/// The finished document, serialized once for `document` to serve.
+#[derive(Clone)]
+pub struct Document(Arc<str>);
+
+impl Document {
+    pub fn new(spec: &utoipa::openapi::OpenApi) -> anyhow::Result<Self> {
+        Ok(Self(spec.to_pretty_json()?.into()))
+    }
+}
+
+/// This document.
+#[utoipa::path(
+    get,
+    path = "/api/openapi.json",
+    tag = "docs",
+    responses((status = OK, description = "The OpenAPI 3.1 document", content_type = "application/json"))
+)]
+pub async fn document(Extension(document): Extension<Document>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], document.0.to_string())
+}
+
+/// Swagger UI for the document at `VIEWER_PATH`, or nothing without the
+/// `openapi-viewer` feature. Its assets are built into the binary, so it
+/// needs no CDN and works under the default content security policy.
+pub fn viewer<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    #[cfg(feature = "openapi-viewer")]
+    {
+        use utoipa_swagger_ui::{Config, SwaggerUi};
+        // Relative, so that it still resolves under an `AppBuilder::prefix`.
+        Router::from(SwaggerUi::new(VIEWER_PATH).config(Config::from("../openapi.json")))
+    }
+    #[cfg(not(feature = "openapi-viewer"))]
+    {
+        Router::new()
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{
+    auth::{CurrentUser, Role},
+    metrics,
+    reload::ConfigVersion,
+    storage,
+    telemetry::username,
+    ApiError, Services,
+};
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::warn;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// Health, readiness and (optionally) metrics endpoints for orchestrators
+/// and monitoring, with their part of the OpenAPI document.
+pub fn router<S: Clone + Send + Sync + 'static>(services: Services, with_metrics: bool) -> OpenApiRouter<S> {
+    let mut router = OpenApiRouter::new()
+        .routes(routes!(healthz))
+        .routes(routes!(readyz));
+    if with_metrics {
+        router = router.routes(routes!(metrics));
+    }
+    router.with_state(services)
+}
+
+/// Endpoints for logged in admins, served with the API since they need
+/// its sessions.
+pub fn admin_router<S: Clone + Send + Sync + 'static>(services: Services) -> OpenApiRouter<S> {
+    OpenApiRouter::new().routes(routes!(config_version)).with_state(services)
+}
+
+/// The process is up and serving requests.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    responses((status = OK, description = "Liveness probe", body = String, content_type = "text/plain", example = "ok"))
+)]
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Serialize, ToSchema)]
+struct Readiness {
+    ready: bool,
+    /// "ok" or a description of what is wrong, per check.
+    #[schema(value_type = BTreeMap<String, String>)]
+    checks: BTreeMap<&'static str, String>,
+}
+
+/// Whether the server should receive traffic: storage is usable, the
+/// configuration is valid and shutdown hasn't started.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = OK, description = "Every check passed", body = Readiness),
+        (status = SERVICE_UNAVAILABLE, description = "A check failed or shutdown has started", body = Readiness),
+    )
+)]
+async fn readyz(State(services): State<Services>) -> impl IntoResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert("storage", outcome(check_storage(&services).await));
+    let problems = services.config.current().config.validate();
+    checks.insert("config", outcome(if problems.is_empty() { Ok(()) } else { Err(problems.join("; ")) }));
+    let shutting_down = services.shutdown.is_triggered();
+    checks.insert("shutdown", outcome(if shutting_down { Err("shutting down".to_string()) } else { Ok(()) }));
+
+    let ready = checks.values().all(|check| check == "ok");
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(Readiness { ready, checks }))
+}
+
+async fn check_storage(services: &Services) -> Result<(), String> {
+    if let Some(data_dir) = services.config.current().config.storage.data_dir.clone() {
+        let version = storage::blocking(move || storage::schema_version(&data_dir))
+            .await
+            .and_then(|version| version)
+            .map_err(|e| format!("{:#}", e))?;
+        if version != storage::SCHEMA_VERSION {
+            return Err(format!("schema version {} but {} is required", version, storage::SCHEMA_VERSION));
+        }
+    }
+    services.user_store.list().await.map(drop).map_err(|e| e.to_string())
+}
+
+fn outcome(result: Result<(), String>) -> String {
+    result.err().unwrap_or_else(|| "ok".to_string())
+}
+
+/// Metrics for Prometheus. Only served here when there is no admin listener.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses((status = OK, description = "Metrics", body = String, content_type = "application/openmetrics-text"))
+)]
+async fn metrics(State(services): State<Services>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        services.metrics.encode(),
+    )
+}
+
+/// Which configuration is in effect, for checking that an edit was applied.
+#[utoipa::path(
+    get,
+    path = "/admin/config/version",
+    tag = "operations",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The active configuration version", body = ConfigVersion),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = FORBIDDEN, description = "Not an admin", body = ApiError),
+    )
+)]
+async fn config_version(CurrentUser(user): CurrentUser, State(services): State<Services>) -> Response {
+    if services.user_store.role_of(&user).await != Role::Admin {
+        warn!("Rejected configuration version request by non-admin {}", username(&user.email));
+        return ApiError::response(StatusCode::FORBIDDEN, "Only admins can see the configuration version");
+    }
+    Json(services.config.current().version.clone()).into_response()
+}
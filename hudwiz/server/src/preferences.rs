@@ -0,0 +1,439 @@
+use crate::{
+    auth::CurrentUser,
+    storage::{self, JsonFile, PREFERENCES_FILE},
+    telemetry::username,
+    ApiError,
+};
+use axum::{
+    extract::{rejection::JsonRejection, DefaultBodyLimit, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// The document format this build reads and writes.
+pub const PREFERENCES_VERSION: u32 = 1;
+
+/// Largest accepted document. Preferences are a handful of short strings.
+pub const MAX_DOCUMENT_BYTES: usize = 16 * 1024;
+
+/// A user's HUD settings, restored on every device they log in from. Fields
+/// that are absent keep the frontend's defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Preferences {
+    /// Format of the document, currently always 1.
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    /// BCP 47 language tag, such as `en-US`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_content: Option<BackgroundContent>,
+    /// Whether each panel is collapsed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub collapsed: BTreeMap<Panel, bool>,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            version: PREFERENCES_VERSION,
+            theme: None,
+            language: None,
+            background_pattern: None,
+            background_content: None,
+            collapsed: BTreeMap::new(),
+        }
+    }
+}
+
+/// What fills the background, mirroring the frontend's `ContentType`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "kind", content = "source", rename_all = "snake_case")]
+pub enum BackgroundContent {
+    Empty,
+    AnimatedSvg,
+    TransparentSvg,
+    SolidColor(String),
+    Image(String),
+    Video(String),
+    #[serde(rename = "iframe")]
+    IFrame(String),
+    #[serde(rename = "webgl")]
+    WebGl(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Panel {
+    SidebarLeft,
+    SidebarRight,
+    HudTopLeft,
+    HudTopRight,
+    HudBottomLeft,
+    HudBottomRight,
+    ActivityBar,
+}
+
+impl Preferences {
+    /// Problems that make the document unusable, empty when it is valid.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.version != PREFERENCES_VERSION {
+            problems.push(format!(
+                "version: {} is not supported, expected {}",
+                self.version, PREFERENCES_VERSION
+            ));
+        }
+        if let Some(theme) = &self.theme
+            && !is_name(theme)
+        {
+            problems.push(format!("theme: '{}' must be 1 to 64 letters, digits, '-' or '_'", theme));
+        }
+        if let Some(language) = &self.language
+            && !is_language_tag(language)
+        {
+            problems.push(format!("language: '{}' is not a language tag", language));
+        }
+        if let Some(pattern) = &self.background_pattern
+            && !is_name(pattern)
+        {
+            problems.push(format!(
+                "background_pattern: '{}' must be 1 to 64 letters, digits, '-' or '_'",
+                pattern
+            ));
+        }
+        match &self.background_content {
+            Some(BackgroundContent::SolidColor(color)) if color.is_empty() || color.len() > 64 => {
+                problems.push("background_content: the color must be 1 to 64 characters".to_string());
+            }
+            Some(
+                BackgroundContent::Image(source)
+                | BackgroundContent::Video(source)
+                | BackgroundContent::IFrame(source)
+                | BackgroundContent::WebGl(source),
+            ) if !is_source(source) => {
+                problems.push(format!(
+                    "background_content: '{}' must be a path on this server or an http(s) URL",
+                    source
+                ));
+            }
+            _ => {}
+        }
+        problems
+    }
+}
+
+fn is_name(value: &str) -> bool {
+    (1..=64).contains(&value.len()) && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A BCP 47 tag in its usual shape: alphanumeric subtags of up to eight
+/// characters, starting with a 2 to 8 letter language.
+fn is_language_tag(value: &str) -> bool {
+    let mut subtags = value.split('-');
+    let language = subtags.next().unwrap_or_default();
+    value.len() <= 35
+        && (2..=8).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+fn is_source(value: &str) -> bool {
+    if value.len() > 2048 {
+        return false;
+    }
+    if value.starts_with('/') && !value.starts_with("//") {
+        return true;
+    }
+    url::Url::parse(value).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// A user's saved preferences. `revision` increases with every save and is
+/// the ETag for optimistic concurrency.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredPreferences {
+    pub revision: u64,
+    /// Unix timestamp of the last save.
+    pub updated_at: u64,
+    pub preferences: Preferences,
+}
+
+impl StoredPreferences {
+    /// What users who never saved anything get, at revision 0.
+    fn initial() -> Self {
+        Self {
+            revision: 0,
+            updated_at: 0,
+            preferences: Preferences::default(),
+        }
+    }
+
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.revision)
+    }
+}
+
+#[derive(Debug)]
+pub enum PreferenceStoreError {
+    /// The stored revision isn't the one the change was based on.
+    Conflict(Box<StoredPreferences>),
+    Storage(anyhow::Error),
+}
+
+impl fmt::Display for PreferenceStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreferenceStoreError::Conflict(current) => {
+                write!(f, "Preferences were changed concurrently, now at revision {}", current.revision)
+            }
+            PreferenceStoreError::Storage(e) => write!(f, "Preference store error: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for PreferenceStoreError {}
+
+impl From<anyhow::Error> for PreferenceStoreError {
+    fn from(e: anyhow::Error) -> Self {
+        PreferenceStoreError::Storage(e)
+    }
+}
+
+/// Preferences keyed by user id. Like the user store, a file-backed store
+/// keeps its file parsed in memory, reads it again when it changes and
+/// accesses it on the blocking pool.
+#[derive(Clone)]
+pub struct PreferenceStore {
+    backend: Arc<Mutex<Backend>>,
+}
+
+enum Backend {
+    Memory(BTreeMap<String, StoredPreferences>),
+    File(JsonFile<BTreeMap<String, StoredPreferences>>),
+}
+
+impl PreferenceStore {
+    pub fn in_memory() -> Self {
+        Self { backend: Arc::new(Mutex::new(Backend::Memory(BTreeMap::new()))) }
+    }
+
+    /// A store persisted to `preferences.json` in `data_dir`.
+    pub fn open(data_dir: &Path) -> Self {
+        Self { backend: Arc::new(Mutex::new(Backend::File(JsonFile::new(data_dir.join(PREFERENCES_FILE))))) }
+    }
+
+    /// Applies `f` to the preferences on the blocking pool, writing them back
+    /// if it reports a change.
+    async fn with_preferences<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, StoredPreferences>) -> Result<(R, bool), PreferenceStoreError> + Send + 'static,
+    ) -> Result<R, PreferenceStoreError> {
+        let backend = self.backend.clone();
+        storage::blocking(move || {
+            let mut backend = backend.lock().unwrap();
+            match &mut *backend {
+                Backend::Memory(preferences) => f(preferences).map(|(result, _)| result),
+                Backend::File(file) => {
+                    let (result, modified) = f(file.read()?)?;
+                    if modified {
+                        file.write()?;
+                    }
+                    Ok(result)
+                }
+            }
+        })
+        .await?
+    }
+
+    /// The preferences of `user_id`, or the defaults at revision 0.
+    pub async fn get(&self, user_id: &str) -> Result<StoredPreferences, PreferenceStoreError> {
+        let user_id = user_id.to_string();
+        self.with_preferences(move |preferences| {
+            let stored = preferences.get(&user_id).cloned().unwrap_or_else(StoredPreferences::initial);
+            Ok((stored, false))
+        })
+        .await
+    }
+
+    /// Replaces the preferences of `user_id` if they are still at
+    /// `expected_revision`, or unconditionally without one.
+    pub async fn put(
+        &self,
+        user_id: &str,
+        expected_revision: Option<u64>,
+        document: Preferences,
+    ) -> Result<StoredPreferences, PreferenceStoreError> {
+        let user_id = user_id.to_string();
+        self.with_preferences(move |preferences| {
+            let current = preferences.get(&user_id).cloned().unwrap_or_else(StoredPreferences::initial);
+            if expected_revision.is_some_and(|revision| revision != current.revision) {
+                return Err(PreferenceStoreError::Conflict(Box::new(current)));
+            }
+            let stored = StoredPreferences {
+                revision: current.revision + 1,
+                updated_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or_default(),
+                preferences: document,
+            };
+            preferences.insert(user_id, stored.clone());
+            Ok((stored, true))
+        })
+        .await
+    }
+}
+
+/// `GET` and `PUT /api/user/preferences`.
+pub fn router<S: Clone + Send + Sync + 'static>(store: PreferenceStore) -> OpenApiRouter<S> {
+    OpenApiRouter::new()
+        .routes(routes!(get_preferences, put_preferences))
+        .layer(DefaultBodyLimit::max(MAX_DOCUMENT_BYTES))
+        .with_state(store)
+}
+
+fn with_etag(stored: &StoredPreferences, response: impl IntoResponse) -> Response {
+    let mut response = response.into_response();
+    if let Ok(etag) = HeaderValue::from_str(&stored.etag()) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    response
+}
+
+/// Tags listed in a conditional request header.
+fn listed_tags(headers: &HeaderMap, name: header::HeaderName) -> Option<Vec<String>> {
+    let values: Vec<String> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().to_string())
+        .collect();
+    (!values.is_empty()).then_some(values)
+}
+
+/// The user's preferences, or the defaults at revision 0 if they never saved
+/// any. The ETag is the revision to send back in `If-Match`.
+#[utoipa::path(
+    get,
+    path = "/api/user/preferences",
+    tag = "preferences",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The preferences, with their revision as ETag", body = Preferences),
+        (status = NOT_MODIFIED, description = "`If-None-Match` lists the current revision"),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+    )
+)]
+async fn get_preferences(
+    CurrentUser(user): CurrentUser,
+    State(store): State<PreferenceStore>,
+    headers: HeaderMap,
+) -> Response {
+    let stored = match store.get(&user.id).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            error!("Failed to read preferences of {}: {}", username(&user.email), e);
+            return ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read preferences");
+        }
+    };
+    let etag = stored.etag();
+    if listed_tags(&headers, header::IF_NONE_MATCH)
+        .is_some_and(|tags| tags.iter().any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag))
+    {
+        return with_etag(&stored, StatusCode::NOT_MODIFIED);
+    }
+    with_etag(&stored, Json(stored.preferences.clone()))
+}
+
+/// Replaces the user's preferences. `If-Match` must carry the ETag they were
+/// read with, so that a device can't overwrite changes it hasn't seen;
+/// `If-Match: *` overwrites whatever is stored.
+#[utoipa::path(
+    put,
+    path = "/api/user/preferences",
+    tag = "preferences",
+    security(("session" = [])),
+    request_body = Preferences,
+    params(("If-Match" = String, Header, description = "ETag of the revision the change is based on, or `*`")),
+    responses(
+        (status = OK, description = "Saved, with the new revision as ETag", body = Preferences),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = PAYLOAD_TOO_LARGE, description = "The document is larger than 16 KiB"),
+        (status = PRECONDITION_FAILED, description = "Changed since it was read; the ETag is the current revision", body = ApiError),
+        (status = UNPROCESSABLE_ENTITY, description = "Not a valid preferences document", body = ApiError),
+        (status = PRECONDITION_REQUIRED, description = "`If-Match` is missing", body = ApiError),
+    )
+)]
+async fn put_preferences(
+    CurrentUser(user): CurrentUser,
+    State(store): State<PreferenceStore>,
+    headers: HeaderMap,
+    document: Result<Json<Preferences>, JsonRejection>,
+) -> Response {
+    let Some(tags) = listed_tags(&headers, header::IF_MATCH) else {
+        return ApiError::response(StatusCode::PRECONDITION_REQUIRED, "If-Match is required");
+    };
+    let document = match document {
+        Ok(Json(document)) => document,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return rejection.into_response();
+        }
+        Err(rejection) => return ApiError::response(StatusCode::UNPROCESSABLE_ENTITY, &rejection.body_text()),
+    };
+    let problems = document.validate();
+    if !problems.is_empty() {
+        return ApiError::response(StatusCode::UNPROCESSABLE_ENTITY, &problems.join("; "));
+    }
+
+    // A single strong tag names the revision the change is based on;
+    // anything else can't match one.
+    let expected = if tags.iter().any(|tag| tag == "*") {
+        None
+    } else {
+        let revisions: Vec<u64> = tags
+            .iter()
+            .filter_map(|tag| tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok())
+            .collect();
+        match revisions.as_slice() {
+            [revision] => Some(*revision),
+            _ => Some(u64::MAX),
+        }
+    };
+    match store.put(&user.id, expected, document).await {
+        Ok(stored) => {
+            info!("Saved preferences of {} at revision {}", username(&user.email), stored.revision);
+            with_etag(&stored, Json(stored.preferences.clone()))
+        }
+        Err(PreferenceStoreError::Conflict(current)) => {
+            warn!(
+                "Rejected stale preferences of {}, now at revision {}",
+                username(&user.email),
+                current.revision
+            );
+            with_etag(
+                &current,
+                ApiError::response(StatusCode::PRECONDITION_FAILED, "Preferences were changed elsewhere"),
+            )
+        }
+        Err(e) => {
+            error!("Failed to save preferences of {}: {}", username(&user.email), e);
+            ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to save preferences")
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use crate::{auth::CurrentUser, reload::LiveConfig, ApiError};
+use anyhow::{Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use futures::stream;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// Request headers passed on to the upstream. Cookies and credentials stay
+/// with this server.
+const FORWARDED_REQUEST_HEADERS: [HeaderName; 6] = [
+    header::ACCEPT,
+    header::ACCEPT_LANGUAGE,
+    header::RANGE,
+    header::IF_RANGE,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+];
+
+/// Response headers passed back to the browser. Upstream framing, CSP, CORS
+/// and cookie headers are dropped.
+const FORWARDED_RESPONSE_HEADERS: [HeaderName; 9] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::CONTENT_LANGUAGE,
+    header::ACCEPT_RANGES,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+    header::EXPIRES,
+];
+
+/// Policy for proxied pages. They may be framed by the UI, but run in a
+/// sandbox with an opaque origin so they can't reach its cookies or API.
+const DOCUMENT_CSP: &str =
+    "sandbox allow-scripts allow-forms allow-popups allow-presentation; frame-ancestors 'self'";
+
+/// Policy for everything else proxied, framed or played by the UI.
+const CONTENT_CSP: &str = "default-src 'none'; frame-ancestors 'self'";
+
+/// Redirects followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Clone)]
+pub struct ProxyState {
+    config: LiveConfig,
+    client: reqwest::Client,
+    cache: Arc<ResponseCache>,
+}
+
+impl ProxyState {
+    /// The client's timeout and the cache sizes are read from `config` once;
+    /// `proxy.upstreams` is read on every request, redirects included.
+    pub fn new(config: &LiveConfig) -> Result<Self> {
+        let proxy = config.current().config.proxy.clone();
+        let live = config.clone();
+        let redirects = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if is_allowed(&live, attempt.url()) {
+                attempt.follow()
+            } else {
+                let origin = attempt.url().origin().ascii_serialization();
+                attempt.error(format!("redirected to {}, which isn't in proxy.upstreams", origin))
+            }
+        });
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(proxy.timeout_secs))
+            .redirect(redirects)
+            .user_agent(concat!("hudwiz/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .context("Failed to build the proxy client")?;
+        Ok(Self {
+            config: config.clone(),
+            client,
+            cache: Arc::new(ResponseCache::new(
+                proxy.cache_bytes,
+                proxy.cache_entry_bytes,
+                Duration::from_secs(proxy.cache_ttl_secs),
+            )),
+        })
+    }
+}
+
+fn is_allowed(config: &LiveConfig, url: &Url) -> bool {
+    let origin = url.origin().ascii_serialization();
+    config.current().config.proxy.upstreams.contains(&origin)
+}
+
+/// `/api/proxy` and `/api/proxy/upstreams`.
+pub fn router<S: Clone + Send + Sync + 'static>(state: ProxyState) -> OpenApiRouter<S> {
+    OpenApiRouter::new()
+        .routes(routes!(proxy))
+        .routes(routes!(upstreams))
+        .with_state(state)
+}
+
+#[derive(Deserialize, IntoParams)]
+struct ProxyQuery {
+    /// Absolute URL on one of the `proxy.upstreams` origins.
+    url: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Upstreams {
+    /// Origins whose URLs the UI loads through `/api/proxy`.
+    upstreams: Vec<String>,
+}
+
+/// The origins `/api/proxy` serves, so the UI knows which content URLs to
+/// rewrite.
+#[utoipa::path(
+    get,
+    path = "/api/proxy/upstreams",
+    tag = "proxy",
+    responses((status = OK, description = "The allowed origins, empty while the proxy is off", body = Upstreams))
+)]
+async fn upstreams(State(state): State<ProxyState>) -> Json<Upstreams> {
+    Json(Upstreams {
+        upstreams: state.config.current().config.proxy.upstreams.clone(),
+    })
+}
+
+/// Fetches `url` for the logged in user, so content loads where the
+/// upstream is unreachable or blocked by CSP. Pages are sandboxed and may
+/// only be framed by the UI. Small responses and media ranges are cached.
+/// Other upstream statuses are passed through.
+#[utoipa::path(
+    get,
+    path = "/api/proxy",
+    tag = "proxy",
+    params(
+        ProxyQuery,
+        ("Range" = Option<String>, Header, description = "Passed on, and part of the cache key"),
+    ),
+    responses(
+        (status = OK, description = "The upstream response"),
+        (status = PARTIAL_CONTENT, description = "The requested range of it"),
+        (status = NOT_MODIFIED, description = "The cached copy matches `If-None-Match`"),
+        (status = BAD_REQUEST, description = "`url` isn't an absolute URL", body = ApiError),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = FORBIDDEN, description = "`url` isn't on an allowed origin", body = ApiError),
+        (status = BAD_GATEWAY, description = "The upstream failed or redirected elsewhere", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "The proxy is off", body = ApiError),
+        (status = GATEWAY_TIMEOUT, description = "The upstream didn't answer in time", body = ApiError),
+    )
+)]
+async fn proxy(
+    _user: CurrentUser,
+    State(state): State<ProxyState>,
+    Query(query): Query<ProxyQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if state.config.current().config.proxy.upstreams.is_empty() {
+        return ApiError::response(StatusCode::SERVICE_UNAVAILABLE, "The proxy is off");
+    }
+    let url = match Url::parse(&query.url) {
+        Ok(url) if ["http", "https"].contains(&url.scheme()) => url,
+        _ => return ApiError::response(StatusCode::BAD_REQUEST, "url must be an absolute http(s) URL"),
+    };
+    if !is_allowed(&state.config, &url) {
+        return ApiError::response(
+            StatusCode::FORBIDDEN,
+            &format!("{} isn't in proxy.upstreams", url.origin().ascii_serialization()),
+        );
+    }
+
+    let key = CacheKey::new(&url, &headers);
+    if let Some(cached) = state.cache.get(&key) {
+        let if_none_match = headers.get(header::IF_NONE_MATCH);
+        if if_none_match.is_some() && if_none_match == cached.headers.get(header::ETAG) {
+            let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+            if let Some(etag) = cached.headers.get(header::ETAG) {
+                not_modified.headers_mut().insert(header::ETAG, etag.clone());
+            }
+            return not_modified;
+        }
+        return cached.into_response();
+    }
+
+    let mut request = state.client.get(url.clone());
+    for name in FORWARDED_REQUEST_HEADERS {
+        if let Some(value) = headers.get(&name) {
+            request = request.header(name, value);
+        }
+    }
+    let upstream = match request.send().await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let timed_out = e.is_timeout();
+            warn!("Proxying {} failed: {:#}", url.origin().ascii_serialization(), anyhow::Error::from(e.without_url()));
+            return if timed_out {
+                ApiError::response(StatusCode::GATEWAY_TIMEOUT, "The upstream didn't answer in time")
+            } else {
+                ApiError::response(StatusCode::BAD_GATEWAY, "The upstream couldn't be reached")
+            };
+        }
+    };
+
+    let status = upstream.status();
+    let mut response_headers = HeaderMap::new();
+    for name in FORWARDED_RESPONSE_HEADERS {
+        if let Some(value) = upstream.headers().get(&name) {
+            response_headers.insert(name, value.clone());
+        }
+    }
+    let is_document = response_headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    let csp = if is_document { DOCUMENT_CSP } else { CONTENT_CSP };
+    response_headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static(csp));
+
+    if state.cache.accepts(status, upstream.headers(), upstream.content_length()) {
+        match upstream.bytes().await {
+            Ok(body) => {
+                let cached = Cached {
+                    status,
+                    headers: response_headers,
+                    body,
+                };
+                state.cache.insert(key, cached.clone());
+                return cached.into_response();
+            }
+            Err(e) => {
+                warn!("Reading {} failed: {:#}", url.origin().ascii_serialization(), anyhow::Error::from(e.without_url()));
+                return ApiError::response(StatusCode::BAD_GATEWAY, "The upstream response was cut short");
+            }
+        }
+    }
+
+    // Larger bodies are streamed as they arrive, stopping after an error.
+    let body = stream::unfold(Some(upstream), |upstream| async move {
+        let mut upstream = upstream?;
+        match upstream.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(upstream))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let mut response = Response::new(Body::from_stream(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = response_headers;
+    response
+}
+
+/// Forwarded request headers an upstream may pick a variant by, which are
+/// part of the cache key. Responses that vary by anything else aren't
+/// cached, except `Accept-Encoding`, which is the same on every proxied
+/// request.
+const CACHE_KEY_HEADERS: [HeaderName; 3] = [header::ACCEPT, header::ACCEPT_LANGUAGE, header::RANGE];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    url: String,
+    /// The values of `CACHE_KEY_HEADERS`, in order.
+    headers: Vec<Option<HeaderValue>>,
+}
+
+impl CacheKey {
+    fn new(url: &Url, headers: &HeaderMap) -> Self {
+        Self {
+            url: url.to_string(),
+            headers: CACHE_KEY_HEADERS.iter().map(|name| headers.get(name).cloned()).collect(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Cached {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl IntoResponse for Cached {
+    fn into_response(self) -> Response {
+        (self.status, self.headers, self.body).into_response()
+    }
+}
+
+struct CacheEntry {
+    response: Cached,
+    stored_at: Instant,
+    used_at: Instant,
+}
+
+/// Upstream responses kept in memory, least recently used first out.
+struct ResponseCache {
+    max_bytes: u64,
+    entry_bytes: u64,
+    ttl: Duration,
+    entries: Mutex<(HashMap<CacheKey, CacheEntry>, u64)>,
+}
+
+impl ResponseCache {
+    fn new(max_bytes: u64, entry_bytes: u64, ttl: Duration) -> Self {
+        Self {
+            max_bytes,
+            entry_bytes,
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Whether a response is small enough and allowed to be shared.
+    fn accepts(&self, status: StatusCode, headers: &HeaderMap, content_length: Option<u64>) -> bool {
+        let shareable = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .all(|directive| !["no-store", "private", "no-cache"].contains(&directive.trim()));
+        let keyed = headers
+            .get_all(header::VARY)
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or("*").split(','))
+            .map(str::trim)
+            .all(|name| {
+                name.eq_ignore_ascii_case(header::ACCEPT_ENCODING.as_str())
+                    || CACHE_KEY_HEADERS.iter().any(|key| key.as_str().eq_ignore_ascii_case(name))
+            });
+        matches!(status, StatusCode::OK | StatusCode::PARTIAL_CONTENT)
+            && shareable
+            && keyed
+            && !headers.contains_key(header::SET_COOKIE)
+            && self.max_bytes > 0
+            && self.ttl > Duration::ZERO
+            && content_length.is_some_and(|length| length <= self.entry_bytes)
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Cached> {
+        let mut entries = self.entries.lock().unwrap();
+        let (map, bytes) = &mut *entries;
+        let entry = map.get_mut(key)?;
+        if entry.stored_at.elapsed() < self.ttl {
+            entry.used_at = Instant::now();
+            return Some(entry.response.clone());
+        }
+        let expired = map.remove(key)?;
+        *bytes -= expired.response.body.len() as u64;
+        None
+    }
+
+    fn insert(&self, key: CacheKey, response: Cached) {
+        let size = response.body.len() as u64;
+        let mut entries = self.entries.lock().unwrap();
+        let (map, bytes) = &mut *entries;
+        if let Some(previous) = map.remove(&key) {
+            *bytes -= previous.response.body.len() as u64;
+        }
+        while *bytes + size > self.max_bytes {
+            let Some(oldest) = map.iter().min_by_key(|(_, entry)| entry.used_at).map(|(key, _)| key.clone()) else {
+                return;
+            };
+            let evicted = map.remove(&oldest).expect("entry exists");
+            *bytes -= evicted.response.body.len() as u64;
+        }
+        *bytes += size;
+        let now = Instant::now();
+        map.insert(
+            key,
+            CacheEntry {
+                response,
+                stored_at: now,
+                used_at: now,
+            },
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{
+    auth::UserInfo,
+    config::{RateLimitGroup, RateLimitsConfig, TokenBucket},
+    metrics::{LimitGroup, LimitScope, Metrics},
+    reload::LiveConfig,
+    ApiError,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tower_sessions::Session;
+use tracing::info;
+
+/// How often buckets that have refilled are forgotten.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Who a request or realtime message comes from, as far as the limits are
+/// concerned. The HTTP middleware adds it to every request's extensions.
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    /// The logged in user's id.
+    pub user: Option<String>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Tops the bucket up for the time since it was last used.
+    fn refill(&mut self, limit: TokenBucket, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    fn is_full(&self, limit: TokenBucket) -> bool {
+        self.tokens >= limit.burst as f64
+    }
+}
+
+type BucketKey = (LimitGroup, LimitScope, String);
+
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    swept: Instant,
+}
+
+/// Token buckets per client for each kind of traffic, following the
+/// `rate_limits` configuration as it is reloaded.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: LiveConfig,
+    metrics: Metrics,
+    state: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: LiveConfig, metrics: Metrics) -> Self {
+        Self {
+            config,
+            metrics,
+            state: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes a token from each of `client`'s buckets in `group`, or returns
+    /// how long until there is one in all of them. Nothing is taken from
+    /// any bucket when one is empty.
+    pub fn check(&self, group: LimitGroup, client: &Client) -> Result<(), Duration> {
+        let current = self.config.current();
+        let limits = &current.config.rate_limits;
+        if !limits.enabled {
+            return Ok(());
+        }
+        let limit = group_config(limits, group);
+        let scopes = [
+            (LimitScope::Ip, limit.per_ip, client.ip.map(|ip| ip.to_string())),
+            (LimitScope::User, limit.per_user, client.user.clone()),
+        ];
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if now.duration_since(state.swept) >= SWEEP_INTERVAL {
+            self.sweep(&mut state, limits, now);
+        }
+        let mut wait = Duration::ZERO;
+        let mut keys = Vec::new();
+        for (scope, bucket_limit, key) in scopes {
+            let (Some(bucket_limit), Some(key)) = (bucket_limit, key) else { continue };
+            let key = (group, scope, key);
+            let bucket = state.buckets.entry(key.clone()).or_insert_with(|| {
+                self.metrics.limit_bucket_added(group, scope);
+                Bucket {
+                    tokens: bucket_limit.burst as f64,
+                    updated: now,
+                }
+            });
+            bucket.refill(bucket_limit, now);
+            if bucket.tokens < 1.0 {
+                let scope_wait = Duration::from_secs_f64((1.0 - bucket.tokens) / bucket_limit.per_sec);
+                wait = wait.max(scope_wait);
+                self.metrics.record_throttled(group, scope);
+            }
+            keys.push(key);
+        }
+        if !wait.is_zero() {
+            info!(?group, "Rate limited for {:?}", wait);
+            return Err(wait);
+        }
+        for key in keys {
+            if let Some(bucket) = state.buckets.get_mut(&key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets buckets that have refilled, or whose limit was removed, since
+    /// a new one would be full anyway.
+    fn sweep(&self, state: &mut Buckets, limits: &RateLimitsConfig, now: Instant) {
+        state.buckets.retain(|(group, scope, _), bucket| {
+            let limit = group_config(limits, *group);
+            let limit = match scope {
+                LimitScope::Ip => limit.per_ip,
+                LimitScope::User => limit.per_user,
+            };
+            let Some(limit) = limit else { return false };
+            bucket.refill(limit, now);
+            !bucket.is_full(limit)
+        });
+        state.swept = now;
+        for group in [LimitGroup::Auth, LimitGroup::Api, LimitGroup::Realtime] {
+            for scope in [LimitScope::Ip, LimitScope::User] {
+                let count = state
+                    .buckets
+                    .keys()
+                    .filter(|(bucket_group, bucket_scope, _)| *bucket_group == group && *bucket_scope == scope)
+                    .count();
+                self.metrics.set_limit_buckets(group, scope, count);
+            }
+        }
+    }
+}
+
+/// Who sent `request`: the connection's address, or the first
+/// `X-Forwarded-For` one when trusted, and the session's user. Takes the
+/// request mutably only so the future is `Send`; bodies aren't `Sync`.
+async fn client(request: &mut Request, trust_forwarded_for: bool) -> Client {
+    let forwarded = trust_forwarded_for
+        .then(|| request.headers().get("x-forwarded-for"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok());
+    let ip = forwarded.or_else(|| {
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    });
+    let user = match request.extensions().get::<Session>() {
+        Some(session) => session.get::<UserInfo>("user").await.ok().flatten().map(|user| user.id),
+        None => None,
+    };
+    Client { ip, user }
+}
+
+fn group_config(limits: &RateLimitsConfig, group: LimitGroup) -> &RateLimitGroup {
+    match group {
+        LimitGroup::Auth => &limits.auth,
+        LimitGroup::Api => &limits.api,
+        LimitGroup::Realtime => &limits.realtime,
+    }
+}
+
+/// Which limits `request` counts against, if any. Assets, health checks and
+/// realtime upgrades aren't limited; realtime messages are, as they arrive.
+fn request_group(request: &Request) -> Option<LimitGroup> {
+    let path = request.uri().path();
+    if path.starts_with("/api/auth/") && request.method() == Method::POST {
+        Some(LimitGroup::Auth)
+    } else if path.starts_with("/api/") {
+        Some(LimitGroup::Api)
+    } else {
+        None
+    }
+}
+
+/// The 429 answer for a request over a limit.
+pub(crate) fn too_many_requests(retry_after: Duration) -> Response {
+    let mut response = ApiError::response(StatusCode::TOO_MANY_REQUESTS, "Too many requests, try again later");
+    let seconds = (retry_after.as_secs_f64().ceil() as u64).max(1);
+    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+/// Refuses `/api` requests over the `rate_limits` with 429 and a
+/// `Retry-After`, and records the `Client` for the realtime handlers.
+pub async fn limit_requests(State(limiter): State<RateLimiter>, mut request: Request, next: Next) -> Response {
+    let current = limiter.config.current();
+    let limits = &current.config.rate_limits;
+    let group = request_group(&request);
+    let path = request.uri().path();
+    let realtime = path == "/ws" || path.starts_with(crate::sse::PATH);
+    if !limits.enabled || (group.is_none() && !realtime) {
+        return next.run(request).await;
+    }
+    let client = client(&mut request, limits.trust_forwarded_for).await;
+    if let Some(group) = group
+        && let Err(wait) = limiter.check(group, &client)
+    {
+        return too_many_requests(wait);
+    }
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
@@ -0,0 +1,57 @@
+use crate::{
+    metrics::LimitGroup,
+    rate_limit::{Client, RateLimiter},
+};
+use protocol::{Envelope, Message};
+
+/// The answer to `text`, received on any transport: `answer` applied to the
+/// envelope in it, or an `Error` when it isn't one.
+pub fn answer(text: &str, answer: impl FnOnce(&Envelope) -> Envelope) -> Envelope {
+    match Envelope::decode(text) {
+        Ok(request) => answer(&request),
+        Err(e) => error(&e.to_string()),
+    }
+}
+
+/// An `Error` that answers no message in particular.
+pub fn error(message: &str) -> Envelope {
+    Envelope::new(Message::Error {
+        message: message.to_string(),
+    })
+}
+
+/// What every transport answers with: text is echoed back, and anything
+/// else refused, since clients only send text for now.
+pub fn echo(request: &Envelope) -> Envelope {
+    match &request.message {
+        Message::Text(text) => Envelope::reply(request, Message::Text(text.clone())),
+        _ => Envelope::reply(
+            request,
+            Message::Error {
+                message: "Clients can only send Text".to_string(),
+            },
+        ),
+    }
+}
+
+/// The answer to `text` from `client`: `answer` applied to the envelope in
+/// it, unless it isn't one or is over `rate_limits.realtime`, in which case
+/// it is dropped and the client told how long to wait.
+pub fn receive(
+    limiter: &RateLimiter,
+    client: &Client,
+    text: &str,
+    answer: impl FnOnce(&Envelope) -> Envelope,
+) -> Envelope {
+    match limiter.check(LimitGroup::Realtime, client) {
+        Ok(()) => self::answer(text, answer),
+        Err(wait) => self::answer(text, |request| {
+            Envelope::reply(
+                request,
+                Message::Throttled {
+                    retry_after_ms: wait.as_millis() as u64,
+                },
+            )
+        }),
+    }
+}
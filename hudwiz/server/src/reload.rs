@@ -0,0 +1,173 @@
+use crate::{
+    config::{load_config_from, Change, Config},
+    file_watch::watch_files,
+    sso::SsoProvider,
+    telemetry, SsoAuth, SsoProviders,
+};
+use anyhow::{bail, Result};
+use arc_swap::ArcSwap;
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+/// How often the configuration file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings that take effect without a restart. Changes anywhere else are
+/// reported and left for the next start.
+const RELOADABLE: &[&str] = &[
+    "logging.level",
+    "logging.redact_usernames",
+    "auth.signup",
+    "auth.sso_providers",
+    "layouts",
+    "proxy.upstreams",
+    "rate_limits",
+];
+
+/// One applied configuration, together with the state built from it.
+pub struct ActiveConfig {
+    pub config: Config,
+    pub sso: SsoAuth,
+    pub version: ConfigVersion,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ConfigVersion {
+    /// Starts at 1 and increases with every applied reload.
+    pub version: u64,
+    /// Unix timestamp of when this version was applied.
+    pub applied_at: u64,
+    /// Hex SHA-256 of the configuration, secrets included, so instances can
+    /// be compared without exposing it.
+    pub digest: String,
+}
+
+/// The configuration currently in effect. Readers get a consistent snapshot;
+/// reloads replace it atomically.
+#[derive(Clone)]
+pub struct LiveConfig {
+    active: Arc<ArcSwap<ActiveConfig>>,
+    /// SSO providers added in code rather than configured, which reloads keep.
+    /// Also serializes changes to `active`.
+    extra_sso: Arc<Mutex<SsoProviders>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Result<Self> {
+        let extra_sso = SsoProviders::new();
+        let active = activate(config, 1, &extra_sso)?;
+        Ok(Self {
+            active: Arc::new(ArcSwap::from_pointee(active)),
+            extra_sso: Arc::new(Mutex::new(extra_sso)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<ActiveConfig> {
+        self.active.load_full()
+    }
+
+    /// Makes `provider` available as `name`, replacing a configured provider
+    /// of the same name. The configuration version doesn't change.
+    pub fn add_sso_provider(&self, name: &str, provider: Arc<dyn SsoProvider + Send + Sync>) -> Result<()> {
+        let mut extra_sso = self.extra_sso.lock().unwrap();
+        extra_sso.insert(name.to_string(), provider);
+        let current = self.current();
+        self.active.store(Arc::new(ActiveConfig {
+            config: current.config.clone(),
+            sso: SsoAuth::new(&current.config.auth, &extra_sso)?,
+            version: current.version.clone(),
+        }));
+        Ok(())
+    }
+
+    /// Re-reads `path` and applies the reloadable changes. Returns the new
+    /// version, or `None` when nothing reloadable changed. Invalid files are
+    /// rejected with the offending diff and leave the active configuration
+    /// untouched.
+    pub fn reload_from(&self, path: &Path) -> Result<Option<u64>> {
+        let extra_sso = self.extra_sso.lock().unwrap();
+        let current = self.current();
+        let edited = load_config_from(path)?;
+        let changes = current.config.diff(&edited);
+        if changes.is_empty() {
+            return Ok(None);
+        }
+
+        let problems = edited.validate();
+        if !problems.is_empty() {
+            bail!("{}\n{}", problems.join("\n"), format_changes(&changes));
+        }
+
+        let (applied, ignored): (Vec<_>, Vec<_>) = changes.into_iter().partition(|change| is_reloadable(&change.key));
+        if !ignored.is_empty() {
+            warn!("Configuration changes that need a restart:\n{}", format_changes(&ignored));
+        }
+        if applied.is_empty() {
+            return Ok(None);
+        }
+
+        let mut config = current.config.clone();
+        config.logging.level = edited.logging.level;
+        config.logging.redact_usernames = edited.logging.redact_usernames;
+        config.auth.signup = edited.auth.signup;
+        config.auth.sso_providers = edited.auth.sso_providers;
+        config.layouts = edited.layouts;
+        config.proxy.upstreams = edited.proxy.upstreams;
+        config.rate_limits = edited.rate_limits;
+
+        let next = activate(config, current.version.version + 1, &extra_sso)
+            .map_err(|e| e.context(format_changes(&applied)))?;
+        telemetry::set_level(&next.config.logging.level)?;
+        telemetry::set_redact_usernames(next.config.logging.redact_usernames);
+        let version = next.version.version;
+        self.active.store(Arc::new(next));
+        info!("Applied configuration version {}:\n{}", version, format_changes(&applied));
+        Ok(Some(version))
+    }
+}
+
+/// Watches the configuration file at `path` and reloads it on every change.
+pub fn spawn_config_reloader(path: PathBuf, live: LiveConfig) {
+    let mut changes = watch_files(vec![path.clone()], POLL_INTERVAL);
+    tokio::spawn(async move {
+        while changes.changed().await.is_ok() {
+            if let Err(e) = live.reload_from(&path) {
+                error!("Rejected configuration change in {}: {:#}", path.display(), e);
+            }
+        }
+    });
+}
+
+fn activate(config: Config, version: u64, extra_sso: &SsoProviders) -> Result<ActiveConfig> {
+    let sso = SsoAuth::new(&config.auth, extra_sso)?;
+    let applied_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let version = ConfigVersion {
+        version,
+        applied_at,
+        digest: config.digest(),
+    };
+    Ok(ActiveConfig { config, sso, version })
+}
+
+fn is_reloadable(key: &str) -> bool {
+    RELOADABLE
+        .iter()
+        .any(|prefix| key == *prefix || key.starts_with(&format!("{}.", prefix)))
+}
+
+fn format_changes(changes: &[Change]) -> String {
+    changes
+        .iter()
+        .map(|change| format!("  {}", change))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,159 @@
+use crate::{config::RealtimeConfig, hub::Push, rate_limit::Client};
+use protocol::{Envelope, Message};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::info;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+/// Where a reconnecting client left off.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct ResumeQuery {
+    /// From the `Session` message of the connection being replaced.
+    pub session: Option<String>,
+    /// The `seq` of the last message received on it, or 0 for none.
+    pub last_seq: Option<u64>,
+}
+
+/// What the server has sent one realtime session, kept so a client that
+/// reconnects can be sent what it missed.
+struct Stream {
+    user: Option<String>,
+    next_seq: u64,
+    sent: VecDeque<Envelope>,
+    /// Counts the connections that attached, so only the latest one
+    /// detaches the stream.
+    connection: u64,
+    detached_at: Option<Instant>,
+}
+
+impl Stream {
+    /// The messages after `last_seq`, or `None` when some of them are no
+    /// longer kept.
+    fn after(&self, last_seq: u64) -> Option<Vec<Envelope>> {
+        let first_kept = self.next_seq - self.sent.len() as u64;
+        if last_seq + 1 < first_kept || last_seq >= self.next_seq {
+            return None;
+        }
+        let skip = (last_seq + 1 - first_kept) as usize;
+        Some(self.sent.iter().skip(skip).cloned().collect())
+    }
+}
+
+/// Realtime sessions, which outlive their connections by
+/// `realtime.resume_secs` and number what they are sent.
+#[derive(Clone)]
+pub struct ReplayStore {
+    config: RealtimeConfig,
+    streams: Arc<Mutex<HashMap<String, Stream>>>,
+}
+
+impl ReplayStore {
+    pub fn new(config: RealtimeConfig) -> Self {
+        Self {
+            config,
+            streams: Arc::default(),
+        }
+    }
+
+    /// Attaches a new connection from `client`, resuming the session `query`
+    /// names when it belongs to the same user and still has every message
+    /// after `last_seq`. Returns the session and what to send first: the
+    /// `Session` message, then any replayed ones.
+    pub fn attach(&self, query: &ResumeQuery, client: &Client) -> (ReplaySession, Vec<Envelope>) {
+        let resume_window = Duration::from_secs(self.config.resume_secs);
+        let now = Instant::now();
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|_, stream| stream.detached_at.is_none_or(|detached_at| now - detached_at < resume_window));
+
+        let resumed = query.session.as_ref().and_then(|session_id| {
+            let stream = streams.get_mut(session_id)?;
+            if stream.user != client.user {
+                return None;
+            }
+            let replay = stream.after(query.last_seq.unwrap_or_default())?;
+            stream.connection += 1;
+            stream.detached_at = None;
+            Some((session_id.clone(), stream.connection, replay))
+        });
+        let (session_id, connection, replay, resumed) = match resumed {
+            Some((session_id, connection, replay)) => {
+                info!(session = %session_id, replayed = replay.len(), "Realtime session resumed");
+                (session_id, connection, replay, true)
+            }
+            None => {
+                let session_id = Uuid::new_v4().to_string();
+                let stream = Stream {
+                    user: client.user.clone(),
+                    next_seq: 1,
+                    sent: VecDeque::new(),
+                    connection: 0,
+                    detached_at: None,
+                };
+                streams.insert(session_id.clone(), stream);
+                (session_id, 0, Vec::new(), false)
+            }
+        };
+
+        let hello = Envelope::new(Message::Session {
+            session_id: session_id.clone(),
+            resumed,
+        });
+        let session = ReplaySession {
+            id: session_id,
+            connection,
+            store: self.clone(),
+        };
+        (session, std::iter::once(hello).chain(replay).collect())
+    }
+}
+
+/// One connection's hold on a realtime session. Dropping it starts the
+/// time the client has to resume.
+pub struct ReplaySession {
+    id: String,
+    connection: u64,
+    store: ReplayStore,
+}
+
+impl ReplaySession {
+    /// Numbers `envelope` as the session's next message and keeps it for
+    /// replay.
+    pub fn sequence(&self, mut envelope: Envelope) -> Envelope {
+        let mut streams = self.store.streams.lock().unwrap();
+        let Some(stream) = streams.get_mut(&self.id) else {
+            return envelope;
+        };
+        envelope.seq = Some(stream.next_seq);
+        stream.next_seq += 1;
+        stream.sent.push_back(envelope.clone());
+        while stream.sent.len() > self.store.config.replay_len {
+            stream.sent.pop_front();
+        }
+        envelope
+    }
+
+    /// `push`, sequenced unless it is telemetry, which isn't worth replaying.
+    pub fn push(&self, push: Push) -> Envelope {
+        if push.telemetry {
+            push.envelope
+        } else {
+            self.sequence(push.envelope)
+        }
+    }
+}
+
+impl Drop for ReplaySession {
+    fn drop(&mut self) {
+        let mut streams = self.store.streams.lock().unwrap();
+        if let Some(stream) = streams.get_mut(&self.id)
+            && stream.connection == self.connection
+        {
+            stream.detached_at = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{
+    assets,
+    config::{Config, CorsConfig},
+};
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Policy for every response that isn't an HTML page. JSON, assets and error
+/// bodies never need to load anything or be framed.
+pub const NON_DOCUMENT_CSP: &str = "default-src 'none'; frame-ancestors 'none'";
+
+/// A `Content-Security-Policy` value, built up one directive at a time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContentSecurityPolicy {
+    directives: Vec<(String, Vec<String>)>,
+}
+
+impl ContentSecurityPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `sources` to `directive`, which is created on first use. Sources
+    /// already present are skipped.
+    pub fn allow<I>(mut self, directive: &str, sources: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let index = match self.directives.iter().position(|(name, _)| name == directive) {
+            Some(index) => index,
+            None => {
+                self.directives.push((directive.to_string(), Vec::new()));
+                self.directives.len() - 1
+            }
+        };
+        let existing = &mut self.directives[index].1;
+        for source in sources {
+            let source = source.into();
+            if !existing.contains(&source) {
+                existing.push(source);
+            }
+        }
+        self
+    }
+
+    pub fn sources(&self, directive: &str) -> Option<&[String]> {
+        self.directives
+            .iter()
+            .find(|(name, _)| name == directive)
+            .map(|(_, sources)| sources.as_slice())
+    }
+
+    /// The policy for the UI: same-origin by default, plus the configured
+    /// content sources and the realtime endpoints. `index_html` is the page
+    /// being served; its inline scripts are allowed by hash.
+    pub fn for_app(config: &Config, index_html: &str) -> Self {
+        let sources = &config.security.content_sources;
+        Self::new()
+            .allow("default-src", ["'self'"])
+            .allow("script-src", ["'self'", "'wasm-unsafe-eval'"])
+            .allow("script-src", inline_script_hashes(index_html))
+            // Components set `style` attributes, which CSP counts as inline styles.
+            .allow("style-src", ["'self'", "'unsafe-inline'"])
+            .allow("style-src", sources.style.iter().cloned())
+            .allow("font-src", ["'self'"])
+            .allow("font-src", sources.font.iter().cloned())
+            .allow("img-src", ["'self'", "data:", "blob:"])
+            .allow("img-src", sources.image.iter().cloned())
+            .allow("media-src", ["'self'", "blob:"])
+            .allow("media-src", sources.media.iter().cloned())
+            // Content rewritten through the proxy is framed from this origin.
+            // Allowed even without `proxy.upstreams`, which can be added by
+            // a reload while this policy can't change.
+            .allow("frame-src", ["'self'"])
+            .allow("frame-src", sources.frame.iter().cloned())
+            .allow("connect-src", ["'self'"])
+            .allow("connect-src", realtime_origins(config))
+            .allow("connect-src", sources.connect.iter().cloned())
+            .allow("object-src", ["'none'"])
+            .allow("base-uri", ["'self'"])
+            .allow("form-action", ["'self'"])
+            .allow("frame-ancestors", ["'self'"])
+    }
+}
+
+impl fmt::Display for ContentSecurityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, sources)) in self.directives.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            f.write_str(name)?;
+            for source in sources {
+                write!(f, " {}", source)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The WebSocket origin matching `server.public_url` and the WebTransport
+/// origin. Not every browser treats `'self'` as covering either.
+fn realtime_origins(config: &Config) -> Vec<String> {
+    let mut origins = Vec::new();
+    if let Ok(mut url) = url::Url::parse(&config.server.public_url) {
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        if url.set_scheme(scheme).is_ok() {
+            origins.push(url.origin().ascii_serialization());
+        }
+    }
+    if let Ok(url) = url::Url::parse(&config.server.webtransport_url()) {
+        origins.push(url.origin().ascii_serialization());
+    }
+    origins
+}
+
+/// `'sha256-…'` sources for the inline `<script>` elements of `html`, such
+/// as the one that loads the wasm bundle.
+fn inline_script_hashes(html: &str) -> Vec<String> {
+    let mut hashes = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("<script") {
+        let element = &rest[start..];
+        let Some(tag_end) = element.find('>') else { break };
+        let Some(body_len) = element[tag_end + 1..].find("</script>") else { break };
+        let body = &element[tag_end + 1..tag_end + 1 + body_len];
+        if !element[..tag_end].contains("src=") && !body.trim().is_empty() {
+            hashes.push(format!("'sha256-{}'", STANDARD.encode(Sha256::digest(body.as_bytes()))));
+        }
+        rest = &element[tag_end + 1 + body_len..];
+    }
+    hashes
+}
+
+/// Header values added to every response by `add_security_headers`.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    document_csp: HeaderValue,
+    referrer_policy: HeaderValue,
+    hsts: Option<HeaderValue>,
+}
+
+impl SecurityHeaders {
+    /// Builds the headers for `config`. The SPA fallback page is read once
+    /// here to hash its inline scripts, so a rebuilt frontend whose loader
+    /// script changed needs a restart.
+    pub fn new(config: &Config) -> Result<Self> {
+        let index_html = assets::spa_fallback_html(&config.server);
+        let csp = ContentSecurityPolicy::for_app(config, &index_html);
+        let security = &config.security;
+        let serves_https = config.tls.enabled || config.server.public_url.starts_with("https://");
+        let hsts = (serves_https && security.hsts_max_age_secs > 0)
+            .then(|| HeaderValue::from_str(&format!("max-age={}", security.hsts_max_age_secs)))
+            .transpose()?;
+        Ok(Self {
+            document_csp: HeaderValue::try_from(csp.to_string()).context("Invalid content source")?,
+            referrer_policy: HeaderValue::try_from(security.referrer_policy.as_str())
+                .context("Invalid security.referrer_policy")?,
+            hsts,
+        })
+    }
+}
+
+/// Middleware adding `Content-Security-Policy`, `X-Content-Type-Options`,
+/// `Referrer-Policy` and, over HTTPS, `Strict-Transport-Security`. HTML
+/// pages get the UI's policy and everything else `NON_DOCUMENT_CSP`.
+/// Headers a handler already set are left alone.
+pub async fn add_security_headers(
+    State(headers): State<SecurityHeaders>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let is_document = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    let csp = if is_document {
+        headers.document_csp
+    } else {
+        HeaderValue::from_static(NON_DOCUMENT_CSP)
+    };
+
+    let response_headers = response.headers_mut();
+    response_headers.entry(header::CONTENT_SECURITY_POLICY).or_insert(csp);
+    response_headers
+        .entry(header::X_CONTENT_TYPE_OPTIONS)
+        .or_insert(HeaderValue::from_static("nosniff"));
+    response_headers.entry(header::REFERRER_POLICY).or_insert(headers.referrer_policy);
+    if let Some(hsts) = headers.hsts {
+        response_headers.entry(header::STRICT_TRANSPORT_SECURITY).or_insert(hsts);
+    }
+    response
+}
+
+/// CORS for the API, or `None` when no other origin may call it.
+pub fn cors_layer(config: &CorsConfig) -> Option<CorsLayer> {
+    if config.allowed_origins.is_empty() {
+        return None;
+    }
+    let origins = if config.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            // Conditional writes send `If-Match` and read back the `ETag`.
+            .allow_headers([header::CONTENT_TYPE, header::IF_MATCH, header::IF_NONE_MATCH])
+            .expose_headers([header::ETAG])
+            .allow_credentials(config.allow_credentials),
+    )
+}
@@ -0,0 +1,186 @@
+use crate::{
+    app_router_with_config, assets,
+    config::Config,
+    file_watch::watch_files,
+    ops,
+    reload::spawn_config_reloader,
+    sessions,
+    shutdown::{self, Shutdown},
+    tls,
+    webtransport::start_webtransport_server,
+    Services,
+};
+use anyhow::{bail, Context, Result};
+use std::{future::Future, net::SocketAddr, path::PathBuf, pin::Pin, time::Duration};
+use tracing::{error, info};
+
+type ServerFuture = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>;
+
+/// Runs the HTTP(S) and WebTransport servers until SIGINT or SIGTERM, then
+/// drains open connections and flushes the stores. Returns an error if the
+/// server failed, connections were still open after the drain timeout, or
+/// the stores couldn't be flushed. Edits to `config_path` are applied while
+/// running where possible.
+pub async fn serve(config: Config, config_path: PathBuf) -> Result<()> {
+    let addr = config.server.http_addr;
+    let drain_timeout = Duration::from_secs(config.server.drain_timeout_secs);
+    let tls_config = config.tls.clone();
+
+    tls::install_crypto_provider();
+    tls::ensure_certificate(&tls_config)?;
+    tls::spawn_dev_cert_renewal(tls_config.clone());
+    let cert_changes = watch_files(
+        vec![tls_config.cert_path.clone(), tls_config.key_path.clone()],
+        Duration::from_secs(tls_config.reload_interval_secs.max(1)),
+    );
+
+    let services = Services::open(&config)?;
+    services.sessions.spawn_persistence(sessions::PERSIST_INTERVAL);
+    spawn_config_reloader(config_path, services.config.clone());
+    let shutdown = services.shutdown.clone();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            let signal = shutdown::signal().await;
+            info!("Received {}, shutting down", signal);
+            shutdown.trigger();
+        }
+    });
+
+    let webtransport_server = config.server.clone();
+    let webtransport_tls = tls_config.clone();
+    let webtransport_cert_changes = cert_changes.clone();
+    let webtransport_services = services.clone();
+    tokio::spawn(shutdown.track(async move {
+        if let Err(e) = start_webtransport_server(
+            webtransport_server,
+            webtransport_tls,
+            webtransport_cert_changes,
+            webtransport_services,
+        )
+        .await
+        {
+            error!("Failed to start WebTransport server: {:#}", e);
+        }
+    }));
+
+    info!("Server starting...");
+    info!("Serving static assets from {}", assets::source(&config.server));
+    let public_url = config.server.public_url.clone();
+    if let Some(admin_addr) = config.server.admin_addr {
+        let listener = tokio::net::TcpListener::bind(admin_addr)
+            .await
+            .with_context(|| format!("Failed to bind admin address {}", admin_addr))?;
+        let admin: axum::Router = ops::router(services.clone(), true).into();
+        let shutdown = shutdown.clone();
+        info!("Serving health checks and metrics on http://{}", admin_addr);
+        tokio::spawn(async move {
+            let stopped = async move { shutdown.triggered().await };
+            if let Err(e) = axum::serve(listener, admin).with_graceful_shutdown(stopped).await {
+                error!("Admin server failed: {}", e);
+            }
+        });
+    }
+    let app = app_router_with_config(config, services.clone())?;
+
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            bail!("Address {} is already in use. Is another instance of the server running?", addr);
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to bind to address {}", addr)),
+    };
+
+    let server: ServerFuture = if !tls_config.enabled {
+        info!("listening on http://{} (public URL: {})", addr, public_url);
+        let shutdown = shutdown.clone();
+        Box::pin(
+            // Connection addresses are what `rate_limits` counts clients by.
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(async move { shutdown.triggered().await })
+                .into_future(),
+        )
+    } else {
+        let rustls_config = tls::rustls_config(&tls_config).await?;
+        tls::spawn_https_reloader(tls_config.clone(), rustls_config.clone(), cert_changes);
+
+        if let Some(redirect_addr) = tls_config.redirect_addr {
+            let redirect = tls::https_redirect_router(&public_url);
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                match tokio::net::TcpListener::bind(redirect_addr).await {
+                    Ok(listener) => {
+                        info!("Redirecting http://{} to {}", redirect_addr, public_url);
+                        let stopped = async move { shutdown.triggered().await };
+                        if let Err(e) = axum::serve(listener, redirect).with_graceful_shutdown(stopped).await {
+                            error!("HTTP redirect server failed: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to bind HTTP redirect address {}: {}", redirect_addr, e),
+                }
+            });
+        }
+
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown.triggered().await;
+                handle.graceful_shutdown(None);
+            }
+        });
+
+        info!("listening on https://{}", addr);
+        Box::pin(
+            axum_server::from_tcp_rustls(listener.into_std()?, rustls_config)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>()),
+        )
+    };
+
+    let served = drain(server, &shutdown, drain_timeout).await;
+    info!("Flushing session and audit stores");
+    let flushed = services.flush().context("Failed to flush stores");
+    if let (Err(_), Err(e)) = (&served, &flushed) {
+        error!("{:#}", e);
+    }
+    served?;
+    flushed?;
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// Waits for shutdown (or the HTTP server failing), then gives in-flight
+/// requests and realtime connections `timeout` to finish.
+async fn drain(mut server: ServerFuture, shutdown: &Shutdown, timeout: Duration) -> Result<()> {
+    let stopped_early = tokio::select! {
+        result = &mut server => Some(result),
+        () = shutdown.triggered() => None,
+    };
+    shutdown.trigger();
+
+    info!(
+        "Draining {} realtime connection(s) for up to {}s",
+        shutdown.open_connections(),
+        timeout.as_secs()
+    );
+    let drained = tokio::time::timeout(timeout, async {
+        let result = match stopped_early {
+            Some(result) => result,
+            None => server.await,
+        };
+        shutdown.connections_closed().await;
+        result
+    })
+    .await;
+
+    match drained {
+        Ok(result) => result.context("HTTP server failed"),
+        Err(_) => bail!(
+            "Drain timeout of {}s expired with {} realtime connection(s) still open",
+            timeout.as_secs(),
+            shutdown.open_connections()
+        ),
+    }
+}
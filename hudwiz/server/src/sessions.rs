@@ -0,0 +1,164 @@
+use crate::storage::{self, SESSIONS_FILE};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use time::OffsetDateTime;
+use tower_sessions::{
+    session::{Id, Record},
+    session_store, SessionStore,
+};
+use tracing::{debug, error};
+
+/// How often expired sessions are dropped and changes written to disk.
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Login sessions, held in memory and written to the data directory so they
+/// survive a restart: every `PERSIST_INTERVAL` once `spawn_persistence` runs,
+/// and by `flush` at shutdown. Without a data directory nothing is persisted.
+#[derive(Debug, Clone)]
+pub struct FileSessionStore {
+    sessions: Arc<Mutex<Sessions>>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    records: HashMap<Id, Record>,
+    /// Changed since they were last written.
+    changed: bool,
+}
+
+impl FileSessionStore {
+    pub fn in_memory() -> Self {
+        Self {
+            sessions: Arc::default(),
+            path: None,
+        }
+    }
+
+    /// Loads the sessions saved in `data_dir`, dropping expired ones.
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(SESSIONS_FILE);
+        let now = OffsetDateTime::now_utc();
+        let records = storage::read_json::<Vec<Record>>(&path)?
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|record| record.expiry_date > now)
+            .map(|record| (record.id, record))
+            .collect();
+        Ok(Self {
+            sessions: Arc::new(Mutex::new(Sessions { records, changed: false })),
+            path: Some(path),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops expired sessions, returning how many there were.
+    pub fn sweep(&self) -> usize {
+        let now = OffsetDateTime::now_utc();
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.records.len();
+        sessions.records.retain(|_, record| record.expiry_date > now);
+        let removed = before - sessions.records.len();
+        sessions.changed |= removed > 0;
+        removed
+    }
+
+    /// Writes the unexpired sessions to the data directory.
+    pub fn flush(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let now = OffsetDateTime::now_utc();
+        let mut records: Vec<Record> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.changed = false;
+            sessions.records.values().filter(|record| record.expiry_date > now).cloned().collect()
+        };
+        records.sort_by_key(|record| record.expiry_date);
+        let written = storage::write_json(path, &records);
+        if written.is_err() {
+            self.sessions.lock().unwrap().changed = true;
+        }
+        written
+    }
+
+    /// Sweeps every `interval` and writes the sessions to disk when they
+    /// changed, so a crash loses at most that much.
+    pub fn spawn_persistence(&self, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let swept = store.sweep();
+                if swept > 0 {
+                    debug!("Dropped {} expired session(s)", swept);
+                }
+                if store.path.is_none() || !store.sessions.lock().unwrap().changed {
+                    continue;
+                }
+                let flushing = store.clone();
+                match storage::blocking(move || flushing.flush()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) | Err(e) => error!("Failed to write sessions: {:#}", e),
+                }
+            }
+        });
+    }
+
+    /// Applies `change` to the sessions and marks them for writing.
+    fn change<R>(&self, change: impl FnOnce(&mut HashMap<Id, Record>) -> R) -> R {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.changed = true;
+        change(&mut sessions.records)
+    }
+}
+
+#[async_trait]
+impl SessionStore for FileSessionStore {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        self.change(|sessions| {
+            while sessions.contains_key(&record.id) {
+                record.id = Id::default();
+            }
+            sessions.insert(record.id, record.clone());
+        });
+        Ok(())
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        self.change(|sessions| sessions.insert(record.id, record.clone()));
+        Ok(())
+    }
+
+    async fn load(&self, id: &Id) -> session_store::Result<Option<Record>> {
+        let now = OffsetDateTime::now_utc();
+        Ok(self
+            .sessions
+            .lock()
+            .unwrap()
+            .records
+            .get(id)
+            .filter(|record| record.expiry_date > now)
+            .cloned())
+    }
+
+    async fn delete(&self, id: &Id) -> session_store::Result<()> {
+        self.change(|sessions| sessions.remove(id));
+        Ok(())
+    }
+}
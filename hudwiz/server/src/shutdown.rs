@@ -0,0 +1,65 @@
+use std::future::Future;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// Coordinates graceful shutdown. Long-lived connections watch for the
+/// trigger to say goodbye to their clients and are tracked so the server can
+/// wait for them before exiting.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    connections: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Wraps a connection's future so `connections_closed` waits for it.
+    pub fn track<F: Future>(&self, future: F) -> impl Future<Output = F::Output> + use<F> {
+        self.connections.track_future(future)
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Resolves once every tracked connection has finished.
+    pub async fn connections_closed(&self) {
+        self.connections.close();
+        self.connections.wait().await
+    }
+}
+
+/// Resolves with the signal's name once SIGINT or SIGTERM arrives.
+pub async fn signal() -> &'static str {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install the SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install the SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => "SIGINT",
+        () = terminate => "SIGTERM",
+    }
+}
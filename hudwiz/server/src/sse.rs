@@ -0,0 +1,227 @@
+use crate::{
+    auth::UserInfo,
+    hub::{Hub, HubConnection, TopicsQuery},
+    metrics::{Direction, LimitGroup, Metrics, Transport},
+    rate_limit::{self, Client, RateLimiter},
+    realtime,
+    replay::{ReplaySession, ReplayStore, ResumeQuery},
+    shutdown::Shutdown,
+    ApiError,
+};
+use axum::{
+    extract::{rejection::QueryRejection, Path, Query, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension,
+};
+use futures::{stream, StreamExt};
+use protocol::Envelope;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tower_sessions::Session;
+use tracing::{info, info_span, Instrument};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+/// Where the event stream is served, published on `/api/realtime/info`.
+pub const PATH: &str = "/sse";
+
+/// Messages queued per direction before senders wait for the other side.
+const QUEUE_LEN: usize = 64;
+
+/// Comment lines sent on an idle stream so proxies don't time it out.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// The first event on a stream, named `session`.
+#[derive(Serialize, ToSchema)]
+pub struct SseSession {
+    /// Where to `POST` messages for this stream, under `/sse/`.
+    #[schema(value_type = String)]
+    pub session_id: Uuid,
+}
+
+/// Open event streams, by the session id their clients post to.
+#[derive(Clone)]
+pub struct SseState {
+    metrics: Metrics,
+    shutdown: Shutdown,
+    rate_limiter: RateLimiter,
+    replay: ReplayStore,
+    hub: Hub,
+    sessions: Arc<Mutex<HashMap<Uuid, mpsc::Sender<String>>>>,
+}
+
+impl SseState {
+    pub fn new(
+        metrics: Metrics,
+        shutdown: Shutdown,
+        rate_limiter: RateLimiter,
+        replay: ReplayStore,
+        hub: Hub,
+    ) -> Self {
+        Self {
+            metrics,
+            shutdown,
+            rate_limiter,
+            replay,
+            hub,
+            sessions: Arc::default(),
+        }
+    }
+}
+
+/// `/sse` and `/sse/{session_id}`: the realtime channel as Server-Sent
+/// Events for networks that block WebSocket and WebTransport, with the same
+/// messages as `/ws`.
+pub fn router<S: Clone + Send + Sync + 'static>(state: SseState) -> OpenApiRouter<S> {
+    OpenApiRouter::new()
+        .routes(routes!(events))
+        .routes(routes!(send))
+        .with_state(state)
+}
+
+/// Opens an event stream, which echoes posted text back and pushes the
+/// topics subscribed to. The first event, `session`, carries the id to post with; every later one is a
+/// message, starting with the realtime `Session`.
+#[utoipa::path(
+    get,
+    path = "/sse",
+    tag = "realtime",
+    params(TopicsQuery, ResumeQuery),
+    responses(
+        (status = OK, description = "The event stream", content_type = "text/event-stream", body = SseSession),
+        (status = BAD_REQUEST, description = "Unknown topic", body = ApiError),
+        (status = UNAUTHORIZED, description = "A topic needs a session", body = ApiError),
+        (status = FORBIDDEN, description = "A topic isn't allowed for the user", body = ApiError),
+    )
+)]
+async fn events(
+    State(state): State<SseState>,
+    Query(query): Query<TopicsQuery>,
+    resume: Result<Query<ResumeQuery>, QueryRejection>,
+    session: Session,
+    client: Option<Extension<Client>>,
+) -> Response {
+    info!("SSE stream requested");
+    let user = session.get::<UserInfo>("user").await.ok().flatten();
+    let topics = state.hub.connect(user);
+    if let Err(e) = topics.subscribe_query(&query) {
+        return ApiError::response(e.status(), &e.to_string());
+    }
+    let session_id = Uuid::new_v4();
+    let (inbound, inbound_rx) = mpsc::channel(QUEUE_LEN);
+    let (outbound, outbound_rx) = mpsc::channel(QUEUE_LEN);
+    state.sessions.lock().unwrap().insert(session_id, inbound);
+
+    // The connection outlives the request, but keeps its span as parent so
+    // its events carry the request id.
+    let span = info_span!("Connection", transport = "sse");
+    let shutdown = state.shutdown.clone();
+    // A resume that doesn't parse starts a new session, as one that has expired does.
+    let resume = resume.map(|Query(resume)| resume).unwrap_or_default();
+    let client = client.map(|Extension(client)| client).unwrap_or_default();
+    let (replay, greeting) = state.replay.attach(&resume, &client);
+    let connection = run(state, session_id, inbound_rx, outbound, topics, replay, greeting);
+    tokio::spawn(shutdown.track(connection.instrument(span)));
+
+    let first = Event::default()
+        .event("session")
+        .json_data(SseSession { session_id })
+        .expect("session serializes");
+    let messages = stream::unfold(outbound_rx, |mut outbound_rx| async move {
+        let event = outbound_rx.recv().await?;
+        Some((Ok::<_, Infallible>(event), outbound_rx))
+    });
+    Sse::new(stream::once(async { Ok(first) }).chain(messages))
+        .keep_alive(KeepAlive::new().interval(KEEP_ALIVE))
+        .into_response()
+}
+
+/// Sends a message on the stream `session_id` names.
+#[utoipa::path(
+    post,
+    path = "/sse/{session_id}",
+    tag = "realtime",
+    params(("session_id" = String, Path, description = "From the stream's `session` event")),
+    request_body(content = String, content_type = "text/plain", description = "An envelope, as it would be sent on `/ws`"),
+    responses(
+        (status = ACCEPTED, description = "The message is queued"),
+        (status = NOT_FOUND, description = "No such stream, or it has closed", body = ApiError),
+        (status = TOO_MANY_REQUESTS, description = "Over `rate_limits.realtime`; see `Retry-After`", body = ApiError),
+    )
+)]
+async fn send(
+    State(state): State<SseState>,
+    Path(session_id): Path<String>,
+    client: Option<Extension<Client>>,
+    message: String,
+) -> Response {
+    let client = client.map(|Extension(client)| client).unwrap_or_default();
+    if let Err(wait) = state.rate_limiter.check(LimitGroup::Realtime, &client) {
+        return rate_limit::too_many_requests(wait);
+    }
+    let inbound = Uuid::parse_str(&session_id)
+        .ok()
+        .and_then(|session_id| state.sessions.lock().unwrap().get(&session_id).cloned());
+    match inbound {
+        Some(inbound) if inbound.send(message).await.is_ok() => StatusCode::ACCEPTED.into_response(),
+        _ => ApiError::response(StatusCode::NOT_FOUND, "No such stream"),
+    }
+}
+
+async fn run(
+    state: SseState,
+    session_id: Uuid,
+    mut inbound: mpsc::Receiver<String>,
+    outbound: mpsc::Sender<Event>,
+    mut topics: HubConnection,
+    replay: ReplaySession,
+    greeting: Vec<Envelope>,
+) {
+    info!("SSE connection established");
+    let _connection = state.metrics.connection_opened(Transport::Sse);
+    for envelope in greeting {
+        if outbound.send(Event::default().data(envelope.encode())).await.is_err() {
+            state.sessions.lock().unwrap().remove(&session_id);
+            return;
+        }
+        state.metrics.record_message(Transport::Sse, Direction::Sent);
+    }
+    loop {
+        let message = tokio::select! {
+            Some(message) = inbound.recv() => {
+                state.metrics.record_message(Transport::Sse, Direction::Received);
+                replay.sequence(realtime::answer(&message, |request| topics.answer(request, realtime::echo)))
+            }
+            push = topics.next() => match push {
+                Some(push) => replay.push(push),
+                None => {
+                    let _ = outbound.send(Event::default().event("close").data("Too far behind on topic messages")).await;
+                    break;
+                }
+            },
+            // client disconnected
+            () = outbound.closed() => break,
+            () = state.shutdown.triggered() => {
+                let _ = outbound.send(Event::default().event("close").data("Server shutting down")).await;
+                break;
+            }
+        };
+        if outbound.send(Event::default().data(message.encode())).await.is_err() {
+            break;
+        }
+        state.metrics.record_message(Transport::Sse, Direction::Sent);
+    }
+    state.sessions.lock().unwrap().remove(&session_id);
+    info!("SSE connection closed");
+}
@@ -0,0 +1,202 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::info;
+
+/// Version of the data directory layout this build reads and writes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub const USERS_FILE: &str = "users.json";
+pub const SESSIONS_FILE: &str = "sessions.json";
+pub const AUDIT_FILE: &str = "audit.log";
+pub const PREFERENCES_FILE: &str = "preferences.json";
+pub const LAYOUTS_FILE: &str = "layouts.json";
+/// Default directory for uploads, which keeps its index in `UPLOADS_INDEX_FILE`.
+pub const UPLOADS_DIR: &str = "uploads";
+pub const UPLOADS_INDEX_FILE: &str = "uploads.json";
+const META_FILE: &str = "meta.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Meta {
+    schema_version: u32,
+}
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    apply: fn(&Path) -> Result<()>,
+}
+
+/// Migrations in order; each one brings the data directory to `version`.
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "create the user store",
+    apply: |dir| {
+        let users = dir.join(USERS_FILE);
+        if !users.exists() {
+            write_json(&users, &Vec::<serde_json::Value>::new())?;
+        }
+        Ok(())
+    },
+}];
+
+/// Reads a JSON document, returning `None` when the file doesn't exist.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    serde_json::from_slice(&data)
+        .map(Some)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Writes a JSON document atomically, so readers never see a partial file.
+pub fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let tmp = tmp_path(path);
+    let data = serde_json::to_vec_pretty(value)?;
+    fs::write(&tmp, data).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+/// A JSON document kept parsed in memory. It is read again only when the
+/// file has changed since it was last read or written, as when the CLI edits
+/// it under a running server, and every change is written through.
+pub struct JsonFile<T> {
+    path: PathBuf,
+    value: T,
+    /// The file's modification time and size when `value` was read or
+    /// written, or `None` before the first read.
+    stamp: Option<Stamp>,
+}
+
+/// Modification time and size of a file, or `None` when it doesn't exist.
+type Stamp = Option<(SystemTime, u64)>;
+
+impl<T: Serialize + DeserializeOwned + Default> JsonFile<T> {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, value: T::default(), stamp: None }
+    }
+
+    /// The document, read again first if the file changed. A missing file
+    /// reads as the default.
+    pub fn read(&mut self) -> Result<&mut T> {
+        let current = stamp(&self.path)?;
+        if self.stamp != Some(current) {
+            self.value = read_json(&self.path)?.unwrap_or_default();
+            self.stamp = Some(current);
+        }
+        Ok(&mut self.value)
+    }
+
+    /// Writes the document as changed through `read`.
+    pub fn write(&mut self) -> Result<()> {
+        // Until a write succeeds the file no longer matches `value`.
+        self.stamp = None;
+        write_json(&self.path, &self.value)?;
+        self.stamp = Some(stamp(&self.path)?);
+        Ok(())
+    }
+}
+
+fn stamp(path: &Path) -> Result<Stamp> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some((metadata.modified()?, metadata.len()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Runs store work that may touch the disk on the blocking pool, keeping
+/// file I/O off the async executor.
+pub async fn blocking<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> Result<R> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| anyhow!("Storage task failed: {}", e))
+}
+
+/// A unique sibling of `path` to write to before renaming it into place.
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.tmp", uuid::Uuid::new_v4()));
+    path.with_file_name(name)
+}
+
+/// Layout version of `data_dir`. A missing or empty directory counts as
+/// current, since there is nothing to migrate; anything else without a
+/// version stamp predates versioning and reports 0.
+pub fn schema_version(data_dir: &Path) -> Result<u32> {
+    if let Some(meta) = read_json::<Meta>(&data_dir.join(META_FILE))? {
+        return Ok(meta.schema_version);
+    }
+    let is_empty = match fs::read_dir(data_dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", data_dir.display())),
+    };
+    Ok(if is_empty { SCHEMA_VERSION } else { 0 })
+}
+
+/// Prepares `data_dir` for the server: initialises a fresh directory and
+/// refuses to run against one that needs `migrate` or was written by a
+/// newer build.
+pub fn open(data_dir: &Path) -> Result<()> {
+    match schema_version(data_dir)? {
+        SCHEMA_VERSION => {
+            if !data_dir.join(META_FILE).exists() {
+                info!("Initialising data directory {}", data_dir.display());
+                run_migrations(data_dir, 0)?;
+            }
+            Ok(())
+        }
+        v if v < SCHEMA_VERSION => bail!(
+            "Data directory {} is at schema version {} but this build needs {}; run `hudwiz-server migrate` first",
+            data_dir.display(),
+            v,
+            SCHEMA_VERSION
+        ),
+        v => bail!(
+            "Data directory {} is at schema version {}, newer than this build supports ({})",
+            data_dir.display(),
+            v,
+            SCHEMA_VERSION
+        ),
+    }
+}
+
+/// Applies all pending migrations to `data_dir`, returning their descriptions.
+pub fn migrate(data_dir: &Path) -> Result<Vec<&'static str>> {
+    let current = match read_json::<Meta>(&data_dir.join(META_FILE))? {
+        Some(meta) => meta.schema_version,
+        None => 0,
+    };
+    if current > SCHEMA_VERSION {
+        bail!(
+            "Data directory {} is at schema version {}, newer than this build supports ({})",
+            data_dir.display(),
+            current,
+            SCHEMA_VERSION
+        );
+    }
+    run_migrations(data_dir, current)
+}
+
+fn run_migrations(data_dir: &Path, from: u32) -> Result<Vec<&'static str>> {
+    fs::create_dir_all(data_dir)
+        .with_context(|| format!("Failed to create {}", data_dir.display()))?;
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > from) {
+        info!("Migrating {} to version {}: {}", data_dir.display(), migration.version, migration.description);
+        (migration.apply)(data_dir)
+            .with_context(|| format!("Migration to version {} failed", migration.version))?;
+        write_json(&data_dir.join(META_FILE), &Meta { schema_version: migration.version })?;
+        applied.push(migration.description);
+    }
+    Ok(applied)
+}
@@ -0,0 +1,572 @@
+use crate::{
+    auth::CurrentUser,
+    config::SystemStatsConfig,
+    hub::{Hub, Topic},
+    shutdown::Shutdown,
+    ApiError,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::warn;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// Realtime topic the samples are pushed on.
+pub const TOPIC: &str = "system.stats";
+
+/// One sample of the host and of this server process.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct SystemStats {
+    /// Unix timestamp in milliseconds.
+    pub timestamp_ms: u64,
+    /// Seconds since the host booted.
+    pub uptime_secs: u64,
+    pub cpu: CpuStats,
+    pub memory: MemoryStats,
+    pub disks: Vec<DiskStats>,
+    /// Every interface but loopback.
+    pub network: Vec<NetworkStats>,
+    pub process: ProcessStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct CpuStats {
+    /// Busy time across all cores since the previous sample.
+    pub usage_percent: f64,
+    pub cores: usize,
+    /// Load averages over 1, 5 and 15 minutes.
+    pub load_average: [f64; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct MemoryStats {
+    pub total_bytes: u64,
+    /// Memory not available to new processes, so excluding caches.
+    pub used_bytes: u64,
+    pub usage_percent: f64,
+    pub swap_total_bytes: u64,
+    pub swap_used_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct DiskStats {
+    pub mount: String,
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    /// Share of the space available to unprivileged users that is used, as
+    /// `df` reports it.
+    pub usage_percent: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct NetworkStats {
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ProcessStats {
+    pub pid: u32,
+    /// CPU time used since the previous sample, where one core is 100.
+    pub cpu_percent: f64,
+    pub resident_bytes: u64,
+    pub threads: u64,
+    pub open_fds: u64,
+    pub uptime_secs: u64,
+}
+
+/// The values the frontend draws sparklines from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct HistoryPoint {
+    pub timestamp_ms: u64,
+    pub cpu_percent: f64,
+    pub memory_percent: f64,
+    /// Summed over all interfaces.
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+}
+
+impl From<&SystemStats> for HistoryPoint {
+    fn from(stats: &SystemStats) -> Self {
+        Self {
+            timestamp_ms: stats.timestamp_ms,
+            cpu_percent: stats.cpu.usage_percent,
+            memory_percent: stats.memory.usage_percent,
+            rx_bytes_per_sec: stats.network.iter().map(|interface| interface.rx_bytes_per_sec).sum(),
+            tx_bytes_per_sec: stats.network.iter().map(|interface| interface.tx_bytes_per_sec).sum(),
+        }
+    }
+}
+
+/// What `/api/system/stats` returns.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct StatsReport {
+    pub current: SystemStats,
+    /// Oldest first, ending with `current`.
+    pub history: Vec<HistoryPoint>,
+    pub interval_secs: u64,
+}
+
+/// The latest sample, as seen by subscribers.
+#[derive(Debug, Clone, Default)]
+pub enum Snapshot {
+    /// The sampler hasn't finished its first sample yet.
+    #[default]
+    Pending,
+    Ready(Arc<SystemStats>),
+    /// Disabled, or not supported on this platform.
+    Unavailable(String),
+}
+
+/// Samples the host in the background, starting with the first request or
+/// subscriber, until shutdown.
+#[derive(Clone)]
+pub struct SystemStatsService {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: SystemStatsConfig,
+    started: AtomicBool,
+    publishing: AtomicBool,
+    latest: watch::Sender<Snapshot>,
+    history: Mutex<VecDeque<HistoryPoint>>,
+    shutdown: Shutdown,
+}
+
+impl SystemStatsService {
+    pub fn new(config: SystemStatsConfig, shutdown: Shutdown) -> Self {
+        let initial = if !config.enabled {
+            Snapshot::Unavailable("System statistics are disabled".to_string())
+        } else if !cfg!(target_os = "linux") {
+            Snapshot::Unavailable("System statistics are only collected on Linux".to_string())
+        } else {
+            Snapshot::Pending
+        };
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                started: AtomicBool::new(false),
+                publishing: AtomicBool::new(false),
+                latest: watch::Sender::new(initial),
+                history: Mutex::new(VecDeque::new()),
+                shutdown,
+            }),
+        }
+    }
+
+    /// Follows the samples as they are taken.
+    pub fn subscribe(&self) -> watch::Receiver<Snapshot> {
+        self.start();
+        self.inner.latest.subscribe()
+    }
+
+    /// The latest sample, waiting for the first one if needed.
+    pub async fn latest(&self) -> Result<Arc<SystemStats>, String> {
+        let mut latest = self.subscribe();
+        let snapshot = latest
+            .wait_for(|snapshot| !matches!(snapshot, Snapshot::Pending))
+            .await
+            .map_err(|_| "The sampler has stopped".to_string())?
+            .clone();
+        match snapshot {
+            Snapshot::Ready(stats) => Ok(stats),
+            Snapshot::Unavailable(reason) => Err(reason),
+            Snapshot::Pending => unreachable!(),
+        }
+    }
+
+    pub fn history(&self) -> Vec<HistoryPoint> {
+        self.inner.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.inner.config.interval_secs)
+    }
+
+    /// The `TOPIC` telemetry topic for logged in users, which publishes
+    /// every sample from the first subscription on.
+    pub fn topic(&self) -> Topic {
+        let service = self.clone();
+        Topic::new(TOPIC)
+            .logged_in()
+            .retain_last()
+            .telemetry()
+            .on_subscribe(move |hub| service.publish_to(hub))
+    }
+
+    fn publish_to(&self, hub: &Hub) {
+        if self.inner.publishing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut samples = self.subscribe();
+        // Publish the latest sample straight away rather than an interval later.
+        samples.mark_changed();
+        let hub = hub.clone();
+        let shutdown = self.inner.shutdown.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    changed = samples.changed() => if changed.is_err() { return },
+                    () = shutdown.triggered() => return,
+                }
+                let snapshot = samples.borrow_and_update().clone();
+                if let Snapshot::Ready(stats) = snapshot {
+                    hub.publish(TOPIC, serde_json::to_value(&*stats).expect("stats serialize"));
+                }
+            }
+        });
+    }
+
+    fn start(&self) {
+        if !matches!(*self.inner.latest.borrow(), Snapshot::Pending) || self.inner.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            let interval = Duration::from_secs(inner.config.interval_secs);
+            let mut sampler = Sampler::new(inner.config.mounts.clone());
+            // Rates need a previous reading, so the first sample follows a
+            // short priming one.
+            if let Err(e) = sampler.sample() {
+                warn!("Failed to read system statistics: {:#}", e);
+                inner.latest.send_replace(Snapshot::Unavailable(format!("Failed to read system statistics: {}", e)));
+                return;
+            }
+            let mut delay = interval.min(Duration::from_millis(500));
+            loop {
+                tokio::select! {
+                    () = tokio::time::sleep(delay) => {}
+                    () = inner.shutdown.triggered() => return,
+                }
+                delay = interval;
+                match sampler.sample() {
+                    Ok(stats) => {
+                        let mut history = inner.history.lock().unwrap();
+                        history.push_back(HistoryPoint::from(&stats));
+                        while history.len() > inner.config.history_len {
+                            history.pop_front();
+                        }
+                        drop(history);
+                        inner.latest.send_replace(Snapshot::Ready(Arc::new(stats)));
+                    }
+                    Err(e) => warn!("Failed to read system statistics: {:#}", e),
+                }
+            }
+        });
+    }
+}
+
+/// `/api/system/stats`.
+pub fn router<S: Clone + Send + Sync + 'static>(service: SystemStatsService) -> OpenApiRouter<S> {
+    OpenApiRouter::new().routes(routes!(get_stats)).with_state(service)
+}
+
+/// The latest sample of the host and of this server, with the history for
+/// sparklines. Clients that want every sample can instead subscribe to the
+/// `system.stats` topic on a realtime connection.
+#[utoipa::path(
+    get,
+    path = "/api/system/stats",
+    tag = "system",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The latest sample", body = StatsReport),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "Disabled, or not supported on this platform", body = ApiError),
+    )
+)]
+async fn get_stats(_user: CurrentUser, State(service): State<SystemStatsService>) -> Response {
+    match service.latest().await {
+        Ok(current) => Json(StatsReport {
+            current: (*current).clone(),
+            history: service.history(),
+            interval_secs: service.interval().as_secs(),
+        })
+        .into_response(),
+        Err(reason) => ApiError::response(StatusCode::SERVICE_UNAVAILABLE, &reason),
+    }
+}
+
+#[cfg(target_os = "linux")]
+use linux::Sampler;
+
+#[cfg(not(target_os = "linux"))]
+struct Sampler;
+
+#[cfg(not(target_os = "linux"))]
+impl Sampler {
+    fn new(_mounts: Vec<std::path::PathBuf>) -> Self {
+        Sampler
+    }
+
+    fn sample(&mut self) -> anyhow::Result<SystemStats> {
+        anyhow::bail!("only supported on Linux")
+    }
+}
+
+/// Reads everything from `/proc`, and disk usage with `statvfs`.
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use anyhow::{Context, Result};
+    use std::{
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+        time::{Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    /// Counters from the previous reading, for rates.
+    struct Previous {
+        at: Instant,
+        cpu: CpuTimes,
+        process_ticks: u64,
+        network: HashMap<String, (u64, u64)>,
+    }
+
+    #[derive(Clone, Copy)]
+    struct CpuTimes {
+        busy: u64,
+        total: u64,
+    }
+
+    pub struct Sampler {
+        mounts: Vec<PathBuf>,
+        ticks_per_sec: u64,
+        previous: Option<Previous>,
+    }
+
+    impl Sampler {
+        pub fn new(mounts: Vec<PathBuf>) -> Self {
+            Self {
+                mounts,
+                ticks_per_sec: rustix::param::clock_ticks_per_second().max(1),
+                previous: None,
+            }
+        }
+
+        pub fn sample(&mut self) -> Result<SystemStats> {
+            let at = Instant::now();
+            let (cpu, cores) = read_cpu()?;
+            let process = read_process()?;
+            let network = read_network()?;
+            let uptime_secs = read_uptime()?;
+            let previous = self.previous.as_ref();
+            let elapsed = previous.map_or(0.0, |previous| at.duration_since(previous.at).as_secs_f64());
+            let rate = |now: u64, before: Option<u64>| match before {
+                Some(before) if elapsed > 0.0 => now.saturating_sub(before) as f64 / elapsed,
+                _ => 0.0,
+            };
+
+            let cpu_usage = match previous {
+                Some(previous) if cpu.total > previous.cpu.total => {
+                    percent(cpu.busy.saturating_sub(previous.cpu.busy), cpu.total - previous.cpu.total)
+                }
+                _ => percent(cpu.busy, cpu.total),
+            };
+            let process_cpu = rate(process.ticks, previous.map(|previous| previous.process_ticks)) / self.ticks_per_sec as f64 * 100.0;
+            let network_stats = network
+                .iter()
+                .map(|(interface, &(rx_bytes, tx_bytes))| {
+                    let before = previous.and_then(|previous| previous.network.get(interface));
+                    NetworkStats {
+                        interface: interface.clone(),
+                        rx_bytes,
+                        tx_bytes,
+                        rx_bytes_per_sec: rate(rx_bytes, before.map(|before| before.0)),
+                        tx_bytes_per_sec: rate(tx_bytes, before.map(|before| before.1)),
+                    }
+                })
+                .collect();
+
+            let stats = SystemStats {
+                timestamp_ms: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_millis() as u64)
+                    .unwrap_or_default(),
+                uptime_secs: uptime_secs as u64,
+                cpu: CpuStats {
+                    usage_percent: cpu_usage,
+                    cores,
+                    load_average: read_load_average()?,
+                },
+                memory: read_memory()?,
+                disks: self.mounts.iter().filter_map(|mount| read_disk(mount)).collect(),
+                network: network_stats,
+                process: ProcessStats {
+                    pid: std::process::id(),
+                    cpu_percent: process_cpu,
+                    resident_bytes: process.resident_bytes,
+                    threads: process.threads,
+                    open_fds: process.open_fds,
+                    uptime_secs: (uptime_secs - process.start_ticks as f64 / self.ticks_per_sec as f64).max(0.0) as u64,
+                },
+            };
+            self.previous = Some(Previous {
+                at,
+                cpu,
+                process_ticks: process.ticks,
+                network,
+            });
+            Ok(stats)
+        }
+    }
+
+    fn percent(part: u64, whole: u64) -> f64 {
+        if whole == 0 { 0.0 } else { part as f64 / whole as f64 * 100.0 }
+    }
+
+    fn read(path: &str) -> Result<String> {
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))
+    }
+
+    /// Aggregate CPU times, and the number of cores, from `/proc/stat`.
+    fn read_cpu() -> Result<(CpuTimes, usize)> {
+        let stat = read("/proc/stat")?;
+        let mut lines = stat.lines();
+        let times: Vec<u64> = lines
+            .next()
+            .and_then(|line| line.strip_prefix("cpu "))
+            .context("No cpu line in /proc/stat")?
+            .split_whitespace()
+            .filter_map(|field| field.parse().ok())
+            .collect();
+        // user nice system idle iowait irq softirq steal; guest time is
+        // already part of user and nice.
+        let total: u64 = times.iter().take(8).sum();
+        let idle = times.get(3).copied().unwrap_or_default() + times.get(4).copied().unwrap_or_default();
+        let cores = lines
+            .filter(|line| line.strip_prefix("cpu").is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit())))
+            .count();
+        Ok((CpuTimes { busy: total.saturating_sub(idle), total }, cores.max(1)))
+    }
+
+    fn read_load_average() -> Result<[f64; 3]> {
+        let loadavg = read("/proc/loadavg")?;
+        let mut fields = loadavg.split_whitespace().map(|field| field.parse::<f64>().unwrap_or_default());
+        Ok([(); 3].map(|()| fields.next().unwrap_or_default()))
+    }
+
+    fn read_uptime() -> Result<f64> {
+        read("/proc/uptime")?
+            .split_whitespace()
+            .next()
+            .and_then(|field| field.parse().ok())
+            .context("Unexpected /proc/uptime")
+    }
+
+    fn read_memory() -> Result<MemoryStats> {
+        let meminfo = read("/proc/meminfo")?;
+        let field = |name: &str| -> u64 {
+            meminfo
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+                .map_or(0, |kib| kib * 1024)
+        };
+        let total = field("MemTotal");
+        let used = total.saturating_sub(field("MemAvailable"));
+        let swap_total = field("SwapTotal");
+        Ok(MemoryStats {
+            total_bytes: total,
+            used_bytes: used,
+            usage_percent: percent(used, total),
+            swap_total_bytes: swap_total,
+            swap_used_bytes: swap_total.saturating_sub(field("SwapFree")),
+        })
+    }
+
+    /// Usage of the file system at `mount`, or `None` if it can't be read.
+    fn read_disk(mount: &Path) -> Option<DiskStats> {
+        let stat = match rustix::fs::statvfs(mount) {
+            Ok(stat) => stat,
+            Err(e) => {
+                warn!("Failed to read disk usage of {}: {}", mount.display(), e);
+                return None;
+            }
+        };
+        let total = stat.f_blocks * stat.f_frsize;
+        let used = stat.f_blocks.saturating_sub(stat.f_bfree) * stat.f_frsize;
+        let available = stat.f_bavail * stat.f_frsize;
+        Some(DiskStats {
+            mount: mount.display().to_string(),
+            total_bytes: total,
+            used_bytes: used,
+            usage_percent: percent(used, used + available),
+        })
+    }
+
+    /// Received and sent bytes by interface, from `/proc/net/dev`.
+    fn read_network() -> Result<HashMap<String, (u64, u64)>> {
+        let dev = read("/proc/net/dev")?;
+        Ok(dev
+            .lines()
+            .skip(2)
+            .filter_map(|line| {
+                let (interface, counters) = line.split_once(':')?;
+                let interface = interface.trim();
+                if interface == "lo" {
+                    return None;
+                }
+                let counters: Vec<u64> = counters.split_whitespace().filter_map(|field| field.parse().ok()).collect();
+                Some((interface.to_string(), (*counters.first()?, *counters.get(8)?)))
+            })
+            .collect())
+    }
+
+    struct Process {
+        /// User and system time, in clock ticks.
+        ticks: u64,
+        /// When the process started, in clock ticks after boot.
+        start_ticks: u64,
+        threads: u64,
+        resident_bytes: u64,
+        open_fds: u64,
+    }
+
+    fn read_process() -> Result<Process> {
+        let stat = read("/proc/self/stat")?;
+        // The command name is in parentheses and may contain spaces.
+        let fields: Vec<u64> = stat
+            .rsplit_once(')')
+            .context("Unexpected /proc/self/stat")?
+            .1
+            .split_whitespace()
+            .skip(1)
+            .map(|field| field.parse().unwrap_or_default())
+            .collect();
+        // Indexed from the field after the state, so utime, which proc(5)
+        // numbers 14, is at 10.
+        let field = |index: usize| fields.get(index).copied().unwrap_or_default();
+        let status = read("/proc/self/status")?;
+        let resident_kib = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+            .unwrap_or_default();
+        Ok(Process {
+            ticks: field(10) + field(11),
+            start_ticks: field(18),
+            threads: field(16),
+            resident_bytes: resident_kib * 1024,
+            open_fds: fs::read_dir("/proc/self/fd").map_or(0, |entries| entries.count() as u64),
+        })
+    }
+}
@@ -0,0 +1,152 @@
+use crate::config::{LogFormat, LoggingConfig};
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, OnceLock, RwLock,
+    },
+};
+use tracing_subscriber::{fmt as log_fmt, prelude::*, reload, EnvFilter, Layer, Registry};
+
+static REDACT_USERNAMES: AtomicBool = AtomicBool::new(true);
+
+/// The `logging.redact_key` pseudonyms are keyed with, random until set.
+static REDACT_KEY: LazyLock<RwLock<Vec<u8>>> = LazyLock::new(|| RwLock::new(rand::random::<[u8; 32]>().to_vec()));
+
+/// Swaps the level filter of the installed subscriber. Unset while `RUST_LOG`
+/// is in charge or no subscriber was installed through `init`.
+static LEVEL: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Keeps trace export running. Dropping it flushes spans that haven't been
+/// exported yet.
+#[must_use]
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush traces: {}", e);
+        }
+    }
+}
+
+/// Installs the global `tracing` subscriber described by `config`. Logs go
+/// to stderr so command output on stdout stays clean.
+pub fn init(config: &LoggingConfig) -> Result<Telemetry> {
+    set_redact_usernames(config.redact_usernames);
+    set_redact_key(&config.redact_key);
+
+    let (filter, from_env) = match EnvFilter::try_from_default_env() {
+        Ok(filter) => (filter, true),
+        Err(_) => {
+            let filter = EnvFilter::try_new(&config.level)
+                .with_context(|| format!("Invalid logging.level '{}'", config.level))?;
+            (filter, false)
+        }
+    };
+    let (filter, level) = reload::Layer::new(filter);
+    let format = match config.format {
+        LogFormat::Text => log_fmt::layer().with_writer(std::io::stderr).boxed(),
+        LogFormat::Json => log_fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(std::io::stderr)
+            .boxed(),
+    };
+
+    let tracer_provider = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()
+                .context("Failed to create the OTLP exporter")?;
+            Some(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+                    .build(),
+            )
+        }
+        None => None,
+    };
+    let otel = tracer_provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(config.service_name.clone()))
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(format)
+        .with(otel)
+        .try_init()
+        .context("Failed to install the log subscriber")?;
+    if !from_env {
+        let _ = LEVEL.set(level);
+    }
+
+    if config.redact_usernames && config.redact_key.is_empty() {
+        tracing::warn!("logging.redact_key is not set, so logged pseudonyms change with every start");
+    }
+    if let Some(endpoint) = &config.otlp_endpoint {
+        tracing::info!("Exporting traces to {}", endpoint);
+    }
+    Ok(Telemetry { tracer_provider })
+}
+
+/// Replaces the level filter with `directives`, as set by `logging.level`.
+pub fn set_level(directives: &str) -> Result<()> {
+    if let Some(level) = LEVEL.get() {
+        let filter = EnvFilter::try_new(directives)
+            .with_context(|| format!("Invalid logging.level '{}'", directives))?;
+        level.reload(filter).context("Failed to change the log level")?;
+    }
+    Ok(())
+}
+
+pub fn set_redact_usernames(redact: bool) {
+    REDACT_USERNAMES.store(redact, Ordering::Relaxed);
+}
+
+/// Keys pseudonyms with `key`. An empty key keeps the current one.
+pub fn set_redact_key(key: &str) {
+    if !key.is_empty() {
+        *REDACT_KEY.write().unwrap() = key.as_bytes().to_vec();
+    }
+}
+
+/// A username or email address for log messages. Unless
+/// `logging.redact_usernames` is off this prints a pseudonym derived from
+/// the name and `logging.redact_key`, so one user's entries can still be
+/// correlated.
+pub fn username(name: &str) -> Username<'_> {
+    Username(name)
+}
+
+pub struct Username<'a>(&'a str);
+
+impl fmt::Display for Username<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !REDACT_USERNAMES.load(Ordering::Relaxed) {
+            return f.write_str(self.0);
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(&REDACT_KEY.read().unwrap()).expect("HMAC takes keys of any length");
+        mac.update(self.0.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        write!(f, "user-")?;
+        for byte in &digest[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
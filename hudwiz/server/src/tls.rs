@@ -0,0 +1,196 @@
+use crate::{config::TlsConfig, storage};
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{http::Uri, response::Redirect, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use rcgen::{CertificateParams, KeyPair};
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path, time::Duration};
+use time::OffsetDateTime;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// Browsers only accept a certificate pinned through `serverCertificateHashes`
+/// when it is valid for at most two weeks.
+pub const DEV_CERT_VALIDITY: time::Duration = time::Duration::days(14);
+
+/// Development certificates are replaced once less than this much validity remains.
+pub const DEV_CERT_RENEW_BEFORE: time::Duration = time::Duration::days(3);
+
+const DEV_CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The parts of a PEM certificate the server cares about.
+#[derive(Debug, Clone)]
+pub struct CertificateInfo {
+    /// SHA-256 of the DER encoded certificate.
+    pub sha256: [u8; 32],
+    /// Expiry as a Unix timestamp.
+    pub not_after: i64,
+    /// Self-signed and short-lived enough to be pinned by hash, i.e. one of ours.
+    pub is_dev_cert: bool,
+}
+
+impl CertificateInfo {
+    /// The SHA-256 hash as lowercase hex.
+    pub fn sha256_hex(&self) -> String {
+        self.sha256.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Reads the first certificate of a PEM file.
+pub fn read_certificate(path: &Path) -> Result<CertificateInfo> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&data)
+        .map_err(|e| anyhow!("{} is not a PEM certificate: {}", path.display(), e))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| anyhow!("Failed to parse certificate {}: {}", path.display(), e))?;
+
+    let validity = cert.validity();
+    let lifetime = validity.not_after.timestamp() - validity.not_before.timestamp();
+    Ok(CertificateInfo {
+        sha256: Sha256::digest(&pem.contents).into(),
+        not_after: validity.not_after.timestamp(),
+        is_dev_cert: cert.subject().as_raw() == cert.issuer().as_raw()
+            && lifetime <= DEV_CERT_VALIDITY.whole_seconds(),
+    })
+}
+
+/// Installs `ring` as the process-wide rustls crypto provider. Safe to call more than once.
+pub fn install_crypto_provider() {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+}
+
+/// Makes sure the configured certificate and key exist, generating a
+/// self-signed development certificate when neither file is present and
+/// renewing a development certificate that is about to expire.
+pub fn ensure_certificate(config: &TlsConfig) -> Result<()> {
+    match (config.cert_path.exists(), config.key_path.exists()) {
+        (true, true) => renew_dev_certificate_if_due(config).map(|_| ()),
+        (false, false) => {
+            warn!(
+                "No certificate found at {}, generating a self-signed development certificate",
+                config.cert_path.display()
+            );
+            generate_dev_certificate(&config.cert_path, &config.key_path, &config.dev_cert_names)
+        }
+        (true, false) => bail!("Certificate {} has no matching key at {}", config.cert_path.display(), config.key_path.display()),
+        (false, true) => bail!("Key {} has no matching certificate at {}", config.key_path.display(), config.cert_path.display()),
+    }
+}
+
+/// Writes a new self-signed ECDSA P-256 certificate for `names`, valid for
+/// [`DEV_CERT_VALIDITY`], and its private key as PEM files.
+pub fn generate_dev_certificate(cert_path: &Path, key_path: &Path, names: &[String]) -> Result<()> {
+    let mut params = CertificateParams::new(names.to_vec())
+        .context("Invalid development certificate subject names")?;
+    // Allow for some clock skew between server and browser.
+    params.not_before = OffsetDateTime::now_utc() - time::Duration::hours(1);
+    params.not_after = params.not_before + DEV_CERT_VALIDITY;
+    let key_pair = KeyPair::generate()?;
+    let cert = params
+        .self_signed(&key_pair)
+        .context("Failed to generate a self-signed certificate")?;
+
+    for path in [cert_path, key_path] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+    }
+    // Both files are written aside and renamed into place, key first, so the
+    // certificate watcher never reads a half-written file. A reload between
+    // the renames pairs the new key with the old certificate, which fails and
+    // keeps the previous identity until the certificate follows.
+    let key_tmp = storage::tmp_path(key_path);
+    let cert_tmp = storage::tmp_path(cert_path);
+    write_private(&key_tmp, key_pair.serialize_pem().as_bytes())
+        .with_context(|| format!("Failed to write {}", key_tmp.display()))?;
+    fs::write(&cert_tmp, cert.pem()).with_context(|| format!("Failed to write {}", cert_tmp.display()))?;
+    fs::rename(&key_tmp, key_path).with_context(|| format!("Failed to replace {}", key_path.display()))?;
+    fs::rename(&cert_tmp, cert_path).with_context(|| format!("Failed to replace {}", cert_path.display()))?;
+
+    info!("Wrote development certificate for {:?} to {}", names, cert_path.display());
+    Ok(())
+}
+
+/// Replaces the configured certificate with a fresh development certificate
+/// when it is one and expires within [`DEV_CERT_RENEW_BEFORE`]. Operator
+/// supplied certificates are never touched. Returns whether it was renewed.
+pub fn renew_dev_certificate_if_due(config: &TlsConfig) -> Result<bool> {
+    let info = read_certificate(&config.cert_path)?;
+    if !info.is_dev_cert {
+        return Ok(false);
+    }
+    let remaining = info.not_after - OffsetDateTime::now_utc().unix_timestamp();
+    if remaining > DEV_CERT_RENEW_BEFORE.whole_seconds() {
+        return Ok(false);
+    }
+    info!("Development certificate {} expires soon, renewing it", config.cert_path.display());
+    generate_dev_certificate(&config.cert_path, &config.key_path, &config.dev_cert_names)?;
+    Ok(true)
+}
+
+/// Periodically renews the development certificate. The file watcher then
+/// hands the new certificate to both servers.
+pub fn spawn_dev_cert_renewal(config: TlsConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(DEV_CERT_CHECK_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = renew_dev_certificate_if_due(&config) {
+                error!("Failed to renew development certificate: {:#}", e);
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+/// Loads the configured certificate into a rustls config for the HTTPS server.
+pub async fn rustls_config(config: &TlsConfig) -> Result<RustlsConfig> {
+    install_crypto_provider();
+    RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .with_context(|| format!("Failed to load certificate {}", config.cert_path.display()))
+}
+
+/// Swaps the HTTPS certificate whenever `changes` fires. Established
+/// connections keep their existing TLS session; new handshakes use the new one.
+pub fn spawn_https_reloader(config: TlsConfig, rustls: RustlsConfig, mut changes: watch::Receiver<u64>) {
+    tokio::spawn(async move {
+        while changes.changed().await.is_ok() {
+            match rustls.reload_from_pem_file(&config.cert_path, &config.key_path).await {
+                Ok(()) => info!("Reloaded HTTPS certificate from {}", config.cert_path.display()),
+                Err(e) => error!("Failed to reload HTTPS certificate, keeping the previous one: {}", e),
+            }
+        }
+    });
+}
+
+/// A router that permanently redirects every request to the same path under `public_url`.
+pub fn https_redirect_router(public_url: &str) -> Router {
+    let base = public_url.trim_end_matches('/').to_string();
+    Router::new().fallback(move |uri: Uri| {
+        let base = base.clone();
+        async move {
+            let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+            Redirect::permanent(&format!("{}{}", base, path))
+        }
+    })
+}
@@ -0,0 +1,812 @@
+use crate::{
+    auth::CurrentUser,
+    config::UploadsConfig,
+    storage::{self, JsonFile, UPLOADS_INDEX_FILE},
+    telemetry::username,
+    ApiError,
+};
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, DefaultBodyLimit, FromRequest, Multipart, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use futures::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use image::{ImageFormat, ImageReader, Limits};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info, warn};
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+/// Longest file name kept for an upload.
+const MAX_NAME_CHARS: usize = 255;
+
+/// Largest width or height of an image that gets a thumbnail.
+const MAX_IMAGE_PX: u32 = 16_384;
+
+/// An uploaded file. The content is stored once per distinct SHA-256, however
+/// many users upload it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredUpload {
+    pub id: String,
+    /// User id of the uploader.
+    pub owner: String,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    /// Hex SHA-256 of the content.
+    pub sha256: String,
+    /// Unix timestamp of the upload.
+    pub created_at: u64,
+    /// Whether a thumbnail was made, which is the case for every image.
+    pub thumbnail: bool,
+}
+
+/// An upload as the API shows it to its owner, with download links signed
+/// until `expires_at`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UploadInfo {
+    pub id: String,
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub sha256: String,
+    pub created_at: u64,
+    pub url: String,
+    pub thumbnail_url: Option<String>,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UploadList {
+    pub uploads: Vec<UploadInfo>,
+    /// Total size of the user's uploads.
+    pub used_bytes: u64,
+    pub quota_bytes: u64,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// Neither `uploads.dir` nor `storage.data_dir` is set.
+    Disabled,
+    /// The file is larger than `uploads.max_file_bytes`.
+    TooLarge(u64),
+    /// The file would take the user over `uploads.quota_bytes`.
+    QuotaExceeded(u64),
+    /// The media type isn't allowed, or the content doesn't match it.
+    Unsupported(String),
+    Storage(anyhow::Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::Disabled => f.write_str("Uploads are not enabled on this server"),
+            UploadError::TooLarge(max) => write!(f, "Files can be at most {} bytes", max),
+            UploadError::QuotaExceeded(quota) => write!(f, "Uploads would exceed the quota of {} bytes", quota),
+            UploadError::Unsupported(reason) => f.write_str(reason),
+            UploadError::Storage(e) => write!(f, "Upload store error: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<anyhow::Error> for UploadError {
+    fn from(e: anyhow::Error) -> Self {
+        UploadError::Storage(e)
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        UploadError::Storage(e.into())
+    }
+}
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            UploadError::Disabled => StatusCode::SERVICE_UNAVAILABLE,
+            UploadError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            UploadError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            UploadError::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            UploadError::Storage(e) => {
+                error!("Upload failed: {:#}", e);
+                return ApiError::response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to store the upload");
+            }
+        };
+        ApiError::response(status, &self.to_string())
+    }
+}
+
+/// Uploaded files in `uploads.dir`: their content under `blobs/` by SHA-256,
+/// thumbnails under `thumbnails/`, and the index in `uploads.json`, which is
+/// kept in memory and accessed on the blocking pool like the other stores.
+#[derive(Clone)]
+pub struct UploadStore {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    config: UploadsConfig,
+    index: Mutex<JsonFile<BTreeMap<String, StoredUpload>>>,
+    /// Signs download links. Generated on start, so links don't outlive the
+    /// process.
+    key: [u8; 32],
+}
+
+/// A file being received into `tmp/`, removed again unless it is committed.
+pub struct PendingUpload {
+    file: tokio::fs::File,
+    path: Option<PathBuf>,
+    hasher: Sha256,
+    size: u64,
+    max_file_bytes: u64,
+}
+
+impl PendingUpload {
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), UploadError> {
+        self.size += chunk.len() as u64;
+        if self.size > self.max_file_bytes {
+            return Err(UploadError::TooLarge(self.max_file_bytes));
+        }
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+}
+
+impl Drop for PendingUpload {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl UploadStore {
+    pub fn disabled() -> Self {
+        Self { inner: None }
+    }
+
+    /// A store in `dir`, created if missing. Files left over from uploads
+    /// that were interrupted by a restart are removed.
+    pub fn open(dir: &std::path::Path, config: UploadsConfig) -> anyhow::Result<Self> {
+        for sub in ["blobs", "thumbnails", "tmp"] {
+            std::fs::create_dir_all(dir.join(sub)).with_context(|| format!("Failed to create {}", dir.join(sub).display()))?;
+        }
+        for entry in std::fs::read_dir(dir.join("tmp"))? {
+            std::fs::remove_file(entry?.path())?;
+        }
+        Ok(Self {
+            inner: Some(Arc::new(Inner {
+                dir: dir.to_path_buf(),
+                config,
+                index: Mutex::new(JsonFile::new(dir.join(UPLOADS_INDEX_FILE))),
+                key: rand::random(),
+            })),
+        })
+    }
+
+    fn inner(&self) -> Result<&Inner, UploadError> {
+        self.inner.as_deref().ok_or(UploadError::Disabled)
+    }
+
+    /// Applies `f` to the index on the blocking pool, writing it back if it
+    /// reports a change.
+    async fn with_index<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Inner, &mut BTreeMap<String, StoredUpload>) -> Result<(R, bool), UploadError> + Send + 'static,
+    ) -> Result<R, UploadError> {
+        let inner = self.inner.clone().ok_or(UploadError::Disabled)?;
+        storage::blocking(move || {
+            let mut file = inner.index.lock().unwrap();
+            let (result, modified) = f(&inner, file.read()?)?;
+            if modified {
+                file.write()?;
+            }
+            Ok(result)
+        })
+        .await?
+    }
+
+    /// The uploads of `owner`, oldest first.
+    pub async fn list(&self, owner: &str) -> Result<Vec<StoredUpload>, UploadError> {
+        let owner = owner.to_string();
+        self.with_index(move |_, index| {
+            let mut uploads: Vec<_> = index.values().filter(|upload| upload.owner == owner).cloned().collect();
+            uploads.sort_by_key(|upload| upload.created_at);
+            Ok((uploads, false))
+        })
+        .await
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<StoredUpload>, UploadError> {
+        let id = id.to_string();
+        self.with_index(move |_, index| Ok((index.get(&id).cloned(), false))).await
+    }
+
+    /// Total size of the uploads of `owner`.
+    pub async fn usage(&self, owner: &str) -> Result<u64, UploadError> {
+        let owner = owner.to_string();
+        self.with_index(move |_, index| Ok((usage(index, &owner), false))).await
+    }
+
+    /// Starts receiving a file. The quota is checked on `commit`, once it
+    /// is known whether the user already has the file.
+    pub async fn begin(&self) -> Result<PendingUpload, UploadError> {
+        let inner = self.inner()?;
+        let path = inner.dir.join("tmp").join(uuid::Uuid::new_v4().to_string());
+        Ok(PendingUpload {
+            file: tokio::fs::File::create(&path).await?,
+            path: Some(path),
+            hasher: Sha256::new(),
+            size: 0,
+            max_file_bytes: inner.config.max_file_bytes,
+        })
+    }
+
+    /// Checks the received file against `content_type`, makes its thumbnail
+    /// and adds it to the index. Returns the upload and whether it is new;
+    /// a file `owner` already uploaded returns the existing upload.
+    pub async fn commit(
+        &self,
+        mut pending: PendingUpload,
+        owner: &str,
+        name: &str,
+        content_type: &str,
+    ) -> Result<(StoredUpload, bool), UploadError> {
+        let inner = self.inner()?;
+        if !inner.config.allowed_types.iter().any(|allowed| allowed == content_type) {
+            return Err(UploadError::Unsupported(format!("Files of type {} are not accepted", content_type)));
+        }
+        pending.file.flush().await?;
+        let sha256 = hex(&pending.hasher.clone().finalize());
+        let temp = pending.path.clone().expect("pending upload was already committed");
+
+        // Images have to decode as what they claim to be, which also makes
+        // sure browsers can't be talked into rendering them as something else.
+        let thumbnail = match ImageFormat::from_mime_type(content_type) {
+            Some(format) => {
+                let source = temp.clone();
+                let target = inner.dir.join("tmp").join(format!("{}.png", uuid::Uuid::new_v4()));
+                let size = inner.config.thumbnail_px;
+                let made = target.clone();
+                tokio::task::spawn_blocking(move || make_thumbnail(&source, format, &made, size))
+                    .await
+                    .map_err(anyhow::Error::from)??;
+                Some(target)
+            }
+            None => None,
+        };
+
+        let (owner, name, content_type) = (owner.to_string(), name.to_string(), content_type.to_string());
+        let size = pending.size;
+        let moved = thumbnail.clone();
+        let result = self.with_index(move |inner, index| {
+            if let Some(existing) = index.values().find(|upload| upload.owner == owner && upload.sha256 == sha256) {
+                return Ok(((existing.clone(), false), false));
+            }
+            if usage(index, &owner) + size > inner.config.quota_bytes {
+                return Err(UploadError::QuotaExceeded(inner.config.quota_bytes));
+            }
+            let blob = inner.dir.join("blobs").join(&sha256);
+            if !blob.exists() {
+                std::fs::rename(&temp, &blob)?;
+            }
+            if let Some(thumbnail) = &moved {
+                std::fs::rename(thumbnail, inner.dir.join("thumbnails").join(format!("{}.png", sha256)))?;
+            }
+            let upload = StoredUpload {
+                id: uuid::Uuid::new_v4().to_string(),
+                owner,
+                name: clean_name(&name),
+                content_type,
+                size,
+                sha256,
+                created_at: now(),
+                thumbnail: moved.is_some(),
+            };
+            index.insert(upload.id.clone(), upload.clone());
+            Ok(((upload, true), true))
+        })
+        .await;
+        if let Some(thumbnail) = thumbnail {
+            let _ = std::fs::remove_file(thumbnail);
+        }
+        result
+    }
+
+    /// Removes the upload `id` of `owner`, and its content once no other
+    /// upload shares it. Returns whether there was such an upload.
+    pub async fn delete(&self, owner: &str, id: &str) -> Result<bool, UploadError> {
+        let (owner, id) = (owner.to_string(), id.to_string());
+        self.with_index(move |inner, index| {
+            let Some(upload) = index.get(&id).filter(|upload| upload.owner == owner).cloned() else {
+                return Ok((false, false));
+            };
+            index.remove(&id);
+            if !index.values().any(|other| other.sha256 == upload.sha256) {
+                for path in [
+                    inner.dir.join("blobs").join(&upload.sha256),
+                    inner.dir.join("thumbnails").join(format!("{}.png", upload.sha256)),
+                ] {
+                    if let Err(e) = std::fs::remove_file(&path)
+                        && e.kind() != std::io::ErrorKind::NotFound
+                    {
+                        warn!("Failed to remove {}: {}", path.display(), e);
+                    }
+                }
+            }
+            Ok((true, true))
+        })
+        .await
+    }
+
+    /// Where the content of `upload`, or its thumbnail, is kept.
+    pub fn path(&self, upload: &StoredUpload, variant: Variant) -> Result<PathBuf, UploadError> {
+        let dir = &self.inner()?.dir;
+        Ok(match variant {
+            Variant::Original => dir.join("blobs").join(&upload.sha256),
+            Variant::Thumbnail => dir.join("thumbnails").join(format!("{}.png", upload.sha256)),
+        })
+    }
+
+    fn mac(&self, id: &str, variant: Variant, expires: u64) -> Result<Hmac<Sha256>, UploadError> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.inner()?.key).expect("HMAC takes keys of any length");
+        mac.update(format!("{}\n{:?}\n{}", id, variant, expires).as_bytes());
+        Ok(mac)
+    }
+
+    /// A signature letting anyone download `variant` of upload `id` until
+    /// `expires`.
+    pub fn sign(&self, id: &str, variant: Variant, expires: u64) -> Result<String, UploadError> {
+        Ok(hex(&self.mac(id, variant, expires)?.finalize().into_bytes()))
+    }
+
+    /// Whether `signature` is current and was made by `sign`.
+    pub fn verify(&self, id: &str, variant: Variant, expires: u64, signature: &str) -> bool {
+        let Some(signature) = unhex(signature) else { return false };
+        expires >= now()
+            && self
+                .mac(id, variant, expires)
+                .is_ok_and(|mac| mac.verify_slice(&signature).is_ok())
+    }
+
+    fn url_ttl_secs(&self) -> u64 {
+        self.inner.as_ref().map_or(0, |inner| inner.config.url_ttl_secs)
+    }
+
+    fn quota_bytes(&self) -> u64 {
+        self.inner.as_ref().map_or(0, |inner| inner.config.quota_bytes)
+    }
+}
+
+/// The file behind a download link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Original,
+    Thumbnail,
+}
+
+fn usage(index: &BTreeMap<String, StoredUpload>, owner: &str) -> u64 {
+    index.values().filter(|upload| upload.owner == owner).map(|upload| upload.size).sum()
+}
+
+fn make_thumbnail(source: &std::path::Path, format: ImageFormat, target: &std::path::Path, size: u32) -> Result<(), UploadError> {
+    let invalid = || UploadError::Unsupported(format!("The file is not a valid {}", format.to_mime_type()));
+    let mut reader = ImageReader::open(source)?.with_guessed_format()?;
+    if reader.format() != Some(format) {
+        return Err(invalid());
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_PX);
+    limits.max_image_height = Some(MAX_IMAGE_PX);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|_| invalid())?;
+    image
+        .thumbnail(size, size)
+        .save_with_format(target, ImageFormat::Png)
+        .context("Failed to write the thumbnail")?;
+    Ok(())
+}
+
+/// The last path component of a client-supplied file name, without control
+/// characters.
+fn clean_name(name: &str) -> String {
+    let name: String = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_CHARS)
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "upload".to_string(),
+        name => name.to_string(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct UploadState {
+    pub store: UploadStore,
+    /// The `AppBuilder::prefix`, if any, which download links start with.
+    pub prefix: String,
+}
+
+impl UploadState {
+    fn info(&self, upload: StoredUpload) -> Result<UploadInfo, UploadError> {
+        let expires_at = now() + self.store.url_ttl_secs();
+        let link = |variant: Variant, path: String| -> Result<String, UploadError> {
+            let signature = self.store.sign(&upload.id, variant, expires_at)?;
+            Ok(format!("{}{}?expires={}&signature={}", self.prefix, path, expires_at, signature))
+        };
+        Ok(UploadInfo {
+            url: link(Variant::Original, format!("/api/files/{}", upload.id))?,
+            thumbnail_url: match upload.thumbnail {
+                true => Some(link(Variant::Thumbnail, format!("/api/files/{}/thumbnail", upload.id))?),
+                false => None,
+            },
+            expires_at,
+            id: upload.id,
+            name: upload.name,
+            content_type: upload.content_type,
+            size: upload.size,
+            sha256: upload.sha256,
+            created_at: upload.created_at,
+        })
+    }
+}
+
+/// Uploading, listing and deleting files, and the signed download links.
+pub fn router<S: Clone + Send + Sync + 'static>(state: UploadState) -> OpenApiRouter<S> {
+    OpenApiRouter::new()
+        // `uploads.max_file_bytes` is enforced while the body streams in.
+        .routes(routes!(upload, list_uploads))
+        .layer(DefaultBodyLimit::disable())
+        .routes(routes!(get_upload, delete_upload))
+        .routes(routes!(download))
+        .routes(routes!(download_thumbnail))
+        .with_state(state)
+}
+
+/// Media type without parameters, such as `text/plain` for
+/// `text/plain; charset=utf-8`.
+fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// Writes `body` into `pending`.
+async fn receive<E: fmt::Display>(
+    pending: &mut PendingUpload,
+    body: impl Stream<Item = Result<Bytes, E>>,
+) -> Result<(), Response> {
+    let mut body = std::pin::pin!(body);
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| ApiError::response(StatusCode::BAD_REQUEST, &format!("Failed to read the upload: {}", e)))?;
+        pending.write(&chunk).await.map_err(IntoResponse::into_response)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, IntoParams)]
+struct UploadQuery {
+    /// File name, for uploads that aren't `multipart/form-data`.
+    name: Option<String>,
+}
+
+/// Uploads a file, either as the `file` field of a `multipart/form-data`
+/// body or as the raw body with its type in `Content-Type` and its name in
+/// `name`. The body is streamed to disk and rejected as soon as it passes
+/// the size limit; the quota applies once it is complete.
+#[utoipa::path(
+    post,
+    path = "/api/uploads",
+    tag = "uploads",
+    security(("session" = [])),
+    params(UploadQuery),
+    request_body(content = Vec<u8>, description = "The file, raw or as the `file` field of a form", content_type = "multipart/form-data"),
+    responses(
+        (status = CREATED, description = "The file was stored", body = UploadInfo),
+        (status = OK, description = "The user had already uploaded this file", body = UploadInfo),
+        (status = BAD_REQUEST, description = "No file, no name or an unreadable body", body = ApiError),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = PAYLOAD_TOO_LARGE, description = "Larger than `uploads.max_file_bytes`", body = ApiError),
+        (status = UNSUPPORTED_MEDIA_TYPE, description = "Not an accepted type, or not what it claims to be", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "Uploads are disabled", body = ApiError),
+        (status = INSUFFICIENT_STORAGE, description = "Over the user's quota", body = ApiError),
+    )
+)]
+async fn upload(
+    CurrentUser(user): CurrentUser,
+    State(state): State<UploadState>,
+    Query(query): Query<UploadQuery>,
+    request: Request,
+) -> Response {
+    let mut pending = match state.store.begin().await {
+        Ok(pending) => pending,
+        Err(e) => return e.into_response(),
+    };
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(essence)
+        .unwrap_or_default();
+
+    let (name, content_type) = if content_type == "multipart/form-data" {
+        let mut multipart = match Multipart::from_request(request, &()).await {
+            Ok(multipart) => multipart,
+            Err(rejection) => return ApiError::response(StatusCode::BAD_REQUEST, &rejection.body_text()),
+        };
+        loop {
+            let field = match multipart.next_field().await {
+                Ok(Some(field)) => field,
+                Ok(None) => return ApiError::response(StatusCode::BAD_REQUEST, "The form has no 'file' field"),
+                Err(e) => return ApiError::response(StatusCode::BAD_REQUEST, &e.body_text()),
+            };
+            if field.name() != Some("file") {
+                continue;
+            }
+            let name = field.file_name().unwrap_or_default().to_string();
+            let content_type = field.content_type().map(essence).unwrap_or_default();
+            if let Err(response) = receive(&mut pending, field).await {
+                return response;
+            }
+            break (name, content_type);
+        }
+    } else {
+        let Some(name) = query.name else {
+            return ApiError::response(StatusCode::BAD_REQUEST, "Raw uploads need a 'name' query parameter");
+        };
+        if let Err(response) = receive(&mut pending, request.into_body().into_data_stream()).await {
+            return response;
+        }
+        (name, content_type)
+    };
+
+    match state.store.commit(pending, &user.id, &name, &content_type).await {
+        Ok((upload, created)) => {
+            if created {
+                info!("{} uploaded {} ({} bytes)", username(&user.email), upload.id, upload.size);
+            }
+            let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+            match state.info(upload) {
+                Ok(info) => (status, Json(info)).into_response(),
+                Err(e) => e.into_response(),
+            }
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The user's uploads, with fresh download links, and how much of their
+/// quota they use.
+#[utoipa::path(
+    get,
+    path = "/api/uploads",
+    tag = "uploads",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The uploads, oldest first", body = UploadList),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "Uploads are disabled", body = ApiError),
+    )
+)]
+async fn list_uploads(CurrentUser(user): CurrentUser, State(state): State<UploadState>) -> Response {
+    let list = state.store.list(&user.id).await.and_then(|uploads| {
+        Ok(UploadList {
+            used_bytes: uploads.iter().map(|upload| upload.size).sum(),
+            quota_bytes: state.store.quota_bytes(),
+            uploads: uploads.into_iter().map(|upload| state.info(upload)).collect::<Result<_, _>>()?,
+        })
+    });
+    match list {
+        Ok(list) => Json(list).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// One of the user's uploads, with fresh download links.
+#[utoipa::path(
+    get,
+    path = "/api/uploads/{id}",
+    tag = "uploads",
+    security(("session" = [])),
+    params(("id" = String, Path, description = "Upload id")),
+    responses(
+        (status = OK, description = "The upload", body = UploadInfo),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = NOT_FOUND, description = "The user has no upload with that id", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "Uploads are disabled", body = ApiError),
+    )
+)]
+async fn get_upload(CurrentUser(user): CurrentUser, State(state): State<UploadState>, Path(id): Path<String>) -> Response {
+    match state.store.get(&id).await {
+        Ok(Some(upload)) if upload.owner == user.id => match state.info(upload) {
+            Ok(info) => Json(info).into_response(),
+            Err(e) => e.into_response(),
+        },
+        Ok(_) => ApiError::response(StatusCode::NOT_FOUND, "No upload with that id"),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Deletes one of the user's uploads. Download links to it stop working.
+#[utoipa::path(
+    delete,
+    path = "/api/uploads/{id}",
+    tag = "uploads",
+    security(("session" = [])),
+    params(("id" = String, Path, description = "Upload id")),
+    responses(
+        (status = NO_CONTENT, description = "The upload was deleted"),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+        (status = NOT_FOUND, description = "The user has no upload with that id", body = ApiError),
+        (status = SERVICE_UNAVAILABLE, description = "Uploads are disabled", body = ApiError),
+    )
+)]
+async fn delete_upload(CurrentUser(user): CurrentUser, State(state): State<UploadState>, Path(id): Path<String>) -> Response {
+    match state.store.delete(&user.id, &id).await {
+        Ok(true) => {
+            info!("{} deleted upload {}", username(&user.email), id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => ApiError::response(StatusCode::NOT_FOUND, "No upload with that id"),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The signature on a download link.
+#[derive(Deserialize, IntoParams)]
+struct Signed {
+    /// Unix timestamp after which the link stops working.
+    expires: u64,
+    signature: String,
+}
+
+/// The content of an upload, for anyone holding a current link from
+/// `/api/uploads`.
+#[utoipa::path(
+    get,
+    path = "/api/files/{id}",
+    tag = "uploads",
+    params(("id" = String, Path, description = "Upload id"), Signed),
+    responses(
+        (status = OK, description = "The file", content_type = "application/octet-stream"),
+        (status = NOT_MODIFIED, description = "The `If-None-Match` ETag is current"),
+        (status = FORBIDDEN, description = "The link is invalid or has expired", body = ApiError),
+        (status = NOT_FOUND, description = "The upload was deleted", body = ApiError),
+    )
+)]
+async fn download(
+    State(state): State<UploadState>,
+    Path(id): Path<String>,
+    signed: Result<Query<Signed>, QueryRejection>,
+    headers: HeaderMap,
+) -> Response {
+    serve(&state, &id, Variant::Original, signed, &headers).await
+}
+
+/// The PNG thumbnail of an image upload.
+#[utoipa::path(
+    get,
+    path = "/api/files/{id}/thumbnail",
+    tag = "uploads",
+    params(("id" = String, Path, description = "Upload id"), Signed),
+    responses(
+        (status = OK, description = "The thumbnail", content_type = "image/png"),
+        (status = NOT_MODIFIED, description = "The `If-None-Match` ETag is current"),
+        (status = FORBIDDEN, description = "The link is invalid or has expired", body = ApiError),
+        (status = NOT_FOUND, description = "The upload was deleted", body = ApiError),
+    )
+)]
+async fn download_thumbnail(
+    State(state): State<UploadState>,
+    Path(id): Path<String>,
+    signed: Result<Query<Signed>, QueryRejection>,
+    headers: HeaderMap,
+) -> Response {
+    serve(&state, &id, Variant::Thumbnail, signed, &headers).await
+}
+
+async fn serve(
+    state: &UploadState,
+    id: &str,
+    variant: Variant,
+    signed: Result<Query<Signed>, QueryRejection>,
+    headers: &HeaderMap,
+) -> Response {
+    let forbidden = || ApiError::response(StatusCode::FORBIDDEN, "The link is invalid or has expired");
+    let Ok(Query(signed)) = signed else { return forbidden() };
+    if !state.store.verify(id, variant, signed.expires, &signed.signature) {
+        return forbidden();
+    }
+    let upload = match state.store.get(id).await {
+        Ok(Some(upload)) if variant == Variant::Original || upload.thumbnail => upload,
+        Ok(_) => return ApiError::response(StatusCode::NOT_FOUND, "The upload was deleted"),
+        Err(e) => return e.into_response(),
+    };
+
+    let etag = format!("\"{}{}\"", upload.sha256, if variant == Variant::Thumbnail { "-thumbnail" } else { "" });
+    let cache_control = format!("private, max-age={}", signed.expires.saturating_sub(now()));
+    if headers.get(header::IF_NONE_MATCH).is_some_and(|value| value.as_bytes() == etag.as_bytes()) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag), (header::CACHE_CONTROL, cache_control)]).into_response();
+    }
+    let file = match state.store.path(&upload, variant) {
+        Ok(path) => match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => return UploadError::from(e).into_response(),
+        },
+        Err(e) => return e.into_response(),
+    };
+    let length = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return UploadError::from(e).into_response(),
+    };
+
+    let (content_type, disposition) = match variant {
+        Variant::Thumbnail => ("image/png".to_string(), "inline".to_string()),
+        // Only images that were decoded on upload are shown in the browser;
+        // anything else is downloaded, so an upload can't run script here.
+        Variant::Original => (
+            upload.content_type.clone(),
+            format!(
+                "{}; filename*=UTF-8''{}",
+                if upload.thumbnail { "inline" } else { "attachment" },
+                utf8_percent_encode(&upload.name, NON_ALPHANUMERIC)
+            ),
+        ),
+    };
+    let mut response = Body::from_stream(ReaderStream::new(file)).into_response();
+    let response_headers = response.headers_mut();
+    for (name, value) in [
+        (header::CONTENT_TYPE, content_type),
+        (header::CONTENT_LENGTH, length.to_string()),
+        (header::CONTENT_DISPOSITION, disposition),
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, cache_control),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            response_headers.insert(name, value);
+        }
+    }
+    response
+}
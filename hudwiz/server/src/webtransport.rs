@@ -1,81 +1,446 @@
-use anyhow::Result;
-use std::time::Duration;
-use tracing::{error, info, info_span, Instrument};
-use wtransport::{endpoint::IncomingSession, Endpoint, Identity, ServerConfig};
+use crate::{
+    auth::{CurrentUser, UserInfo},
+    config::{self, TlsConfig},
+    hub::{SubscribeError, TopicsQuery},
+    metrics::{Direction, Metrics, Transport},
+    rate_limit::Client,
+    realtime,
+    replay::ResumeQuery,
+    tls::{self, CertificateInfo},
+    ApiError, Services,
+};
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Query, State},
+    http::Uri,
+    response::Json,
+};
+use protocol::Envelope;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::{mpsc, watch},
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+use wtransport::{endpoint::IncomingSession, Endpoint, Identity, RecvStream, SendStream, ServerConfig, VarInt};
 
-pub async fn start_webtransport_server() -> Result<()> {
-    let config = ServerConfig::builder()
-        .with_bind_address("0.0.0.0:4433".parse()?)
-        .with_identity(Identity::self_signed(["localhost"])?)
-        .keep_alive_interval(Some(Duration::from_secs(3)))
-        .build();
+/// Largest message a client may send in one frame.
+pub const MAX_FRAME_BYTES: usize = 64 * 1024;
+
+/// Frames queued per stream before the session waits for the other side.
+const STREAM_QUEUE_LEN: usize = 64;
+
+/// How long a session token can be used for.
+const TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// How clients reach the WebTransport server, as published on `/api/realtime/info`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WebTransportInfo {
+    pub url: String,
+    /// Hex SHA-256 of the served certificate for the browser's
+    /// `serverCertificateHashes` option. Only set for development
+    /// certificates; CA-issued certificates validate normally.
+    pub cert_hash: Option<String>,
+    /// Certificate expiry as a Unix timestamp. Clients should refetch the info after it.
+    pub cert_expires_at: i64,
+}
+
+/// What the running WebTransport server is currently serving. Empty until it
+/// is listening.
+#[derive(Debug, Clone, Default)]
+pub struct WebTransportStatus(Arc<RwLock<Option<WebTransportInfo>>>);
+
+impl WebTransportStatus {
+    pub fn get(&self) -> Option<WebTransportInfo> {
+        self.0.read().unwrap().clone()
+    }
 
+    fn publish(&self, url: &str, cert: &CertificateInfo) {
+        *self.0.write().unwrap() = Some(WebTransportInfo {
+            url: url.to_string(),
+            cert_hash: cert.is_dev_cert.then(|| cert.sha256_hex()),
+            cert_expires_at: cert.not_after,
+        });
+    }
+}
+
+/// Runs the WebTransport server with the certificate from `tls`, swapping in
+/// the new certificate whenever `cert_changes` fires. Established sessions
+/// are unaffected by a reload. Once shutdown is triggered it stops accepting
+/// and returns when every session has been closed.
+pub async fn start_webtransport_server(
+    server_config: config::ServerConfig,
+    tls: TlsConfig,
+    mut cert_changes: watch::Receiver<u64>,
+    services: Services,
+) -> Result<()> {
+    let status = &services.webtransport;
+    let shutdown = &services.shutdown;
+    let bind_addr = server_config.webtransport_addr;
+    let url = server_config.webtransport_url();
+    let (config, cert) = load_config(bind_addr, &tls).await?;
     let server = Endpoint::server(config)?;
+    status.publish(&url, &cert);
 
-    info!("WebTransport server listening on port 4433");
+    info!("WebTransport server listening on {}", bind_addr);
 
-    for id in 0.. {
-        let incoming_session = server.accept().await;
-        tokio::spawn(
-            handle_connection(incoming_session).instrument(info_span!("Connection", id)),
-        );
+    let mut id: u64 = 0;
+    loop {
+        tokio::select! {
+            incoming_session = server.accept() => {
+                // Sessions have no HTTP request, so each gets its own request id.
+                let span = info_span!(
+                    "Connection",
+                    id,
+                    transport = "webtransport",
+                    request_id = %uuid::Uuid::new_v4(),
+                );
+                tokio::spawn(shutdown.track(handle_connection(incoming_session, services.clone()).instrument(span)));
+                id += 1;
+            }
+            () = shutdown.triggered() => break,
+            Ok(()) = cert_changes.changed() => {
+                let reloaded = match load_config(bind_addr, &tls).await {
+                    Ok((config, cert)) => server.reload_config(config, false).map(|()| cert).map_err(Into::into),
+                    Err(e) => Err(e),
+                };
+                match reloaded {
+                    Ok(cert) => {
+                        status.publish(&url, &cert);
+                        info!("Reloaded WebTransport certificate from {}", tls.cert_path.display());
+                    }
+                    Err(e) => error!("Failed to reload WebTransport certificate, keeping the previous one: {:?}", e),
+                }
+            }
+        }
     }
 
+    info!("WebTransport server stopped accepting, waiting for {} session(s)", server.open_connections());
+    server.wait_idle().await;
     Ok(())
 }
 
-async fn handle_connection(incoming_session: IncomingSession) {
-    if let Err(err) = handle_connection_impl(incoming_session).await {
+/// Single-use tokens that log a WebTransport session in. Browsers send
+/// WebTransport sessions no cookies, so clients fetch a token with theirs
+/// and pass it in the session URL.
+#[derive(Clone, Default)]
+pub struct SessionTokens(Arc<Mutex<HashMap<String, (UserInfo, Instant)>>>);
+
+impl SessionTokens {
+    /// A new token for `user`, valid for `TOKEN_TTL`.
+    pub fn issue(&self, user: UserInfo) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        let mut tokens = self.0.lock().unwrap();
+        tokens.retain(|_, (_, issued)| issued.elapsed() < TOKEN_TTL);
+        tokens.insert(token.clone(), (user, Instant::now()));
+        token
+    }
+
+    /// The user `token` was issued to, unless it has expired or been used.
+    pub fn redeem(&self, token: &str) -> Option<UserInfo> {
+        let (user, issued) = self.0.lock().unwrap().remove(token)?;
+        (issued.elapsed() < TOKEN_TTL).then_some(user)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SessionToken {
+    /// Passed as `?token=` in the WebTransport session URL.
+    pub token: String,
+    pub expires_in_secs: u64,
+}
+
+/// `/api/realtime/token`.
+pub fn router<S: Clone + Send + Sync + 'static>(tokens: SessionTokens) -> OpenApiRouter<S> {
+    OpenApiRouter::new().routes(routes!(issue_token)).with_state(tokens)
+}
+
+/// A token logging the next WebTransport session in as the current user.
+/// It can be used once, within a minute.
+#[utoipa::path(
+    post,
+    path = "/api/realtime/token",
+    tag = "realtime",
+    security(("session" = [])),
+    responses(
+        (status = OK, description = "The token", body = SessionToken),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ApiError),
+    )
+)]
+async fn issue_token(CurrentUser(user): CurrentUser, State(tokens): State<SessionTokens>) -> Json<SessionToken> {
+    Json(SessionToken {
+        token: tokens.issue(user),
+        expires_in_secs: TOKEN_TTL.as_secs(),
+    })
+}
+
+async fn load_config(bind_addr: SocketAddr, tls: &TlsConfig) -> Result<(ServerConfig, CertificateInfo)> {
+    let identity = Identity::load_pemfiles(&tls.cert_path, &tls.key_path)
+        .await
+        .with_context(|| format!("Failed to load certificate {}", tls.cert_path.display()))?;
+    let cert = tls::read_certificate(&tls.cert_path)?;
+    let config = ServerConfig::builder()
+        .with_bind_address(bind_addr)
+        .with_identity(identity)
+        .keep_alive_interval(Some(Duration::from_secs(3)))
+        .build();
+    Ok((config, cert))
+}
+
+async fn handle_connection(incoming_session: IncomingSession, services: Services) {
+    if let Err(err) = handle_connection_impl(incoming_session, services).await {
         error!("Error handling connection: {:?}", err);
     }
 }
 
-async fn handle_connection_impl(incoming_session: IncomingSession) -> Result<()> {
-    let mut buffer = vec![0; 65536].into_boxed_slice();
+#[derive(Default, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// The parameters in the session URL's query, or the defaults when they
+/// don't parse.
+fn query<T: DeserializeOwned + Default>(path: &str) -> T {
+    path.parse::<Uri>()
+        .ok()
+        .and_then(|uri| Query::<T>::try_from_uri(&uri).ok())
+        .map(|Query(query)| query)
+        .unwrap_or_default()
+}
+
+async fn handle_connection_impl(incoming_session: IncomingSession, services: Services) -> Result<()> {
+    let metrics = &services.metrics;
 
     info!("Waiting for session request...");
 
     let session_request = incoming_session.await?;
+    let path = session_request.path().to_string();
 
+    // The query carries the token, so only the path is logged.
     info!(
         "New session: Authority: '{}', Path: '{}'",
         session_request.authority(),
-        session_request.path()
+        path.split('?').next().unwrap_or_default()
     );
 
+    let user = match query::<TokenQuery>(&path).token {
+        Some(token) => match services.session_tokens.redeem(&token) {
+            Some(user) => Some(user),
+            None => {
+                warn!("Refused a session with an unknown or expired token");
+                session_request.forbidden().await;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let mut topics = services.hub.connect(user.clone());
+    if let Err(e) = topics.subscribe_query(&query::<TopicsQuery>(&path)) {
+        warn!("Refused a session: {}", e);
+        match e {
+            SubscribeError::Unknown(_) => session_request.not_found().await,
+            SubscribeError::NotLoggedIn | SubscribeError::Forbidden(_) => session_request.forbidden().await,
+        }
+        return Ok(());
+    }
     let connection = session_request.accept().await?;
+    let _connection = metrics.connection_opened(Transport::WebTransport);
+    let client = Client {
+        ip: Some(connection.remote_address().ip()),
+        user: user.map(|user| user.id),
+    };
 
-    info!("Connection accepted. Waiting for data from client...");
+    info!("Connection accepted. Waiting for the session stream...");
 
-    loop {
+    // The first bidirectional stream carries the session: its greeting, the
+    // topic messages and the replies to what is sent on it.
+    let (send, recv) = tokio::select! {
+        stream = connection.accept_bi() => stream?,
+        () = services.shutdown.triggered() => {
+            connection.close(VarInt::from_u32(0), b"Server shutting down");
+            return Ok(());
+        }
+    };
+    let (inbound_tx, mut inbound) = mpsc::channel(STREAM_QUEUE_LEN);
+    let mut streams = HashMap::from([(0, open_stream(0, send, recv, &inbound_tx))]);
+    let mut next_stream = 1;
+    let (replay, greeting) = services.replay.attach(&query::<ResumeQuery>(&path), &client);
+    for envelope in greeting {
+        if !send_frame(&streams, 0, &envelope, metrics).await {
+            return Ok(());
+        }
+    }
+
+    let reason = loop {
         tokio::select! {
-            stream = connection.accept_bi() => {
-                let mut stream = stream?;
-                info!("Accepted BI stream");
-
-                if let Some(bytes_read) = stream.1.read(&mut buffer).await? {
-                    let str_data = std::str::from_utf8(&buffer[..bytes_read])?;
-                    info!("Received (bi) '{str_data}' from client");
-                    stream.0.write_all(b"ACK").await?;
+            stream = connection.accept_bi() => match stream {
+                Ok((send, recv)) => {
+                    streams.insert(next_stream, open_stream(next_stream, send, recv, &inbound_tx));
+                    next_stream += 1;
+                }
+                Err(e) => {
+                    info!("Session closed: {}", e);
+                    break "Session closed";
+                }
+            },
+            Some(event) = inbound.recv() => {
+                let (stream, reply) = match event {
+                    Inbound::Frame { stream, frame } => {
+                        metrics.record_message(Transport::WebTransport, Direction::Received);
+                        let reply = match String::from_utf8(frame) {
+                            Ok(text) => realtime::receive(&services.rate_limiter, &client, &text, |request| {
+                                topics.answer(request, realtime::echo)
+                            }),
+                            Err(_) => realtime::error("Messages are sent as JSON text"),
+                        };
+                        (stream, reply)
+                    }
+                    Inbound::Invalid { stream, problem } => (stream, realtime::error(&problem)),
+                    Inbound::Finished { stream } => {
+                        streams.remove(&stream);
+                        if stream == 0 {
+                            info!("Client finished the session stream");
+                            break "Session stream finished";
+                        }
+                        continue;
+                    }
+                };
+                if !send_frame(&streams, stream, &replay.sequence(reply), metrics).await && stream == 0 {
+                    break "Session stream closed";
                 }
             }
-            stream = connection.accept_uni() => {
-                let mut stream = stream?;
-                info!("Accepted UNI stream");
-
-                if let Some(bytes_read) = stream.read(&mut buffer).await? {
-                    let str_data = std::str::from_utf8(&buffer[..bytes_read])?;
-                    info!("Received (uni) '{str_data}' from client");
-                    let mut ack_stream = connection.open_uni().await?.await?;
-                    ack_stream.write_all(b"ACK").await?;
+            push = topics.next() => {
+                let Some(push) = push else {
+                    info!("Closing session that fell behind on topic messages");
+                    break "Too far behind on topic messages";
+                };
+                let telemetry = push.telemetry;
+                let envelope = replay.push(push);
+                // Telemetry that doesn't fit in a datagram goes on the stream instead.
+                if telemetry && connection.send_datagram(envelope.encode()).is_ok() {
+                    metrics.record_message(Transport::WebTransport, Direction::Sent);
+                } else if !send_frame(&streams, 0, &envelope, metrics).await {
+                    break "Session stream closed";
                 }
             }
-            dgram = connection.receive_datagram() => {
-                let dgram = dgram?;
-                let str_data = std::str::from_utf8(&dgram)?;
-                info!("Received (dgram) '{str_data}' from client");
-                connection.send_datagram(b"ACK")?;
+            datagram = connection.receive_datagram() => match datagram {
+                Ok(_) => debug!("Dropped a datagram; clients send messages on streams"),
+                Err(e) => {
+                    info!("Session closed: {}", e);
+                    break "Session closed";
+                }
+            },
+            () = services.shutdown.triggered() => {
+                info!("Closing session for shutdown");
+                break "Server shutting down";
+            }
+        }
+    };
+    // Closed explicitly, since the stream tasks would otherwise keep it open.
+    connection.close(VarInt::from_u32(0), reason.as_bytes());
+    Ok(())
+}
+
+/// What the tasks reading a session's streams tell the session.
+enum Inbound {
+    Frame { stream: u64, frame: Vec<u8> },
+    /// The stream sent something that isn't a frame, and is no longer read.
+    Invalid { stream: u64, problem: String },
+    /// The stream has ended; nothing more will be written to it either.
+    Finished { stream: u64 },
+}
+
+/// Starts reading and writing frames on a stream. Returns where to queue
+/// frames to write, which finishes the stream when dropped.
+fn open_stream(id: u64, send: SendStream, recv: RecvStream, inbound: &mpsc::Sender<Inbound>) -> mpsc::Sender<Vec<u8>> {
+    let (frames, frames_rx) = mpsc::channel(STREAM_QUEUE_LEN);
+    tokio::spawn(write_frames(id, send, frames_rx).in_current_span());
+    tokio::spawn(read_frames(id, recv, inbound.clone()).in_current_span());
+    frames
+}
+
+/// Queues `envelope` on `stream`, returning false if the stream is gone.
+async fn send_frame(
+    streams: &HashMap<u64, mpsc::Sender<Vec<u8>>>,
+    stream: u64,
+    envelope: &Envelope,
+    metrics: &Metrics,
+) -> bool {
+    let Some(frames) = streams.get(&stream) else {
+        return false;
+    };
+    if frames.send(frame(envelope)).await.is_err() {
+        return false;
+    }
+    metrics.record_message(Transport::WebTransport, Direction::Sent);
+    true
+}
+
+async fn read_frames(stream: u64, mut recv: RecvStream, inbound: mpsc::Sender<Inbound>) {
+    loop {
+        let event = match read_frame(&mut recv).await {
+            Ok(Some(frame)) => Inbound::Frame { stream, frame },
+            Ok(None) => break,
+            Err(e) => {
+                info!(stream, "Stopped reading stream: {:#}", e);
+                Inbound::Invalid {
+                    stream,
+                    problem: format!("{:#}", e),
+                }
             }
+        };
+        let last = matches!(event, Inbound::Invalid { .. });
+        if inbound.send(event).await.is_err() {
+            return;
         }
+        if last {
+            break;
+        }
+    }
+    let _ = inbound.send(Inbound::Finished { stream }).await;
+}
+
+async fn write_frames(stream: u64, mut send: SendStream, mut frames: mpsc::Receiver<Vec<u8>>) {
+    while let Some(frame) = frames.recv().await {
+        if let Err(e) = send.write_all(&frame).await {
+            info!(stream, "Stopped writing stream: {}", e);
+            return;
+        }
+    }
+    let _ = send.finish().await;
+}
+
+/// `envelope` as a frame: its length as a big-endian `u32`, then its JSON.
+pub fn frame(envelope: &Envelope) -> Vec<u8> {
+    let text = envelope.encode();
+    let mut frame = Vec::with_capacity(4 + text.len());
+    frame.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    frame.extend_from_slice(text.as_bytes());
+    frame
+}
+
+/// Reads one frame from `stream`, or `None` when it ends between frames.
+/// Fails on frames over `MAX_FRAME_BYTES` and streams that end inside one.
+pub async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    if stream.read(&mut length[..1]).await? == 0 {
+        return Ok(None);
+    }
+    stream.read_exact(&mut length[1..]).await.context("The stream ended inside a frame")?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_BYTES {
+        bail!("A frame of {} bytes is over the limit of {}", length, MAX_FRAME_BYTES);
     }
+    let mut frame = vec![0; length];
+    stream.read_exact(&mut frame).await.context("The stream ended inside a frame")?;
+    Ok(Some(frame))
 }
@@ -0,0 +1,178 @@
+use async_trait::async_trait;
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::Redirect,
+    routing::get,
+    Router,
+};
+use server_lib::{
+    auth::{hash_password, Credentials, Role, User, UserInfo, UserStore},
+    config::load_config,
+    sessions::FileSessionStore,
+    sso::SsoProvider,
+    AppBuilder, Services,
+};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+async fn spawn(app: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+async fn login(client: &reqwest::Client, url: String, username: &str, password: &str) -> StatusCode {
+    client
+        .post(url)
+        .json(&Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+struct FakeSso;
+
+#[async_trait]
+impl SsoProvider for FakeSso {
+    fn get_login_redirect(&self) -> Result<Redirect, anyhow::Error> {
+        Ok(Redirect::to("https://idp.example.com/authorize"))
+    }
+
+    async fn handle_callback(&self, _code: String, _state: String) -> Result<UserInfo, anyhow::Error> {
+        anyhow::bail!("not used")
+    }
+}
+
+#[tokio::test]
+async fn test_injected_stores() {
+    let users = UserStore::in_memory();
+    users
+        .create(User::new("embedded", hash_password("secret").unwrap(), Role::User))
+        .await
+        .unwrap();
+    let sessions = FileSessionStore::in_memory();
+    let config = load_config().unwrap();
+    let services = Services::open(&config).unwrap();
+    let app = AppBuilder::new(config)
+        .services(services.clone())
+        .user_store(users)
+        .session_store(sessions.clone())
+        .build()
+        .unwrap();
+    let addr = spawn(app).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api/auth/login", addr);
+
+    // Only the injected users exist, without the configured one.
+    assert_eq!(login(&client, url.clone(), "testuser", "password").await, StatusCode::UNAUTHORIZED);
+    assert_eq!(login(&client, url, "embedded", "secret").await, StatusCode::OK);
+
+    // The session went to the injected store.
+    assert_eq!(sessions.len(), 1);
+    assert!(services.sessions.is_empty());
+}
+
+#[tokio::test]
+async fn test_sso_provider() {
+    let app = AppBuilder::new(load_config().unwrap())
+        .sso_provider("fake", FakeSso)
+        .build()
+        .unwrap();
+    let addr = spawn(app).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let res = client
+        .get(format!("http://{}/api/auth/sso/login/fake", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(res.headers()[header::LOCATION], "https://idp.example.com/authorize");
+
+    // Configured providers are still there.
+    let res = client
+        .get(format!("http://{}/api/auth/sso/login/google", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+}
+
+async fn tag(request: Request, next: Next) -> axum::response::Response {
+    let mut response = next.run(request).await;
+    response.headers_mut().insert("x-embedder", HeaderValue::from_static("yes"));
+    response
+}
+
+#[tokio::test]
+async fn test_extra_routes_and_layers() {
+    let extra = Router::new().route("/api/custom", get(|| async { "custom" }));
+    let app = AppBuilder::new(load_config().unwrap())
+        .merge(extra)
+        .layer(middleware::from_fn(tag))
+        .build()
+        .unwrap();
+    let addr = spawn(app).await;
+
+    for path in ["/api/custom", "/api/auth/user", "/"] {
+        let res = reqwest::get(format!("http://{}{}", addr, path)).await.unwrap();
+        assert_eq!(res.headers()["x-embedder"], "yes", "{}", path);
+        // The built-in layers wrap extra routes too.
+        assert_eq!(res.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff", "{}", path);
+        assert!(res.headers().contains_key("x-request-id"), "{}", path);
+    }
+    let res = reqwest::get(format!("http://{}/api/custom", addr)).await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "custom");
+}
+
+#[tokio::test]
+async fn test_prefix() {
+    let app = AppBuilder::new(load_config().unwrap()).prefix("/hud").build().unwrap();
+    let app = Router::new().route("/", get(|| async { "host app" })).merge(app);
+    let addr = spawn(app).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let res = client.get(format!("http://{}/hud", addr)).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(res.headers()[header::LOCATION], "/hud/");
+    let res = client.get(format!("http://{}/hud/healthz", addr)).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get(format!("http://{}/hud/", addr)).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+    let url = format!("http://{}/hud/api/auth/login", addr);
+    assert_eq!(login(&client, url, "testuser", "password").await, StatusCode::OK);
+
+    // Nothing is served outside the prefix, leaving the rest to the host app.
+    let res = client.get(format!("http://{}/healthz", addr)).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = client.get(format!("http://{}/", addr)).send().await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "host app");
+}
+
+#[test]
+fn test_errors_are_returned() {
+    for prefix in ["hud", "/hud/", "/{hud}"] {
+        let built = AppBuilder::new(load_config().unwrap()).prefix(prefix).build();
+        assert!(built.is_err(), "{}", prefix);
+    }
+
+    let mut config = load_config().unwrap();
+    config.security.referrer_policy = "no-referrer\n".to_string();
+    assert!(AppBuilder::new(config).build().is_err());
+}
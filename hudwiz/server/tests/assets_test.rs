@@ -0,0 +1,118 @@
+// These serve a dist directory written per test through `server.static_dir`,
+// which builds with `embed-assets` ignore; embedded_assets_test.rs covers those.
+#![cfg(not(feature = "embed-assets"))]
+
+use axum::http::{header, StatusCode};
+use server_lib::{
+    app_router_with_config,
+    assets::{IMMUTABLE, NO_CACHE},
+    config::load_config,
+    Services,
+};
+use std::{fs, net::SocketAddr, path::PathBuf};
+use tokio::net::TcpListener;
+
+const BUNDLE: &str = "hudwiz-frontend-3f2a9c1e0b7d4a65.js";
+
+/// A dist directory with a hashed bundle and its brotli variant, plus the
+/// unhashed files every build has.
+fn write_dist() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hudwiz-dist-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(dir.join("static")).unwrap();
+    fs::write(dir.join("index.html"), "<html>index</html>").unwrap();
+    fs::write(dir.join("wasm.js"), "export default function init() {}").unwrap();
+    fs::write(dir.join(BUNDLE), "console.log('bundle')").unwrap();
+    fs::write(dir.join(format!("{}.br", BUNDLE)), b"\x0b\x0abrotli bytes").unwrap();
+    fs::write(dir.join("static/config.json"), "{}").unwrap();
+    dir
+}
+
+async fn spawn(static_dir: PathBuf) -> SocketAddr {
+    let mut config = load_config().unwrap();
+    config.server.static_dir = static_dir;
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+fn header(res: &reqwest::Response, name: header::HeaderName) -> Option<&str> {
+    res.headers().get(name).map(|value| value.to_str().unwrap())
+}
+
+#[tokio::test]
+async fn test_cache_control() {
+    let addr = spawn(write_dist()).await;
+
+    for (path, cache_control) in [
+        (format!("/{}", BUNDLE), IMMUTABLE),
+        ("/wasm.js".to_string(), NO_CACHE),
+        ("/".to_string(), NO_CACHE),
+        ("/index.html".to_string(), NO_CACHE),
+        ("/static/config.json".to_string(), NO_CACHE),
+        // Client-side routes get the SPA fallback, which is never immutable.
+        ("/deadbeef-12345678".to_string(), NO_CACHE),
+    ] {
+        let res = reqwest::get(format!("http://{}{}", addr, path)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{}", path);
+        assert_eq!(header(&res, header::CACHE_CONTROL), Some(cache_control), "{}", path);
+        assert!(header(&res, header::ETAG).is_some(), "{}", path);
+    }
+
+    let res = reqwest::get(format!("http://{}/deadbeef-12345678", addr)).await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "<html>index</html>");
+}
+
+#[tokio::test]
+async fn test_etag_revalidation() {
+    let dist = write_dist();
+    let addr = spawn(dist.clone()).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/wasm.js", addr);
+
+    let res = client.get(&url).send().await.unwrap();
+    let etag = header(&res, header::ETAG).unwrap().to_string();
+
+    let res = client.get(&url).header(header::IF_NONE_MATCH, &etag).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(header(&res, header::ETAG), Some(etag.as_str()));
+    assert_eq!(header(&res, header::CACHE_CONTROL), Some(NO_CACHE));
+    assert!(res.bytes().await.unwrap().is_empty());
+
+    // Any listed tag matches.
+    let res = client
+        .get(&url)
+        .header(header::IF_NONE_MATCH, format!("\"other\", {}", etag))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+    // A rebuilt file gets a new tag and is sent in full.
+    fs::write(dist.join("wasm.js"), "export default function init() { return 1; }").unwrap();
+    let res = client.get(&url).header(header::IF_NONE_MATCH, &etag).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_ne!(header(&res, header::ETAG), Some(etag.as_str()));
+}
+
+#[tokio::test]
+async fn test_precompressed_variants() {
+    let addr = spawn(write_dist()).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/{}", addr, BUNDLE);
+
+    let res = client.get(&url).header(header::ACCEPT_ENCODING, "gzip, br").send().await.unwrap();
+    assert_eq!(header(&res, header::CONTENT_ENCODING), Some("br"));
+    assert_eq!(header(&res, header::VARY), Some("accept-encoding"));
+    assert_eq!(header(&res, header::CACHE_CONTROL), Some(IMMUTABLE));
+    assert_eq!(res.bytes().await.unwrap().as_ref(), b"\x0b\x0abrotli bytes");
+
+    // Without a gzip variant, gzip-only clients get the original.
+    let res = client.get(&url).header(header::ACCEPT_ENCODING, "gzip").send().await.unwrap();
+    assert_eq!(header(&res, header::CONTENT_ENCODING), None);
+    assert_eq!(res.text().await.unwrap(), "console.log('bundle')");
+}
@@ -0,0 +1,54 @@
+// Fixtures shared by the integration tests; not every test file uses all of them.
+#![allow(dead_code)]
+
+use axum::http::{header, StatusCode};
+use server_lib::{
+    app_router_with_config,
+    auth::{hash_password, Credentials, Role, User},
+    config::{load_config, Config},
+    Services,
+};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+/// Serves the app for `config` on a free local port.
+pub async fn spawn_with(config: Config) -> (SocketAddr, Services) {
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services.clone()).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (addr, services)
+}
+
+/// Serves the app for the default config on a free local port.
+pub async fn spawn() -> (SocketAddr, Services) {
+    spawn_with(load_config().unwrap()).await
+}
+
+/// Logs in and returns the session cookie.
+pub async fn login(addr: SocketAddr, username: &str, password: &str) -> String {
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/api/auth/login", addr))
+        .json(&Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+    cookie.split(';').next().unwrap().to_string()
+}
+
+/// Adds a user whose password is "secret".
+pub async fn add_user(services: &Services, username: &str, role: Role) {
+    services
+        .user_store
+        .create(User::new(username, hash_password("secret").unwrap(), role))
+        .await
+        .unwrap();
+}
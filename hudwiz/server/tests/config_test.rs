@@ -0,0 +1,70 @@
+use server_lib::config::load_config_from;
+use std::{fs, path::PathBuf};
+
+fn write_config(contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hudwiz-config-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server_config.toml");
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+const AUTH_SECTION: &str = r#"
+[auth]
+primary_provider = "mock"
+
+[auth.providers.mock]
+username = "testuser"
+password_hash = "unused"
+
+[auth.sso_providers.google]
+client_id = "id"
+client_secret = "secret"
+
+[auth.sso_providers.microsoft]
+client_id = "id"
+client_secret = "secret"
+redirect_uri = "https://example.com/callback"
+
+[auth.sso_providers.apple]
+client_id = "id"
+client_secret = "secret"
+"#;
+
+#[test]
+fn test_server_section_defaults_and_path_resolution() {
+    let path = write_config(&format!(
+        r#"
+[server]
+http_addr = "0.0.0.0:9090"
+public_url = "https://hud.example.com/"
+static_dir = "dist"
+{AUTH_SECTION}"#
+    ));
+    let config = load_config_from(&path).unwrap();
+    let base_dir = path.parent().unwrap();
+
+    assert_eq!(config.server.http_addr.port(), 9090);
+    assert_eq!(config.server.webtransport_addr.port(), 4433);
+    assert_eq!(config.server.static_dir, base_dir.join("dist"));
+    assert_eq!(config.server.spa_fallback_path(), base_dir.join("dist/index.html"));
+
+    // Missing redirect URIs are derived from the public URL; explicit ones are kept.
+    assert_eq!(
+        config.auth.sso_providers.google.redirect_uri,
+        "https://hud.example.com/api/auth/sso/callback/google"
+    );
+    assert_eq!(config.auth.sso_providers.microsoft.redirect_uri, "https://example.com/callback");
+}
+
+#[test]
+fn test_server_section_is_optional() {
+    let path = write_config(AUTH_SECTION);
+    let config = load_config_from(&path).unwrap();
+
+    assert_eq!(config.server.http_addr.to_string(), "127.0.0.1:8080");
+    assert_eq!(
+        config.server.static_dir,
+        path.parent().unwrap().join("hudwiz/frontend/dist")
+    );
+}
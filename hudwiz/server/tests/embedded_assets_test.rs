@@ -0,0 +1,48 @@
+// Needs the frontend built into hudwiz/frontend/dist, like the feature itself.
+#![cfg(feature = "embed-assets")]
+
+use axum::http::{header, StatusCode};
+use server_lib::{app_router_with_config, assets::NO_CACHE, config::load_config, Services};
+use std::{fs, net::SocketAddr, path::PathBuf};
+use tokio::net::TcpListener;
+
+async fn spawn() -> SocketAddr {
+    let mut config = load_config().unwrap();
+    // The embedded assets are served whatever the configured directory holds.
+    config.server.static_dir = std::env::temp_dir().join(format!("hudwiz-missing-{}", uuid::Uuid::new_v4()));
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+fn header(res: &reqwest::Response, name: header::HeaderName) -> Option<&str> {
+    res.headers().get(name).map(|value| value.to_str().unwrap())
+}
+
+#[tokio::test]
+async fn test_embedded_index_and_fallback() {
+    let addr = spawn().await;
+    let client = reqwest::Client::new();
+    let index = fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../frontend/dist/index.html")).unwrap();
+
+    for path in ["/", "/index.html", "/some/client/route", "/..%2fCargo.toml"] {
+        let res = client.get(format!("http://{}{}", addr, path)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{}", path);
+        assert_eq!(header(&res, header::CACHE_CONTROL), Some(NO_CACHE), "{}", path);
+        assert_eq!(header(&res, header::CONTENT_TYPE), Some("text/html"), "{}", path);
+        assert_eq!(res.bytes().await.unwrap().as_ref(), index.as_slice(), "{}", path);
+    }
+
+    let url = format!("http://{}/", addr);
+    let res = client.get(&url).send().await.unwrap();
+    let etag = header(&res, header::ETAG).unwrap().to_string();
+    assert!(!etag.starts_with("W/"), "{}", etag);
+    let res = client.get(&url).header(header::IF_NONE_MATCH, &etag).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(header(&res, header::ETAG), Some(etag.as_str()));
+}
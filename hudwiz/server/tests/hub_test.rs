@@ -0,0 +1,203 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use common::{login, spawn};
+use futures::{SinkExt, StreamExt};
+use protocol::{Envelope, Message};
+use serde_json::json;
+use server_lib::{
+    auth::{Role, UserInfo},
+    config::{RealtimeConfig, SlowConsumers},
+    hub::{Hub, HubConnection, Push, SubscribeError, Topic},
+    metrics::Metrics,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest},
+    MaybeTlsStream, WebSocketStream,
+};
+
+type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+fn hub(slow_consumers: SlowConsumers) -> (Hub, Metrics) {
+    let config = RealtimeConfig {
+        queue_len: 2,
+        slow_consumers,
+        ..RealtimeConfig::default()
+    };
+    let metrics = Metrics::default();
+    let hub = Hub::new(config, metrics.clone());
+    hub.register(Topic::new("weather"));
+    (hub, metrics)
+}
+
+fn user(name: &str) -> UserInfo {
+    UserInfo {
+        id: name.to_string(),
+        name: name.to_string(),
+        email: format!("{}@example.com", name),
+    }
+}
+
+async fn next(connection: &mut HubConnection) -> Option<Push> {
+    tokio::time::timeout(Duration::from_secs(5), connection.next()).await.expect("nothing published")
+}
+
+#[tokio::test]
+async fn test_publishes_reach_subscribers() {
+    let (hub, _) = hub(SlowConsumers::Drop);
+    let mut subscribed = hub.connect(None);
+    let other = hub.connect(None);
+    subscribed.subscribe("weather").unwrap();
+
+    assert_eq!(hub.publish("weather", json!({"rain": true})), 1);
+    assert_eq!(hub.publish("traffic", json!({})), 0);
+    let push = next(&mut subscribed).await.unwrap();
+    assert!(!push.telemetry);
+    assert_eq!(push.envelope.topic.as_deref(), Some("weather"));
+    assert_eq!(push.envelope.message, Message::Publish(json!({"rain": true})));
+
+    subscribed.unsubscribe("weather");
+    assert_eq!(hub.publish("weather", json!({"rain": false})), 0);
+    drop(other);
+    assert_eq!(
+        subscribed.subscribe("traffic"),
+        Err(SubscribeError::Unknown("traffic".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn test_topics_authorize_subscribers() {
+    let (hub, _) = hub(SlowConsumers::Drop);
+    hub.register(Topic::new("admin").authorize(|user| user.is_some_and(|user| user.name == "root")));
+
+    assert_eq!(hub.connect(None).subscribe("admin"), Err(SubscribeError::NotLoggedIn));
+    let refused = hub.connect(Some(user("alice"))).subscribe("admin");
+    assert_eq!(refused, Err(SubscribeError::Forbidden("admin".to_string())));
+    assert_eq!(refused.unwrap_err().status(), StatusCode::FORBIDDEN);
+    assert_eq!(hub.connect(Some(user("root"))).subscribe("admin"), Ok(()));
+}
+
+#[tokio::test]
+async fn test_retained_telemetry_greets_subscribers() {
+    let (hub, _) = hub(SlowConsumers::Drop);
+    hub.register(Topic::new("weather").retain_last().telemetry());
+    hub.publish("weather", json!(1));
+    hub.publish("weather", json!(2));
+
+    let mut connection = hub.connect(None);
+    connection.subscribe("weather").unwrap();
+    let push = next(&mut connection).await.unwrap();
+    assert_eq!(push.envelope.message, Message::Publish(json!(2)));
+    assert!(push.telemetry);
+}
+
+#[tokio::test]
+async fn test_slow_consumers_lose_messages() {
+    let (hub, metrics) = hub(SlowConsumers::Drop);
+    let mut connection = hub.connect(None);
+    connection.subscribe("weather").unwrap();
+    for n in 0..3 {
+        hub.publish("weather", json!(n));
+    }
+
+    assert_eq!(next(&mut connection).await.unwrap().envelope.message, Message::Publish(json!(0)));
+    assert_eq!(next(&mut connection).await.unwrap().envelope.message, Message::Publish(json!(1)));
+    assert!(metrics.encode().contains("hudwiz_realtime_topic_dropped_total 1"));
+    hub.publish("weather", json!(3));
+    assert_eq!(next(&mut connection).await.unwrap().envelope.message, Message::Publish(json!(3)));
+}
+
+#[tokio::test]
+async fn test_slow_consumers_are_disconnected() {
+    let (hub, metrics) = hub(SlowConsumers::Disconnect);
+    let mut slow = hub.connect(None);
+    let mut fast = hub.connect(None);
+    slow.subscribe("weather").unwrap();
+    fast.subscribe("weather").unwrap();
+    for n in 0..2 {
+        hub.publish("weather", json!(n));
+    }
+    next(&mut fast).await.unwrap();
+    next(&mut fast).await.unwrap();
+
+    assert_eq!(hub.publish("weather", json!(2)), 1);
+    assert!(next(&mut slow).await.is_none());
+    assert_eq!(next(&mut fast).await.unwrap().envelope.message, Message::Publish(json!(2)));
+    assert!(metrics.encode().contains("hudwiz_realtime_slow_disconnects_total 1"));
+}
+
+/// Opens `/ws`, logged in with `cookie` if given, past the `Session` message.
+async fn open(addr: SocketAddr, cookie: Option<&str>) -> Socket {
+    let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+    if let Some(cookie) = cookie {
+        request.headers_mut().insert(header::COOKIE, cookie.parse().unwrap());
+    }
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    let greeting = receive(&mut socket).await;
+    assert!(matches!(greeting.message, Message::Session { .. }), "{:?}", greeting);
+    socket
+}
+
+async fn receive(socket: &mut Socket) -> Envelope {
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("no message")
+        .unwrap()
+        .unwrap();
+    Envelope::decode(message.to_text().unwrap()).unwrap()
+}
+
+async fn send(socket: &mut Socket, request: &Envelope) -> Envelope {
+    socket.send(tungstenite::Message::text(request.encode())).await.unwrap();
+    let reply = receive(socket).await;
+    assert_eq!(reply.correlation_id.as_ref(), Some(&request.id));
+    reply
+}
+
+#[tokio::test]
+async fn test_subscribe_messages_are_authorized() {
+    let (addr, _) = spawn().await;
+    let mut socket = open(addr, None).await;
+
+    for topic in ["layouts", "system.stats"] {
+        let reply = send(&mut socket, &Envelope::subscribe(topic)).await;
+        assert_eq!(reply.message, Message::Error { message: "Not logged in".to_string() });
+    }
+    let reply = send(&mut socket, &Envelope::subscribe("weather")).await;
+    assert_eq!(reply.message, Message::Error { message: "Unknown topic 'weather'".to_string() });
+    let reply = send(&mut socket, &Envelope::new(Message::Subscribe)).await;
+    assert!(matches!(reply.message, Message::Error { .. }), "{:?}", reply);
+}
+
+#[tokio::test]
+async fn test_layout_changes_are_published() {
+    let (addr, services) = spawn().await;
+    services.user_store.update("testuser", |user| user.role = Role::Admin).await.unwrap();
+    let cookie = login(addr, "testuser", "password").await;
+    let mut socket = open(addr, Some(&cookie)).await;
+    assert_eq!(send(&mut socket, &Envelope::subscribe("layouts")).await.message, Message::Ack);
+
+    let res = reqwest::Client::new()
+        .put(format!("http://{}/api/layouts/ops", addr))
+        .header(header::COOKIE, &cookie)
+        .json(&json!({"layout": {}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let push = receive(&mut socket).await;
+    assert_eq!(push.topic.as_deref(), Some("layouts"));
+    assert_eq!(push.message, Message::Publish(json!({"name": "ops", "deleted": false})));
+
+    assert_eq!(send(&mut socket, &Envelope::unsubscribe("layouts")).await.message, Message::Ack);
+    let res = reqwest::Client::new()
+        .delete(format!("http://{}/api/layouts/ops", addr))
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let request = Envelope::new(Message::Text("still there".to_string()));
+    assert_eq!(send(&mut socket, &request).await.message, request.message);
+}
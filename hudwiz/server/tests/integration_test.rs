@@ -1,10 +1,10 @@
-use axum::http::StatusCode;
-use server_lib::{app_router, auth::Credentials};
+use axum::http::{header, StatusCode};
+use server_lib::{app_router, app_router_with_config, auth::Credentials, config::load_config, Services};
 use tokio::net::TcpListener;
 
 #[tokio::test]
 async fn test_sso_login() {
-    let app = app_router();
+    let app = app_router().unwrap();
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
 
@@ -36,7 +36,7 @@ async fn test_sso_login() {
 
 #[tokio::test]
 async fn test_login() {
-    let app = app_router();
+    let app = app_router().unwrap();
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
 
@@ -70,3 +70,55 @@ async fn test_login() {
         .unwrap();
     assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
 }
+
+#[tokio::test]
+async fn test_disabling_a_user_ends_their_sessions() {
+    let config = load_config().unwrap();
+    let services = Services::open(&config).unwrap();
+    let app = app_router_with_config(config, services.clone()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post(format!("http://{}/api/auth/login", addr))
+        .json(&Credentials {
+            username: "testuser".to_string(),
+            password: "password".to_string(),
+        })
+        .send()
+        .await
+        .unwrap();
+    let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+    let user_url = format!("http://{}/api/auth/user", addr);
+    let res = client.get(&user_url).header(header::COOKIE, &cookie).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    services.user_store.update("testuser", |user| user.disabled = true).await.unwrap();
+    let res = client.get(&user_url).header(header::COOKIE, &cookie).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(services.sessions.len(), 0);
+}
+
+#[tokio::test]
+async fn test_realtime_info_without_webtransport() {
+    let app = app_router().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let res = reqwest::get(format!("http://{}/api/realtime/info", addr))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let info: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(info["websocket_path"], "/ws");
+    assert_eq!(info["sse_path"], "/sse");
+    assert!(info["webtransport"].is_null());
+}
@@ -0,0 +1,335 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use common::{add_user, login, spawn, spawn_with};
+use serde_json::{json, Value};
+use server_lib::{
+    auth::Role,
+    config::{load_config, load_config_from},
+    layouts::{Layout, LayoutStore, StoredLayout},
+    Services,
+};
+use std::{fs, net::SocketAddr, path::PathBuf};
+
+async fn make_admin(services: &Services, username: &str) {
+    services.user_store.update(username, |user| user.role = Role::Admin).await.unwrap();
+}
+
+async fn put(addr: SocketAddr, cookie: &str, path: &str, body: Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .put(format!("http://{}{}", addr, path))
+        .header(header::COOKIE, cookie)
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn current(addr: SocketAddr, cookie: &str) -> Value {
+    let res = reqwest::Client::new()
+        .get(format!("http://{}/api/layout/current", addr))
+        .header(header::COOKIE, cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    res.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_requires_login() {
+    let (addr, _) = spawn().await;
+    for path in ["/api/layout/current", "/api/layouts", "/api/layouts/standard", "/api/layout/override"] {
+        let res = reqwest::get(format!("http://{}{}", addr, path)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_standard_layout_matches_static_config() {
+    let (addr, _) = spawn().await;
+    let cookie = login(addr, "testuser", "password").await;
+    let body = current(addr, &cookie).await;
+    assert_eq!(body["applied"], json!(["default: standard"]));
+
+    let static_config: Value = serde_json::from_str(
+        &fs::read_to_string(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../frontend/static/config.json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(body["layout"], static_config["layout"]);
+    assert_eq!(serde_json::to_value(Layout::standard()).unwrap(), static_config);
+}
+
+#[tokio::test]
+async fn test_admins_manage_named_layouts() {
+    let (addr, services) = spawn().await;
+    add_user(&services, "regular", Role::User).await;
+    make_admin(&services, "testuser").await;
+    let admin = login(addr, "testuser", "password").await;
+    let regular = login(addr, "regular", "secret").await;
+    let client = reqwest::Client::new();
+    let layout = json!({ "layout": { "main_panel": { "component": "BentoGrid" }, "hud_top_left": null } });
+
+    let res = put(addr, &regular, "/api/layouts/operations", layout.clone()).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert!(res.json::<Value>().await.unwrap()["error"].is_string());
+
+    assert_eq!(put(addr, &admin, "/api/layouts/operations", layout.clone()).await.status(), StatusCode::CREATED);
+    assert_eq!(put(addr, &admin, "/api/layouts/operations", layout.clone()).await.status(), StatusCode::OK);
+    assert_eq!(put(addr, &admin, "/api/layouts/Not%20a%20name", layout.clone()).await.status(), StatusCode::BAD_REQUEST);
+
+    let res = client
+        .get(format!("http://{}/api/layouts/operations", addr))
+        .header(header::COOKIE, &regular)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.json::<Value>().await.unwrap(), layout);
+    let res = client
+        .get(format!("http://{}/api/layouts", addr))
+        .header(header::COOKIE, &regular)
+        .send()
+        .await
+        .unwrap();
+    let list: Value = res.json().await.unwrap();
+    assert_eq!(list[0]["name"], "operations");
+    assert_eq!(list[0]["updated_by"], "testuser");
+
+    let delete = |cookie: &str| {
+        client
+            .delete(format!("http://{}/api/layouts/operations", addr))
+            .header(header::COOKIE, cookie)
+            .send()
+    };
+    assert_eq!(delete(&regular).await.unwrap().status(), StatusCode::FORBIDDEN);
+    assert_eq!(delete(&admin).await.unwrap().status(), StatusCode::NO_CONTENT);
+    assert_eq!(delete(&admin).await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_invalid_layouts_are_rejected() {
+    let (addr, services) = spawn().await;
+    make_admin(&services, "testuser").await;
+    let cookie = login(addr, "testuser", "password").await;
+    for (document, problem) in [
+        (json!({ "layout": { "chat_window": { "component": "BentoGrid" } } }), "chat_window"),
+        (json!({ "layout": { "main_panel": { "component": "Minesweeper" } } }), "Minesweeper"),
+        (json!({ "layout": {}, "theme": "dark" }), "theme"),
+        (json!({ "regions": {} }), "regions"),
+    ] {
+        for path in ["/api/layouts/broken", "/api/layout/override"] {
+            let res = put(addr, &cookie, path, document.clone()).await;
+            assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{} {}", path, document);
+            let body: Value = res.json().await.unwrap();
+            assert!(body["error"].as_str().unwrap().contains(problem), "{}: {}", document, body);
+        }
+    }
+
+    let res = put(addr, &cookie, "/api/layout/override", json!({ "layout": { "main_panel": { "component": "Minesweeper" } } })).await;
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["problems"][0]["path"], "/layout/main_panel/component", "{}", body);
+}
+
+#[tokio::test]
+async fn test_invalid_stored_layouts_are_skipped() {
+    let (addr, services) = spawn().await;
+    let cookie = login(addr, "testuser", "password").await;
+    let mut broken = Layout::standard();
+    broken.layout.get_mut("main_panel").unwrap().as_mut().unwrap().component = "Minesweeper".to_string();
+    assert_eq!(broken.problems()[0].path, "/layout/main_panel/component");
+    services
+        .layouts
+        .put(
+            "standard",
+            StoredLayout {
+                layout: broken,
+                updated_at: 0,
+                updated_by: "testuser".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+
+    let body = current(addr, &cookie).await;
+    assert_eq!(body["applied"], json!([]));
+    assert_eq!(body["layout"], json!({}));
+}
+
+/// A static directory whose `static/config.json` holds `contents`.
+#[cfg(not(feature = "embed-assets"))]
+fn static_dir(contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hudwiz-static-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(dir.join("static")).unwrap();
+    fs::write(dir.join("static/config.json"), contents).unwrap();
+    dir
+}
+
+#[tokio::test]
+#[cfg(not(feature = "embed-assets"))]
+async fn test_static_config_is_served_when_valid() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../frontend/static/config.json");
+    let contents = fs::read_to_string(path).unwrap();
+    let mut config = load_config().unwrap();
+    config.server.static_dir = static_dir(&contents);
+    let (addr, _) = spawn_with(config).await;
+
+    let res = reqwest::get(format!("http://{}/api/config", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<Value>().await.unwrap(), serde_json::from_str::<Value>(&contents).unwrap());
+
+    let res = reqwest::get(format!("http://{}/api/layout/schema", addr)).await.unwrap();
+    let schema: Value = res.json().await.unwrap();
+    let components = schema["$defs"]["ComponentType"]["enum"].as_array().unwrap();
+    assert!(components.contains(&json!("ChatHistoryView")), "{}", schema);
+}
+
+#[tokio::test]
+#[cfg(not(feature = "embed-assets"))]
+async fn test_invalid_static_config_is_refused() {
+    for (contents, path) in [
+        (r#"{"layout": {"main_panel": {"component": "Minesweeper"}}}"#, "/layout/main_panel/component"),
+        (r#"{"layout": {"main_panel": {"componnet": "BentoGrid"}}}"#, "/layout/main_panel"),
+        (r#"{"layout": {"chat_window": {"component": "BentoGrid"}}}"#, "/layout"),
+        (r#"{"layout": "#, ""),
+    ] {
+        let mut config = load_config().unwrap();
+        config.server.static_dir = static_dir(contents);
+        let (addr, _) = spawn_with(config).await;
+        let res = reqwest::get(format!("http://{}/api/config", addr)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR, "{}", contents);
+        let body: Value = res.json().await.unwrap();
+        assert!(body["error"].is_string());
+        assert_eq!(body["problems"][0]["path"], path, "{}", body);
+    }
+
+    let mut config = load_config().unwrap();
+    config.server.static_dir = std::env::temp_dir().join(format!("hudwiz-static-{}", uuid::Uuid::new_v4()));
+    let (addr, _) = spawn_with(config).await;
+    let res = reqwest::get(format!("http://{}/api/config", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_layouts_merge_by_org_role_and_user() {
+    let mut config = load_config().unwrap();
+    config.layouts.orgs.insert("example.com".to_string(), "field".to_string());
+    config.layouts.roles.insert("admin".to_string(), "operations".to_string());
+    config.layouts.roles.insert("user".to_string(), "missing".to_string());
+    let (addr, services) = spawn_with(config).await;
+    add_user(&services, "alice@example.com", Role::Admin).await;
+    add_user(&services, "bob@other.org", Role::User).await;
+    make_admin(&services, "testuser").await;
+    let admin = login(addr, "testuser", "password").await;
+
+    put(addr, &admin, "/api/layouts/field", json!({ "layout": {
+        "main_panel": { "component": "BentoGrid" },
+        "sidebar_right": { "component": "ChatHistoryView" },
+    } }))
+    .await;
+    put(addr, &admin, "/api/layouts/operations", json!({ "layout": {
+        "main_panel": { "component": "ChatInputBar" },
+        "hud_top_left": null,
+    } }))
+    .await;
+
+    let alice = login(addr, "alice@example.com", "secret").await;
+    let body = current(addr, &alice).await;
+    assert_eq!(
+        body["applied"],
+        json!(["default: standard", "org example.com: field", "role admin: operations"])
+    );
+    assert_eq!(body["layout"]["main_panel"]["component"], "ChatInputBar");
+    assert_eq!(body["layout"]["sidebar_right"]["component"], "ChatHistoryView");
+    assert_eq!(body["layout"]["sidebar_left"]["component"], "ControlsDrawer");
+    assert!(body["layout"].get("hud_top_left").is_none());
+
+    let res = put(addr, &alice, "/api/layout/override", json!({ "layout": {
+        "hud_top_left": { "component": "TopLeftHud" },
+        "sidebar_left": null,
+    } }))
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = current(addr, &alice).await;
+    assert_eq!(body["applied"][3], "user override");
+    assert_eq!(body["layout"]["hud_top_left"]["component"], "TopLeftHud");
+    assert!(body["layout"].get("sidebar_left").is_none());
+
+    // Bob's role layout doesn't exist and his organisation has none.
+    let bob = login(addr, "bob@other.org", "secret").await;
+    let body = current(addr, &bob).await;
+    assert_eq!(body["applied"], json!(["default: standard"]));
+    assert_eq!(body["layout"]["hud_top_left"]["component"], "TopLeftHud");
+
+    let res = reqwest::Client::new()
+        .delete(format!("http://{}/api/layout/override", addr))
+        .header(header::COOKIE, &alice)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(current(addr, &alice).await["applied"].as_array().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_reload_reassigns_layouts() {
+    let server_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let dir = std::env::temp_dir().join(format!("hudwiz-layouts-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server_config.toml");
+    let contents = fs::read_to_string(server_dir.join("server_config.toml")).unwrap().replace(
+        r#"static_dir = "frontend/dist""#,
+        &format!("static_dir = {:?}", server_dir.join("frontend/dist").display().to_string()),
+    );
+    fs::write(&path, contents).unwrap();
+    let (addr, services) = spawn_with(load_config_from(&path).unwrap()).await;
+    make_admin(&services, "testuser").await;
+    let cookie = login(addr, "testuser", "password").await;
+    put(addr, &cookie, "/api/layouts/operations", json!({ "layout": { "main_panel": { "component": "BentoGrid" } } }))
+        .await;
+    assert_eq!(current(addr, &cookie).await["layout"]["main_panel"]["component"], "ChatHistoryView");
+
+    let contents = fs::read_to_string(&path).unwrap();
+    assert!(contents.contains("roles = {}"));
+    fs::write(&path, contents.replacen("roles = {}", r#"roles = { admin = "operations" }"#, 1)).unwrap();
+    assert_eq!(services.config.reload_from(&path).unwrap(), Some(2));
+    assert_eq!(current(addr, &cookie).await["layout"]["main_panel"]["component"], "BentoGrid");
+}
+
+#[test]
+fn test_invalid_assignments_fail_validation() {
+    let mut config = load_config().unwrap();
+    config.layouts.default = "Has Spaces".to_string();
+    config.layouts.roles.insert("owner".to_string(), "operations".to_string());
+    config.layouts.orgs.insert("Example.com".to_string(), "field".to_string());
+    let problems = config.validate().join("\n");
+    for problem in ["layouts.default", "layouts.roles.owner", "layouts.orgs.Example.com"] {
+        assert!(problems.contains(problem), "{} missing from {}", problem, problems);
+    }
+}
+
+#[tokio::test]
+async fn test_store_persists_layouts() {
+    let dir = std::env::temp_dir().join(format!("hudwiz-layouts-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let store = LayoutStore::open(&dir);
+    assert_eq!(store.get("standard").await.unwrap(), Some(Layout::standard()));
+    assert_eq!(store.get("field").await.unwrap(), None);
+    assert!(store.list().await.unwrap().is_empty());
+
+    let field = Layout::standard();
+    let stored = |layout: &Layout| StoredLayout {
+        layout: layout.clone(),
+        updated_at: 0,
+        updated_by: "testuser".to_string(),
+    };
+    assert!(store.put("field", stored(&field)).await.unwrap());
+    store.put_override("user-1", stored(&Layout::default())).await.unwrap();
+
+    let reopened = LayoutStore::open(&dir);
+    assert_eq!(reopened.get("field").await.unwrap(), Some(field));
+    assert_eq!(reopened.get_override("user-1").await.unwrap(), Some(Layout::default()));
+    assert!(reopened.delete("field").await.unwrap());
+    assert!(!reopened.delete("field").await.unwrap());
+    // The first store sees the deletion made through the other.
+    assert_eq!(store.get("field").await.unwrap(), None);
+}
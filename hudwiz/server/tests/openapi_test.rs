@@ -0,0 +1,141 @@
+use axum::{
+    http::{header, StatusCode},
+    Router,
+};
+use serde_json::Value;
+use server_lib::{config::load_config, AppBuilder};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+async fn spawn(app: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+async fn document(addr: SocketAddr) -> Value {
+    let res = reqwest::get(format!("http://{}/api/openapi.json", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+    res.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_document_is_served() {
+    let addr = spawn(AppBuilder::new(load_config().unwrap()).build().unwrap()).await;
+    let spec = document(addr).await;
+
+    assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+    assert_eq!(spec["info"]["title"], "hudwiz");
+    for schema in ["ApiError", "Credentials", "UserInfo", "RealtimeInfo", "Readiness", "ConfigVersion"] {
+        assert!(spec["components"]["schemas"][schema].is_object(), "{} is missing", schema);
+    }
+    let login = &spec["paths"]["/api/auth/login"]["post"];
+    assert_eq!(
+        login["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/Credentials"
+    );
+    assert_eq!(
+        login["responses"]["401"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/ApiError"
+    );
+    assert!(spec.get("servers").is_none());
+}
+
+/// Calls every documented operation and checks that the route exists with
+/// that method and answers with one of the documented responses, so that
+/// changing a handler without its annotation fails here.
+#[tokio::test]
+async fn test_routes_match_document() {
+    let addr = spawn(AppBuilder::new(load_config().unwrap()).build().unwrap()).await;
+    let spec = document(addr).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let mut checked = 0;
+    for (path, item) in spec["paths"].as_object().unwrap() {
+        for (method, operation) in item.as_object().unwrap() {
+            let mut url = format!("http://{}{}", addr, path);
+            let mut query = Vec::new();
+            let mut headers = Vec::new();
+            for param in operation["parameters"].as_array().into_iter().flatten() {
+                let name = param["name"].as_str().unwrap();
+                match param["in"].as_str().unwrap() {
+                    // No provider by that name, which the SSO routes reject
+                    // before talking to anyone.
+                    "path" => url = url.replace(&format!("{{{}}}", name), "unknown"),
+                    "query" => query.push((name.to_string(), "x".to_string())),
+                    "header" => headers.push((name.to_string(), "*".to_string())),
+                    other => panic!("Unexpected parameter location {} in {} {}", other, method, path),
+                }
+            }
+
+            let method = reqwest::Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+            let mut request = client.request(method.clone(), &url).query(&query);
+            for (name, value) in &headers {
+                request = request.header(name, value);
+            }
+            if operation.get("requestBody").is_some() {
+                request = request.json(&serde_json::json!({
+                    "username": format!("drift-{}", uuid::Uuid::new_v4()),
+                    "password": "password",
+                }));
+            }
+            let responses = operation["responses"].as_object().unwrap();
+            if responses.contains_key("101") {
+                request = request
+                    .header(header::CONNECTION, "upgrade")
+                    .header(header::UPGRADE, "websocket")
+                    .header(header::SEC_WEBSOCKET_VERSION, "13")
+                    .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==");
+            }
+            let res = request.send().await.unwrap();
+
+            let status = res.status().as_u16().to_string();
+            let Some(documented) = responses.get(&status) else {
+                panic!("{} {} answered {}, documented are {:?}", method, path, status, responses.keys());
+            };
+            let content_type = res
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .map(|value| value.to_str().unwrap().to_string())
+                .unwrap_or_default();
+            assert!(!content_type.starts_with("text/html"), "{} {} fell through to the assets", method, path);
+            if documented["content"].get("application/json").is_some() {
+                assert!(content_type.starts_with("application/json"), "{} {}: {}", method, path, content_type);
+                let body: Value = res.json().await.unwrap();
+                if documented["content"]["application/json"]["schema"]["$ref"] == "#/components/schemas/ApiError" {
+                    assert!(body["error"].is_string(), "{} {}: {}", method, path, body);
+                }
+            }
+            checked += 1;
+        }
+    }
+    assert!(checked >= 14, "only {} operations are documented", checked);
+}
+
+#[tokio::test]
+async fn test_prefix_is_documented_as_server() {
+    let app = AppBuilder::new(load_config().unwrap()).prefix("/hud").build().unwrap();
+    let addr = spawn(app).await;
+    let res = reqwest::get(format!("http://{}/hud/api/openapi.json", addr)).await.unwrap();
+    let spec: Value = res.json().await.unwrap();
+    assert_eq!(spec["servers"][0]["url"], "/hud");
+    assert!(spec["paths"]["/api/auth/user"].is_object());
+}
+
+#[cfg(feature = "openapi-viewer")]
+#[tokio::test]
+async fn test_viewer() {
+    let addr = spawn(AppBuilder::new(load_config().unwrap()).build().unwrap()).await;
+    let res = reqwest::get(format!("http://{}/api/docs/", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+    let res = reqwest::get(format!("http://{}/api/docs/swagger-initializer.js", addr)).await.unwrap();
+    assert!(res.text().await.unwrap().contains("../openapi.json"));
+}
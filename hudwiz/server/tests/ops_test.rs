@@ -0,0 +1,82 @@
+use axum::{http::StatusCode, Router};
+use server_lib::{app_router_with_config, auth::Credentials, config::load_config, Services};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+async fn spawn(app: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_health_and_readiness() {
+    let config = load_config().unwrap();
+    let services = Services::open(&config).unwrap();
+    let addr = spawn(app_router_with_config(config, services.clone()).unwrap()).await;
+
+    let res = reqwest::get(format!("http://{}/healthz", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = reqwest::get(format!("http://{}/readyz", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["ready"], true);
+    assert_eq!(body["checks"]["storage"], "ok");
+    assert_eq!(body["checks"]["config"], "ok");
+
+    services.shutdown.trigger();
+    let res = reqwest::get(format!("http://{}/readyz", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["checks"]["shutdown"], "shutting down");
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let config = load_config().unwrap();
+    let services = Services::open(&config).unwrap();
+    let addr = spawn(app_router_with_config(config, services).unwrap()).await;
+
+    let client = reqwest::Client::new();
+    for password in ["password", "wrongpassword"] {
+        client
+            .post(format!("http://{}/api/auth/login", addr))
+            .json(&Credentials {
+                username: "testuser".to_string(),
+                password: password.to_string(),
+            })
+            .send()
+            .await
+            .unwrap();
+    }
+
+    let res = client.get(format!("http://{}/metrics", addr)).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers()["content-type"].to_str().unwrap().starts_with("application/openmetrics-text"));
+    let text = res.text().await.unwrap();
+    assert!(text.contains(r#"hudwiz_logins_total{method="password",outcome="success"} 1"#), "{}", text);
+    assert!(text.contains(r#"hudwiz_logins_total{method="password",outcome="failure"} 1"#), "{}", text);
+    assert!(
+        text.contains(r#"hudwiz_http_requests_total{method="POST",route="/api/auth/login",status="401"} 1"#),
+        "{}",
+        text
+    );
+    assert!(text.contains("hudwiz_http_request_duration_seconds_bucket"), "{}", text);
+}
+
+#[tokio::test]
+async fn test_metrics_move_to_admin_addr() {
+    let mut config = load_config().unwrap();
+    config.server.admin_addr = Some("127.0.0.1:0".parse().unwrap());
+    let services = Services::open(&config).unwrap();
+    let addr = spawn(app_router_with_config(config, services).unwrap()).await;
+
+    let res = reqwest::get(format!("http://{}/healthz", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let text = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap().text().await.unwrap();
+    assert!(!text.contains("hudwiz_"));
+}
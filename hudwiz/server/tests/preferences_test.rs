@@ -0,0 +1,176 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use common::{add_user, login, spawn};
+use serde_json::json;
+use server_lib::{
+    auth::Role,
+    preferences::{Panel, PreferenceStore, PreferenceStoreError, Preferences},
+};
+
+fn etag(res: &reqwest::Response) -> &str {
+    res.headers()[header::ETAG].to_str().unwrap()
+}
+
+#[tokio::test]
+async fn test_requires_login() {
+    let (addr, _) = spawn().await;
+    let url = format!("http://{}/api/user/preferences", addr);
+    let res = reqwest::get(&url).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["error"], "Not logged in");
+
+    let res = reqwest::Client::new()
+        .put(&url)
+        .header(header::IF_MATCH, "*")
+        .json(&json!({ "version": 1 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_optimistic_concurrency() {
+    let (addr, _) = spawn().await;
+    let cookie = login(addr, "testuser", "password").await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api/user/preferences", addr);
+    let get = |if_none_match: Option<&str>| {
+        let mut request = client.get(&url).header(header::COOKIE, &cookie);
+        if let Some(tag) = if_none_match {
+            request = request.header(header::IF_NONE_MATCH, tag);
+        }
+        request.send()
+    };
+    let put = |if_match: Option<&str>, body: serde_json::Value| {
+        let mut request = client.put(&url).header(header::COOKIE, &cookie).json(&body);
+        if let Some(tag) = if_match {
+            request = request.header(header::IF_MATCH, tag);
+        }
+        request.send()
+    };
+
+    // Nothing saved yet: the defaults, at revision 0.
+    let res = get(None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(etag(&res), "\"0\"");
+    assert_eq!(res.json::<serde_json::Value>().await.unwrap(), json!({ "version": 1 }));
+
+    let document = json!({
+        "version": 1,
+        "theme": "light",
+        "language": "de-DE",
+        "background_pattern": "grid",
+        "background_content": { "kind": "image", "source": "/static/images/background_content.jpg" },
+        "collapsed": { "sidebar_left": false, "activity_bar": true },
+    });
+    let res = put(None, document.clone()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+
+    let res = put(Some("\"0\""), document.clone()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(etag(&res), "\"1\"");
+
+    let res = get(None).await.unwrap();
+    assert_eq!(etag(&res), "\"1\"");
+    assert_eq!(res.json::<serde_json::Value>().await.unwrap(), document);
+    let res = get(Some("\"1\"")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+    // Another device still holding revision 0 can't overwrite revision 1.
+    let res = put(Some("\"0\""), json!({ "version": 1, "theme": "dark" })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(etag(&res), "\"1\"");
+    let res = get(None).await.unwrap();
+    assert_eq!(res.json::<serde_json::Value>().await.unwrap()["theme"], "light");
+
+    let res = put(Some("*"), json!({ "version": 1, "theme": "dark" })).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(etag(&res), "\"2\"");
+}
+
+#[tokio::test]
+async fn test_invalid_documents_are_rejected() {
+    let (addr, _) = spawn().await;
+    let cookie = login(addr, "testuser", "password").await;
+    let client = reqwest::Client::new();
+    let put = |body: String| {
+        client
+            .put(format!("http://{}/api/user/preferences", addr))
+            .header(header::COOKIE, &cookie)
+            .header(header::IF_MATCH, "*")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+    };
+
+    for (document, problem) in [
+        (json!({ "version": 2 }), "version"),
+        (json!({ "version": 1, "language": "not a language" }), "language"),
+        (json!({ "version": 1, "theme": "" }), "theme"),
+        (json!({ "version": 1, "background_content": { "kind": "iframe", "source": "javascript:alert(1)" } }), "background_content"),
+        (json!({ "version": 1, "collapsed": { "chat_window": true } }), "chat_window"),
+        (json!({ "version": 1, "colour": "red" }), "colour"),
+        (json!({ "theme": "dark" }), "version"),
+    ] {
+        let res = put(document.to_string()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", document);
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert!(body["error"].as_str().unwrap().contains(problem), "{}: {}", document, body);
+    }
+
+    let res = put(json!({ "version": 1, "theme": "x".repeat(20_000) }).to_string()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn test_preferences_are_per_user() {
+    let (addr, services) = spawn().await;
+    add_user(&services, "other", Role::User).await;
+    let first = login(addr, "testuser", "password").await;
+    let second = login(addr, "other", "secret").await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api/user/preferences", addr);
+
+    let res = client
+        .put(&url)
+        .header(header::COOKIE, &first)
+        .header(header::IF_MATCH, "\"0\"")
+        .json(&json!({ "version": 1, "theme": "light" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = client.get(&url).header(header::COOKIE, &second).send().await.unwrap();
+    assert_eq!(etag(&res), "\"0\"");
+    assert_eq!(res.json::<serde_json::Value>().await.unwrap(), json!({ "version": 1 }));
+}
+
+#[tokio::test]
+async fn test_store_persists_revisions() {
+    let dir = std::env::temp_dir().join(format!("hudwiz-preferences-{}", uuid::Uuid::new_v4()));
+    let store = PreferenceStore::open(&dir);
+    let mut document = Preferences {
+        theme: Some("light".to_string()),
+        ..Preferences::default()
+    };
+    document.collapsed.insert(Panel::HudTopLeft, false);
+    assert_eq!(store.put("user-1", Some(0), document.clone()).await.unwrap().revision, 1);
+
+    let reopened = PreferenceStore::open(&dir);
+    let stored = reopened.get("user-1").await.unwrap();
+    assert_eq!(stored.revision, 1);
+    assert_eq!(stored.preferences, document);
+    assert_eq!(reopened.get("user-2").await.unwrap().revision, 0);
+
+    match reopened.put("user-1", Some(0), Preferences::default()).await {
+        Err(PreferenceStoreError::Conflict(current)) => assert_eq!(current.revision, 1),
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(reopened.put("user-1", None, Preferences::default()).await.unwrap().revision, 2);
+    // The first store sees the change made through the other.
+    assert_eq!(store.get("user-1").await.unwrap().revision, 2);
+}
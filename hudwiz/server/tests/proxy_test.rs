@@ -0,0 +1,244 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect},
+    routing::get,
+    Router,
+};
+use serde_json::Value;
+use server_lib::{
+    app_router_with_config,
+    auth::Credentials,
+    config::{load_config, Config},
+    Services,
+};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::net::TcpListener;
+
+const VIDEO: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// A third-party site, counting the requests it serves.
+async fn spawn_upstream() -> (String, Arc<AtomicUsize>) {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let app = Router::new()
+        .route(
+            "/embed.html",
+            get(|headers: HeaderMap| async move {
+                let cookie = if headers.contains_key(header::COOKIE) { "cookie" } else { "no cookie" };
+                (
+                    [
+                        (header::CONTENT_TYPE, "text/html"),
+                        (header::X_FRAME_OPTIONS, "DENY"),
+                        (header::CONTENT_SECURITY_POLICY, "frame-ancestors 'none'"),
+                        (header::SET_COOKIE, "tracker=1"),
+                    ],
+                    format!("<p>{}</p>", cookie),
+                )
+            }),
+        )
+        .route(
+            "/video.mp4",
+            get(|State(hits): State<Arc<AtomicUsize>>, headers: HeaderMap| async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                let range = headers.get(header::RANGE).and_then(|value| value.to_str().ok());
+                let Some((start, end)) = range
+                    .and_then(|range| range.strip_prefix("bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .and_then(|(start, end)| Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?)))
+                else {
+                    return ([(header::CONTENT_TYPE, "video/mp4")], VIDEO).into_response();
+                };
+                (
+                    StatusCode::PARTIAL_CONTENT,
+                    [
+                        (header::CONTENT_TYPE, "video/mp4".to_string()),
+                        (header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, VIDEO.len())),
+                    ],
+                    &VIDEO[start..=end],
+                )
+                    .into_response()
+            }),
+        )
+        .route(
+            "/greeting",
+            get(|State(hits): State<Arc<AtomicUsize>>, headers: HeaderMap| async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                let greeting = match headers.get(header::ACCEPT_LANGUAGE).and_then(|value| value.to_str().ok()) {
+                    Some("de") => "Hallo",
+                    _ => "Hello",
+                };
+                ([(header::VARY, "Accept-Language")], greeting)
+            }),
+        )
+        .route(
+            "/per-browser",
+            get(|State(hits): State<Arc<AtomicUsize>>| async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                ([(header::VARY, "User-Agent")], "For you")
+            }),
+        )
+        .route("/elsewhere", get(|| async { Redirect::temporary("http://example.invalid/") }))
+        .with_state(hits.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    (format!("http://{}", addr), hits)
+}
+
+async fn spawn_with(config: Config) -> SocketAddr {
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+/// A server proxying `upstream`, and a logged in session cookie.
+async fn spawn(upstream: &str) -> (SocketAddr, String) {
+    let mut config = load_config().unwrap();
+    config.proxy.upstreams = vec![upstream.to_string()];
+    config.proxy.cache_entry_bytes = 16;
+    let addr = spawn_with(config).await;
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/api/auth/login", addr))
+        .json(&Credentials {
+            username: "testuser".to_string(),
+            password: "password".to_string(),
+        })
+        .send()
+        .await
+        .unwrap();
+    let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+    (addr, cookie)
+}
+
+async fn get_proxied(addr: SocketAddr, cookie: &str, url: &str, range: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new()
+        .get(format!("http://{}/api/proxy", addr))
+        .query(&[("url", url)])
+        .header(header::COOKIE, cookie);
+    if let Some(range) = range {
+        request = request.header(header::RANGE, range);
+    }
+    request.send().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_refusals() {
+    let (upstream, _) = spawn_upstream().await;
+    let (addr, cookie) = spawn(&upstream).await;
+
+    let res = reqwest::get(format!("http://{}/api/proxy?url={}/embed.html", addr, upstream)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    for (url, status) in [
+        ("https://example.com/", StatusCode::FORBIDDEN),
+        ("/embed.html", StatusCode::BAD_REQUEST),
+        ("file:///etc/passwd", StatusCode::BAD_REQUEST),
+        (&format!("{}/elsewhere", upstream), StatusCode::BAD_GATEWAY),
+    ] {
+        let res = get_proxied(addr, &cookie, url, None).await;
+        assert_eq!(res.status(), status, "{}", url);
+        assert!(res.json::<Value>().await.unwrap()["error"].is_string());
+    }
+
+    let mut config = load_config().unwrap();
+    config.proxy.upstreams.clear();
+    let off = spawn_with(config).await;
+    let res = reqwest::get(format!("http://{}/api/proxy/upstreams", off)).await.unwrap();
+    assert_eq!(res.json::<Value>().await.unwrap()["upstreams"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn test_pages_may_be_framed_in_a_sandbox() {
+    let (upstream, _) = spawn_upstream().await;
+    let (addr, cookie) = spawn(&upstream).await;
+
+    let res = reqwest::get(format!("http://{}/api/proxy/upstreams", addr)).await.unwrap();
+    assert_eq!(res.json::<Value>().await.unwrap()["upstreams"][0], upstream);
+
+    let res = get_proxied(addr, &cookie, &format!("{}/embed.html", upstream), None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let headers = res.headers();
+    assert!(!headers.contains_key(header::X_FRAME_OPTIONS));
+    assert!(!headers.contains_key(header::SET_COOKIE));
+    let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+    assert!(csp.starts_with("sandbox allow-scripts"), "{}", csp);
+    assert!(csp.contains("frame-ancestors 'self'"), "{}", csp);
+    // The session cookie isn't passed on.
+    assert_eq!(res.text().await.unwrap(), "<p>no cookie</p>");
+}
+
+#[tokio::test]
+async fn test_media_ranges_are_cached() {
+    let (upstream, hits) = spawn_upstream().await;
+    let (addr, cookie) = spawn(&upstream).await;
+    let video = format!("{}/video.mp4", upstream);
+
+    for _ in 0..2 {
+        let res = get_proxied(addr, &cookie, &video, Some("bytes=0-9")).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 0-9/36");
+        assert_eq!(res.headers()[header::CONTENT_SECURITY_POLICY], "default-src 'none'; frame-ancestors 'self'");
+        assert_eq!(res.bytes().await.unwrap(), &VIDEO[..10]);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    let res = get_proxied(addr, &cookie, &video, Some("bytes=10-19")).await;
+    assert_eq!(res.bytes().await.unwrap(), &VIDEO[10..20]);
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // Bodies over proxy.cache_entry_bytes are streamed every time.
+    for _ in 0..2 {
+        let res = get_proxied(addr, &cookie, &video, None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.bytes().await.unwrap(), VIDEO);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn test_variants_are_cached_apart() {
+    let (upstream, hits) = spawn_upstream().await;
+    let (addr, cookie) = spawn(&upstream).await;
+    let greet = |language: &'static str| {
+        reqwest::Client::new()
+            .get(format!("http://{}/api/proxy", addr))
+            .query(&[("url", format!("{}/greeting", upstream))])
+            .header(header::COOKIE, &cookie)
+            .header(header::ACCEPT_LANGUAGE, language)
+            .send()
+    };
+
+    for (language, greeting) in [("en", "Hello"), ("de", "Hallo"), ("en", "Hello"), ("de", "Hallo")] {
+        assert_eq!(greet(language).await.unwrap().text().await.unwrap(), greeting);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    // Responses varying by headers that aren't passed on aren't cached.
+    let url = format!("{}/per-browser", upstream);
+    for _ in 0..2 {
+        assert_eq!(get_proxied(addr, &cookie, &url, None).await.text().await.unwrap(), "For you");
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn test_upstreams_must_be_origins() {
+    let mut config = load_config().unwrap();
+    config.proxy.upstreams = ["https://example.com/path", "ftp://example.com", "example.com", "https://example.com"]
+        .map(String::from)
+        .to_vec();
+    let problems = config.validate();
+    assert_eq!(problems.iter().filter(|problem| problem.starts_with("proxy.upstreams")).count(), 3, "{:?}", problems);
+}
@@ -0,0 +1,181 @@
+use axum::http::{header, StatusCode};
+use futures::{SinkExt, StreamExt};
+use protocol::Envelope;
+use serde_json::Value;
+use server_lib::{
+    app_router_with_config,
+    auth::Credentials,
+    config::{load_config, Config, RateLimitGroup, TokenBucket},
+    Services,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Limits of `burst` with a refill too slow to matter during a test.
+fn limit(burst: u32) -> Option<TokenBucket> {
+    Some(TokenBucket { per_sec: 0.001, burst })
+}
+
+/// Serves `config` with connection addresses, as `serve` does.
+async fn spawn_with(config: Config) -> SocketAddr {
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+    addr
+}
+
+async fn get(addr: SocketAddr, path: &str, cookie: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", addr, path));
+    if let Some(cookie) = cookie {
+        request = request.header(header::COOKIE, cookie);
+    }
+    request.send().await.unwrap()
+}
+
+async fn assert_limited(res: reqwest::Response) {
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = res.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+    assert!(retry_after >= 1);
+    assert!(res.json::<Value>().await.unwrap()["error"].is_string());
+}
+
+#[tokio::test]
+async fn test_api_requests_are_limited_per_ip() {
+    let mut config = load_config().unwrap();
+    config.rate_limits.api = RateLimitGroup {
+        per_ip: limit(3),
+        per_user: None,
+    };
+    let addr = spawn_with(config).await;
+
+    for _ in 0..3 {
+        assert_eq!(get(addr, "/api/realtime/info", None).await.status(), StatusCode::OK);
+    }
+    assert_limited(get(addr, "/api/realtime/info", None).await).await;
+    // Only /api counts.
+    assert_eq!(get(addr, "/healthz", None).await.status(), StatusCode::OK);
+
+    let metrics = get(addr, "/metrics", None).await.text().await.unwrap();
+    assert!(metrics.contains(r#"rate_limited_total{group="api",scope="ip"} 1"#), "{}", metrics);
+    assert!(metrics.contains(r#"rate_limit_buckets{group="api",scope="ip"} 1"#), "{}", metrics);
+}
+
+#[tokio::test]
+async fn test_logins_are_limited_separately() {
+    let mut config = load_config().unwrap();
+    config.rate_limits.auth = RateLimitGroup {
+        per_ip: limit(1),
+        per_user: None,
+    };
+    config.rate_limits.api = RateLimitGroup {
+        per_ip: None,
+        per_user: limit(2),
+    };
+    let addr = spawn_with(config).await;
+    let login = || {
+        reqwest::Client::new()
+            .post(format!("http://{}/api/auth/login", addr))
+            .json(&Credentials {
+                username: "testuser".to_string(),
+                password: "password".to_string(),
+            })
+            .send()
+    };
+
+    let res = login().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+    assert_limited(login().await.unwrap()).await;
+
+    for _ in 0..2 {
+        assert_eq!(get(addr, "/api/auth/user", Some(&cookie)).await.status(), StatusCode::OK);
+    }
+    assert_limited(get(addr, "/api/auth/user", Some(&cookie)).await).await;
+    // Without the session the user's limit doesn't apply.
+    assert_eq!(get(addr, "/api/auth/user", None).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_websocket_messages_are_throttled() {
+    let mut config = load_config().unwrap();
+    config.rate_limits.realtime = RateLimitGroup {
+        per_ip: limit(2),
+        per_user: None,
+    };
+    let addr = spawn_with(config).await;
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+    // The `Session` message.
+    socket.next().await.unwrap().unwrap();
+
+    let mut replies = Vec::new();
+    for text in ["one", "two", "three"] {
+        let message = Envelope::new(protocol::Message::Text(text.to_string()));
+        socket.send(Message::Text(message.encode().into())).await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no reply")
+            .unwrap()
+            .unwrap();
+        let reply = Envelope::decode(reply.to_text().unwrap()).unwrap();
+        assert_eq!(reply.correlation_id, Some(message.id));
+        replies.push(reply.message);
+    }
+    assert_eq!(replies[0], protocol::Message::Text("one".to_string()));
+    assert_eq!(replies[1], protocol::Message::Text("two".to_string()));
+    let protocol::Message::Throttled { retry_after_ms } = replies[2] else {
+        panic!("not throttled: {:?}", replies[2]);
+    };
+    assert!(retry_after_ms > 0);
+}
+
+#[tokio::test]
+async fn test_sse_posts_are_limited() {
+    let mut config = load_config().unwrap();
+    config.rate_limits.realtime = RateLimitGroup {
+        per_ip: limit(1),
+        per_user: None,
+    };
+    let addr = spawn_with(config).await;
+    let mut events = get(addr, "/sse", None).await;
+    let chunk = String::from_utf8(events.chunk().await.unwrap().unwrap().to_vec()).unwrap();
+    let data = chunk.lines().find_map(|line| line.strip_prefix("data:")).unwrap();
+    let session_id = serde_json::from_str::<Value>(data.trim()).unwrap()["session_id"].as_str().unwrap().to_string();
+
+    let post = || {
+        reqwest::Client::new()
+            .post(format!("http://{}/sse/{}", addr, session_id))
+            .body("hello")
+            .send()
+    };
+    assert_eq!(post().await.unwrap().status(), StatusCode::ACCEPTED);
+    assert_limited(post().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_disabled_limits_let_everything_through() {
+    let mut config = load_config().unwrap();
+    config.rate_limits.enabled = false;
+    config.rate_limits.api = RateLimitGroup {
+        per_ip: limit(1),
+        per_user: None,
+    };
+    let addr = spawn_with(config).await;
+    for _ in 0..3 {
+        assert_eq!(get(addr, "/api/realtime/info", None).await.status(), StatusCode::OK);
+    }
+}
+
+#[test]
+fn test_buckets_must_be_positive() {
+    let mut config = load_config().unwrap();
+    config.rate_limits.api.per_ip = Some(TokenBucket { per_sec: 0.0, burst: 0 });
+    config.rate_limits.realtime.per_user = Some(TokenBucket { per_sec: f64::NAN, burst: 1 });
+    let problems = config.validate();
+    let limits: Vec<_> = problems.iter().filter(|problem| problem.starts_with("rate_limits.")).collect();
+    assert_eq!(limits.len(), 3, "{:?}", problems);
+}
@@ -0,0 +1,154 @@
+use futures::{SinkExt, StreamExt};
+use protocol::{ContentType, Envelope, Message};
+use server_lib::{app_router_with_config, config::load_config, Services};
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn spawn() -> SocketAddr {
+    let mut config = load_config().unwrap();
+    config.realtime.replay_len = 2;
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+/// Opens `/ws` with `query` and reads the `Session` message, returning the
+/// session's id and whether it was resumed.
+async fn open(addr: SocketAddr, query: &str) -> (Socket, String, bool) {
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws{}", addr, query)).await.unwrap();
+    let greeting = receive(&mut socket).await;
+    assert_eq!(greeting.seq, None);
+    let Message::Session { session_id, resumed } = greeting.message else {
+        panic!("expected a Session message, got {:?}", greeting);
+    };
+    (socket, session_id, resumed)
+}
+
+async fn connect() -> Socket {
+    open(spawn().await, "").await.0
+}
+
+async fn receive(socket: &mut Socket) -> Envelope {
+    let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("no message")
+        .unwrap()
+        .unwrap();
+    Envelope::decode(message.to_text().unwrap()).unwrap()
+}
+
+/// Sends `frame` and decodes the envelope that answers it.
+async fn send(socket: &mut Socket, frame: tungstenite::Message) -> Envelope {
+    socket.send(frame).await.unwrap();
+    receive(socket).await
+}
+
+async fn send_text(socket: &mut Socket, text: &str) -> Envelope {
+    let message = Envelope::new(Message::Text(text.to_string()));
+    send(socket, tungstenite::Message::text(message.encode())).await
+}
+
+#[tokio::test]
+async fn test_text_is_echoed_in_reply() {
+    let mut socket = connect().await;
+    let request = Envelope::new(Message::Text("hello".to_string()));
+    let reply = send(&mut socket, tungstenite::Message::text(request.encode())).await;
+    assert_eq!(reply.version, protocol::VERSION);
+    assert_eq!(reply.message, Message::Text("hello".to_string()));
+    assert_eq!(reply.correlation_id, Some(request.id.clone()));
+    assert_ne!(reply.id, request.id);
+}
+
+#[tokio::test]
+async fn test_invalid_messages_are_answered_with_errors() {
+    let mut socket = connect().await;
+
+    let content = Envelope::new(Message::SetMainContent(ContentType::Empty));
+    let reply = send(&mut socket, tungstenite::Message::text(content.encode())).await;
+    assert!(matches!(reply.message, Message::Error { .. }), "{:?}", reply);
+    assert_eq!(reply.correlation_id, Some(content.id));
+
+    let newer = r#"{"version":2,"id":"1","type":"Text","payload":"hi"}"#;
+    let unknown = r#"{"version":1,"id":"1","type":"Shout","payload":"hi"}"#;
+    let frames = [
+        tungstenite::Message::text("hello"),
+        tungstenite::Message::text(newer),
+        tungstenite::Message::text(unknown),
+        tungstenite::Message::binary(b"hello".to_vec()),
+    ];
+    for frame in frames {
+        let reply = send(&mut socket, frame.clone()).await;
+        let Message::Error { message } = &reply.message else {
+            panic!("{:?} was answered with {:?}", frame, reply);
+        };
+        assert!(!message.is_empty());
+        assert_eq!(reply.correlation_id, None);
+    }
+    // The socket is still usable afterwards.
+    let text = Envelope::new(Message::Text("still here".to_string()));
+    let reply = send(&mut socket, tungstenite::Message::text(text.encode())).await;
+    assert_eq!(reply.message, text.message);
+}
+
+#[tokio::test]
+async fn test_missed_messages_are_replayed_on_resume() {
+    let addr = spawn().await;
+    let (mut socket, session_id, resumed) = open(addr, "").await;
+    assert!(!resumed);
+    assert_eq!(send_text(&mut socket, "one").await.seq, Some(1));
+    assert_eq!(send_text(&mut socket, "two").await.seq, Some(2));
+    socket.close(None).await.unwrap();
+
+    // The client only saw the first reply.
+    let (mut socket, resumed_id, resumed) = open(addr, &format!("?session={}&last_seq=1", session_id)).await;
+    assert!(resumed);
+    assert_eq!(resumed_id, session_id);
+    let replayed = receive(&mut socket).await;
+    assert_eq!(replayed.seq, Some(2));
+    assert_eq!(replayed.message, Message::Text("two".to_string()));
+    assert_eq!(send_text(&mut socket, "three").await.seq, Some(3));
+}
+
+#[tokio::test]
+async fn test_sessions_that_cant_be_replayed_start_over() {
+    let addr = spawn().await;
+    let (mut socket, session_id, _) = open(addr, "").await;
+    for text in ["one", "two", "three"] {
+        send_text(&mut socket, text).await;
+    }
+
+    // Only the last two messages are kept, so the first is gone.
+    let queries = [
+        format!("?session={}&last_seq=0", session_id),
+        format!("?session={}&last_seq=9", session_id),
+        "?session=unknown&last_seq=0".to_string(),
+        "?session=x&last_seq=x".to_string(),
+    ];
+    for query in queries {
+        let (mut socket, new_id, resumed) = open(addr, &query).await;
+        assert!(!resumed, "{}", query);
+        assert_ne!(new_id, session_id);
+        assert_eq!(send_text(&mut socket, "hello").await.seq, Some(1));
+    }
+    let (_, _, resumed) = open(addr, &format!("?session={}&last_seq=1", session_id)).await;
+    assert!(resumed);
+}
+
+#[test]
+fn test_realtime_settings_must_be_positive() {
+    let mut config = load_config().unwrap();
+    config.realtime.resume_secs = 0;
+    config.realtime.replay_len = 0;
+    config.realtime.queue_len = 0;
+    let problems = config.validate();
+    let realtime: Vec<_> = problems.iter().filter(|problem| problem.starts_with("realtime.")).collect();
+    assert_eq!(realtime.len(), 3, "{:?}", problems);
+}
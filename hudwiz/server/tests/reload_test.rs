@@ -0,0 +1,169 @@
+use axum::http::{header, StatusCode};
+use server_lib::{
+    app_router_with_config,
+    auth::{Credentials, Role},
+    config::{load_config_from, SignupMode},
+    reload::LiveConfig,
+    Services,
+};
+use std::{fs, path::PathBuf};
+use tokio::net::TcpListener;
+
+/// Copies the server's own configuration into a temporary directory so that
+/// tests can edit it, keeping the static assets pointed at the real ones.
+fn write_config() -> PathBuf {
+    let server_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let contents = fs::read_to_string(server_dir.join("server_config.toml"))
+        .unwrap()
+        .replace(
+            r#"static_dir = "frontend/dist""#,
+            &format!("static_dir = {:?}", server_dir.join("frontend/dist").display().to_string()),
+        );
+    let dir = std::env::temp_dir().join(format!("hudwiz-reload-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server_config.toml");
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn edit(path: &PathBuf, from: &str, to: &str) {
+    let contents = fs::read_to_string(path).unwrap();
+    assert!(contents.contains(from), "{} not found in the configuration", from);
+    fs::write(path, contents.replacen(from, to, 1)).unwrap();
+}
+
+#[test]
+fn test_safe_changes_are_applied() {
+    let path = write_config();
+    let live = LiveConfig::new(load_config_from(&path).unwrap()).unwrap();
+    let initial = live.current().version.clone();
+    assert_eq!(initial.version, 1);
+
+    // Unchanged files don't create a new version.
+    assert_eq!(live.reload_from(&path).unwrap(), None);
+
+    edit(&path, r#"signup = "open""#, r#"signup = "disabled""#);
+    assert_eq!(live.reload_from(&path).unwrap(), Some(2));
+    let active = live.current();
+    assert_eq!(active.config.auth.signup, SignupMode::Disabled);
+    assert_eq!(active.version.version, 2);
+    assert_ne!(active.version.digest, initial.digest);
+}
+
+#[test]
+fn test_invalid_changes_are_rejected() {
+    let path = write_config();
+    let live = LiveConfig::new(load_config_from(&path).unwrap()).unwrap();
+
+    edit(&path, r#"level = "info""#, r#"level = "info,=nonsense=""#);
+    let error = format!("{:#}", live.reload_from(&path).unwrap_err());
+    assert!(error.contains("logging.level"), "{}", error);
+    assert!(error.contains(r#"logging.level: "info" -> "info,=nonsense=""#), "{}", error);
+    assert_eq!(live.current().version.version, 1);
+    assert_eq!(live.current().config.logging.level, "info");
+}
+
+#[test]
+fn test_restart_only_changes_are_not_applied() {
+    let path = write_config();
+    let live = LiveConfig::new(load_config_from(&path).unwrap()).unwrap();
+
+    edit(&path, r#"http_addr = "127.0.0.1:8080""#, r#"http_addr = "127.0.0.1:8081""#);
+    assert_eq!(live.reload_from(&path).unwrap(), None);
+    assert_eq!(live.current().config.server.http_addr.port(), 8080);
+
+    // Reloadable changes in the same edit still apply.
+    edit(&path, r#"signup = "open""#, r#"signup = "disabled""#);
+    assert_eq!(live.reload_from(&path).unwrap(), Some(2));
+    let active = live.current();
+    assert_eq!(active.config.auth.signup, SignupMode::Disabled);
+    assert_eq!(active.config.server.http_addr.port(), 8080);
+}
+
+#[tokio::test]
+async fn test_upstreams_added_by_reload_can_be_framed() {
+    let path = write_config();
+    let upstreams = r#"upstreams = ["https://www.openstreetmap.org", "https://codepen.io", "http://commondatastorage.googleapis.com"]"#;
+    edit(&path, upstreams, "upstreams = []");
+    let config = load_config_from(&path).unwrap();
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services.clone()).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    edit(&path, "upstreams = []", upstreams);
+    assert_eq!(services.config.reload_from(&path).unwrap(), Some(2));
+    let listed: serde_json::Value = reqwest::get(format!("http://{}/api/proxy/upstreams", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed["upstreams"].as_array().unwrap().len(), 3);
+    // The UI frames them through the proxy, on this origin.
+    let res = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    let csp = res.headers()[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+    assert!(csp.contains("frame-src 'self'"), "{}", csp);
+}
+
+#[tokio::test]
+async fn test_reload_disables_signup_and_reports_version() {
+    let path = write_config();
+    let config = load_config_from(&path).unwrap();
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services.clone()).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let client = reqwest::Client::new();
+    let signup = |username: &str| {
+        client
+            .post(format!("http://{}/api/auth/signup", addr))
+            .json(&Credentials {
+                username: username.to_string(),
+                password: "password".to_string(),
+            })
+            .send()
+    };
+    assert_eq!(signup("first").await.unwrap().status(), StatusCode::CREATED);
+
+    edit(&path, r#"signup = "open""#, r#"signup = "disabled""#);
+    services.config.reload_from(&path).unwrap();
+    assert_eq!(signup("second").await.unwrap().status(), StatusCode::FORBIDDEN);
+
+    // Only admins can see which configuration is in effect. The configured
+    // user has a published password, so it isn't one until made one.
+    assert_eq!(services.user_store.get("testuser").await.unwrap().unwrap().role, Role::User);
+    services.user_store.update("testuser", |user| user.role = Role::Admin).await.unwrap();
+    let version_url = format!("http://{}/admin/config/version", addr);
+    assert_eq!(client.get(&version_url).send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    let login = |username: &str| {
+        client
+            .post(format!("http://{}/api/auth/login", addr))
+            .json(&Credentials {
+                username: username.to_string(),
+                password: "password".to_string(),
+            })
+            .send()
+    };
+    let mut cookies = Vec::new();
+    for username in ["first", "testuser"] {
+        let res = login(username).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        cookies.push(cookie.split(';').next().unwrap().to_string());
+    }
+    let res = client.get(&version_url).header(header::COOKIE, &cookies[0]).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = client.get(&version_url).header(header::COOKIE, &cookies[1]).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert_eq!(body["version"], 2);
+    assert_eq!(body["digest"], services.config.current().version.digest.as_str());
+}
@@ -0,0 +1,186 @@
+use axum::http::{header, StatusCode};
+use server_lib::{
+    app_router_with_config,
+    config::{load_config, Config},
+    security::NON_DOCUMENT_CSP,
+    Services,
+};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+async fn spawn(config: Config) -> SocketAddr {
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+fn header(res: &reqwest::Response, name: header::HeaderName) -> Option<&str> {
+    res.headers().get(name).map(|value| value.to_str().unwrap())
+}
+
+#[tokio::test]
+async fn test_headers_per_route() {
+    let mut config = load_config().unwrap();
+    config.security.content_sources.frame = vec!["https://maps.example.com".to_string()];
+    config.security.content_sources.media = vec!["https://videos.example.com".to_string()];
+    let addr = spawn(config).await;
+
+    // Pages get the UI's policy, with the configured content sources.
+    let res = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let csp = header(&res, header::CONTENT_SECURITY_POLICY).unwrap();
+    assert!(csp.contains("default-src 'self'"), "{}", csp);
+    assert!(csp.contains("frame-src 'self' https://maps.example.com;"), "{}", csp);
+    assert!(csp.contains("media-src 'self' blob: https://videos.example.com;"), "{}", csp);
+    assert!(csp.contains("connect-src 'self' ws://localhost:8080 https://localhost:4433"), "{}", csp);
+    assert!(csp.contains("frame-ancestors 'self'"), "{}", csp);
+    assert_eq!(header(&res, header::X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+    assert_eq!(header(&res, header::REFERRER_POLICY), Some("strict-origin-when-cross-origin"));
+    assert_eq!(header(&res, header::STRICT_TRANSPORT_SECURITY), None);
+
+    // Unknown paths fall back to the same page.
+    let res = reqwest::get(format!("http://{}/some/client/route", addr)).await.unwrap();
+    assert!(header(&res, header::CONTENT_SECURITY_POLICY).unwrap().contains("frame-src"));
+
+    // API and operations responses load nothing.
+    for path in ["/api/auth/user", "/api/realtime/info", "/healthz"] {
+        let res = reqwest::get(format!("http://{}{}", addr, path)).await.unwrap();
+        assert_eq!(header(&res, header::CONTENT_SECURITY_POLICY), Some(NON_DOCUMENT_CSP), "{}", path);
+        assert_eq!(header(&res, header::X_CONTENT_TYPE_OPTIONS), Some("nosniff"), "{}", path);
+    }
+}
+
+#[tokio::test]
+#[cfg(not(feature = "embed-assets"))]
+async fn test_inline_scripts_are_allowed_by_hash() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use sha2::{Digest, Sha256};
+    use std::fs;
+
+    let static_dir = std::env::temp_dir().join(format!("hudwiz-static-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&static_dir).unwrap();
+    let script = "\n        import init from './wasm.js';\n        init();\n    ";
+    fs::write(
+        static_dir.join("index.html"),
+        format!("<html><body><script src=\"other.js\"></script><script type=\"module\">{}</script></body></html>", script),
+    )
+    .unwrap();
+
+    let mut config = load_config().unwrap();
+    config.server.static_dir = static_dir;
+    let addr = spawn(config).await;
+
+    let res = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    let csp = header(&res, header::CONTENT_SECURITY_POLICY).unwrap();
+    let hash = STANDARD.encode(Sha256::digest(script.as_bytes()));
+    assert!(
+        csp.contains(&format!("script-src 'self' 'wasm-unsafe-eval' 'sha256-{}';", hash)),
+        "{}",
+        csp
+    );
+}
+
+#[tokio::test]
+async fn test_hsts_over_https() {
+    let mut config = load_config().unwrap();
+    config.server.public_url = "https://hud.example.com".to_string();
+    let addr = spawn(config).await;
+
+    let res = reqwest::get(format!("http://{}/api/auth/user", addr)).await.unwrap();
+    assert_eq!(header(&res, header::STRICT_TRANSPORT_SECURITY), Some("max-age=31536000"));
+    let res = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    let csp = header(&res, header::CONTENT_SECURITY_POLICY).unwrap();
+    assert!(csp.contains("wss://hud.example.com https://hud.example.com:4433"), "{}", csp);
+}
+
+#[tokio::test]
+async fn test_cors() {
+    let client = reqwest::Client::new();
+    let preflight = |addr: SocketAddr, origin: &'static str| {
+        client
+            .request(reqwest::Method::OPTIONS, format!("http://{}/api/auth/login", addr))
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .send()
+    };
+
+    // Without configured origins there is no CORS at all.
+    let addr = spawn(load_config().unwrap()).await;
+    let res = preflight(addr, "https://app.example.com").await.unwrap();
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+
+    let mut config = load_config().unwrap();
+    config.security.cors.allowed_origins = vec!["https://app.example.com".to_string()];
+    config.security.cors.allow_credentials = true;
+    let addr = spawn(config).await;
+
+    let res = preflight(addr, "https://app.example.com").await.unwrap();
+    assert!(res.status().is_success());
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some("https://app.example.com"));
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS), Some("true"));
+
+    let res = preflight(addr, "https://evil.example.com").await.unwrap();
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+
+    // Conditional writes need `PUT` and `If-Match`.
+    let res = client
+        .request(reqwest::Method::OPTIONS, format!("http://{}/api/user/preferences", addr))
+        .header(header::ORIGIN, "https://app.example.com")
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type,if-match")
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    let methods = header(&res, header::ACCESS_CONTROL_ALLOW_METHODS).unwrap();
+    assert!(methods.contains("PUT") && methods.contains("DELETE"), "{}", methods);
+    let headers = header(&res, header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap();
+    assert!(headers.contains("if-match"), "{}", headers);
+
+    let res = client
+        .get(format!("http://{}/api/realtime/info", addr))
+        .header(header::ORIGIN, "https://app.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some("https://app.example.com"));
+    let res = client
+        .get(format!("http://{}/api/user/preferences", addr))
+        .header(header::ORIGIN, "https://app.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some("https://app.example.com"));
+    assert_eq!(header(&res, header::ACCESS_CONTROL_EXPOSE_HEADERS), Some("etag"));
+
+    // Static assets aren't part of the API.
+    let res = client
+        .get(format!("http://{}/", addr))
+        .header(header::ORIGIN, "https://app.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+}
+
+#[test]
+fn test_invalid_security_settings_are_reported() {
+    let mut config = load_config().unwrap();
+    config.security.referrer_policy = "sometimes".to_string();
+    config.security.cors.allowed_origins = vec!["*".to_string(), "https://app.example.com/path".to_string()];
+    config.security.cors.allow_credentials = true;
+    config.security.content_sources.frame = vec!["https://a.example.com https://b.example.com".to_string()];
+
+    let problems = config.validate();
+    assert_eq!(problems.len(), 4, "{:#?}", problems);
+    assert!(problems.iter().any(|problem| problem.contains("security.referrer_policy")));
+    assert!(problems.iter().any(|problem| problem.contains("'*' with allow_credentials")));
+    assert!(problems.iter().any(|problem| problem.contains("https://app.example.com/path")));
+    assert!(problems.iter().any(|problem| problem.contains("security.content_sources.frame")));
+}
@@ -0,0 +1,48 @@
+use futures::{SinkExt, StreamExt};
+use protocol::Envelope;
+use server_lib::{app_router_with_config, config::load_config, Services};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
+
+#[tokio::test]
+async fn test_shutdown_closes_websockets() {
+    let config = load_config().unwrap();
+    let services = Services::open(&config).unwrap();
+    let shutdown = services.shutdown.clone();
+    let app = app_router_with_config(config, services).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server_shutdown = shutdown.clone();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { server_shutdown.triggered().await })
+            .await
+            .unwrap();
+    });
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+        .await
+        .unwrap();
+    let greeting = socket.next().await.unwrap().unwrap();
+    assert!(greeting.to_text().unwrap().contains(r#""type":"Session""#), "{:?}", greeting);
+    let ping = Envelope::new(protocol::Message::Text("ping".to_string()));
+    socket.send(Message::text(ping.encode())).await.unwrap();
+    let reply = socket.next().await.unwrap().unwrap();
+    assert_eq!(Envelope::decode(reply.to_text().unwrap()).unwrap().message, ping.message);
+    assert_eq!(shutdown.open_connections(), 1);
+
+    shutdown.trigger();
+    match socket.next().await.unwrap().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        server.await.unwrap();
+        shutdown.connections_closed().await;
+    })
+    .await
+    .expect("server didn't drain");
+}
@@ -0,0 +1,251 @@
+use axum::http::{header, StatusCode};
+use protocol::{ContentType, Envelope, Message};
+use serde_json::{json, Value};
+use server_lib::{app_router_with_config, auth::Credentials, config::load_config, hub::Topic, Services};
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+
+async fn spawn() -> (SocketAddr, Services) {
+    let mut config = load_config().unwrap();
+    config.system_stats.interval_secs = 1;
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services.clone()).unwrap();
+    let shutdown = services.shutdown.clone();
+    tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { shutdown.triggered().await })
+            .await
+            .unwrap();
+    });
+    (addr, services)
+}
+
+/// Reads `text/event-stream` events as (name, data) pairs, skipping
+/// keep-alive comments.
+struct EventStream {
+    response: reqwest::Response,
+    buffer: String,
+}
+
+impl EventStream {
+    async fn open(addr: SocketAddr, query: &str, cookie: Option<&str>) -> reqwest::Response {
+        let mut request = reqwest::Client::new().get(format!("http://{}/sse{}", addr, query));
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+        request.send().await.unwrap()
+    }
+
+    fn new(response: reqwest::Response) -> Self {
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+        Self { response, buffer: String::new() }
+    }
+
+    /// The next event, or `None` once the server ends the stream.
+    async fn next(&mut self) -> Option<(String, String)> {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let block: String = self.buffer.drain(..end + 2).collect();
+                let mut name = "message".to_string();
+                let mut data = Vec::new();
+                for line in block.lines() {
+                    if let Some(value) = line.strip_prefix("event:") {
+                        name = value.trim_start().to_string();
+                    } else if let Some(value) = line.strip_prefix("data:") {
+                        data.push(value.strip_prefix(' ').unwrap_or(value));
+                    }
+                }
+                if data.is_empty() {
+                    continue;
+                }
+                return Some((name, data.join("\n")));
+            }
+            let chunk = tokio::time::timeout(Duration::from_secs(5), self.response.chunk())
+                .await
+                .expect("no event sent")
+                .unwrap()?;
+            self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+
+    /// The id to post to, after which the realtime `Session` message is
+    /// skipped.
+    async fn session_id(&mut self) -> String {
+        let (name, data) = self.next().await.unwrap();
+        assert_eq!(name, "session");
+        let session: Value = serde_json::from_str(&data).unwrap();
+        let (_, greeting) = self.next().await.unwrap();
+        let greeting = Envelope::decode(&greeting).unwrap();
+        assert!(matches!(greeting.message, Message::Session { resumed: false, .. }), "{:?}", greeting);
+        session["session_id"].as_str().unwrap().to_string()
+    }
+}
+
+async fn post(addr: SocketAddr, session_id: &str, message: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("http://{}/sse/{}", addr, session_id))
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(message.to_string())
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_posted_messages_come_back_on_the_stream() {
+    let (addr, services) = spawn().await;
+    let mut events = EventStream::new(EventStream::open(addr, "", None).await);
+    let session_id = events.session_id().await;
+    assert_eq!(services.shutdown.open_connections(), 1);
+
+    let text = Envelope::new(Message::Text("two\nlines".to_string()));
+    let content = Envelope::new(Message::SetBackgroundContent(ContentType::SolidColor("#1a1a1a".to_string())));
+    for message in [&text.encode(), &content.encode(), "hello"] {
+        assert_eq!(post(addr, &session_id, message).await.status(), StatusCode::ACCEPTED);
+    }
+    let mut replies = Vec::new();
+    for _ in 0..3 {
+        let (name, data) = events.next().await.unwrap();
+        assert_eq!(name, "message");
+        replies.push(Envelope::decode(&data).unwrap());
+    }
+    assert_eq!(replies[0].message, text.message);
+    assert_eq!(replies[0].correlation_id.as_ref(), Some(&text.id));
+    // Clients can't change content, and what isn't an envelope answers nothing.
+    assert!(matches!(replies[1].message, Message::Error { .. }));
+    assert_eq!(replies[1].correlation_id.as_ref(), Some(&content.id));
+    assert!(matches!(replies[2].message, Message::Error { .. }));
+    assert_eq!(replies[2].correlation_id, None);
+
+    let res = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap();
+    let metrics = res.text().await.unwrap();
+    assert!(metrics.contains(r#"realtime_messages_total{transport="sse",direction="received"} 3"#), "{}", metrics);
+    assert!(metrics.contains(r#"realtime_connections{transport="sse"} 1"#), "{}", metrics);
+}
+
+#[tokio::test]
+async fn test_unknown_and_closed_streams_are_not_found() {
+    let (addr, services) = spawn().await;
+    for session_id in ["unknown", &uuid::Uuid::new_v4().to_string()] {
+        let res = post(addr, session_id, "hello").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.json::<Value>().await.unwrap()["error"].is_string());
+    }
+
+    let mut events = EventStream::new(EventStream::open(addr, "", None).await);
+    let session_id = events.session_id().await;
+    drop(events);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while services.shutdown.open_connections() > 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("stream wasn't closed");
+    assert_eq!(post(addr, &session_id, "hello").await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_topics_need_login() {
+    let (addr, _) = spawn().await;
+    for (query, status) in [("?topics=system.stats", StatusCode::UNAUTHORIZED), ("?topics=weather", StatusCode::BAD_REQUEST)] {
+        let res = EventStream::open(addr, query, None).await;
+        assert_eq!(res.status(), status, "{}", query);
+        assert!(res.json::<Value>().await.unwrap()["error"].is_string());
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_topics_are_pushed() {
+    let (addr, _) = spawn().await;
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/api/auth/login", addr))
+        .json(&Credentials {
+            username: "testuser".to_string(),
+            password: "password".to_string(),
+        })
+        .send()
+        .await
+        .unwrap();
+    let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+
+    let mut events = EventStream::new(EventStream::open(addr, "?topics=system.stats", Some(&cookie)).await);
+    events.session_id().await;
+    let (name, data) = events.next().await.unwrap();
+    assert_eq!(name, "message");
+    let push: Value = serde_json::from_str(&data).unwrap();
+    assert_eq!(push["topic"], "system.stats");
+    assert!(push["payload"]["cpu"]["cores"].as_u64().unwrap() >= 1);
+}
+
+/// The realtime session greeting, after the `session` event.
+async fn greeting(events: &mut EventStream) -> (String, bool) {
+    assert_eq!(events.next().await.unwrap().0, "session");
+    let (_, greeting) = events.next().await.unwrap();
+    let Message::Session { session_id, resumed } = Envelope::decode(&greeting).unwrap().message else {
+        panic!("{}", greeting);
+    };
+    (session_id, resumed)
+}
+
+async fn next_envelope(events: &mut EventStream) -> Envelope {
+    let (name, data) = events.next().await.unwrap();
+    assert_eq!(name, "message");
+    Envelope::decode(&data).unwrap()
+}
+
+#[tokio::test]
+async fn test_pushes_are_sequenced_once_and_telemetry_never() {
+    let (addr, services) = spawn().await;
+    services.hub.register(Topic::new("weather"));
+    services.hub.register(Topic::new("gauge").telemetry());
+    let mut events = EventStream::new(EventStream::open(addr, "?topics=weather,gauge", None).await);
+    let (session_id, _) = greeting(&mut events).await;
+
+    services.hub.publish("weather", json!("rain"));
+    services.hub.publish("gauge", json!(1));
+    services.hub.publish("weather", json!("sun"));
+    let mut seqs = Vec::new();
+    for _ in 0..3 {
+        seqs.push(next_envelope(&mut events).await.seq);
+    }
+    assert_eq!(seqs, [Some(1), None, Some(2)]);
+
+    // Resuming replays each publish once, and no telemetry.
+    drop(events);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while services.shutdown.open_connections() > 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("stream wasn't closed");
+    let query = format!("?session={}&last_seq=0&topics=weather", session_id);
+    let mut resumed = EventStream::new(EventStream::open(addr, &query, None).await);
+    assert_eq!(greeting(&mut resumed).await, (session_id, true));
+    for (seq, payload) in [(1, "rain"), (2, "sun")] {
+        let replayed = next_envelope(&mut resumed).await;
+        assert_eq!(replayed.seq, Some(seq));
+        assert_eq!(replayed.message, Message::Publish(json!(payload)));
+    }
+    services.hub.publish("weather", json!("snow"));
+    assert_eq!(next_envelope(&mut resumed).await.seq, Some(3));
+}
+
+#[tokio::test]
+async fn test_shutdown_ends_streams() {
+    let (addr, services) = spawn().await;
+    let mut events = EventStream::new(EventStream::open(addr, "", None).await);
+    events.session_id().await;
+
+    services.shutdown.trigger();
+    assert_eq!(events.next().await.unwrap().0, "close");
+    assert_eq!(events.next().await, None);
+    tokio::time::timeout(Duration::from_secs(5), services.shutdown.connections_closed())
+        .await
+        .expect("stream wasn't drained");
+}
@@ -0,0 +1,161 @@
+use server_lib::{
+    auth::{hash_password, Role, User, UserStore, UserStoreError},
+    storage,
+};
+use std::{fs, path::PathBuf};
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("hudwiz-data-{}", uuid::Uuid::new_v4()))
+}
+
+#[test]
+fn test_fresh_data_dir_is_initialised() {
+    let dir = temp_dir();
+    storage::open(&dir).unwrap();
+    assert_eq!(storage::schema_version(&dir).unwrap(), storage::SCHEMA_VERSION);
+    assert!(dir.join(storage::USERS_FILE).exists());
+    assert!(storage::migrate(&dir).unwrap().is_empty());
+}
+
+#[test]
+fn test_unversioned_data_dir_needs_migration() {
+    let dir = temp_dir();
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join(storage::USERS_FILE), "[]").unwrap();
+
+    let err = storage::open(&dir).unwrap_err();
+    assert!(err.to_string().contains("migrate"), "{}", err);
+    assert_eq!(storage::migrate(&dir).unwrap().len(), 1);
+    storage::open(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_user_store_persists_changes() {
+    let dir = temp_dir();
+    storage::open(&dir).unwrap();
+    let store = UserStore::open(&dir);
+    store
+        .create(User::new("alice", hash_password("secret").unwrap(), Role::User))
+        .await
+        .unwrap();
+    assert!(matches!(
+        store.create(User::new("alice", String::new(), Role::User)).await,
+        Err(UserStoreError::AlreadyExists(_))
+    ));
+
+    store.update("alice", |user| user.role = Role::Admin).await.unwrap();
+    store.update("alice", |user| user.disabled = true).await.unwrap();
+
+    let reopened = UserStore::open(&dir);
+    let alice = reopened.get("alice").await.unwrap().unwrap();
+    assert_eq!(alice.role, Role::Admin);
+    assert!(alice.disabled);
+    assert_eq!(reopened.list().await.unwrap().len(), 1);
+    assert!(matches!(
+        reopened.update("bob", |_| {}).await,
+        Err(UserStoreError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_user_store_sees_edits_made_elsewhere() {
+    let dir = temp_dir();
+    storage::open(&dir).unwrap();
+    let server = UserStore::open(&dir);
+    assert!(server.get("alice").await.unwrap().is_none());
+
+    // As the CLI would, through its own store on the same file.
+    let cli = UserStore::open(&dir);
+    cli.create(User::new("alice", String::new(), Role::User)).await.unwrap();
+    assert_eq!(server.get("alice").await.unwrap().unwrap().role, Role::User);
+    cli.update("alice", |user| user.role = Role::Admin).await.unwrap();
+    assert_eq!(server.get("alice").await.unwrap().unwrap().role, Role::Admin);
+
+    // And the server's own changes keep the CLI's.
+    server.create(User::new("bob", String::new(), Role::User)).await.unwrap();
+    let names: Vec<String> = cli.list().await.unwrap().into_iter().map(|user| user.username).collect();
+    assert_eq!(names, ["alice", "bob"]);
+}
+
+#[tokio::test]
+async fn test_sessions_survive_flush_and_reopen() {
+    use server_lib::sessions::FileSessionStore;
+    use tower_sessions::{session::Record, SessionStore};
+
+    let dir = temp_dir();
+    storage::open(&dir).unwrap();
+    let store = FileSessionStore::open(&dir).unwrap();
+    let now = time::OffsetDateTime::now_utc();
+    let record = |expiry_date| Record {
+        id: Default::default(),
+        data: Default::default(),
+        expiry_date,
+    };
+    let mut live = record(now + time::Duration::hours(1));
+    let mut expired = record(now - time::Duration::hours(1));
+    store.create(&mut live).await.unwrap();
+    store.create(&mut expired).await.unwrap();
+    store.flush().unwrap();
+
+    let reopened = FileSessionStore::open(&dir).unwrap();
+    assert_eq!(reopened.len(), 1);
+    assert_eq!(reopened.load(&live.id).await.unwrap(), Some(live));
+}
+
+#[tokio::test]
+async fn test_sessions_are_swept_and_written_periodically() {
+    use server_lib::sessions::FileSessionStore;
+    use std::time::Duration;
+    use tower_sessions::{session::Record, SessionStore};
+
+    let dir = temp_dir();
+    storage::open(&dir).unwrap();
+    let store = FileSessionStore::open(&dir).unwrap();
+    store.spawn_persistence(Duration::from_millis(20));
+    let now = time::OffsetDateTime::now_utc();
+    let record = |expiry_date| Record {
+        id: Default::default(),
+        data: Default::default(),
+        expiry_date,
+    };
+    let mut live = record(now + time::Duration::hours(1));
+    store.create(&mut live).await.unwrap();
+    for _ in 0..3 {
+        store.create(&mut record(now - time::Duration::hours(1))).await.unwrap();
+    }
+
+    // Written without a flush, as if the process were then killed.
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while store.len() > 1 || FileSessionStore::open(&dir).unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("sessions weren't swept and written");
+    let reopened = FileSessionStore::open(&dir).unwrap();
+    assert_eq!(reopened.load(&live.id).await.unwrap(), Some(live));
+}
+
+#[test]
+fn test_audit_log_is_written_on_flush() {
+    use server_lib::audit::{AuditEvent, AuditLog};
+
+    let dir = temp_dir();
+    storage::open(&dir).unwrap();
+    let audit = AuditLog::open(&dir).unwrap();
+    audit.record(AuditEvent::Login {
+        username: "alice".to_string(),
+        method: "password".to_string(),
+        success: false,
+    });
+    let path = dir.join(storage::AUDIT_FILE);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+    audit.flush().unwrap();
+    let log = fs::read_to_string(&path).unwrap();
+    assert_eq!(log.lines().count(), 1);
+    let entry: serde_json::Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+    assert_eq!(entry["event"], "login");
+    assert_eq!(entry["username"], "alice");
+    assert_eq!(entry["success"], false);
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use common::{login, spawn_with};
+use futures::StreamExt;
+use serde_json::Value;
+use server_lib::{config::load_config, system_stats::StatsReport};
+use std::{net::SocketAddr, time::Duration};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Error, Message};
+
+async fn stats(addr: SocketAddr, cookie: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("http://{}/api/system/stats", addr))
+        .header(header::COOKIE, cookie)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_requires_login() {
+    let (addr, _) = spawn_with(load_config().unwrap()).await;
+    let res = reqwest::get(format!("http://{}/api/system/stats", addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_reports_host_and_process() {
+    let (addr, _) = spawn_with(load_config().unwrap()).await;
+    let cookie = login(addr, "testuser", "password").await;
+    let res = stats(addr, &cookie).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let report: StatsReport = res.json().await.unwrap();
+    let current = report.current;
+    assert!(current.cpu.cores >= 1);
+    assert!((0.0..=100.0).contains(&current.cpu.usage_percent));
+    assert!(current.memory.total_bytes > 0);
+    assert!(current.memory.used_bytes <= current.memory.total_bytes);
+    assert_eq!(current.disks.len(), 1);
+    assert_eq!(current.disks[0].mount, "/");
+    assert!(current.disks[0].total_bytes > 0);
+    assert_eq!(current.process.pid, std::process::id());
+    assert!(current.process.resident_bytes > 0);
+    assert!(!report.history.is_empty());
+    assert_eq!(report.interval_secs, 5);
+}
+
+#[tokio::test]
+async fn test_disabled_is_unavailable() {
+    let mut config = load_config().unwrap();
+    config.system_stats.enabled = false;
+    let (addr, _) = spawn_with(config).await;
+    let cookie = login(addr, "testuser", "password").await;
+    let res = stats(addr, &cookie).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(res.json::<Value>().await.unwrap()["error"].is_string());
+}
+
+#[test]
+fn test_invalid_settings_fail_validation() {
+    let mut config = load_config().unwrap();
+    config.system_stats.interval_secs = 0;
+    config.system_stats.history_len = 0;
+    config.system_stats.mounts = vec!["relative".into()];
+    let problems = config.validate().join("\n");
+    for problem in ["system_stats.interval_secs", "system_stats.history_len", "system_stats.mounts"] {
+        assert!(problems.contains(problem), "{} missing from {}", problem, problems);
+    }
+}
+
+#[tokio::test]
+async fn test_websocket_topic_needs_login() {
+    let (addr, _) = spawn_with(load_config().unwrap()).await;
+    for (query, status) in [("topics=system.stats", StatusCode::UNAUTHORIZED), ("topics=weather", StatusCode::BAD_REQUEST)] {
+        match tokio_tungstenite::connect_async(format!("ws://{}/ws?{}", addr, query)).await {
+            Err(Error::Http(res)) => assert_eq!(res.status(), status, "{}", query),
+            other => panic!("{} connected: {:?}", query, other.map(|(_, res)| res.status())),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_websocket_pushes_samples() {
+    let mut config = load_config().unwrap();
+    config.system_stats.interval_secs = 1;
+    let (addr, _) = spawn_with(config).await;
+    let cookie = login(addr, "testuser", "password").await;
+
+    let mut request = format!("ws://{}/ws?topics=system.stats", addr).into_client_request().unwrap();
+    request.headers_mut().insert(header::COOKIE, cookie.parse().unwrap());
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    let mut timestamps = Vec::new();
+    while timestamps.len() < 2 {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no sample pushed")
+            .unwrap()
+            .unwrap();
+        let Message::Text(text) = message else { continue };
+        let push: Value = serde_json::from_str(&text).unwrap();
+        if push["type"] == "Session" {
+            continue;
+        }
+        assert_eq!(push["topic"], "system.stats");
+        assert!(push["payload"]["memory"]["total_bytes"].as_u64().unwrap() > 0);
+        timestamps.push(push["payload"]["timestamp_ms"].as_u64().unwrap());
+    }
+    assert!(timestamps[1] > timestamps[0]);
+}
@@ -0,0 +1,52 @@
+use axum::http::StatusCode;
+use server_lib::{app_router, auth::Credentials, telemetry::{set_redact_key, username}};
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn test_request_ids() {
+    let app = app_router().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    let client = reqwest::Client::new();
+
+    let res = client.get(format!("http://{}/healthz", addr)).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let assigned = res.headers()["x-request-id"].to_str().unwrap();
+    assert!(uuid::Uuid::parse_str(assigned).is_ok(), "{}", assigned);
+
+    let res = client
+        .get(format!("http://{}/healthz", addr))
+        .header("x-request-id", "from-the-proxy")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["x-request-id"], "from-the-proxy");
+}
+
+#[test]
+fn test_usernames_are_redacted() {
+    let redacted = username("alice@example.com").to_string();
+    assert!(redacted.starts_with("user-"), "{}", redacted);
+    assert!(!redacted.contains("alice"));
+    assert_eq!(redacted, username("alice@example.com").to_string());
+    assert_ne!(redacted, username("bob@example.com").to_string());
+
+    // Keyed, so the pseudonym can't be found by hashing likely names.
+    set_redact_key("first deployment key");
+    let first = username("alice@example.com").to_string();
+    set_redact_key("second deployment key");
+    let second = username("alice@example.com").to_string();
+    assert_ne!(first, second);
+    set_redact_key("");
+    assert_eq!(second, username("alice@example.com").to_string());
+
+    let creds = Credentials {
+        username: "alice@example.com".to_string(),
+        password: "hunter2".to_string(),
+    };
+    let debug = format!("{:?}", creds);
+    assert!(!debug.contains("hunter2") && !debug.contains("alice"), "{}", debug);
+}
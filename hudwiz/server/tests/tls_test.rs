@@ -0,0 +1,60 @@
+use axum::http::StatusCode;
+use server_lib::{config::TlsConfig, tls};
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn test_dev_certificate_is_generated_once() {
+    let dir = std::env::temp_dir().join(format!("hudwiz-tls-{}", uuid::Uuid::new_v4()));
+    let config = TlsConfig {
+        cert_path: dir.join("certs/cert.pem"),
+        key_path: dir.join("certs/key.pem"),
+        ..TlsConfig::default()
+    };
+
+    tls::ensure_certificate(&config).unwrap();
+    let cert = std::fs::read(&config.cert_path).unwrap();
+    tls::rustls_config(&config).await.unwrap();
+    // Written aside and renamed into place, leaving nothing else behind.
+    assert_eq!(std::fs::read_dir(dir.join("certs")).unwrap().count(), 2);
+
+    // Generated certificates are short-lived enough to be pinned by hash.
+    let info = tls::read_certificate(&config.cert_path).unwrap();
+    assert!(info.is_dev_cert);
+    assert_eq!(info.sha256_hex().len(), 64);
+    assert!(!tls::renew_dev_certificate_if_due(&config).unwrap());
+
+    // An existing certificate is left alone.
+    tls::ensure_certificate(&config).unwrap();
+    assert_eq!(std::fs::read(&config.cert_path).unwrap(), cert);
+
+    // A lone certificate without its key is a configuration error.
+    std::fs::remove_file(&config.key_path).unwrap();
+    assert!(tls::ensure_certificate(&config).is_err());
+}
+
+#[tokio::test]
+async fn test_https_redirect() {
+    let app = tls::https_redirect_router("https://hud.example.com/");
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let res = client
+        .get(format!("http://{}/settings?tab=theme", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        res.headers()["location"],
+        "https://hud.example.com/settings?tab=theme"
+    );
+}
@@ -0,0 +1,241 @@
+mod common;
+
+use axum::http::{header, StatusCode};
+use common::{add_user, login, spawn_with};
+use serde_json::Value;
+use server_lib::{
+    auth::Role,
+    config::{load_config, Config},
+};
+use std::{fs, io::Cursor, net::SocketAddr, path::PathBuf};
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("hudwiz-uploads-{}", uuid::Uuid::new_v4()))
+}
+
+/// Config with uploads in a fresh directory.
+fn config() -> (Config, PathBuf) {
+    let dir = temp_dir();
+    let mut config = load_config().unwrap();
+    config.uploads.dir = Some(dir.clone());
+    (config, dir)
+}
+
+async fn upload_raw(addr: SocketAddr, cookie: &str, name: &str, content_type: &str, body: Vec<u8>) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("http://{}/api/uploads", addr))
+        .query(&[("name", name)])
+        .header(header::COOKIE, cookie)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn upload_form(addr: SocketAddr, cookie: &str, name: &str, content_type: &str, content: &[u8]) -> reqwest::Response {
+    let boundary = "hudwiz-test-boundary";
+    let mut body = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"caption\"\r\n\r\nignored\r\n\
+         --{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\
+         Content-Type: {content_type}\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(content);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    reqwest::Client::new()
+        .post(format!("http://{}/api/uploads", addr))
+        .header(header::COOKIE, cookie)
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let image = image::RgbImage::from_pixel(width, height, image::Rgb([200, 30, 30]));
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageFormat::Png).unwrap();
+    bytes.into_inner()
+}
+
+#[tokio::test]
+async fn test_disabled_without_a_directory() {
+    let (addr, _) = spawn_with(load_config().unwrap()).await;
+    let res = upload_raw(addr, "", "notes.txt", "text/plain", b"hello".to_vec()).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let cookie = login(addr, "testuser", "password").await;
+    let res = upload_raw(addr, &cookie, "notes.txt", "text/plain", b"hello".to_vec()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(res.json::<Value>().await.unwrap()["error"].is_string());
+}
+
+#[tokio::test]
+async fn test_upload_and_download() {
+    let (config, _) = config();
+    let (addr, _) = spawn_with(config).await;
+    let cookie = login(addr, "testuser", "password").await;
+
+    let res = upload_raw(addr, &cookie, "../../notes.txt", "text/plain; charset=utf-8", b"hello".to_vec()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let upload: Value = res.json().await.unwrap();
+    assert_eq!(upload["name"], "notes.txt");
+    assert_eq!(upload["content_type"], "text/plain");
+    assert_eq!(upload["size"], 5);
+    assert_eq!(upload["sha256"], "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+    assert!(upload["thumbnail_url"].is_null());
+
+    let url = format!("http://{}{}", addr, upload["url"].as_str().unwrap());
+    let res = reqwest::get(&url).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "text/plain");
+    assert_eq!(res.headers()[header::CONTENT_DISPOSITION], "attachment; filename*=UTF-8''notes%2Etxt");
+    let etag = res.headers()[header::ETAG].clone();
+    assert_eq!(res.text().await.unwrap(), "hello");
+
+    let res = reqwest::Client::new()
+        .get(&url)
+        .header(header::IF_NONE_MATCH, etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+    // Tampered and expired links are refused.
+    for tampered in [
+        url.replace("signature=", "signature=00"),
+        url.replace("expires=", "expires=1"),
+        format!("http://{}/api/files/{}", addr, upload["id"].as_str().unwrap()),
+    ] {
+        let res = reqwest::get(&tampered).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN, "{}", tampered);
+    }
+}
+
+#[tokio::test]
+async fn test_images_get_thumbnails() {
+    let (config, _) = config();
+    let (addr, _) = spawn_with(config).await;
+    let cookie = login(addr, "testuser", "password").await;
+
+    let res = upload_form(addr, &cookie, "map.png", "image/png", &png(1024, 512)).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let upload: Value = res.json().await.unwrap();
+    assert_eq!(upload["name"], "map.png");
+
+    let res = reqwest::get(format!("http://{}{}", addr, upload["url"].as_str().unwrap())).await.unwrap();
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "image/png");
+    assert!(res.headers()[header::CONTENT_DISPOSITION].to_str().unwrap().starts_with("inline"));
+
+    let res = reqwest::get(format!("http://{}{}", addr, upload["thumbnail_url"].as_str().unwrap())).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "image/png");
+    let thumbnail = image::load_from_memory(&res.bytes().await.unwrap()).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+
+    // A thumbnail link doesn't open the original, or the other way around.
+    let swapped = upload["url"].as_str().unwrap().replace('?', "/thumbnail?");
+    assert_eq!(reqwest::get(format!("http://{}{}", addr, swapped)).await.unwrap().status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_types_are_checked() {
+    let (config, _) = config();
+    let (addr, _) = spawn_with(config).await;
+    let cookie = login(addr, "testuser", "password").await;
+
+    let res = upload_raw(addr, &cookie, "page.html", "text/html", b"<script>alert(1)</script>".to_vec()).await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let res = upload_raw(addr, &cookie, "fake.png", "image/png", b"<script>alert(1)</script>".to_vec()).await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    let body: Value = res.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("image/png"), "{}", body);
+    let res = upload_raw(addr, &cookie, "map.png", "image/jpeg", png(8, 8)).await;
+    assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let res = upload_form(addr, &cookie, "", "text/plain", b"").await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.json::<Value>().await.unwrap()["name"], "upload");
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/api/uploads", addr))
+        .header(header::COOKIE, &cookie)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body("no name")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_size_limit_and_quota() {
+    let (mut config, dir) = config();
+    config.uploads.max_file_bytes = 4096;
+    config.uploads.quota_bytes = 6000;
+    let (addr, _) = spawn_with(config).await;
+    let cookie = login(addr, "testuser", "password").await;
+
+    let res = upload_raw(addr, &cookie, "big.txt", "text/plain", vec![b'a'; 5000]).await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let res = upload_raw(addr, &cookie, "first.txt", "text/plain", vec![b'a'; 4000]).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let first: Value = res.json().await.unwrap();
+
+    // The same content again doesn't use more of the quota.
+    let res = upload_raw(addr, &cookie, "again.txt", "text/plain", vec![b'a'; 4000]).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.json::<Value>().await.unwrap()["id"], first["id"]);
+
+    let res = upload_raw(addr, &cookie, "second.txt", "text/plain", vec![b'b'; 4000]).await;
+    assert_eq!(res.status(), StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+
+    let res = reqwest::Client::new()
+        .get(format!("http://{}/api/uploads", addr))
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    let list: Value = res.json().await.unwrap();
+    assert_eq!(list["used_bytes"], 4000);
+    assert_eq!(list["quota_bytes"], 6000);
+    assert_eq!(list["uploads"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_content_is_shared_and_uploads_are_private() {
+    let (config, dir) = config();
+    let (addr, services) = spawn_with(config).await;
+    add_user(&services, "other", Role::User).await;
+    let first = login(addr, "testuser", "password").await;
+    let second = login(addr, "other", "secret").await;
+    let client = reqwest::Client::new();
+
+    let mine: Value = upload_raw(addr, &first, "a.txt", "text/plain", b"shared".to_vec()).await.json().await.unwrap();
+    let res = upload_raw(addr, &second, "b.txt", "text/plain", b"shared".to_vec()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let theirs: Value = res.json().await.unwrap();
+    assert_ne!(mine["id"], theirs["id"]);
+    assert_eq!(fs::read_dir(dir.join("blobs")).unwrap().count(), 1);
+
+    let item = |id: &Value| format!("http://{}/api/uploads/{}", addr, id.as_str().unwrap());
+    let res = client.get(item(&mine["id"])).header(header::COOKIE, &second).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = client.delete(item(&mine["id"])).header(header::COOKIE, &second).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = client.delete(item(&mine["id"])).header(header::COOKIE, &first).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = reqwest::get(format!("http://{}{}", addr, mine["url"].as_str().unwrap())).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    // The other user's upload still has the content.
+    assert_eq!(fs::read_dir(dir.join("blobs")).unwrap().count(), 1);
+    let res = reqwest::get(format!("http://{}{}", addr, theirs["url"].as_str().unwrap())).await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "shared");
+
+    let res = client.delete(item(&theirs["id"])).header(header::COOKIE, &second).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(fs::read_dir(dir.join("blobs")).unwrap().count(), 0);
+}
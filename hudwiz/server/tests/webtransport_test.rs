@@ -0,0 +1,219 @@
+use axum::http::{header, StatusCode};
+use protocol::{Envelope, Message};
+use serde_json::{json, Value};
+use server_lib::{
+    app_router_with_config,
+    auth::{Credentials, UserInfo},
+    config::{load_config, TlsConfig},
+    hub::Topic,
+    tls,
+    webtransport::{frame, read_frame, start_webtransport_server, SessionTokens, MAX_FRAME_BYTES},
+    Services,
+};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::watch};
+use wtransport::{tls::Sha256Digest, ClientConfig, Connection, Endpoint, RecvStream};
+
+#[tokio::test]
+async fn test_frames_round_trip() {
+    let messages = [
+        Envelope::new(Message::Text("hello".to_string())),
+        Envelope::subscribe("system.stats"),
+    ];
+    let bytes: Vec<u8> = messages.iter().flat_map(frame).collect();
+    assert_eq!(&bytes[..4], &(messages[0].encode().len() as u32).to_be_bytes());
+
+    let mut stream = bytes.as_slice();
+    for message in &messages {
+        let frame = read_frame(&mut stream).await.unwrap().unwrap();
+        assert_eq!(&Envelope::decode(std::str::from_utf8(&frame).unwrap()).unwrap(), message);
+    }
+    assert!(read_frame(&mut stream).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_bad_frames_are_refused() {
+    let oversized = ((MAX_FRAME_BYTES + 1) as u32).to_be_bytes();
+    let error = read_frame(&mut oversized.as_slice()).await.unwrap_err();
+    assert!(error.to_string().contains("over the limit"), "{}", error);
+
+    let whole = frame(&Envelope::new(Message::Ack));
+    for truncated in [&whole[..2], &whole[..whole.len() - 1]] {
+        let error = read_frame(&mut &truncated[..]).await.unwrap_err();
+        assert!(error.to_string().contains("ended inside a frame"), "{}", error);
+    }
+}
+
+#[test]
+fn test_session_tokens_are_single_use() {
+    let tokens = SessionTokens::default();
+    let user = UserInfo {
+        id: "1".to_string(),
+        name: "alice".to_string(),
+        email: "alice@example.com".to_string(),
+    };
+    let token = tokens.issue(user);
+    assert_eq!(tokens.redeem(&token).unwrap().name, "alice");
+    assert!(tokens.redeem(&token).is_none());
+    assert!(tokens.redeem("guess").is_none());
+}
+
+async fn spawn() -> SocketAddr {
+    let config = load_config().unwrap();
+    let services = Services::open(&config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = app_router_with_config(config, services).unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_tokens_need_login() {
+    let addr = spawn().await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api/realtime/token", addr);
+    assert_eq!(client.post(&url).send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+    let res = client
+        .post(format!("http://{}/api/auth/login", addr))
+        .json(&Credentials {
+            username: "testuser".to_string(),
+            password: "password".to_string(),
+        })
+        .send()
+        .await
+        .unwrap();
+    let cookie = res.headers()[header::SET_COOKIE].to_str().unwrap().split(';').next().unwrap().to_string();
+    let res = client.post(&url).header(header::COOKIE, cookie).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let token: Value = res.json().await.unwrap();
+    assert_eq!(token["token"].as_str().unwrap().len(), 32);
+    assert_eq!(token["expires_in_secs"], 60);
+}
+
+/// Starts a WebTransport server on a free port with a fresh development
+/// certificate. Returns the port and the certificate's hash for clients.
+async fn spawn_webtransport(services: &Services) -> (u16, [u8; 32]) {
+    tls::install_crypto_provider();
+    let dir = std::env::temp_dir().join(format!("hudwiz-wt-{}", uuid::Uuid::new_v4()));
+    let tls_config = TlsConfig {
+        cert_path: dir.join("cert.pem"),
+        key_path: dir.join("key.pem"),
+        ..TlsConfig::default()
+    };
+    tls::ensure_certificate(&tls_config).unwrap();
+    let cert_hash = tls::read_certificate(&tls_config.cert_path).unwrap().sha256;
+
+    let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut config = load_config().unwrap();
+    config.server.webtransport_addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let (cert_changes, cert_changes_rx) = watch::channel(0);
+    let services = services.clone();
+    tokio::spawn(async move {
+        let _cert_changes = cert_changes;
+        start_webtransport_server(config.server, tls_config, cert_changes_rx, services).await.unwrap();
+    });
+    (port, cert_hash)
+}
+
+async fn connect(port: u16, cert_hash: [u8; 32], query: &str) -> Result<Connection, wtransport::error::ConnectingError> {
+    let config = ClientConfig::builder()
+        .with_bind_default()
+        .with_server_certificate_hashes([Sha256Digest::new(cert_hash)])
+        .build();
+    Endpoint::client(config).unwrap().connect(format!("https://127.0.0.1:{}/{}", port, query)).await
+}
+
+async fn next_frame(recv: &mut RecvStream) -> Option<Envelope> {
+    let frame = tokio::time::timeout(Duration::from_secs(5), read_frame(recv))
+        .await
+        .expect("no frame arrived")
+        .unwrap()?;
+    Some(Envelope::decode(std::str::from_utf8(&frame).unwrap()).unwrap())
+}
+
+#[tokio::test]
+async fn test_sessions() {
+    let config = load_config().unwrap();
+    let services = Services::open(&config).unwrap();
+    services.hub.register(Topic::new("weather").logged_in());
+    services.hub.register(Topic::new("gauge").telemetry());
+    let (port, cert_hash) = spawn_webtransport(&services).await;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while services.webtransport.get().is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("WebTransport server didn't start");
+
+    // Topics for logged in users need a token, and a token is only good once.
+    assert!(connect(port, cert_hash, "?topics=weather").await.is_err());
+    let user = UserInfo {
+        id: "1".to_string(),
+        name: "alice".to_string(),
+        email: "alice@example.com".to_string(),
+    };
+    let token = services.session_tokens.issue(user);
+    let query = format!("?token={}&topics=weather,gauge", token);
+    let connection = connect(port, cert_hash, &query).await.unwrap();
+    assert!(connect(port, cert_hash, &query).await.is_err());
+
+    // The first stream carries the greeting, the replies to what is sent on
+    // it and the topic messages. Sending opens it on the server's side.
+    let (mut send, mut recv) = connection.open_bi().await.unwrap().await.unwrap();
+    let request = Envelope::new(Message::Text("hello".to_string()));
+    send.write_all(&frame(&request)).await.unwrap();
+    let greeting = next_frame(&mut recv).await.unwrap();
+    assert!(matches!(greeting.message, Message::Session { resumed: false, .. }), "{:?}", greeting);
+    assert_eq!(greeting.seq, None);
+    let reply = next_frame(&mut recv).await.unwrap();
+    assert_eq!(reply.correlation_id, Some(request.id));
+    assert_eq!(reply.seq, Some(1));
+
+    services.hub.publish("weather", json!("rain"));
+    let pushed = next_frame(&mut recv).await.unwrap();
+    assert_eq!(pushed.message, Message::Publish(json!("rain")));
+    assert_eq!(pushed.seq, Some(2));
+
+    // Telemetry arrives as datagrams, unsequenced.
+    services.hub.publish("gauge", json!(1));
+    let datagram = tokio::time::timeout(Duration::from_secs(5), connection.receive_datagram())
+        .await
+        .expect("no datagram arrived")
+        .unwrap();
+    let telemetry = Envelope::decode(std::str::from_utf8(&datagram).unwrap()).unwrap();
+    assert_eq!(telemetry.topic.as_deref(), Some("gauge"));
+    assert_eq!(telemetry.message, Message::Publish(json!(1)));
+    assert_eq!(telemetry.seq, None);
+
+    // Other streams get their own replies.
+    let (mut other_send, mut other_recv) = connection.open_bi().await.unwrap().await.unwrap();
+    let request = Envelope::new(Message::Text("hello".to_string()));
+    other_send.write_all(&frame(&request)).await.unwrap();
+    let reply = next_frame(&mut other_recv).await.unwrap();
+    assert_eq!(reply.correlation_id, Some(request.id.clone()));
+
+    // A stream that breaks framing is answered with an error and ended,
+    // leaving the session up.
+    let (mut bad_send, mut bad_recv) = connection.open_bi().await.unwrap().await.unwrap();
+    bad_send.write_all(&((MAX_FRAME_BYTES + 1) as u32).to_be_bytes()).await.unwrap();
+    let error = next_frame(&mut bad_recv).await.unwrap();
+    assert!(matches!(&error.message, Message::Error { message } if message.contains("over the limit")), "{:?}", error);
+    assert_eq!(next_frame(&mut bad_recv).await, None);
+
+    let request = Envelope::new(Message::Text("still there?".to_string()));
+    send.write_all(&frame(&request)).await.unwrap();
+    let reply = next_frame(&mut recv).await.unwrap();
+    assert_eq!(reply.correlation_id, Some(request.id));
+    services.hub.publish("weather", json!("sun"));
+    let pushed = next_frame(&mut recv).await.unwrap();
+    assert_eq!(pushed.message, Message::Publish(json!("sun")));
+    assert_eq!(pushed.seq, reply.seq.map(|seq| seq + 1));
+}
@@ -0,0 +1,55 @@
+//! The layout document, `config.json`: which component the frontend renders
+//! in which region.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Regions the frontend's `MainLayout` renders.
+pub const REGIONS: &[&str] = &[
+    "sidebar_left",
+    "sidebar_right",
+    "activity_bar",
+    "floating_toolbar",
+    "main_panel",
+    "hud_top_left",
+    "hud_top_right",
+    "hud_bottom_left",
+    "hud_bottom_right",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Config {
+    pub layout: LayoutConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "schema", schemars(extend("propertyNames" = {"enum": REGIONS})))]
+pub struct LayoutConfig {
+    /// Regions by name, one of `REGIONS`. Regions left out stay empty.
+    #[serde(flatten)]
+    pub regions: HashMap<String, RegionConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RegionConfig {
+    pub component: ComponentType,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ComponentType {
+    ControlsDrawer,
+    DetailsDrawer,
+    UserDrawer,
+    DrawingToolbar,
+    TopLeftHud,
+    TopRightHud,
+    BottomLeftHud,
+    BottomRightHud,
+    BentoGrid,
+    ChatInputBar,
+    ChatHistoryView,
+}
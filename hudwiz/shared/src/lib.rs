@@ -0,0 +1,4 @@
+//! Types the frontend and server both read and write, so the server can
+//! check documents against exactly what the frontend will parse.
+
+pub mod layout;
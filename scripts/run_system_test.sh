@@ -31,9 +31,18 @@ cp hudwiz/frontend/style/main.css hudwiz/frontend/dist/style/
 # Copy the static directory which contains config.json
 cp -r hudwiz/frontend/static hudwiz/frontend/dist/
 
+log "Precompressing static assets..."
+# The server sends file.br or file.gz to browsers accepting them, and the original otherwise.
+find hudwiz/frontend/dist -type f \( -name '*.wasm' -o -name '*.js' -o -name '*.css' -o -name '*.html' -o -name '*.json' \) \
+    -exec gzip -9 --keep --force {} \;
+if command -v brotli > /dev/null; then
+    find hudwiz/frontend/dist -type f \( -name '*.wasm' -o -name '*.js' -o -name '*.css' -o -name '*.html' -o -name '*.json' \) \
+        -exec brotli --keep --force {} \;
+fi
+
 log "Starting server in the background..."
 # Run the already-compiled binary, redirecting its logs
-./target/release/server > "$OUTPUT_DIR/server.log" 2>&1 &
+./target/release/hudwiz-server > "$OUTPUT_DIR/server.log" 2>&1 &
 SERVER_PID=$!
 # Ensure server is killed on script exit, and ignore errors if it's already gone
 trap 'log "Shutting down server..."; kill $SERVER_PID 2>/dev/null || true' EXIT